@@ -0,0 +1,119 @@
+//! Benchmarks of the measurement and export hot paths.
+//!
+//! Each benchmark runs a fixed number of iterations after a warm-up and
+//! prints the mean time per iteration.
+
+use std::hint::black_box;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bsec::bme::test_support::FakeBmeSensor;
+use bsec::clock::test_support::FakeClock;
+use bsec::{Bsec, Input, InputKind, Output, OutputKind, SampleRate, SubscriptionRequest};
+use linux_bsec_exporter::metrics::BsecGaugeRegistry;
+use prometheus::Encoder;
+
+const SENSORS: [OutputKind; 5] = [
+    OutputKind::RawTemperature,
+    OutputKind::RawPressure,
+    OutputKind::RawHumidity,
+    OutputKind::RawGas,
+    OutputKind::Co2Equivalent,
+];
+
+const WARM_UP_ITERATIONS: u32 = 100;
+const ITERATIONS: u32 = 10_000;
+
+fn bench<T>(name: &str, mut f: impl FnMut() -> T) {
+    for _ in 0..WARM_UP_ITERATIONS {
+        black_box(f());
+    }
+    let start = Instant::now();
+    for _ in 0..ITERATIONS {
+        black_box(f());
+    }
+    println!(
+        "{:<20} {:>10} ns/iter",
+        name,
+        start.elapsed().as_nanos() / u128::from(ITERATIONS)
+    );
+}
+
+fn bsec_cycle() {
+    let clock = Arc::new(FakeClock::new());
+    let bme = FakeBmeSensor::new(Ok(vec![
+        Input {
+            sensor: InputKind::Temperature,
+            signal: 22.,
+        },
+        Input {
+            sensor: InputKind::Pressure,
+            signal: 1013.,
+        },
+        Input {
+            sensor: InputKind::Humidity,
+            signal: 40.,
+        },
+        Input {
+            sensor: InputKind::GasResistor,
+            signal: 100_000.,
+        },
+    ]));
+    let mut bsec: Bsec<_, FakeClock, _> = Bsec::init(bme, clock.clone()).unwrap();
+    let subscriptions: Vec<_> = SENSORS
+        .iter()
+        .map(|&sensor| SubscriptionRequest {
+            sensor,
+            sample_rate: SampleRate::Lp,
+        })
+        .collect();
+    bsec.update_subscription(&subscriptions).unwrap();
+
+    bench("bsec_cycle", || {
+        clock.advance_by(Duration::from_secs(3));
+        let duration = nb::block!(bsec.start_next_measurement()).unwrap();
+        clock.advance_by(duration);
+        nb::block!(bsec.process_last_measurement()).unwrap()
+    });
+}
+
+fn outputs() -> Vec<Output> {
+    SENSORS
+        .iter()
+        .map(|&sensor| Output {
+            timestamp_ns: 0,
+            signal: 42.,
+            sensor,
+            accuracy: bsec::Accuracy::HighAccuracy,
+        })
+        .collect()
+}
+
+fn registry_update() {
+    let registry = BsecGaugeRegistry::new(&SENSORS).unwrap();
+    let outputs = outputs();
+    bench("registry_update", || {
+        for output in outputs.iter() {
+            registry.set(black_box(output));
+        }
+    });
+}
+
+fn metrics_encoding() {
+    let registry = BsecGaugeRegistry::new(&SENSORS).unwrap();
+    for output in outputs().iter() {
+        registry.set(output);
+    }
+    let encoder = prometheus::TextEncoder::new();
+    bench("metrics_encoding", || {
+        let mut buffer = vec![];
+        encoder.encode(&registry.gather(), &mut buffer).unwrap();
+        buffer
+    });
+}
+
+fn main() {
+    bsec_cycle();
+    registry_update();
+    metrics_encoding();
+}
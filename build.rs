@@ -0,0 +1,39 @@
+//! Checks of fully static builds with the `static` feature.
+//!
+//! Static builds require a musl target with a statically linked C runtime,
+//! the default of the musl targets. `libalgobsec-sys` links the BSEC library
+//! statically from the directory given by the `bsec_library_path` rustflag,
+//! which thus has to contain the `libalgobsec.a` variant for the target
+//! architecture. The checks fail the build early instead of producing a
+//! binary that depends on libraries missing on the device.
+
+use std::env;
+use std::path::Path;
+
+const LIBRARY: &str = "libalgobsec.a";
+
+fn main() {
+    println!("cargo:rerun-if-changed=build.rs");
+    if env::var_os("CARGO_FEATURE_STATIC").is_none() {
+        return;
+    }
+
+    let target_env = env::var("CARGO_CFG_TARGET_ENV").unwrap_or_default();
+    let target_features = env::var("CARGO_CFG_TARGET_FEATURE").unwrap_or_default();
+    if target_env != "musl" || !target_features.split(',').any(|f| f == "crt-static") {
+        panic!(
+            "The static feature requires a musl target with a statically linked C runtime, \
+             e.g. --target armv7-unknown-linux-musleabihf."
+        );
+    }
+
+    let library_dir = env::var("CARGO_CFG_BSEC_LIBRARY_PATH")
+        .unwrap_or_else(|_| panic!("The static feature requires the bsec_library_path rustflag."));
+    if !Path::new(&library_dir).join(LIBRARY).is_file() {
+        panic!(
+            "{} not found in the bsec_library_path {}, \
+             point it to the variant for the target architecture.",
+            LIBRARY, library_dir
+        );
+    }
+}
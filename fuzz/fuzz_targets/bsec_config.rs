@@ -0,0 +1,19 @@
+#![no_main]
+use std::sync::{Arc, Mutex};
+
+use bsec::Bsec;
+use lazy_static::lazy_static;
+use libfuzzer_sys::fuzz_target;
+use linux_bsec_exporter::config::parse_bsec_config;
+use linux_bsec_exporter::test_support::{fake_bsec, FakeBmeSensor, FakeClock};
+
+lazy_static! {
+    static ref BSEC: Mutex<Bsec<FakeBmeSensor, FakeClock, Arc<FakeClock>>> =
+        Mutex::new(fake_bsec(vec![], &[], Arc::new(FakeClock::new())));
+}
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(config) = parse_bsec_config(data) {
+        let _ = BSEC.lock().unwrap().set_configuration(config);
+    }
+});
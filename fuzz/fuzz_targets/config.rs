@@ -0,0 +1,7 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+use linux_bsec_exporter::config::Config;
+
+fuzz_target!(|data: &str| {
+    let _ = toml::from_str::<Config>(data);
+});
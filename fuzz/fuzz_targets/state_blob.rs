@@ -0,0 +1,24 @@
+#![no_main]
+use std::fs;
+use std::sync::{Arc, Mutex};
+
+use bsec::Bsec;
+use lazy_static::lazy_static;
+use libfuzzer_sys::fuzz_target;
+use linux_bsec_exporter::monitor::PersistState;
+use linux_bsec_exporter::persistance::StateFile;
+use linux_bsec_exporter::test_support::{fake_bsec, FakeBmeSensor, FakeClock};
+
+lazy_static! {
+    static ref BSEC: Mutex<Bsec<FakeBmeSensor, FakeClock, Arc<FakeClock>>> =
+        Mutex::new(fake_bsec(vec![], &[], Arc::new(FakeClock::new())));
+    static ref STATE_PATH: std::path::PathBuf =
+        std::env::temp_dir().join(format!("bsec-state-fuzz-{}.bin", std::process::id()));
+}
+
+fuzz_target!(|data: &[u8]| {
+    fs::write(&*STATE_PATH, data).unwrap();
+    if let Ok(Some(state)) = StateFile::new(&*STATE_PATH).load_state() {
+        let _ = BSEC.lock().unwrap().set_state(&state);
+    }
+});
@@ -0,0 +1,480 @@
+//! Built-in alerting on the output history.
+//!
+//! In contrast to the generated Prometheus rules of [`crate::alerts`], the
+//! conditions are evaluated by the exporter itself on the samples of the
+//! [`History`](crate::history::History) whenever a sample is added, so that no
+//! Prometheus server is required. The state of each rule is exported as
+//! `bsec_alert_firing` metric. The firing alerts are listed by the
+//! `/api/v1/alerts` endpoint, which also allows acknowledging them to silence
+//! their notifications for a period. The acknowledgements are persisted
+//! across restarts.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use bsec::OutputKind;
+use serde::{Deserialize, Serialize};
+
+use crate::config::{deserialize_output_kind, output_kind_name, serialize_output_kind};
+use crate::history::Sample;
+use crate::maintenance::ReadOnlySwitch;
+use crate::persistance::write_atomically;
+use crate::{log_error, log_warn};
+
+/// Condition of an alerting rule.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum Condition {
+    /// Least-squares slope of the output over the window in units per
+    /// minute, e.g. to catch a room filling up faster than absolute
+    /// thresholds would.
+    RateOfChange {
+        /// Fires if the output rises faster than this.
+        #[serde(default)]
+        above_per_minute: Option<f64>,
+        /// Fires if the output falls faster than this (given as negative
+        /// value).
+        #[serde(default)]
+        below_per_minute: Option<f64>,
+        #[serde(default = "default_window_minutes")]
+        window_minutes: u64,
+    },
+}
+
+fn default_window_minutes() -> u64 {
+    10
+}
+
+impl Condition {
+    fn window_seconds(&self) -> u64 {
+        match self {
+            Condition::RateOfChange { window_minutes, .. } => window_minutes * 60,
+        }
+    }
+
+    /// Value the condition is based on and whether it is met.
+    fn evaluate(&self, sensor: OutputKind, samples: &[Sample]) -> Option<(f64, bool)> {
+        match *self {
+            Condition::RateOfChange {
+                above_per_minute,
+                below_per_minute,
+                ..
+            } => {
+                let rate = rate_per_minute(sensor, samples, self.window_seconds())?;
+                let met = above_per_minute.is_some_and(|above| rate > above)
+                    || below_per_minute.is_some_and(|below| rate < below);
+                Some((rate, met))
+            }
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct AlertingRule {
+    /// Name of the rule used as `alert` label.
+    pub name: String,
+
+    #[serde(
+        deserialize_with = "deserialize_output_kind",
+        serialize_with = "serialize_output_kind"
+    )]
+    pub output: OutputKind,
+
+    pub condition: Condition,
+}
+
+/// Least-squares slope of the output in the samples within the window before
+/// the most recent sample, `None` if there are not enough samples.
+pub fn rate_per_minute(sensor: OutputKind, samples: &[Sample], window_seconds: u64) -> Option<f64> {
+    let latest = samples.last()?.timestamp;
+    let points: Vec<(f64, f64)> = samples
+        .iter()
+        .filter(|sample| sample.timestamp + window_seconds >= latest)
+        .filter_map(|sample| {
+            let (_, signal) = sample.values.iter().find(|(kind, _)| *kind == sensor)?;
+            let minutes = -((latest - sample.timestamp) as f64) / 60.;
+            Some((minutes, f64::from(*signal)))
+        })
+        .filter(|(_, signal)| !signal.is_nan())
+        .collect();
+    if points.len() < 2 {
+        return None;
+    }
+    let n = points.len() as f64;
+    let mean_t = points.iter().map(|(t, _)| t).sum::<f64>() / n;
+    let mean_v = points.iter().map(|(_, v)| v).sum::<f64>() / n;
+    let covariance: f64 = points
+        .iter()
+        .map(|(t, v)| (t - mean_t) * (v - mean_v))
+        .sum();
+    let variance: f64 = points.iter().map(|(t, _)| (t - mean_t).powi(2)).sum();
+    if variance == 0. {
+        return None;
+    }
+    Some(covariance / variance)
+}
+
+/// Change of the state of an alerting rule.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertChange {
+    pub name: String,
+    pub firing: bool,
+    /// Value the condition is based on, e.g. the rate of change.
+    pub value: f64,
+}
+
+/// Evaluates the alerting rules and tracks which are firing.
+#[derive(Debug, Default)]
+pub struct AlertEngine {
+    rules: Vec<AlertingRule>,
+    firing: Vec<bool>,
+}
+
+impl AlertEngine {
+    /// Engine for the `rules` evaluated on a history keeping a sample every
+    /// `history_interval_seconds`.
+    pub fn new(rules: &[AlertingRule], history_interval_seconds: u64) -> anyhow::Result<Self> {
+        for (i, rule) in rules.iter().enumerate() {
+            if rules[..i].iter().any(|other| other.name == rule.name) {
+                anyhow::bail!("duplicate alerting rule {}", rule.name);
+            }
+            match rule.condition {
+                Condition::RateOfChange {
+                    above_per_minute: None,
+                    below_per_minute: None,
+                    ..
+                } => anyhow::bail!(
+                    "alerting rule {} on {} has no threshold",
+                    rule.name,
+                    output_kind_name(rule.output)
+                ),
+                Condition::RateOfChange {
+                    window_minutes: 0, ..
+                } => anyhow::bail!("alerting rule {} has an empty window", rule.name),
+                Condition::RateOfChange { window_minutes, .. }
+                    if window_minutes.checked_mul(60).is_none() =>
+                {
+                    anyhow::bail!("the window of alerting rule {} is too long", rule.name)
+                }
+                _ => (),
+            }
+            if rule.condition.window_seconds() < history_interval_seconds {
+                anyhow::bail!(
+                    "the window of alerting rule {} is shorter than the history interval of {} s",
+                    rule.name,
+                    history_interval_seconds
+                );
+            }
+        }
+        Ok(Self {
+            rules: rules.to_vec(),
+            firing: vec![false; rules.len()],
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Names of the rules with whether they are firing.
+    pub fn states(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.rules
+            .iter()
+            .zip(self.firing.iter())
+            .map(|(rule, firing)| (rule.name.as_str(), *firing))
+    }
+
+    /// Longest window of the conditions in seconds.
+    pub fn max_window_seconds(&self) -> u64 {
+        self.rules
+            .iter()
+            .map(|rule| rule.condition.window_seconds())
+            .max()
+            .unwrap_or_default()
+    }
+
+    /// Evaluates the rules on the `samples` and returns the changes. Rules
+    /// without enough samples keep their state.
+    pub fn evaluate(&mut self, samples: &[Sample]) -> Vec<AlertChange> {
+        let mut changes = vec![];
+        for (rule, firing) in self.rules.iter().zip(self.firing.iter_mut()) {
+            if let Some((value, met)) = rule.condition.evaluate(rule.output, samples) {
+                if met != *firing {
+                    *firing = met;
+                    changes.push(AlertChange {
+                        name: rule.name.clone(),
+                        firing: met,
+                        value,
+                    });
+                }
+            }
+        }
+        changes
+    }
+}
+
+/// Firing alert as listed by the API.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ActiveAlert {
+    /// Name of the rule.
+    pub id: String,
+    /// Seconds since the Unix epoch since which the alert is firing.
+    pub since: u64,
+    pub value: f64,
+    /// Seconds since the Unix epoch until which the alert is acknowledged.
+    pub acknowledged_until: Option<u64>,
+}
+
+#[derive(Debug, Default)]
+struct Alerts {
+    /// Start and value of the firing alerts by name.
+    firing: BTreeMap<String, (u64, f64)>,
+    /// End of the acknowledgement by alert name.
+    acknowledged: BTreeMap<String, u64>,
+    file: Option<PathBuf>,
+}
+
+/// Firing and acknowledged alerts shared between the monitoring, the
+/// notifications, and the API.
+#[derive(Clone, Debug, Default)]
+pub struct ActiveAlerts {
+    alerts: Arc<Mutex<Alerts>>,
+    read_only: ReadOnlySwitch,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl ActiveAlerts {
+    /// Loads the acknowledgements from `path` and saves new ones to it.
+    ///
+    /// Corrupted acknowledgements are discarded with a warning.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let acknowledged = match fs::read(&path) {
+            Ok(content) => serde_json::from_slice(&content).unwrap_or_else(|err| {
+                log_warn!(
+                    "Discarding the corrupted alert acknowledgements in {}: {}",
+                    path.as_ref().display(),
+                    err
+                );
+                BTreeMap::new()
+            }),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        Ok(Self {
+            alerts: Arc::new(Mutex::new(Alerts {
+                firing: BTreeMap::new(),
+                acknowledged,
+                file: Some(path.as_ref().into()),
+            })),
+            read_only: ReadOnlySwitch::new(),
+        })
+    }
+
+    /// Keeps new acknowledgements only in memory while the switch is in
+    /// read-only mode.
+    pub fn with_read_only(mut self, read_only: ReadOnlySwitch) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn update(&self, change: &AlertChange) {
+        let mut alerts = self.alerts.lock().unwrap();
+        if change.firing {
+            alerts
+                .firing
+                .insert(change.name.clone(), (unix_now(), change.value));
+        } else {
+            alerts.firing.remove(&change.name);
+        }
+    }
+
+    /// Firing alerts at `now` in seconds since the Unix epoch.
+    pub fn firing_at(&self, now: u64) -> Vec<ActiveAlert> {
+        let alerts = self.alerts.lock().unwrap();
+        alerts
+            .firing
+            .iter()
+            .map(|(id, (since, value))| ActiveAlert {
+                id: id.clone(),
+                since: *since,
+                value: *value,
+                acknowledged_until: alerts
+                    .acknowledged
+                    .get(id)
+                    .copied()
+                    .filter(|until| *until > now),
+            })
+            .collect()
+    }
+
+    pub fn firing(&self) -> Vec<ActiveAlert> {
+        self.firing_at(unix_now())
+    }
+
+    /// Silences the notifications of the firing alert until `until` in
+    /// seconds since the Unix epoch. Returns whether the alert is firing.
+    pub fn acknowledge(&self, id: &str, until: u64) -> bool {
+        let mut alerts = self.alerts.lock().unwrap();
+        if !alerts.firing.contains_key(id) {
+            return false;
+        }
+        alerts.acknowledged.insert(id.to_string(), until);
+        let now = unix_now();
+        alerts.acknowledged.retain(|_, until| *until > now);
+        if !self.read_only.is_read_only() {
+            if let Some(file) = &alerts.file {
+                let result = serde_json::to_vec(&alerts.acknowledged)
+                    .map_err(io::Error::from)
+                    .and_then(|content| write_atomically(file, &content));
+                if let Err(err) = result {
+                    log_error!("Failed to persist the alert acknowledgements: {}", err);
+                }
+            }
+        }
+        true
+    }
+
+    pub fn is_acknowledged_at(&self, id: &str, now: u64) -> bool {
+        self.alerts
+            .lock()
+            .unwrap()
+            .acknowledged
+            .get(id)
+            .is_some_and(|until| *until > now)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(values: &[f32]) -> Vec<Sample> {
+        values
+            .iter()
+            .enumerate()
+            .map(|(i, value)| Sample {
+                timestamp: 1_000 + 60 * i as u64,
+                values: vec![(OutputKind::Co2Equivalent, *value)],
+                accuracy: None,
+            })
+            .collect()
+    }
+
+    fn rule() -> AlertingRule {
+        AlertingRule {
+            name: "co2_rising".into(),
+            output: OutputKind::Co2Equivalent,
+            condition: Condition::RateOfChange {
+                above_per_minute: Some(50.),
+                below_per_minute: None,
+                window_minutes: 3,
+            },
+        }
+    }
+
+    #[test]
+    fn test_rate_per_minute() {
+        let samples = samples(&[0., 400., 500., 600., 700.]);
+        let rate = rate_per_minute(OutputKind::Co2Equivalent, &samples, 180).unwrap();
+        assert!((rate - 100.).abs() < 1e-9);
+        assert_eq!(rate_per_minute(OutputKind::Iaq, &samples, 180), None);
+        assert_eq!(
+            rate_per_minute(OutputKind::Co2Equivalent, &samples[..1], 180),
+            None
+        );
+    }
+
+    #[test]
+    fn test_alert_engine() {
+        let mut engine = AlertEngine::new(&[rule()], 60).unwrap();
+        assert_eq!(engine.evaluate(&samples(&[400.])), vec![]);
+        assert_eq!(
+            engine.evaluate(&samples(&[400., 500., 600.])),
+            vec![AlertChange {
+                name: "co2_rising".into(),
+                firing: true,
+                value: 100.,
+            }]
+        );
+        assert_eq!(engine.evaluate(&samples(&[400., 500., 600., 700.])), vec![]);
+        let changes = engine.evaluate(&samples(&[400., 500., 600., 600., 600., 600.]));
+        assert_eq!(changes.len(), 1);
+        assert!(!changes[0].firing);
+        assert_eq!(
+            engine.states().collect::<Vec<_>>(),
+            vec![("co2_rising", false)]
+        );
+    }
+
+    #[test]
+    fn test_active_alerts_acknowledgement() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("alert-acks.json");
+        let alerts = ActiveAlerts::load(&path).unwrap();
+        let firing = AlertChange {
+            name: "co2_rising".into(),
+            firing: true,
+            value: 60.,
+        };
+        let until = unix_now() + 3600;
+        assert!(!alerts.acknowledge("co2_rising", until));
+        alerts.update(&firing);
+        assert!(alerts.acknowledge("co2_rising", until));
+        assert_eq!(alerts.firing()[0].acknowledged_until, Some(until));
+        assert!(!alerts.is_acknowledged_at("co2_rising", until));
+
+        let restored = ActiveAlerts::load(&path).unwrap();
+        restored.update(&firing);
+        assert!(restored.is_acknowledged_at("co2_rising", until - 1));
+        restored.update(&AlertChange {
+            firing: false,
+            ..firing
+        });
+        assert_eq!(restored.firing(), vec![]);
+    }
+
+    #[test]
+    fn test_active_alerts_discard_corrupted_acknowledgements() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("alert-acks.json");
+        fs::write(&path, "{\"co2_rising\": ").unwrap();
+        let alerts = ActiveAlerts::load(&path).unwrap();
+        alerts.update(&AlertChange {
+            name: "co2_rising".into(),
+            firing: true,
+            value: 60.,
+        });
+        assert!(!alerts.is_acknowledged_at("co2_rising", unix_now()));
+    }
+
+    #[test]
+    fn test_rejects_invalid_rules() {
+        assert!(AlertEngine::new(&[rule(), rule()], 60).is_err());
+        assert!(AlertEngine::new(&[rule()], 180).is_ok());
+        assert!(AlertEngine::new(&[rule()], 181).is_err());
+        let mut without_threshold = rule();
+        without_threshold.condition = Condition::RateOfChange {
+            above_per_minute: None,
+            below_per_minute: None,
+            window_minutes: 10,
+        };
+        assert!(AlertEngine::new(&[without_threshold], 60).is_err());
+        let mut overflowing_window = rule();
+        overflowing_window.condition = Condition::RateOfChange {
+            above_per_minute: Some(1.),
+            below_per_minute: None,
+            window_minutes: u64::MAX,
+        };
+        assert!(AlertEngine::new(&[overflowing_window], 60).is_err());
+    }
+}
@@ -0,0 +1,137 @@
+//! Generation of Prometheus alerting rules for the exported metrics.
+
+use std::fmt::Write;
+
+use bsec::OutputKind;
+
+use crate::config::output_kind_name;
+use crate::metrics::MetricSchema;
+
+/// IAQ above which the air quality is considered unhealthy.
+pub const IAQ_THRESHOLD: u32 = 200;
+
+/// An alerting rule in the Prometheus rule format.
+#[derive(Clone, Debug, PartialEq)]
+pub struct AlertRule {
+    pub alert: String,
+    pub expr: String,
+    pub duration: &'static str,
+    pub severity: &'static str,
+    pub summary: String,
+}
+
+/// Rules for stale data and low accuracy for each metric and thresholds for
+/// the IAQ if exported.
+pub fn rules(schema: &[MetricSchema]) -> Vec<AlertRule> {
+    let mut rules = vec![AlertRule {
+        alert: "BsecMonitoringStalled".into(),
+        expr: "increase(bsec_watchdog_stalls_total[15m]) > 0".into(),
+        duration: "0m",
+        severity: "warning",
+        summary: "The BSEC monitoring stalled.".into(),
+    }];
+    for metric in schema {
+        let alert_name = camel_case(&metric.name);
+        rules.push(AlertRule {
+            alert: format!("Bsec{}Stale", alert_name),
+            // The gauges keep their last value, so stale data shows up as a
+            // value that no longer changes rather than an absent one.
+            expr: format!("changes({}[15m]) == 0", metric.name),
+            duration: "0m",
+            severity: "warning",
+            summary: format!("No new {} data for 15 minutes.", metric.name),
+        });
+        rules.push(AlertRule {
+            alert: format!("Bsec{}LowAccuracy", alert_name),
+            expr: format!("{} < 2", metric.accuracy_name),
+            duration: "6h",
+            severity: "info",
+            summary: format!("Low accuracy of {} for 6 hours.", metric.name),
+        });
+        if metric.output_kind == output_kind_name(OutputKind::Iaq) {
+            rules.push(AlertRule {
+                alert: "BsecIaqUnhealthy".into(),
+                expr: format!(
+                    "{} > {} and {} >= 2",
+                    metric.name, IAQ_THRESHOLD, metric.accuracy_name
+                ),
+                duration: "15m",
+                severity: "warning",
+                summary: format!("IAQ above {} for 15 minutes.", IAQ_THRESHOLD),
+            });
+        }
+    }
+    rules
+}
+
+fn camel_case(name: &str) -> String {
+    name.split('_')
+        .map(|word| {
+            let mut chars = word.chars();
+            match chars.next() {
+                Some(first) => first.to_uppercase().chain(chars).collect(),
+                None => String::new(),
+            }
+        })
+        .collect()
+}
+
+/// Renders the rules as a Prometheus rule file.
+pub fn to_yaml(rules: &[AlertRule]) -> String {
+    let mut yaml = String::from("groups:\n  - name: bsec\n    rules:\n");
+    for rule in rules {
+        // Writing to a String cannot fail.
+        let _ = write!(
+            yaml,
+            "      - alert: {}\n        expr: '{}'\n        for: {}\n        labels:\n          severity: {}\n        annotations:\n          summary: '{}'\n",
+            rule.alert,
+            rule.expr.replace('\'', "''"),
+            rule.duration,
+            rule.severity,
+            rule.summary.replace('\'', "''"),
+        );
+    }
+    yaml
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_rules() {
+        let rules = rules(&[
+            MetricSchema::from(OutputKind::Iaq),
+            MetricSchema::from(OutputKind::SensorHeatCompensatedTemperature),
+        ]);
+        let alerts: Vec<&str> = rules.iter().map(|rule| rule.alert.as_str()).collect();
+        assert_eq!(
+            alerts,
+            vec![
+                "BsecMonitoringStalled",
+                "BsecIaqStale",
+                "BsecIaqLowAccuracy",
+                "BsecIaqUnhealthy",
+                "BsecTemperatureCelsiusStale",
+                "BsecTemperatureCelsiusLowAccuracy",
+            ]
+        );
+        assert_eq!(rules[1].expr, "changes(iaq[15m]) == 0");
+        assert_eq!(rules[5].expr, "temperature_accuracy < 2");
+    }
+
+    #[test]
+    fn test_to_yaml() {
+        let yaml = to_yaml(&[AlertRule {
+            alert: "Test".into(),
+            expr: "up == 0".into(),
+            duration: "5m",
+            severity: "warning",
+            summary: "It's down.".into(),
+        }]);
+        assert_eq!(
+            yaml,
+            "groups:\n  - name: bsec\n    rules:\n      - alert: Test\n        expr: 'up == 0'\n        for: 5m\n        labels:\n          severity: warning\n        annotations:\n          summary: 'It''s down.'\n"
+        );
+    }
+}
@@ -0,0 +1,215 @@
+//! Bearer token authorization of the HTTP endpoints.
+//!
+//! Each configured token grants a set of scopes. The endpoints require the
+//! scope of their kind, e.g. the metrics endpoints the `metrics` scope and
+//! the control endpoints the `control` scope. Without tokens, all endpoints
+//! are public. Once any token is configured, the control endpoints require a
+//! token granting the `control` scope, while the `metrics` scope stays public
+//! unless a token grants it, so that, for example, the metrics can be exposed
+//! while the control endpoints are locked.
+
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use tide::http::headers::{AUTHORIZATION, WWW_AUTHENTICATE};
+use tide::{utils::async_trait, Middleware, Next, Request, Response, Result, StatusCode};
+
+use crate::config::{AuthConfig, TokenConfig};
+
+/// Kind of endpoints a token grants access to.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    /// Metrics and read-only API endpoints.
+    Metrics,
+    /// Endpoints controlling or debugging the exporter.
+    Control,
+}
+
+/// Compares without returning early to not leak the matching prefix length.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (a, b)| diff | (a ^ b)) == 0
+}
+
+/// Checks the tokens of the requests.
+#[derive(Clone, Debug, Default)]
+pub struct TokenAuth {
+    tokens: Arc<Vec<TokenConfig>>,
+}
+
+impl TokenAuth {
+    pub fn new(config: &AuthConfig) -> Self {
+        Self {
+            tokens: Arc::new(config.tokens.clone()),
+        }
+    }
+
+    /// Whether the `scope` is accessible without a token.
+    fn is_public(&self, scope: Scope) -> bool {
+        match scope {
+            Scope::Metrics => !self
+                .tokens
+                .iter()
+                .any(|token| token.scopes.contains(&scope)),
+            Scope::Control => self.tokens.is_empty(),
+        }
+    }
+
+    /// Checks the value of the `Authorization` header for the `scope`.
+    pub fn authorize(&self, authorization: Option<&str>, scope: Scope) -> StatusCode {
+        if self.is_public(scope) {
+            return StatusCode::Ok;
+        }
+        let token = match authorization.and_then(|value| value.strip_prefix("Bearer ")) {
+            Some(token) => token.trim(),
+            None => return StatusCode::Unauthorized,
+        };
+        match self
+            .tokens
+            .iter()
+            .find(|config| constant_time_eq(config.token.as_bytes(), token.as_bytes()))
+        {
+            Some(config) if config.scopes.contains(&scope) => StatusCode::Ok,
+            Some(_) => StatusCode::Forbidden,
+            None => StatusCode::Unauthorized,
+        }
+    }
+
+    /// Middleware rejecting requests without a token for the `scope`.
+    pub fn require(&self, scope: Scope) -> RequireScope {
+        RequireScope {
+            auth: self.clone(),
+            scope,
+        }
+    }
+}
+
+pub struct RequireScope {
+    auth: TokenAuth,
+    scope: Scope,
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for RequireScope {
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+        let authorization = request.header(AUTHORIZATION).map(|value| value.as_str());
+        match self.auth.authorize(authorization, self.scope) {
+            StatusCode::Ok => Ok(next.run(request).await),
+            status => {
+                let mut response = Response::new(status);
+                if status == StatusCode::Unauthorized {
+                    response.insert_header(WWW_AUTHENTICATE, "Bearer");
+                }
+                Ok(response)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn auth() -> TokenAuth {
+        TokenAuth::new(&AuthConfig {
+            tokens: vec![TokenConfig {
+                token: "secret".into(),
+                scopes: vec![Scope::Control],
+            }],
+        })
+    }
+
+    #[test]
+    fn test_authorize() {
+        let auth = auth();
+        assert_eq!(auth.authorize(None, Scope::Metrics), StatusCode::Ok);
+        assert_eq!(
+            auth.authorize(None, Scope::Control),
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer other"), Scope::Control),
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer secret"), Scope::Control),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            TokenAuth::default().authorize(None, Scope::Control),
+            StatusCode::Ok
+        );
+    }
+
+    #[test]
+    fn test_control_locked_with_any_token() {
+        let auth = TokenAuth::new(&AuthConfig {
+            tokens: vec![TokenConfig {
+                token: "metrics".into(),
+                scopes: vec![Scope::Metrics],
+            }],
+        });
+        assert_eq!(
+            auth.authorize(None, Scope::Control),
+            StatusCode::Unauthorized
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer metrics"), Scope::Control),
+            StatusCode::Forbidden
+        );
+    }
+
+    #[test]
+    fn test_token_without_scope_is_forbidden() {
+        let auth = TokenAuth::new(&AuthConfig {
+            tokens: vec![
+                TokenConfig {
+                    token: "metrics".into(),
+                    scopes: vec![Scope::Metrics],
+                },
+                TokenConfig {
+                    token: "admin".into(),
+                    scopes: vec![Scope::Metrics, Scope::Control],
+                },
+            ],
+        });
+        assert_eq!(
+            auth.authorize(Some("Bearer metrics"), Scope::Metrics),
+            StatusCode::Ok
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer metrics"), Scope::Control),
+            StatusCode::Forbidden
+        );
+        assert_eq!(
+            auth.authorize(Some("Bearer admin"), Scope::Control),
+            StatusCode::Ok
+        );
+    }
+
+    #[tokio::test]
+    async fn test_require_scope() {
+        use tide::http::{Method, Url};
+
+        let mut app = tide::new();
+        app.at("/api/v1/gas")
+            .with(auth().require(Scope::Control))
+            .get(|_| async { Ok("gas") });
+        let request = |token: Option<&str>| {
+            let mut request = tide::http::Request::new(
+                Method::Get,
+                Url::parse("http://localhost/api/v1/gas").unwrap(),
+            );
+            if let Some(token) = token {
+                request.insert_header(AUTHORIZATION, format!("Bearer {}", token));
+            }
+            request
+        };
+
+        let response: tide::http::Response = app.respond(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Unauthorized);
+        assert_eq!(response[WWW_AUTHENTICATE], "Bearer");
+        let response: tide::http::Response = app.respond(request(Some("secret"))).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+    }
+}
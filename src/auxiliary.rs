@@ -0,0 +1,280 @@
+//! Auxiliary sources of BSEC inputs.
+//!
+//! Readings of external sensors, e.g. an SHT31 with a better humidity
+//! accuracy than the BME680, are injected as BSEC inputs before processing,
+//! replacing the inputs measured by the sensor. The sources are polled
+//! periodically. Once the last reading is older than the maximum age, the
+//! measured input is used again until the source recovers.
+//!
+//! BSEC expects the relative humidity at the die temperature of the sensor,
+//! which is warmer than the ambient air measured by an external sensor. An
+//! auxiliary humidity is thus converted through the absolute humidity to the
+//! die temperature, unless the temperature is replaced as well.
+
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::{Input, InputKind};
+
+use crate::config::{AuxiliaryInputConfig, AuxiliaryInputKind, Config};
+use crate::{log_error, log_info};
+
+/// Latest reading of an auxiliary input shared between the polling and the
+/// sensor.
+#[derive(Clone, Debug)]
+pub struct AuxiliaryInput {
+    kind: InputKind,
+    max_age: Duration,
+    reading: Arc<Mutex<Option<(f32, Instant)>>>,
+}
+
+impl AuxiliaryInput {
+    pub fn new(kind: InputKind, max_age: Duration) -> Self {
+        Self {
+            kind,
+            max_age,
+            reading: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn from_config(config: &AuxiliaryInputConfig) -> Self {
+        Self::new(
+            config.input.into(),
+            Duration::from_secs(config.max_age_seconds),
+        )
+    }
+
+    pub fn kind(&self) -> InputKind {
+        self.kind
+    }
+
+    pub fn set(&self, signal: f32) {
+        *self.reading.lock().unwrap() = Some((signal, Instant::now()));
+    }
+
+    /// Latest reading, `None` if there is none or it is stale.
+    pub fn current(&self) -> Option<f32> {
+        match *self.reading.lock().unwrap() {
+            Some((signal, read)) if read.elapsed() <= self.max_age => Some(signal),
+            _ => None,
+        }
+    }
+}
+
+/// Checks that an auxiliary heat source does not replace the temperature
+/// offset of the configuration or the temperature calibration.
+pub fn check_heat_source(config: &Config) -> anyhow::Result<()> {
+    let heat_source = config
+        .auxiliary_inputs
+        .iter()
+        .any(|auxiliary| auxiliary.input == AuxiliaryInputKind::HeatSource);
+    if heat_source
+        && (config.temperature_calibration.is_some()
+            || config.bsec.temperature_offset_celsius != 0.)
+    {
+        anyhow::bail!(
+            "an auxiliary heat_source input replaces the temperature offset; remove \
+             temperature_offset_celsius and the [temperature_calibration] section"
+        );
+    }
+    Ok(())
+}
+
+/// Relative humidity in %RH of air at `from_celsius` when warmed or cooled to
+/// `to_celsius` at the same absolute humidity.
+pub fn convert_relative_humidity(humidity: f32, from_celsius: f32, to_celsius: f32) -> f32 {
+    // Magnus formula of the saturation vapor pressure over water.
+    let saturation = |celsius: f32| (17.62 * celsius / (243.12 + celsius)).exp();
+    (humidity * saturation(from_celsius) / saturation(to_celsius)).clamp(0., 100.)
+}
+
+/// Converts the humidity `inputs` from the ambient temperature, i.e. the
+/// temperature input less the heat source, to the temperature input.
+fn humidity_at_die_temperature(inputs: &mut [Input]) {
+    let signal = |inputs: &[Input], kind: InputKind| {
+        inputs
+            .iter()
+            .find(|input| input.sensor == kind)
+            .map(|input| input.signal)
+    };
+    let temperature = match signal(inputs, InputKind::Temperature) {
+        Some(temperature) => temperature,
+        None => return,
+    };
+    let ambient = temperature - signal(inputs, InputKind::HeatSource).unwrap_or(0.);
+    for input in inputs.iter_mut() {
+        if input.sensor == InputKind::Humidity {
+            input.signal = convert_relative_humidity(input.signal, ambient, temperature);
+        }
+    }
+}
+
+/// Polls the source of the auxiliary input at the configured interval.
+pub async fn run_auxiliary_input(config: AuxiliaryInputConfig, input: AuxiliaryInput) {
+    let mut interval = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+    let mut failing = false;
+    loop {
+        interval.tick().await;
+        match config.source.read().await {
+            Ok(signal) => {
+                if failing {
+                    log_info!("Reading the auxiliary {:?} input recovered.", input.kind);
+                    failing = false;
+                }
+                input.set(signal as f32);
+            }
+            Err(err) if !failing => {
+                log_error!(
+                    "Failed to read the auxiliary {:?} input: {}",
+                    input.kind,
+                    err
+                );
+                failing = true;
+            }
+            Err(_) => (),
+        }
+    }
+}
+
+/// Replaces the inputs of the wrapped sensor with the current readings of
+/// the auxiliary inputs.
+pub struct AuxiliarySensor<S: BmeSensor> {
+    sensor: S,
+    inputs: Vec<AuxiliaryInput>,
+}
+
+impl<S: BmeSensor> AuxiliarySensor<S> {
+    pub fn new(sensor: S, inputs: Vec<AuxiliaryInput>) -> Self {
+        Self { sensor, inputs }
+    }
+}
+
+impl<S: BmeSensor> BmeSensor for AuxiliarySensor<S> {
+    type Error = S::Error;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        self.sensor.start_measurement(settings)
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        let mut inputs = self.sensor.get_measurement()?;
+        let mut replaced = vec![];
+        for auxiliary in self.inputs.iter() {
+            if let Some(signal) = auxiliary.current() {
+                inputs.retain(|input| input.sensor != auxiliary.kind);
+                inputs.push(Input {
+                    sensor: auxiliary.kind,
+                    signal,
+                });
+                replaced.push(auxiliary.kind);
+            }
+        }
+        if replaced.contains(&InputKind::Humidity) && !replaced.contains(&InputKind::Temperature) {
+            humidity_at_die_temperature(&mut inputs);
+        }
+        Ok(inputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::FakeBmeSensor;
+
+    #[test]
+    fn test_replaces_measured_inputs() {
+        let humidity = AuxiliaryInput::new(InputKind::Humidity, Duration::from_secs(60));
+        let heat_source = AuxiliaryInput::new(InputKind::HeatSource, Duration::from_secs(60));
+        let mut sensor = AuxiliarySensor::new(
+            FakeBmeSensor::new(Ok(vec![
+                Input {
+                    sensor: InputKind::Temperature,
+                    signal: 22.,
+                },
+                Input {
+                    sensor: InputKind::Humidity,
+                    signal: 40.,
+                },
+            ])),
+            vec![humidity.clone(), heat_source],
+        );
+
+        let signals = |sensor: &mut AuxiliarySensor<FakeBmeSensor>| {
+            sensor
+                .get_measurement()
+                .unwrap()
+                .iter()
+                .map(|input| (input.sensor, input.signal))
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            signals(&mut sensor),
+            vec![(InputKind::Temperature, 22.), (InputKind::Humidity, 40.)]
+        );
+
+        humidity.set(45.5);
+        assert_eq!(
+            signals(&mut sensor),
+            vec![(InputKind::Temperature, 22.), (InputKind::Humidity, 45.5)]
+        );
+    }
+
+    #[test]
+    fn test_ignores_stale_readings() {
+        let input = AuxiliaryInput::new(InputKind::Humidity, Duration::ZERO);
+        assert_eq!(input.current(), None);
+        input.set(45.);
+        std::thread::sleep(Duration::from_millis(1));
+        assert_eq!(input.current(), None);
+
+        let input = AuxiliaryInput::new(InputKind::Humidity, Duration::from_secs(60));
+        input.set(45.);
+        assert_eq!(input.current(), Some(45.));
+    }
+
+    #[test]
+    fn test_converts_humidity_to_die_temperature() {
+        assert_eq!(convert_relative_humidity(50., 20., 20.), 50.);
+        assert!((convert_relative_humidity(50., 20., 25.) - 36.9).abs() < 0.1);
+        assert_eq!(convert_relative_humidity(90., 20., 10.), 100.);
+
+        let humidity = AuxiliaryInput::new(InputKind::Humidity, Duration::from_secs(60));
+        humidity.set(50.);
+        let mut sensor = AuxiliarySensor::new(
+            FakeBmeSensor::new(Ok(vec![
+                Input {
+                    sensor: InputKind::Temperature,
+                    signal: 25.,
+                },
+                Input {
+                    sensor: InputKind::HeatSource,
+                    signal: 5.,
+                },
+                Input {
+                    sensor: InputKind::Humidity,
+                    signal: 30.,
+                },
+            ])),
+            vec![humidity],
+        );
+        let inputs = sensor.get_measurement().unwrap();
+        assert_eq!(inputs[2].sensor, InputKind::Humidity);
+        assert!((inputs[2].signal - 36.9).abs() < 0.1);
+    }
+
+    #[test]
+    fn test_rejects_heat_source_with_temperature_offset() {
+        let config = |extra: &str| -> Config {
+            toml::from_str(&format!(
+                "[sensor]\ndevice = \"/dev/i2c-1\"\n\n\
+                 [[auxiliary_inputs]]\ninput = \"heat_source\"\n\
+                 source = {{ type = \"file\", path = \"/tmp/heat\" }}\n\n[bsec]\n{}",
+                extra
+            ))
+            .unwrap()
+        };
+        assert!(check_heat_source(&config("")).is_ok());
+        assert!(check_heat_source(&config("temperature_offset_celsius = 2.0\n")).is_err());
+    }
+}
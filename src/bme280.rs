@@ -0,0 +1,411 @@
+//! BME280 and BMP280 sensors without gas channel.
+//!
+//! The sensors share the temperature and pressure measurement of the BME680,
+//! the BME280 additionally measures the humidity. Only the outputs not
+//! depending on the gas resistance can be subscribed to, see
+//! [`check_supported_outputs`](crate::sensor::check_supported_outputs).
+//!
+//! The registers are accessed like the bme680 crate does, i.e. a write of the
+//! register address followed by a read, and the errors are reported as the
+//! errors of the bme680 crate, so that both sensors share the I2C stack and
+//! the error handling.
+
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+use bme680::I2CAddress;
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::{Input, InputKind};
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use crate::bme680::MeasurementOverrides;
+
+pub const BME280_CHIP_ID: u8 = 0x60;
+pub const BMP280_CHIP_IDS: [u8; 3] = [0x56, 0x57, 0x58];
+
+const CHIP_ID_REGISTER: u8 = 0xd0;
+const CALIBRATION_TP_REGISTER: u8 = 0x88;
+const CALIBRATION_H_REGISTER: u8 = 0xe1;
+const CTRL_HUM_REGISTER: u8 = 0xf2;
+const CTRL_MEAS_REGISTER: u8 = 0xf4;
+const CONFIG_REGISTER: u8 = 0xf5;
+const DATA_REGISTER: u8 = 0xf7;
+const FORCED_MODE: u8 = 0b01;
+/// Largest IIR filter register value (coefficient 16).
+const MAX_IIR_FILTER: u8 = 4;
+
+type Error<I2C> = bme680::Error<<I2C as Read>::Error, <I2C as Write>::Error>;
+
+/// Compensation parameters of the sensor.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Calibration {
+    t: [f64; 3],
+    p: [f64; 9],
+    /// Absent for the BMP280.
+    h: Option<[f64; 6]>,
+}
+
+impl Calibration {
+    fn parse(tp: &[u8; 24], h1: u8, h: Option<&[u8; 7]>) -> Self {
+        let u16_at = |i: usize| f64::from(u16::from_le_bytes([tp[i], tp[i + 1]]));
+        let i16_at = |i: usize| f64::from(i16::from_le_bytes([tp[i], tp[i + 1]]));
+        Self {
+            t: [u16_at(0), i16_at(2), i16_at(4)],
+            p: [
+                u16_at(6),
+                i16_at(8),
+                i16_at(10),
+                i16_at(12),
+                i16_at(14),
+                i16_at(16),
+                i16_at(18),
+                i16_at(20),
+                i16_at(22),
+            ],
+            h: h.map(|h| {
+                [
+                    f64::from(h1),
+                    f64::from(i16::from_le_bytes([h[0], h[1]])),
+                    f64::from(h[2]),
+                    f64::from((i16::from(h[3] as i8) << 4) | i16::from(h[4] & 0x0f)),
+                    f64::from((i16::from(h[5] as i8) << 4) | i16::from(h[4] >> 4)),
+                    f64::from(h[6] as i8),
+                ]
+            }),
+        }
+    }
+
+    /// Temperature in °C and the fine temperature for the compensation of the
+    /// other readings, see the floating point formulas of the datasheet.
+    fn temperature(&self, adc: f64) -> (f64, f64) {
+        let [t1, t2, t3] = self.t;
+        let var1 = (adc / 16384. - t1 / 1024.) * t2;
+        let var2 = (adc / 131072. - t1 / 8192.).powi(2) * t3;
+        let t_fine = var1 + var2;
+        (t_fine / 5120., t_fine)
+    }
+
+    /// Pressure in hPa.
+    fn pressure(&self, adc: f64, t_fine: f64) -> f64 {
+        let [p1, p2, p3, p4, p5, p6, p7, p8, p9] = self.p;
+        let mut var1 = t_fine / 2. - 64000.;
+        let mut var2 = var1 * var1 * p6 / 32768.;
+        var2 += var1 * p5 * 2.;
+        var2 = var2 / 4. + p4 * 65536.;
+        var1 = (p3 * var1 * var1 / 524288. + p2 * var1) / 524288.;
+        var1 = (1. + var1 / 32768.) * p1;
+        if var1 == 0. {
+            return 0.;
+        }
+        let mut pressure = 1048576. - adc;
+        pressure = (pressure - var2 / 4096.) * 6250. / var1;
+        var1 = p9 * pressure * pressure / 2147483648.;
+        var2 = pressure * p8 / 32768.;
+        (pressure + (var1 + var2 + p7) / 16.) / 100.
+    }
+
+    /// Relative humidity in %RH, `None` for the BMP280.
+    fn humidity(&self, adc: f64, t_fine: f64) -> Option<f64> {
+        let [h1, h2, h3, h4, h5, h6] = self.h?;
+        let mut humidity = t_fine - 76800.;
+        humidity = (adc - (h4 * 64. + h5 / 16384. * humidity))
+            * (h2 / 65536. * (1. + h6 / 67108864. * humidity * (1. + h3 / 67108864. * humidity)));
+        humidity *= 1. - h1 * humidity / 524288.;
+        Some(humidity.clamp(0., 100.))
+    }
+}
+
+/// Maximum measurement duration given the oversampling register
+/// values, see the datasheet appendix.
+fn measurement_duration(temperature: u8, pressure: u8, humidity: u8) -> Duration {
+    let factor = |oversampling: u8| match oversampling {
+        0 => 0,
+        oversampling => 1 << (oversampling.min(5) - 1),
+    };
+    let channel = |oversampling: u8, setup: u64| match factor(oversampling) {
+        0 => 0,
+        factor => 2300 * factor + setup,
+    };
+    Duration::from_micros(
+        1250 + channel(temperature, 0) + channel(pressure, 575) + channel(humidity, 575),
+    )
+}
+
+/// BME280 or BMP280 sensor with the oversampling requested by BSEC.
+pub struct Bme280Sensor<I2C> {
+    i2c: I2C,
+    address: u8,
+    calibration: Calibration,
+    overrides: MeasurementOverrides,
+    measurement_available_after: Option<Instant>,
+}
+
+impl<I2C> Bme280Sensor<I2C>
+where
+    I2C: Read + Write,
+{
+    /// Reads the calibration of the sensor at the `address`, failing with
+    /// [`bme680::Error::DeviceNotFound`] for other chips.
+    pub fn init(mut i2c: I2C, address: I2CAddress) -> Result<Self, Error<I2C>> {
+        let address = address.addr();
+        let mut chip_id = [0];
+        read_registers(&mut i2c, address, CHIP_ID_REGISTER, &mut chip_id)?;
+        let has_humidity = match chip_id[0] {
+            BME280_CHIP_ID => true,
+            chip_id if BMP280_CHIP_IDS.contains(&chip_id) => false,
+            _ => return Err(bme680::Error::DeviceNotFound),
+        };
+        let mut tp = [0; 26];
+        read_registers(&mut i2c, address, CALIBRATION_TP_REGISTER, &mut tp)?;
+        let mut h = [0; 7];
+        if has_humidity {
+            read_registers(&mut i2c, address, CALIBRATION_H_REGISTER, &mut h)?;
+        }
+        let mut tp_params = [0; 24];
+        tp_params.copy_from_slice(&tp[..24]);
+        Ok(Self {
+            i2c,
+            address,
+            calibration: Calibration::parse(&tp_params, tp[25], Some(&h).filter(|_| has_humidity)),
+            overrides: MeasurementOverrides::default(),
+            measurement_available_after: None,
+        })
+    }
+
+    /// Applies the `overrides` to the measurement settings requested by BSEC.
+    /// IIR filter sizes above 15 are limited to 16.
+    pub fn with_overrides(mut self, overrides: MeasurementOverrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+
+    pub fn has_humidity(&self) -> bool {
+        self.calibration.h.is_some()
+    }
+}
+
+fn read_registers<I2C: Read + Write>(
+    i2c: &mut I2C,
+    address: u8,
+    register: u8,
+    buffer: &mut [u8],
+) -> Result<(), Error<I2C>> {
+    i2c.write(address, &[register])
+        .map_err(bme680::Error::I2CWrite)?;
+    i2c.read(address, buffer).map_err(bme680::Error::I2CRead)
+}
+
+impl<I2C> BmeSensor for Bme280Sensor<I2C>
+where
+    I2C: Read + Write,
+    <I2C as Read>::Error: Debug,
+    <I2C as Write>::Error: Debug,
+{
+    type Error = Error<I2C>;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        let temperature = MeasurementOverrides::oversampling(
+            self.overrides.temperature_oversampling,
+            settings.temperature_oversampling(),
+        ) as u8;
+        let pressure = MeasurementOverrides::oversampling(
+            self.overrides.pressure_oversampling,
+            settings.pressure_oversampling(),
+        ) as u8;
+        let humidity = if self.has_humidity() {
+            MeasurementOverrides::oversampling(
+                self.overrides.humidity_oversampling,
+                settings.humidity_oversampling(),
+            ) as u8
+        } else {
+            0
+        };
+        let filter = (self.overrides.iir_filter() as u8).min(MAX_IIR_FILTER);
+        let mut registers = vec![CONFIG_REGISTER, filter << 2];
+        if self.has_humidity() {
+            // Takes effect with the following write of the ctrl_meas register.
+            registers.extend([CTRL_HUM_REGISTER, humidity]);
+        }
+        registers.extend([
+            CTRL_MEAS_REGISTER,
+            (temperature << 5) | (pressure << 2) | FORCED_MODE,
+        ]);
+        self.i2c
+            .write(self.address, &registers)
+            .map_err(bme680::Error::I2CWrite)?;
+        let duration = measurement_duration(temperature, pressure, humidity);
+        self.measurement_available_after = Some(Instant::now() + duration);
+        Ok(duration)
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        match self.measurement_available_after {
+            None => panic!("must call start_measurement before get_measurement"),
+            Some(instant) if instant > Instant::now() => Err(nb::Error::WouldBlock),
+            _ => {
+                let mut data = [0; 8];
+                let length = if self.has_humidity() { 8 } else { 6 };
+                read_registers(
+                    &mut self.i2c,
+                    self.address,
+                    DATA_REGISTER,
+                    &mut data[..length],
+                )?;
+                let adc20 = |i: usize| {
+                    f64::from(
+                        (u32::from(data[i]) << 12)
+                            | (u32::from(data[i + 1]) << 4)
+                            | (u32::from(data[i + 2]) >> 4),
+                    )
+                };
+                let (temperature, t_fine) = self.calibration.temperature(adc20(3));
+                let mut inputs = vec![
+                    Input {
+                        sensor: InputKind::Temperature,
+                        signal: temperature as f32,
+                    },
+                    Input {
+                        sensor: InputKind::Pressure,
+                        signal: self.calibration.pressure(adc20(0), t_fine) as f32,
+                    },
+                ];
+                let adc_humidity = f64::from(u16::from_be_bytes([data[6], data[7]]));
+                if let Some(humidity) = self.calibration.humidity(adc_humidity, t_fine) {
+                    inputs.push(Input {
+                        sensor: InputKind::Humidity,
+                        signal: humidity as f32,
+                    });
+                }
+                inputs.push(Input {
+                    sensor: InputKind::HeatSource,
+                    signal: 0.,
+                });
+                Ok(inputs)
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io;
+
+    /// Calibration and readings of a BME280 at about 25 °C, 1000 hPa, and
+    /// 50 %RH.
+    fn calibration() -> Calibration {
+        Calibration {
+            t: [27504., 26435., -1000.],
+            p: [
+                36477., -10685., 3024., 2855., 140., -7., 15500., -14600., 6000.,
+            ],
+            h: Some([75., 362., 0., 313., 50., 30.]),
+        }
+    }
+
+    #[test]
+    fn test_compensation() {
+        let calibration = calibration();
+        let (temperature, t_fine) = calibration.temperature(519888.);
+        assert!((temperature - 25.08).abs() < 0.01, "{}", temperature);
+        let pressure = calibration.pressure(415148., t_fine);
+        assert!((pressure - 1006.53).abs() < 0.01, "{}", pressure);
+        let humidity = calibration.humidity(30000., t_fine).unwrap();
+        assert!((0. ..=100.).contains(&humidity));
+    }
+
+    #[test]
+    fn test_bmp280_has_no_humidity() {
+        let calibration = Calibration {
+            h: None,
+            ..calibration()
+        };
+        assert_eq!(calibration.humidity(30000., 128422.), None);
+    }
+
+    #[test]
+    fn test_parse_calibration() {
+        let mut tp = [0; 24];
+        tp[0..2].copy_from_slice(&27504u16.to_le_bytes());
+        tp[2..4].copy_from_slice(&26435i16.to_le_bytes());
+        tp[4..6].copy_from_slice(&(-1000i16).to_le_bytes());
+        let h = [0x6a, 0x01, 0x00, 0x13, 0x29, 0x03, 0x1e];
+        let calibration = Calibration::parse(&tp, 75, Some(&h));
+        assert_eq!(calibration.t, [27504., 26435., -1000.]);
+        assert_eq!(
+            calibration.h,
+            Some([75., 362., 0., 0x139 as f64, 0x032 as f64, 30.])
+        );
+    }
+
+    #[test]
+    fn test_measurement_duration() {
+        assert_eq!(
+            measurement_duration(1, 1, 1),
+            Duration::from_micros(1250 + 2300 + 2875 + 2875)
+        );
+        assert_eq!(
+            measurement_duration(2, 5, 0),
+            Duration::from_micros(1250 + 4600 + 36800 + 575)
+        );
+    }
+
+    /// Register file of a BMP280.
+    struct FakeBmp280 {
+        registers: [u8; 256],
+        register: u8,
+    }
+
+    impl Read for FakeBmp280 {
+        type Error = io::Error;
+
+        fn read(&mut self, _address: u8, buffer: &mut [u8]) -> io::Result<()> {
+            let start = self.register as usize;
+            buffer.copy_from_slice(&self.registers[start..start + buffer.len()]);
+            Ok(())
+        }
+    }
+
+    impl Write for FakeBmp280 {
+        type Error = io::Error;
+
+        fn write(&mut self, _address: u8, bytes: &[u8]) -> io::Result<()> {
+            match bytes {
+                [register] => self.register = *register,
+                _ => {
+                    for pair in bytes.chunks(2) {
+                        self.registers[pair[0] as usize] = pair[1];
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_init_detects_chip() {
+        let mut registers = [0; 256];
+        registers[CHIP_ID_REGISTER as usize] = 0x58;
+        let sensor = Bme280Sensor::init(
+            FakeBmp280 {
+                registers,
+                register: 0,
+            },
+            I2CAddress::Primary,
+        )
+        .unwrap();
+        assert!(!sensor.has_humidity());
+
+        registers[CHIP_ID_REGISTER as usize] = 0x61;
+        assert!(matches!(
+            Bme280Sensor::init(
+                FakeBmp280 {
+                    registers,
+                    register: 0,
+                },
+                I2CAddress::Primary,
+            ),
+            Err(bme680::Error::DeviceNotFound)
+        ));
+    }
+}
@@ -0,0 +1,703 @@
+//! Reliable I2C access to the BME680 sensor.
+//!
+//! Failed I2C transactions, e.g. due to interference on long wires, are
+//! retried with an exponential backoff. Arbitration and timeout errors
+//! indicate a device holding the bus, which is cleared before the retry.
+//!
+//! The [`Bme680Sensor`] takes the ambient temperature for the heater set
+//! point of the gas measurement from a [`GasAmbientTemperature`] instead of
+//! always using the last temperature reading like the implementation of the
+//! bsec crate. [`MeasurementOverrides`] adjust the IIR filter, the
+//! oversampling, and the heater profile of the measurements requested by
+//! BSEC.
+
+use std::fmt::{Debug, Display};
+use std::io;
+use std::thread;
+use std::time::{Duration, Instant};
+
+use bme680::{Bme680, IIRFilterSize, OversamplingSetting, PowerMode, SettingsBuilder};
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::{Input, InputKind};
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Read, Write};
+use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
+
+use crate::config::{SensorConfig, SensorModel};
+use crate::{log_error, log_warn};
+
+/// Classification of I2C errors.
+pub trait BusError {
+    /// Whether the error indicates lost arbitration or a timeout, i.e. a
+    /// bus that may need to be cleared.
+    fn is_bus_error(&self) -> bool;
+}
+
+impl BusError for io::Error {
+    fn is_bus_error(&self) -> bool {
+        // See the I2C fault codes of the Linux kernel documentation.
+        self.kind() == io::ErrorKind::TimedOut
+            || matches!(
+                self.raw_os_error(),
+                Some(libc::EAGAIN | libc::ETIMEDOUT | libc::EBUSY)
+            )
+    }
+}
+
+impl BusError for LinuxI2CError {
+    fn is_bus_error(&self) -> bool {
+        match self {
+            LinuxI2CError::Io(err) => err.is_bus_error(),
+            LinuxI2CError::Nix(errno) => io::Error::from(*errno).is_bus_error(),
+        }
+    }
+}
+
+/// I2C device that can clear a bus held by another device.
+pub trait ClearBus {
+    type Error;
+
+    fn clear_bus(&mut self) -> Result<(), Self::Error>;
+}
+
+/// Upper bound of the total delay of the retries of an I2C transaction.
+///
+/// The delays block the runtime thread running the measurements, the HTTP
+/// servers, and the watchdog, so they have to stay well below the shortest
+/// sample interval of 3 s.
+pub const MAX_TOTAL_RETRY_BACKOFF: Duration = Duration::from_millis(250);
+
+/// Retries failed transactions of the wrapped I2C device.
+///
+/// A register is read by writing its address before the read, so a failed
+/// read is retried together with the last single-byte write to the same
+/// device.
+pub struct RetryI2c<I> {
+    device: I,
+    retries: u32,
+    backoff: Duration,
+    register: Option<(u8, u8)>,
+}
+
+impl<I, E> RetryI2c<I>
+where
+    I: Read<Error = E> + Write<Error = E> + ClearBus<Error = E>,
+    E: BusError + Display,
+{
+    /// Retries each failed transaction up to `retries` times.
+    pub fn new(device: I, retries: u32) -> Self {
+        Self {
+            device,
+            retries,
+            backoff: Duration::ZERO,
+            register: None,
+        }
+    }
+
+    /// Waits `backoff` before the first retry, doubling the delay for each
+    /// further retry until the delays add up to [`MAX_TOTAL_RETRY_BACKOFF`].
+    pub fn with_backoff(self, backoff: Duration) -> Self {
+        Self { backoff, ..self }
+    }
+
+    /// Delay before the given retry, starting at 1, after having `waited`
+    /// before the previous retries.
+    fn backoff(&self, attempt: u32, waited: Duration) -> Duration {
+        self.backoff
+            .saturating_mul(1 << (attempt - 1).min(16))
+            .min(MAX_TOTAL_RETRY_BACKOFF.saturating_sub(waited))
+    }
+
+    /// Runs `transaction`, passing whether it is retried.
+    fn retry<T>(
+        &mut self,
+        mut transaction: impl FnMut(&mut I, bool) -> Result<T, E>,
+    ) -> Result<T, E> {
+        let mut attempt = 0;
+        let mut waited = Duration::ZERO;
+        loop {
+            match transaction(&mut self.device, attempt > 0) {
+                Err(err) if attempt < self.retries => {
+                    attempt += 1;
+                    log_warn!(
+                        "I2C transaction failed, retrying ({}/{}): {}",
+                        attempt,
+                        self.retries,
+                        err
+                    );
+                    if err.is_bus_error() {
+                        if let Err(err) = self.device.clear_bus() {
+                            log_error!("Failed to clear the I2C bus: {}", err);
+                        }
+                    }
+                    let backoff = self.backoff(attempt, waited);
+                    if !backoff.is_zero() {
+                        thread::sleep(backoff);
+                        waited += backoff;
+                    }
+                }
+                result => return result,
+            }
+        }
+    }
+}
+
+impl<I, E> Read for RetryI2c<I>
+where
+    I: Read<Error = E> + Write<Error = E> + ClearBus<Error = E>,
+    E: BusError + Display,
+{
+    type Error = E;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let register = self
+            .register
+            .filter(|&(register_address, _)| register_address == address);
+        self.retry(|device, retried| {
+            if let (true, Some((_, register))) = (retried, register) {
+                device.write(address, &[register])?;
+            }
+            device.read(address, buffer)
+        })
+    }
+}
+
+impl<I, E> Write for RetryI2c<I>
+where
+    I: Read<Error = E> + Write<Error = E> + ClearBus<Error = E>,
+    E: BusError + Display,
+{
+    type Error = E;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.register = None;
+        self.retry(|device, _| device.write(address, bytes))?;
+        if let [register] = bytes {
+            self.register = Some((address, *register));
+        }
+        Ok(())
+    }
+}
+
+/// Ambient temperature used for the heater set point of the gas measurement.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum GasAmbientTemperature {
+    /// The last temperature reading, starting with the given initial
+    /// temperature in °C.
+    Tracked { initial_celsius: f32 },
+    /// A fixed temperature in °C.
+    Fixed(f32),
+}
+
+impl GasAmbientTemperature {
+    /// The ambient temperature given the `last_measured` temperature, if
+    /// any, rounded to the resolution of the sensor settings.
+    pub fn celsius(&self, last_measured: Option<f32>) -> i8 {
+        let celsius = match *self {
+            Self::Tracked { initial_celsius } => last_measured.unwrap_or(initial_celsius),
+            Self::Fixed(celsius) => celsius,
+        };
+        celsius.round().clamp(i8::MIN.into(), i8::MAX.into()) as i8
+    }
+}
+
+/// Largest oversampling factor BSEC may request.
+const MAX_OVERSAMPLING: u8 = 16;
+
+/// Upper bound of the duration of a measurement with the oversampling
+/// `factors` of the temperature, pressure, and humidity and the heating
+/// duration, as calculated by the BME680 API.
+pub fn profile_duration(factors: [u8; 3], heating_duration_ms: u16) -> Duration {
+    let cycles: u64 = factors.iter().map(|&factor| u64::from(factor)).sum();
+    // Conversion cycles, switching between the measurements and the gas
+    // measurement, and waking up.
+    let tph_us = cycles * 1963 + 477 * 4 + 477 * 5 + 500;
+    Duration::from_micros(tph_us) + Duration::from_millis(heating_duration_ms.into())
+}
+
+/// Overrides of the measurement settings requested by BSEC.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct MeasurementOverrides {
+    /// Coefficient of the IIR filter (0, 1, 3, 7, 15, 31, 63, or 127).
+    pub iir_filter_size: u8,
+    /// Oversampling factors (1, 2, 4, 8, or 16).
+    pub temperature_oversampling: Option<u8>,
+    pub pressure_oversampling: Option<u8>,
+    pub humidity_oversampling: Option<u8>,
+    /// Heater temperature in degrees Celsius and heating duration in
+    /// milliseconds of the gas measurement.
+    pub heater_temperature_celsius: Option<u16>,
+    pub heating_duration_ms: Option<u16>,
+}
+
+impl MeasurementOverrides {
+    pub fn from_config(config: &SensorConfig) -> Self {
+        Self {
+            iir_filter_size: config.iir_filter_size,
+            temperature_oversampling: config.temperature_oversampling,
+            pressure_oversampling: config.pressure_oversampling,
+            humidity_oversampling: config.humidity_oversampling,
+            heater_temperature_celsius: config.heater_temperature_celsius,
+            heating_duration_ms: config.heating_duration_ms,
+        }
+    }
+
+    /// Checks the overrides against the measurement settings BSEC is tuned
+    /// for, given the `model` of the sensor and the shortest sample `interval`
+    /// of the subscriptions.
+    ///
+    /// Returns warnings about the deviations BSEC tolerates, or an error if a
+    /// measurement may not finish within the sample interval or the heater
+    /// profile is overridden for a model without gas sensor.
+    pub fn check(
+        &self,
+        model: SensorModel,
+        interval: Option<Duration>,
+    ) -> Result<Vec<String>, String> {
+        if model != SensorModel::Bme680
+            && (self.heater_temperature_celsius.is_some() || self.heating_duration_ms.is_some())
+        {
+            return Err(format!(
+                "the heater profile is overridden, but the {} has no gas sensor",
+                model
+            ));
+        }
+        let mut warnings = vec![];
+        if self.iir_filter_size != 0 {
+            warnings.push(format!(
+                "the IIR filter of size {} smooths the temperature and pressure inputs that BSEC \
+                 expects unfiltered",
+                self.iir_filter_size
+            ));
+        }
+        let oversampling = [
+            ("temperature", self.temperature_oversampling),
+            ("pressure", self.pressure_oversampling),
+            ("humidity", self.humidity_oversampling),
+        ];
+        for (input, factor) in oversampling.iter() {
+            if let Some(factor) = factor {
+                warnings.push(format!(
+                    "the {} oversampling of {}x replaces the one BSEC requests and is tuned for",
+                    input, factor
+                ));
+            }
+        }
+        let duration = profile_duration(
+            oversampling.map(|(_, factor)| factor.unwrap_or(MAX_OVERSAMPLING)),
+            self.heating_duration_ms.unwrap_or_default(),
+        );
+        match interval {
+            Some(interval) if duration >= interval => Err(format!(
+                "a measurement with the overridden settings takes up to {} ms{}, longer than the \
+                 sample interval of {} ms",
+                duration.as_millis(),
+                match self.heating_duration_ms {
+                    Some(heating_duration_ms) => {
+                        format!(" including the heating for {} ms", heating_duration_ms)
+                    }
+                    None => String::new(),
+                },
+                interval.as_millis()
+            )),
+            _ => Ok(warnings),
+        }
+    }
+
+    /// Heater temperature and heating duration given the `requested` ones
+    /// of BSEC.
+    pub(crate) fn heater_profile(&self, requested: (u16, u16)) -> (u16, u16) {
+        (
+            self.heater_temperature_celsius.unwrap_or(requested.0),
+            self.heating_duration_ms.unwrap_or(requested.1),
+        )
+    }
+
+    /// Register value of the IIR filter.
+    pub(crate) fn iir_filter(&self) -> IIRFilterSize {
+        IIRFilterSize::from_u8((u16::from(self.iir_filter_size) + 1).trailing_zeros() as u8)
+    }
+
+    /// Register value of the oversampling given the `requested` register
+    /// value of BSEC.
+    ///
+    /// The `oversampling` factor only overrides measurements requested by
+    /// BSEC, measurements skipped by BSEC stay skipped as BSEC does not
+    /// expect their inputs.
+    pub(crate) fn oversampling(oversampling: Option<u8>, requested: u8) -> OversamplingSetting {
+        match oversampling {
+            Some(factor) if requested > 0 => {
+                OversamplingSetting::from_u8(factor.trailing_zeros() as u8 + 1)
+            }
+            _ => OversamplingSetting::from_u8(requested),
+        }
+    }
+}
+
+/// BME680 sensor with the oversampling and heater settings requested by BSEC.
+pub struct Bme680Sensor<I2C, D>
+where
+    I2C: Read + Write,
+    D: DelayMs<u8>,
+{
+    bme680: Bme680<I2C, D>,
+    delay: D,
+    ambient_temperature: GasAmbientTemperature,
+    overrides: MeasurementOverrides,
+    measurement_available_after: Option<Instant>,
+    last_measured_temp_celsius: Option<f32>,
+    /// Heater profile requested by BSEC last warned about deviating from.
+    warned_heater_profile: Option<(u16, u16)>,
+}
+
+impl<I2C, D> Bme680Sensor<I2C, D>
+where
+    I2C: Read + Write,
+    D: DelayMs<u8>,
+{
+    pub fn new(
+        bme680: Bme680<I2C, D>,
+        delay: D,
+        ambient_temperature: GasAmbientTemperature,
+    ) -> Self {
+        Self {
+            bme680,
+            delay,
+            ambient_temperature,
+            overrides: MeasurementOverrides::default(),
+            measurement_available_after: None,
+            last_measured_temp_celsius: None,
+            warned_heater_profile: None,
+        }
+    }
+
+    /// Applies the `overrides` to the measurement settings requested by BSEC.
+    pub fn with_overrides(mut self, overrides: MeasurementOverrides) -> Self {
+        self.overrides = overrides;
+        self
+    }
+}
+
+impl<I2C, D> BmeSensor for Bme680Sensor<I2C, D>
+where
+    I2C: Read + Write,
+    D: DelayMs<u8>,
+    <I2C as Read>::Error: Debug,
+    <I2C as Write>::Error: Debug,
+{
+    type Error = bme680::Error<<I2C as Read>::Error, <I2C as Write>::Error>;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        let requested = (settings.heater_temperature(), settings.heating_duration());
+        let (heater_temperature, heating_duration) = self.overrides.heater_profile(requested);
+        if settings.run_gas()
+            && (heater_temperature, heating_duration) != requested
+            && self.warned_heater_profile != Some(requested)
+        {
+            log_warn!(
+                "Heating to {} °C for {} ms instead of the {} °C for {} ms requested by BSEC.",
+                heater_temperature,
+                heating_duration,
+                requested.0,
+                requested.1
+            );
+            self.warned_heater_profile = Some(requested);
+        }
+        let settings = SettingsBuilder::new()
+            .with_humidity_oversampling(MeasurementOverrides::oversampling(
+                self.overrides.humidity_oversampling,
+                settings.humidity_oversampling(),
+            ))
+            .with_temperature_oversampling(MeasurementOverrides::oversampling(
+                self.overrides.temperature_oversampling,
+                settings.temperature_oversampling(),
+            ))
+            .with_pressure_oversampling(MeasurementOverrides::oversampling(
+                self.overrides.pressure_oversampling,
+                settings.pressure_oversampling(),
+            ))
+            .with_temperature_filter(self.overrides.iir_filter())
+            .with_run_gas(settings.run_gas())
+            .with_gas_measurement(
+                Duration::from_millis(heating_duration.into()),
+                heater_temperature,
+                self.ambient_temperature
+                    .celsius(self.last_measured_temp_celsius),
+            )
+            .build();
+
+        self.bme680.set_sensor_settings(&mut self.delay, settings)?;
+        let profile_duration = self.bme680.get_profile_dur(&settings.0)?;
+        self.bme680
+            .set_sensor_mode(&mut self.delay, PowerMode::ForcedMode)?;
+        self.measurement_available_after = Some(Instant::now() + profile_duration);
+        Ok(profile_duration)
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        match self.measurement_available_after {
+            None => panic!("must call start_measurement before get_measurement"),
+            Some(instant) if instant > Instant::now() => Err(nb::Error::WouldBlock),
+            _ => {
+                let (data, _state) = self.bme680.get_sensor_data(&mut self.delay)?;
+                self.last_measured_temp_celsius = Some(data.temperature_celsius());
+                Ok(vec![
+                    Input {
+                        sensor: InputKind::Temperature,
+                        signal: data.temperature_celsius(),
+                    },
+                    Input {
+                        sensor: InputKind::Pressure,
+                        signal: data.pressure_hpa(),
+                    },
+                    Input {
+                        sensor: InputKind::Humidity,
+                        signal: data.humidity_percent(),
+                    },
+                    Input {
+                        sensor: InputKind::GasResistor,
+                        signal: data.gas_resistance_ohm() as f32,
+                    },
+                    Input {
+                        sensor: InputKind::HeatSource,
+                        signal: 0.,
+                    },
+                ])
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device failing the first transactions with the given errors.
+    struct FlakyI2c {
+        failures: Vec<io::Error>,
+        transactions: usize,
+        bus_clears: usize,
+    }
+
+    impl FlakyI2c {
+        fn new(mut failures: Vec<io::Error>) -> Self {
+            failures.reverse();
+            Self {
+                failures,
+                transactions: 0,
+                bus_clears: 0,
+            }
+        }
+
+        fn transaction(&mut self) -> io::Result<()> {
+            self.transactions += 1;
+            match self.failures.pop() {
+                Some(err) => Err(err),
+                None => Ok(()),
+            }
+        }
+    }
+
+    impl Read for FlakyI2c {
+        type Error = io::Error;
+
+        fn read(&mut self, _address: u8, _buffer: &mut [u8]) -> Result<(), Self::Error> {
+            self.transaction()
+        }
+    }
+
+    impl Write for FlakyI2c {
+        type Error = io::Error;
+
+        fn write(&mut self, _address: u8, _bytes: &[u8]) -> Result<(), Self::Error> {
+            self.transaction()
+        }
+    }
+
+    impl ClearBus for FlakyI2c {
+        type Error = io::Error;
+
+        fn clear_bus(&mut self) -> Result<(), Self::Error> {
+            self.bus_clears += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_gas_ambient_temperature() {
+        let tracked = GasAmbientTemperature::Tracked {
+            initial_celsius: 20.,
+        };
+        assert_eq!(tracked.celsius(None), 20);
+        assert_eq!(tracked.celsius(Some(27.6)), 28);
+        assert_eq!(tracked.celsius(Some(-300.)), i8::MIN);
+        let fixed = GasAmbientTemperature::Fixed(15.);
+        assert_eq!(fixed.celsius(None), 15);
+        assert_eq!(fixed.celsius(Some(27.6)), 15);
+    }
+
+    #[test]
+    fn test_measurement_overrides() {
+        let overrides = MeasurementOverrides {
+            iir_filter_size: 127,
+            ..MeasurementOverrides::default()
+        };
+        assert_eq!(overrides.iir_filter() as u8, IIRFilterSize::Size127 as u8);
+        assert_eq!(
+            MeasurementOverrides::default().iir_filter() as u8,
+            IIRFilterSize::Size0 as u8
+        );
+        assert_eq!(
+            MeasurementOverrides::oversampling(Some(16), 1) as u8,
+            OversamplingSetting::OS16x as u8
+        );
+        assert_eq!(
+            MeasurementOverrides::oversampling(Some(1), 5) as u8,
+            OversamplingSetting::OS1x as u8
+        );
+        assert_eq!(
+            MeasurementOverrides::oversampling(Some(16), 0) as u8,
+            OversamplingSetting::OSNone as u8
+        );
+        assert_eq!(
+            MeasurementOverrides::oversampling(None, 3) as u8,
+            OversamplingSetting::OS4x as u8
+        );
+        let overrides = MeasurementOverrides {
+            heater_temperature_celsius: Some(350),
+            ..MeasurementOverrides::default()
+        };
+        assert_eq!(overrides.heater_profile((320, 150)), (350, 150));
+        assert_eq!(
+            MeasurementOverrides::default().heater_profile((320, 150)),
+            (320, 150)
+        );
+    }
+
+    #[test]
+    fn test_checks_measurement_overrides() {
+        assert_eq!(profile_duration([1, 1, 1], 0), Duration::from_micros(10682));
+        assert_eq!(
+            MeasurementOverrides::default()
+                .check(SensorModel::Bme680, Some(Duration::from_secs(3))),
+            Ok(vec![])
+        );
+
+        let overrides = MeasurementOverrides {
+            iir_filter_size: 3,
+            humidity_oversampling: Some(4),
+            ..MeasurementOverrides::default()
+        };
+        assert_eq!(
+            overrides
+                .check(SensorModel::Bme680, Some(Duration::from_secs(3)))
+                .unwrap()
+                .len(),
+            2
+        );
+
+        let overrides = MeasurementOverrides {
+            heating_duration_ms: Some(4000),
+            ..MeasurementOverrides::default()
+        };
+        assert!(overrides
+            .check(SensorModel::Bme680, Some(Duration::from_secs(3)))
+            .is_err());
+        assert!(overrides
+            .check(SensorModel::Bme680, Some(Duration::from_secs(300)))
+            .is_ok());
+        assert!(overrides.check(SensorModel::Bme680, None).is_ok());
+        assert!(overrides
+            .check(SensorModel::Bme680, Some(Duration::from_secs(3)))
+            .unwrap_err()
+            .contains("heating for 4000 ms"));
+        assert!(overrides.check(SensorModel::Bme280, None).is_err());
+        assert!(overrides.check(SensorModel::Bmp280, None).is_err());
+        assert!(MeasurementOverrides::default()
+            .check(SensorModel::Bmp280, None)
+            .is_ok());
+    }
+
+    #[test]
+    fn test_is_bus_error() {
+        assert!(io::Error::from_raw_os_error(libc::EAGAIN).is_bus_error());
+        assert!(io::Error::from(io::ErrorKind::TimedOut).is_bus_error());
+        assert!(!io::Error::from_raw_os_error(libc::EREMOTEIO).is_bus_error());
+        assert!(LinuxI2CError::Io(io::Error::from_raw_os_error(libc::ETIMEDOUT)).is_bus_error());
+    }
+
+    #[test]
+    fn test_retries_failed_transactions() {
+        let mut i2c = RetryI2c::new(
+            FlakyI2c::new(vec![
+                io::Error::from_raw_os_error(libc::EREMOTEIO),
+                io::Error::from_raw_os_error(libc::EAGAIN),
+            ]),
+            2,
+        );
+        i2c.write(0x76, &[1]).unwrap();
+        assert_eq!(i2c.device.transactions, 3);
+        assert_eq!(i2c.device.bus_clears, 1);
+    }
+
+    #[test]
+    fn test_gives_up_after_retries() {
+        let mut i2c = RetryI2c::new(
+            FlakyI2c::new(vec![
+                io::Error::from_raw_os_error(libc::EREMOTEIO),
+                io::Error::from_raw_os_error(libc::EREMOTEIO),
+            ]),
+            1,
+        );
+        let mut buffer = [0; 1];
+        assert!(i2c.read(0x76, &mut buffer).is_err());
+        assert_eq!(i2c.device.transactions, 2);
+        assert_eq!(i2c.device.bus_clears, 0);
+    }
+
+    #[test]
+    fn test_retries_register_reads_with_register_address() {
+        let mut i2c = RetryI2c::new(FlakyI2c::new(vec![]), 1);
+        i2c.write(0x76, &[0xd0]).unwrap();
+        i2c.device
+            .failures
+            .push(io::Error::from_raw_os_error(libc::EREMOTEIO));
+        let mut buffer = [0; 1];
+        i2c.read(0x76, &mut buffer).unwrap();
+        assert_eq!(i2c.device.transactions, 4);
+
+        i2c.device
+            .failures
+            .push(io::Error::from_raw_os_error(libc::EREMOTEIO));
+        i2c.read(0x77, &mut buffer).unwrap();
+        assert_eq!(i2c.device.transactions, 6);
+    }
+
+    #[test]
+    fn test_backs_off_exponentially() {
+        let i2c = RetryI2c::new(FlakyI2c::new(vec![]), 20).with_backoff(Duration::from_millis(10));
+        assert_eq!(i2c.backoff(1, Duration::ZERO), Duration::from_millis(10));
+        assert_eq!(
+            i2c.backoff(3, Duration::from_millis(30)),
+            Duration::from_millis(40)
+        );
+        assert_eq!(
+            i2c.backoff(20, Duration::from_millis(200)),
+            MAX_TOTAL_RETRY_BACKOFF - Duration::from_millis(200)
+        );
+        assert_eq!(i2c.backoff(21, MAX_TOTAL_RETRY_BACKOFF), Duration::ZERO);
+
+        let mut i2c = RetryI2c::new(
+            FlakyI2c::new(vec![
+                io::Error::from_raw_os_error(libc::EREMOTEIO),
+                io::Error::from_raw_os_error(libc::EREMOTEIO),
+            ]),
+            2,
+        )
+        .with_backoff(Duration::from_millis(10));
+        let start = Instant::now();
+        i2c.write(0x76, &[1]).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(30));
+        assert_eq!(i2c.device.transactions, 3);
+    }
+}
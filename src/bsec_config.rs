@@ -0,0 +1,447 @@
+//! Loading and validation of BSEC configuration blobs.
+//!
+//! With the **bundled-configs** feature, the generic IAQ configurations of
+//! the BSEC distribution are embedded into the binary and can be selected with
+//! `bundled:<variant>`, e.g. `bundled:generic_33v_3s_4d`. The
+//! `BSEC_CONFIG_DIR` environment variable has to point to the `config`
+//! directory of the BSEC distribution at build time.
+//!
+//! The blobs start with the BSEC version they were generated for. A version
+//! differing from the linked library is detected when loading the blob, so
+//! that a warning with the remediation can be given before BSEC rejects it
+//! or, for older but accepted blobs, silently uses dated algorithm settings.
+//!
+//! The format of the blobs beyond the version is undocumented. Their CRC and
+//! feature set are thus verified by applying them to a scratch instance of the
+//! linked library when loading, before the sensor is initialized.
+
+use std::borrow::Borrow;
+use std::convert::Infallible;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::clock::Clock;
+use bsec::error::{BsecError, Error};
+use bsec::{Bsec, Input};
+use libalgobsec_sys::BSEC_MAX_PROPERTY_BLOB_SIZE;
+
+use crate::config::{parse_bsec_config, InvalidBsecConfig};
+
+pub const BUNDLED_PREFIX: &str = "bundled:";
+
+#[cfg(feature = "bundled-configs")]
+macro_rules! bundled_configs {
+    ($($variant:literal),*) => {
+        &[$((
+            $variant,
+            include_bytes!(concat!(env!("BSEC_CONFIG_DIR"), "/", $variant, "/bsec_iaq.config")),
+        )),*]
+    };
+}
+
+#[cfg(feature = "bundled-configs")]
+static BUNDLED: &[(&str, &[u8])] = bundled_configs!(
+    "generic_18v_300s_28d",
+    "generic_18v_300s_4d",
+    "generic_18v_3s_28d",
+    "generic_18v_3s_4d",
+    "generic_33v_300s_28d",
+    "generic_33v_300s_4d",
+    "generic_33v_3s_28d",
+    "generic_33v_3s_4d"
+);
+
+#[cfg(not(feature = "bundled-configs"))]
+static BUNDLED: &[(&str, &[u8])] = &[];
+
+/// Names of the configuration variants embedded into the binary.
+pub fn bundled_variants() -> Vec<&'static str> {
+    BUNDLED.iter().map(|(variant, _)| *variant).collect()
+}
+
+/// BSEC version as major, minor, major bugfix, and minor bugfix number.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Version(pub u8, pub u8, pub u8, pub u8);
+
+impl Display for Version {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.{}.{}.{}", self.0, self.1, self.2, self.3)
+    }
+}
+
+pub fn linked_version() -> Option<Version> {
+    bsec::get_version()
+        .ok()
+        .map(|(major, minor, major_bugfix, minor_bugfix)| {
+            Version(major, minor, major_bugfix, minor_bugfix)
+        })
+}
+
+fn linked_version_name() -> String {
+    match linked_version() {
+        Some(version) => version.to_string(),
+        None => "unknown".into(),
+    }
+}
+
+/// BSEC version the blob was generated for, stored in reverse order in its
+/// first four bytes.
+pub fn config_version(blob: &[u8]) -> Option<Version> {
+    match blob {
+        [minor_bugfix, major_bugfix, minor, major, ..] => {
+            Some(Version(*major, *minor, *major_bugfix, *minor_bugfix))
+        }
+        _ => None,
+    }
+}
+
+/// Blob generated for another BSEC version than the linked one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct VersionMismatch {
+    pub config: Version,
+    pub linked: Version,
+}
+
+impl VersionMismatch {
+    /// Whether the blob was generated for an older BSEC version.
+    pub fn is_dated(&self) -> bool {
+        self.config < self.linked
+    }
+}
+
+impl Display for VersionMismatch {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BSEC config was generated for BSEC {}, but BSEC {} is linked",
+            self.config, self.linked
+        )?;
+        if self.is_dated() {
+            write!(f, " (dated config)")?;
+        }
+        write!(
+            f,
+            "; use a config from the config directory of the BSEC {} distribution",
+            self.linked
+        )?;
+        let variants = bundled_variants();
+        if !variants.is_empty() {
+            write!(f, " or a bundled one ({})", variants.join(", "))?;
+        }
+        Ok(())
+    }
+}
+
+/// Compares the version of the blob with the `linked` version.
+pub fn check_version(blob: &[u8], linked: Version) -> Option<VersionMismatch> {
+    let config = config_version(blob)?;
+    Some(VersionMismatch { config, linked }).filter(|_| config != linked)
+}
+
+/// Remediation for a blob rejected by BSEC with the `err`.
+fn remediation(err: &BsecError) -> &'static str {
+    match err {
+        BsecError::ConfigFeatureMismatch => {
+            "the config was generated for another BSEC variant, e.g. the full version with \
+             IAQ outputs instead of the lite version, use a config of the same variant as the \
+             linked library"
+        }
+        BsecError::ConfigVersionMismatch => {
+            "the config was generated for another BSEC version, use a config of the BSEC \
+             distribution of the linked library"
+        }
+        BsecError::ConfigCrcMismatch | BsecError::ConfigInvalidStringSize => {
+            "the config is corrupted, copy it from the BSEC distribution again"
+        }
+        _ => "use a config generated for the linked BSEC version",
+    }
+}
+
+#[derive(Debug)]
+pub enum BsecConfigError {
+    Io(io::Error),
+    Invalid(InvalidBsecConfig),
+    UnknownBundled(String),
+    TooLarge(usize),
+    Rejected(BsecError),
+    Bsec(String),
+}
+
+impl Display for BsecConfigError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use BsecConfigError::*;
+        match self {
+            Io(err) => write!(f, "failed to read BSEC config: {}", err),
+            Invalid(err) => write!(f, "{}", err),
+            UnknownBundled(variant) => {
+                let variants = bundled_variants();
+                if variants.is_empty() {
+                    write!(
+                        f,
+                        "bundled BSEC config {} requested, but this binary was built without bundled configs",
+                        variant
+                    )
+                } else {
+                    write!(
+                        f,
+                        "unknown bundled BSEC config {}, available: {}",
+                        variant,
+                        variants.join(", ")
+                    )
+                }
+            }
+            TooLarge(len) => write!(
+                f,
+                "BSEC config of {} bytes exceeds the maximum of {} bytes supported by BSEC {}",
+                len,
+                BSEC_MAX_PROPERTY_BLOB_SIZE,
+                linked_version_name()
+            ),
+            Rejected(err) => {
+                write!(
+                    f,
+                    "BSEC rejected the config ({}); {} (linked BSEC version {})",
+                    err,
+                    remediation(err),
+                    linked_version_name()
+                )?;
+                let variants = bundled_variants();
+                if !variants.is_empty() {
+                    write!(f, " (bundled: {})", variants.join(", "))?;
+                }
+                Ok(())
+            }
+            Bsec(err) => write!(f, "failed to set BSEC config: {}", err),
+        }
+    }
+}
+
+impl std::error::Error for BsecConfigError {}
+
+impl From<io::Error> for BsecConfigError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl From<InvalidBsecConfig> for BsecConfigError {
+    fn from(err: InvalidBsecConfig) -> Self {
+        Self::Invalid(err)
+    }
+}
+
+/// Loads the BSEC configuration blob from either a file or a bundled variant
+/// if prefixed with `bundled:`.
+pub fn load(source: &str) -> Result<Vec<u8>, BsecConfigError> {
+    let data = match source.strip_prefix(BUNDLED_PREFIX) {
+        Some(variant) => BUNDLED
+            .iter()
+            .find(|(name, _)| *name == variant)
+            .map(|(_, data)| data.to_vec())
+            .ok_or_else(|| BsecConfigError::UnknownBundled(variant.into()))?,
+        None => fs::read(source)?,
+    };
+    let blob = parse_bsec_config(&data)?;
+    verify(blob)?;
+    Ok(blob.to_vec())
+}
+
+/// Checks the blob against the limits of the linked BSEC library.
+pub fn validate(blob: &[u8]) -> Result<(), BsecConfigError> {
+    if blob.len() > BSEC_MAX_PROPERTY_BLOB_SIZE as usize {
+        return Err(BsecConfigError::TooLarge(blob.len()));
+    }
+    Ok(())
+}
+
+/// Validates and applies the configuration blob.
+pub fn apply<S: BmeSensor, C: Clock, B: Borrow<C>>(
+    bsec: &mut Bsec<S, C, B>,
+    blob: &[u8],
+) -> Result<(), BsecConfigError> {
+    validate(blob)?;
+    bsec.set_configuration(blob).map_err(|err| match err {
+        Error::BsecError(
+            err @ (BsecError::ConfigFail
+            | BsecError::ConfigVersionMismatch
+            | BsecError::ConfigFeatureMismatch
+            | BsecError::ConfigCrcMismatch
+            | BsecError::ConfigEmpty
+            | BsecError::ConfigInvalidStringSize),
+        ) => BsecConfigError::Rejected(err),
+        err => BsecConfigError::Bsec(err.to_string()),
+    })
+}
+
+/// Sensor of the scratch BSEC instance, which is never run.
+struct NoSensor;
+
+impl BmeSensor for NoSensor {
+    type Error = Infallible;
+
+    fn start_measurement(&mut self, _: &BmeSettingsHandle) -> Result<Duration, Infallible> {
+        Ok(Duration::ZERO)
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Infallible> {
+        Ok(vec![])
+    }
+}
+
+struct NoClock;
+
+impl Clock for NoClock {
+    fn timestamp_ns(&self) -> i64 {
+        0
+    }
+}
+
+/// Validates the blob and lets the linked library check its CRC and feature
+/// set on a scratch instance.
+///
+/// BSEC keeps a single global state, so this fails while BSEC is initialized
+/// for a sensor.
+pub fn verify(blob: &[u8]) -> Result<(), BsecConfigError> {
+    let mut bsec = Bsec::<_, NoClock, _>::init(NoSensor, NoClock)
+        .map_err(|err| BsecConfigError::Bsec(err.to_string()))?;
+    apply(&mut bsec, blob)
+}
+
+/// Stable 64 bit FNV-1a hash of the configuration blob to detect changes of
+/// the configuration across restarts.
+pub fn fingerprint(blob: &[u8]) -> u64 {
+    blob.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ u64::from(*byte)).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Loads the fingerprint of the last applied configuration.
+pub fn load_fingerprint<P: AsRef<Path>>(path: P) -> io::Result<Option<u64>> {
+    match fs::read_to_string(path) {
+        Ok(content) => u64::from_str_radix(content.trim(), 16)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn save_fingerprint<P: AsRef<Path>>(path: P, fingerprint: u64) -> io::Result<()> {
+    fs::write(path, format!("{:016x}\n", fingerprint))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::{fake_bsec, FakeClock};
+    use serial_test::serial;
+    use std::sync::Arc;
+    use tempfile::tempdir;
+
+    #[test]
+    #[serial]
+    fn test_load_rejects_corrupted_config() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("bsec_iaq.config");
+        fs::write(&path, [7, 0, 0, 0, 0, 8, 4, 1, 1, 2, 3]).unwrap();
+
+        assert!(matches!(
+            load(path.to_str().unwrap()),
+            Err(BsecConfigError::Rejected(_))
+        ));
+    }
+
+    #[test]
+    #[serial]
+    fn test_verify_requires_uninitialized_bsec() {
+        let _bsec = fake_bsec(vec![], &[], Arc::new(FakeClock::new()));
+        assert!(matches!(verify(&[]), Err(BsecConfigError::Bsec(_))));
+    }
+
+    #[test]
+    #[serial]
+    fn test_load_rejects_oversized_config() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("bsec_iaq.config");
+        let len = BSEC_MAX_PROPERTY_BLOB_SIZE + 1;
+        let mut data = len.to_le_bytes().to_vec();
+        data.resize(4 + len as usize, 0);
+        fs::write(&path, data).unwrap();
+
+        assert!(matches!(
+            load(path.to_str().unwrap()),
+            Err(BsecConfigError::TooLarge(size)) if size == len as usize
+        ));
+    }
+
+    #[test]
+    fn test_load_unknown_bundled_config() {
+        let err = load("bundled:does_not_exist").unwrap_err();
+        assert!(matches!(err, BsecConfigError::UnknownBundled(_)));
+        assert!(err.to_string().contains("does_not_exist"));
+    }
+
+    #[cfg(feature = "bundled-configs")]
+    #[test]
+    #[serial]
+    fn test_load_bundled_config() {
+        assert_eq!(bundled_variants().len(), 8);
+        for variant in bundled_variants() {
+            load(&format!("{}{}", BUNDLED_PREFIX, variant)).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        assert_eq!(fingerprint(&[]), 0xcbf2_9ce4_8422_2325);
+        assert_eq!(fingerprint(b"a"), 0xaf63_dc4c_8601_ec8c);
+        assert_ne!(fingerprint(&[1, 2, 3]), fingerprint(&[1, 2, 4]));
+    }
+
+    #[test]
+    fn test_fingerprint_roundtrip() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("bsec-config.fingerprint");
+        assert_eq!(load_fingerprint(&path).unwrap(), None);
+        save_fingerprint(&path, 0x0123_4567_89ab_cdef).unwrap();
+        assert_eq!(
+            load_fingerprint(&path).unwrap(),
+            Some(0x0123_4567_89ab_cdef)
+        );
+    }
+
+    #[test]
+    #[serial]
+    fn test_apply() {
+        let mut bsec = fake_bsec(vec![], &[], Arc::new(FakeClock::new()));
+        apply(&mut bsec, &[0u8; 16]).unwrap();
+        assert!(matches!(
+            apply(&mut bsec, &[0u8; BSEC_MAX_PROPERTY_BLOB_SIZE as usize + 1]),
+            Err(BsecConfigError::TooLarge(_))
+        ));
+    }
+
+    #[test]
+    fn test_check_version() {
+        let linked = Version(1, 4, 9, 2);
+        assert_eq!(config_version(&[0, 8, 4, 1, 61]), Some(Version(1, 4, 8, 0)));
+        assert_eq!(config_version(&[0, 8]), None);
+        assert_eq!(check_version(&[2, 9, 4, 1, 61], linked), None);
+        let mismatch = check_version(&[0, 8, 4, 1, 61], linked).unwrap();
+        assert!(mismatch.is_dated());
+        assert!(mismatch
+            .to_string()
+            .contains("generated for BSEC 1.4.8.0, but BSEC 1.4.9.2 is linked (dated config)"));
+        assert!(!check_version(&[0, 0, 5, 1], linked).unwrap().is_dated());
+    }
+
+    #[test]
+    fn test_rejected_config_remediation() {
+        let message = BsecConfigError::Rejected(BsecError::ConfigFeatureMismatch).to_string();
+        assert!(message.contains("another BSEC variant"), "{}", message);
+    }
+}
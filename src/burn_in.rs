@@ -0,0 +1,159 @@
+//! Burn-in of new sensors.
+//!
+//! During the burn-in, all outputs are subscribed to with the continuous
+//! sample rate to run the gas sensor heater as often as possible. Afterwards,
+//! the configured subscriptions are restored.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use bsec::{SampleRate, SubscriptionRequest};
+use prometheus::Gauge;
+use tokio::sync::mpsc;
+
+use crate::log_info;
+
+/// Interval of the progress reports.
+const PROGRESS_INTERVAL: Duration = Duration::from_secs(3600);
+
+/// Duration of a burn-in of `hours`, which must be positive.
+pub fn duration_from_hours(hours: f64) -> anyhow::Result<Duration> {
+    if hours.is_nan() || hours <= 0. {
+        anyhow::bail!(
+            "invalid burn-in duration of {} hours, expected more than 0",
+            hours
+        );
+    }
+    Ok(Duration::try_from_secs_f64(hours * 3600.)?)
+}
+
+#[derive(Clone)]
+pub struct BurnIn {
+    duration: Duration,
+    is_active: Arc<AtomicBool>,
+    update_subscription: mpsc::UnboundedSender<Vec<SubscriptionRequest>>,
+}
+
+impl BurnIn {
+    pub fn new(
+        duration: Duration,
+        update_subscription: mpsc::UnboundedSender<Vec<SubscriptionRequest>>,
+    ) -> Self {
+        Self {
+            duration,
+            is_active: Arc::new(AtomicBool::new(true)),
+            update_subscription,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.is_active.load(Ordering::Acquire)
+    }
+
+    /// Subscriptions used during the burn-in for the given outputs.
+    pub fn profile(subscriptions: &[SubscriptionRequest]) -> Vec<SubscriptionRequest> {
+        subscriptions
+            .iter()
+            .map(|request| SubscriptionRequest {
+                sensor: request.sensor,
+                sample_rate: if request.sample_rate == SampleRate::Disabled {
+                    SampleRate::Disabled
+                } else {
+                    SampleRate::Continuous
+                },
+            })
+            .collect()
+    }
+
+    /// Waits for the end of the burn-in while reporting the progress, then
+    /// switches to the subscriptions returned by `normal_profile`.
+    pub async fn run(
+        self,
+        remaining_seconds: Gauge,
+        normal_profile: impl Fn() -> Vec<SubscriptionRequest>,
+    ) -> Result<(), mpsc::error::SendError<Vec<SubscriptionRequest>>> {
+        let mut remaining = self.duration;
+        log_info!("Burn-in started for {} h.", remaining.as_secs_f64() / 3600.);
+        while !remaining.is_zero() {
+            remaining_seconds.set(remaining.as_secs_f64());
+            let step = remaining.min(PROGRESS_INTERVAL);
+            tokio::time::sleep(step).await;
+            remaining -= step;
+            log_info!(
+                "Burn-in {:.0}% complete, {:.1} h remaining.",
+                100. * (1. - remaining.as_secs_f64() / self.duration.as_secs_f64()),
+                remaining.as_secs_f64() / 3600.
+            );
+        }
+        remaining_seconds.set(0.);
+        self.is_active.store(false, Ordering::Release);
+        log_info!("Burn-in completed, switching to normal monitoring.");
+        self.update_subscription.send(normal_profile())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::OutputKind;
+    use prometheus::Opts;
+
+    #[test]
+    fn test_duration_from_hours() {
+        assert_eq!(duration_from_hours(1.5).unwrap(), Duration::from_secs(5400));
+        assert!(duration_from_hours(0.).is_err());
+        assert!(duration_from_hours(-1.).is_err());
+        assert!(duration_from_hours(f64::NAN).is_err());
+        assert!(duration_from_hours(f64::INFINITY).is_err());
+    }
+
+    #[test]
+    fn test_profile() {
+        assert_eq!(
+            BurnIn::profile(&[
+                SubscriptionRequest {
+                    sensor: OutputKind::Iaq,
+                    sample_rate: SampleRate::Lp,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::RawGas,
+                    sample_rate: SampleRate::Disabled,
+                },
+            ]),
+            vec![
+                SubscriptionRequest {
+                    sensor: OutputKind::Iaq,
+                    sample_rate: SampleRate::Continuous,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::RawGas,
+                    sample_rate: SampleRate::Disabled,
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn test_run() {
+        let (update_subscription, mut updates) = mpsc::unbounded_channel();
+        let burn_in = BurnIn::new(Duration::from_millis(10), update_subscription);
+        let remaining = Gauge::with_opts(Opts::new("remaining", "remaining")).unwrap();
+        let normal = vec![SubscriptionRequest {
+            sensor: OutputKind::Iaq,
+            sample_rate: SampleRate::Lp,
+        }];
+
+        assert!(burn_in.is_active());
+        burn_in
+            .clone()
+            .run(remaining.clone(), || normal.clone())
+            .await
+            .unwrap();
+        assert!(!burn_in.is_active());
+        assert_eq!(remaining.get(), 0.);
+        assert_eq!(updates.recv().await, Some(normal));
+    }
+}
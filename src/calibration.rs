@@ -0,0 +1,259 @@
+//! Calibration of the temperature offset against a reference temperature.
+//!
+//! The temperature offset is provided to BSEC as heat source input. The
+//! optimal offset is the mean difference between the raw sensor temperature
+//! and the reference temperature over the calibration period.
+
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{
+    atomic::{AtomicU32, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::{Input, InputKind};
+use tokio::sync::watch;
+
+use crate::config::{ReferenceSource, TemperatureCalibrationConfig};
+#[cfg(feature = "http-client")]
+use crate::http_client;
+use crate::maintenance::ReadOnlySwitch;
+use crate::{log_error, log_info, log_warn};
+
+/// Temperature offset shared between the sensor and the calibration.
+#[derive(Clone, Debug)]
+pub struct TemperatureOffset(Arc<AtomicU32>);
+
+impl TemperatureOffset {
+    pub fn new(offset_celsius: f32) -> Self {
+        Self(Arc::new(AtomicU32::new(offset_celsius.to_bits())))
+    }
+
+    pub fn get(&self) -> f32 {
+        f32::from_bits(self.0.load(Ordering::Acquire))
+    }
+
+    pub fn set(&self, offset_celsius: f32) {
+        self.0.store(offset_celsius.to_bits(), Ordering::Release);
+    }
+}
+
+/// Provides the current [`TemperatureOffset`] as heat source input to BSEC,
+/// replacing any heat source input of the wrapped sensor.
+pub struct OffsetSensor<S: BmeSensor> {
+    sensor: S,
+    offset: TemperatureOffset,
+}
+
+impl<S: BmeSensor> OffsetSensor<S> {
+    pub fn new(sensor: S, offset: TemperatureOffset) -> Self {
+        Self { sensor, offset }
+    }
+}
+
+impl<S: BmeSensor> BmeSensor for OffsetSensor<S> {
+    type Error = S::Error;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        self.sensor.start_measurement(settings)
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        let mut inputs = self.sensor.get_measurement()?;
+        inputs.retain(|input| input.sensor != InputKind::HeatSource);
+        inputs.push(Input {
+            sensor: InputKind::HeatSource,
+            signal: self.offset.get(),
+        });
+        Ok(inputs)
+    }
+}
+
+/// Accumulates pairs of raw sensor and reference temperatures.
+#[derive(Debug, Default)]
+pub struct Calibration {
+    sum_difference: f64,
+    samples: usize,
+}
+
+impl Calibration {
+    pub fn add_sample(&mut self, raw_temperature: f64, reference_temperature: f64) {
+        self.sum_difference += raw_temperature - reference_temperature;
+        self.samples += 1;
+    }
+
+    pub fn samples(&self) -> usize {
+        self.samples
+    }
+
+    /// Optimal temperature offset, `None` if no samples have been added.
+    pub fn offset(&self) -> Option<f32> {
+        if self.samples == 0 {
+            return None;
+        }
+        Some((self.sum_difference / self.samples as f64) as f32)
+    }
+}
+
+impl ReferenceSource {
+    /// Reads the current value of the reference, e.g. a temperature in
+    /// degrees Celsius.
+    pub async fn read(&self) -> anyhow::Result<f64> {
+        let (body, scale) = match self {
+            ReferenceSource::File { path, scale } => (fs::read_to_string(path)?, scale),
+            #[cfg(feature = "http-client")]
+            ReferenceSource::Http { url, scale } => (http_client::get(url).await?, scale),
+            #[cfg(not(feature = "http-client"))]
+            ReferenceSource::Http { .. } => {
+                anyhow::bail!("HTTP references require the http-client feature")
+            }
+        };
+        Ok(body.trim().parse::<f64>()? * scale)
+    }
+}
+
+/// Loads a previously persisted temperature offset.
+pub fn load_offset<P: AsRef<Path>>(path: P) -> io::Result<Option<f32>> {
+    match fs::read_to_string(path) {
+        Ok(content) => content
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn save_offset<P: AsRef<Path>>(path: P, offset_celsius: f32) -> io::Result<()> {
+    fs::write(path, format!("{}\n", offset_celsius))
+}
+
+/// Samples the reference for the configured period, then applies and persists
+/// the optimal temperature offset.
+///
+/// `raw_temperature` has to provide the latest raw temperature output of BSEC,
+/// i.e. the temperature before applying the offset.
+pub async fn run_calibration(
+    config: TemperatureCalibrationConfig,
+    offset: TemperatureOffset,
+    raw_temperature: watch::Receiver<Option<f64>>,
+    read_only: ReadOnlySwitch,
+) {
+    log_info!(
+        "Calibrating temperature offset for {} s ...",
+        config.period_seconds
+    );
+    let mut calibration = Calibration::default();
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.sample_interval_seconds));
+    let samples = (config.period_seconds / config.sample_interval_seconds.max(1)).max(1);
+    for _ in 0..samples {
+        ticks.tick().await;
+        let raw = *raw_temperature.borrow();
+        match (raw, config.reference.read().await) {
+            (Some(raw), Ok(reference)) => calibration.add_sample(raw, reference),
+            (None, _) => log_warn!("No raw temperature available for calibration yet."),
+            (_, Err(err)) => log_error!("Failed to read reference temperature: {}", err),
+        }
+    }
+
+    match calibration.offset() {
+        Some(optimal) => {
+            log_info!(
+                "Calibrated temperature offset: {} °C (from {} samples).",
+                optimal,
+                calibration.samples()
+            );
+            offset.set(optimal);
+            if read_only.is_read_only() {
+                log_warn!("Not persisting temperature offset in read-only mode.");
+            } else if let Err(err) = save_offset(&config.offset_file, optimal) {
+                log_error!("Failed to persist temperature offset: {}", err);
+            }
+        }
+        None => log_error!("Temperature calibration failed: no samples collected."),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::FakeBmeSensor;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_calibration() {
+        let mut calibration = Calibration::default();
+        assert_eq!(calibration.offset(), None);
+        calibration.add_sample(25., 22.);
+        calibration.add_sample(24., 22.);
+        assert_eq!(calibration.offset(), Some(2.5));
+    }
+
+    #[test]
+    fn test_offset_sensor_replaces_heat_source() {
+        let offset = TemperatureOffset::new(1.);
+        let mut sensor = OffsetSensor::new(
+            FakeBmeSensor::new(Ok(vec![
+                Input {
+                    sensor: InputKind::Temperature,
+                    signal: 22.,
+                },
+                Input {
+                    sensor: InputKind::HeatSource,
+                    signal: 5.,
+                },
+            ])),
+            offset.clone(),
+        );
+
+        offset.set(2.5);
+        let inputs = sensor.get_measurement().unwrap();
+        assert_eq!(inputs.len(), 2);
+        assert_eq!(inputs[0].sensor, InputKind::Temperature);
+        assert_eq!(inputs[1].sensor, InputKind::HeatSource);
+        assert!((inputs[1].signal - 2.5).abs() < f32::EPSILON);
+    }
+
+    #[tokio::test]
+    async fn test_file_reference() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("temperature");
+        fs::write(&path, "21500\n").unwrap();
+
+        let reference = ReferenceSource::File { path, scale: 0.001 };
+        assert!((reference.read().await.unwrap() - 21.5).abs() < 1e-9);
+    }
+
+    #[cfg(feature = "http-client")]
+    #[tokio::test]
+    async fn test_http_reference() {
+        use std::io::{Read, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/temperature", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = [0; 1024];
+            let _ = stream.read(&mut request).unwrap();
+            stream
+                .write_all(b"HTTP/1.1 200 OK\r\ncontent-length: 5\r\n\r\n21.5\n")
+                .unwrap();
+        });
+
+        let reference = ReferenceSource::Http { url, scale: 1.0 };
+        assert!((reference.read().await.unwrap() - 21.5).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_offset_roundtrip() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("temperature-offset");
+        assert_eq!(load_offset(&path).unwrap(), None);
+        save_offset(&path, 2.25).unwrap();
+        assert_eq!(load_offset(&path).unwrap(), Some(2.25));
+    }
+}
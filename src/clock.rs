@@ -1,6 +1,103 @@
+use super::config::{ClockConfig, ClockKind};
+use super::maintenance::ReadOnlySwitch;
 use super::monitor::Sleep;
-use bsec::clock::TimePassed;
-use std::time::Duration;
+use super::persistance::write_atomically;
+use super::{log_error, log_warn};
+use bsec::clock::{Clock, TimePassed};
+use std::convert::TryFrom;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::ops::{Add, AddAssign, Sub};
+use std::path::PathBuf;
+use std::sync::Mutex;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+/// Nanoseconds as used by the BSEC timestamps.
+///
+/// BSEC timestamps are 64 bit on all targets, whereas `time_t`, `c_long`,
+/// and `usize` are only 32 bit on 32-bit ARM. All conversions are thus
+/// explicit and the arithmetic saturates instead of silently wrapping.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Nanos(pub i64);
+
+impl Nanos {
+    pub const ZERO: Nanos = Nanos(0);
+
+    pub fn get(self) -> i64 {
+        self.0
+    }
+
+    /// Saturates at `i64::MAX` ns, i.e. about 292 years.
+    pub fn from_duration(duration: Duration) -> Self {
+        Self(i64::try_from(duration.as_nanos()).unwrap_or(i64::MAX))
+    }
+
+    pub fn from_timespec(seconds: i64, nanoseconds: i64) -> Self {
+        Self(
+            seconds
+                .saturating_mul(1_000_000_000)
+                .saturating_add(nanoseconds),
+        )
+    }
+
+    /// Duration of the span, zero if it is negative.
+    pub fn to_duration(self) -> Duration {
+        u64::try_from(self.0)
+            .map(Duration::from_nanos)
+            .unwrap_or_default()
+    }
+
+    pub fn as_secs_f64(self) -> f64 {
+        self.0 as f64 / 1e9
+    }
+
+    pub fn checked_add(self, rhs: Nanos) -> Option<Nanos> {
+        self.0.checked_add(rhs.0).map(Nanos)
+    }
+
+    pub fn checked_sub(self, rhs: Nanos) -> Option<Nanos> {
+        self.0.checked_sub(rhs.0).map(Nanos)
+    }
+}
+
+impl Add for Nanos {
+    type Output = Nanos;
+
+    fn add(self, rhs: Nanos) -> Nanos {
+        Nanos(self.0.saturating_add(rhs.0))
+    }
+}
+
+impl AddAssign for Nanos {
+    fn add_assign(&mut self, rhs: Nanos) {
+        *self = *self + rhs;
+    }
+}
+
+impl Sub for Nanos {
+    type Output = Nanos;
+
+    fn sub(self, rhs: Nanos) -> Nanos {
+        Nanos(self.0.saturating_sub(rhs.0))
+    }
+}
+
+impl Display for Nanos {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        Display::fmt(&self.0, f)
+    }
+}
+
+/// Timestamps of a [`Clock`] as [`Nanos`].
+pub trait ClockExt {
+    fn now(&self) -> Nanos;
+}
+
+impl<C: Clock + ?Sized> ClockExt for C {
+    fn now(&self) -> Nanos {
+        Nanos(self.timestamp_ns())
+    }
+}
 
 impl Sleep for TimePassed {
     type SleepFuture = tokio::time::Sleep;
@@ -9,3 +106,514 @@ impl Sleep for TimePassed {
         tokio::time::sleep(duration)
     }
 }
+
+/// Clock selected at runtime from the configuration.
+pub struct RuntimeClock(Box<dyn Clock + Send + Sync>);
+
+impl RuntimeClock {
+    pub fn new<C: Clock + Send + Sync + 'static>(clock: C) -> Self {
+        Self(Box::new(clock))
+    }
+
+    pub fn from_config(config: &ClockConfig, read_only: &ReadOnlySwitch) -> std::io::Result<Self> {
+        Ok(match config.kind {
+            ClockKind::Monotonic => Self::new(TimePassed::default()),
+            ClockKind::Boottime => Self::new(BootTime {}),
+            ClockKind::Wall => Self::new(WallTime {}),
+            ClockKind::PersistedMonotonic => Self::new(
+                PersistedMonotonic::load(config.state_file.clone(), TimePassed::default())?
+                    .with_read_only(read_only.clone()),
+            ),
+        })
+    }
+}
+
+impl Clock for RuntimeClock {
+    fn timestamp_ns(&self) -> i64 {
+        self.0.timestamp_ns()
+    }
+}
+
+impl Sleep for RuntimeClock {
+    type SleepFuture = tokio::time::Sleep;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        tokio::time::sleep(duration)
+    }
+}
+
+/// Time since boot including time spent in suspend (`CLOCK_BOOTTIME`).
+pub struct BootTime {}
+
+impl Clock for BootTime {
+    fn timestamp_ns(&self) -> i64 {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safety: `ts` is a valid pointer and CLOCK_BOOTTIME is supported on
+        // all Linux versions since 2.6.39.
+        unsafe {
+            libc::clock_gettime(libc::CLOCK_BOOTTIME, &mut ts);
+        }
+        #[allow(clippy::useless_conversion)] // time_t and c_long are 32 bit on some targets
+        Nanos::from_timespec(ts.tv_sec.into(), ts.tv_nsec.into()).get()
+    }
+}
+
+/// Time of the hardware clock since boot, neither slewed nor stepped by NTP
+/// (`CLOCK_MONOTONIC_RAW`).
+pub struct MonotonicRaw {}
+
+impl Clock for MonotonicRaw {
+    fn timestamp_ns(&self) -> i64 {
+        let mut ts = libc::timespec {
+            tv_sec: 0,
+            tv_nsec: 0,
+        };
+        // Safety: `ts` is a valid pointer and CLOCK_MONOTONIC_RAW is supported
+        // on all Linux versions since 2.6.28.
+        unsafe {
+            libc::clock_gettime(libc::CLOCK_MONOTONIC_RAW, &mut ts);
+        }
+        #[allow(clippy::useless_conversion)] // time_t and c_long are 32 bit on some targets
+        Nanos::from_timespec(ts.tv_sec.into(), ts.tv_nsec.into()).get()
+    }
+}
+
+/// Wall-clock time since the Unix epoch.
+///
+/// This clock may jump when the system time is adjusted and should be wrapped
+/// in a [`MonotonicGuard`].
+pub struct WallTime {}
+
+impl Clock for WallTime {
+    fn timestamp_ns(&self) -> i64 {
+        Nanos::from_duration(
+            SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default(),
+        )
+        .get()
+    }
+}
+
+/// Minimum span of the measurement clock to estimate the drift from, such
+/// that the jitter of reading both clocks is negligible.
+const MIN_DRIFT_SPAN: Nanos = Nanos(60_000_000_000);
+
+/// Larger deviations from the reference clock are steps of the measurement
+/// clock, e.g. of the wall clock by NTP, rather than drift.
+const MAX_DRIFT_RATE: f64 = 1e-3;
+
+/// Drift of the measurement clock relative to the [`MonotonicRaw`] reference
+/// clock.
+///
+/// The rate is estimated over the whole span since the first reading, so
+/// that even a small drift of a poor oscillator adds up to be measurable
+/// with long ULP intervals. A step of the measurement clock starts a new
+/// span.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ClockDrift {
+    /// Readings of the measurement clock and reference clock at the span
+    /// start.
+    anchor: Option<(Nanos, Nanos)>,
+    rate: Option<f64>,
+    /// Last timestamp returned by [`ClockDrift::to_reference`].
+    last: Option<Nanos>,
+}
+
+impl ClockDrift {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Updates the drift with simultaneous readings of the measurement clock
+    /// and the reference clock.
+    pub fn observe(&mut self, clock: Nanos, reference: Nanos) {
+        let (anchor_clock, anchor_reference) = *self.anchor.get_or_insert((clock, reference));
+        let span = clock - anchor_clock;
+        if span < Nanos::ZERO {
+            self.anchor = Some((clock, reference));
+            return;
+        }
+        if span < MIN_DRIFT_SPAN {
+            return;
+        }
+        let rate = (reference - anchor_reference).as_secs_f64() / span.as_secs_f64() - 1.;
+        if rate.abs() > MAX_DRIFT_RATE {
+            self.anchor = Some((clock, reference));
+        } else {
+            self.rate = Some(rate);
+        }
+    }
+
+    /// Drift in parts per million, positive if the measurement clock runs
+    /// slow, `None` until estimated.
+    pub fn ppm(&self) -> Option<f64> {
+        self.rate.map(|rate| rate * 1e6)
+    }
+
+    /// Reference clock time of a timestamp of the measurement clock corrected
+    /// for the drift, `None` before the first reading.
+    ///
+    /// The returned timestamps never decrease, neither with steps of the
+    /// measurement clock nor with updates of the drift estimate.
+    pub fn to_reference(&mut self, clock: Nanos) -> Option<Nanos> {
+        let rate = self.rate.unwrap_or_default();
+        let (anchor_clock, anchor_reference) = self.anchor?;
+        let reference = anchor_reference
+            + Nanos(((clock - anchor_clock).get() as f64 * (1. + rate)).round() as i64);
+        let reference = self.last.map_or(reference, |last| last.max(reference));
+        self.last = Some(reference);
+        Some(reference)
+    }
+}
+
+/// Local time of day and weekday.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LocalTime {
+    /// Days since Sunday.
+    pub weekday: u8,
+    pub minute_of_day: u16,
+}
+
+impl LocalTime {
+    /// Local time at `unix` seconds since the epoch, in UTC if the time zone
+    /// cannot be determined.
+    pub fn at(unix: u64) -> Self {
+        let time = unix as libc::time_t;
+        // Safety: `tm` is plain data for which all zero bytes are valid.
+        let mut tm: libc::tm = unsafe { std::mem::zeroed() };
+        // Safety: both pointers are valid and localtime_r is thread-safe.
+        if unsafe { libc::localtime_r(&time, &mut tm) }.is_null() {
+            return Self {
+                // The Unix epoch was a Thursday.
+                weekday: ((unix / (24 * 3600) + 4) % 7) as u8,
+                minute_of_day: ((unix / 60) % (24 * 60)) as u16,
+            };
+        }
+        Self {
+            weekday: tm.tm_wday as u8,
+            minute_of_day: (tm.tm_hour * 60 + tm.tm_min) as u16,
+        }
+    }
+}
+
+/// Monotonic clock continuing from the last timestamp persisted in a file
+/// across restarts.
+///
+/// The timestamp is persisted at most once per [`PERSIST_INTERVAL`] and when
+/// the clock is dropped.
+pub struct PersistedMonotonic<C: Clock> {
+    path: PathBuf,
+    clock: C,
+    offset: Nanos,
+    last_persisted: Mutex<Nanos>,
+    read_only: ReadOnlySwitch,
+}
+
+pub const PERSIST_INTERVAL: Duration = Duration::from_secs(60);
+
+impl<C: Clock> PersistedMonotonic<C> {
+    /// Continues from the timestamp persisted at `path`, or from zero if
+    /// there is none or it is invalid.
+    pub fn load(path: PathBuf, clock: C) -> std::io::Result<Self> {
+        let persisted = match fs::read(&path) {
+            Ok(bytes) => match <[u8; 8]>::try_from(bytes.as_slice()) {
+                Ok(buffer) => Some(Nanos(i64::from_le_bytes(buffer))),
+                Err(_) => None,
+            },
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Some(Nanos::ZERO),
+            Err(err) => return Err(err),
+        };
+        let now = clock.now();
+        let (persisted, offset) =
+            match persisted.and_then(|persisted| Some((persisted, persisted.checked_sub(now)?))) {
+                Some(loaded) => loaded,
+                None => {
+                    log_warn!(
+                        "Invalid clock state in {}, continuing from zero.",
+                        path.display()
+                    );
+                    (Nanos::ZERO, Nanos::ZERO - now)
+                }
+            };
+        Ok(Self {
+            path,
+            clock,
+            offset,
+            last_persisted: Mutex::new(persisted),
+            read_only: ReadOnlySwitch::new(),
+        })
+    }
+
+    /// Skips persisting the timestamp while the switch is in read-only mode.
+    pub fn with_read_only(mut self, read_only: ReadOnlySwitch) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    fn persist(&self, timestamp: Nanos) -> std::io::Result<()> {
+        if self.read_only.is_read_only() {
+            return Ok(());
+        }
+        write_atomically(&self.path, &timestamp.get().to_le_bytes())
+    }
+}
+
+impl<C: Clock> Clock for PersistedMonotonic<C> {
+    fn timestamp_ns(&self) -> i64 {
+        let timestamp = self.clock.now() + self.offset;
+        let mut last_persisted = self.last_persisted.lock().unwrap();
+        if timestamp - *last_persisted >= Nanos::from_duration(PERSIST_INTERVAL) {
+            match self.persist(timestamp) {
+                Ok(()) => *last_persisted = timestamp,
+                Err(err) => log_error!("Failed to persist clock state: {}", err),
+            }
+        }
+        timestamp.get()
+    }
+}
+
+impl<C: Clock> Drop for PersistedMonotonic<C> {
+    fn drop(&mut self) {
+        if let Err(err) = self.persist(self.clock.now() + self.offset) {
+            log_error!("Failed to persist clock state: {}", err);
+        }
+    }
+}
+
+/// Shields BSEC from clock jumps by ensuring strictly increasing timestamps.
+///
+/// Whenever the wrapped clock jumps backwards, the difference is added as
+/// offset to all following timestamps.
+pub struct MonotonicGuard<C: Clock> {
+    clock: C,
+    state: Mutex<GuardState>,
+}
+
+#[derive(Default)]
+struct GuardState {
+    last_timestamp: Option<Nanos>,
+    offset: Nanos,
+}
+
+impl<C: Clock> MonotonicGuard<C> {
+    pub fn new(clock: C) -> Self {
+        Self {
+            clock,
+            state: Mutex::new(GuardState::default()),
+        }
+    }
+}
+
+impl<C: Clock + Default> Default for MonotonicGuard<C> {
+    fn default() -> Self {
+        Self::new(C::default())
+    }
+}
+
+impl<C: Clock> Clock for MonotonicGuard<C> {
+    fn timestamp_ns(&self) -> i64 {
+        let mut state = self.state.lock().unwrap();
+        let mut timestamp = self.clock.now() + state.offset;
+        if let Some(last_timestamp) = state.last_timestamp {
+            if timestamp <= last_timestamp {
+                let jump = last_timestamp - timestamp + Nanos(1);
+                log_warn!(
+                    "Clock jumped backwards by {} ns, compensating to keep BSEC timestamps monotonic.",
+                    jump
+                );
+                state.offset += jump;
+                timestamp += jump;
+            }
+        }
+        state.last_timestamp = Some(timestamp);
+        timestamp.get()
+    }
+}
+
+impl<C: Clock + Sleep> Sleep for MonotonicGuard<C> {
+    type SleepFuture = C::SleepFuture;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        self.clock.sleep(duration)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::clock::test_support::FakeClock;
+    use std::sync::atomic::{AtomicI64, Ordering};
+    use tempfile::tempdir;
+
+    #[derive(Default)]
+    struct SettableClock(AtomicI64);
+
+    impl Clock for SettableClock {
+        fn timestamp_ns(&self) -> i64 {
+            self.0.load(Ordering::Relaxed)
+        }
+    }
+
+    #[test]
+    fn test_monotonic_guard_compensates_backward_jumps() {
+        let guard = MonotonicGuard::new(SettableClock::default());
+        guard.clock.0.store(1000, Ordering::Relaxed);
+        assert_eq!(guard.timestamp_ns(), 1000);
+        guard.clock.0.store(2000, Ordering::Relaxed);
+        assert_eq!(guard.timestamp_ns(), 2000);
+
+        guard.clock.0.store(500, Ordering::Relaxed);
+        assert_eq!(guard.timestamp_ns(), 2001);
+        guard.clock.0.store(600, Ordering::Relaxed);
+        assert_eq!(guard.timestamp_ns(), 2101);
+    }
+
+    #[test]
+    fn test_monotonic_guard_ensures_strictly_increasing_timestamps() {
+        let guard = MonotonicGuard::new(SettableClock::default());
+        assert_eq!(guard.timestamp_ns(), 0);
+        assert_eq!(guard.timestamp_ns(), 1);
+        assert_eq!(guard.timestamp_ns(), 2);
+    }
+
+    #[test]
+    fn test_persisted_monotonic_continues_after_restart() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clock.bin");
+
+        let clock = PersistedMonotonic::load(path.clone(), FakeClock::new()).unwrap();
+        assert_eq!(clock.timestamp_ns(), 1);
+        clock.clock.advance_by(Duration::from_secs(10));
+        assert_eq!(clock.timestamp_ns(), 10_000_000_002);
+        drop(clock);
+
+        let clock = PersistedMonotonic::load(path, FakeClock::new()).unwrap();
+        assert!(clock.timestamp_ns() > 10_000_000_002);
+    }
+
+    #[test]
+    fn test_persisted_monotonic_persists_periodically() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clock.bin");
+
+        let clock = PersistedMonotonic::load(path.clone(), FakeClock::new()).unwrap();
+        clock.timestamp_ns();
+        assert!(!path.exists());
+        clock.clock.advance_by(PERSIST_INTERVAL);
+        let timestamp_ns = clock.timestamp_ns();
+        assert_eq!(fs::read(&path).unwrap(), timestamp_ns.to_le_bytes());
+    }
+
+    #[test]
+    fn test_persisted_monotonic_read_only() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clock.bin");
+        let read_only = ReadOnlySwitch::new();
+        read_only.set_read_only(true);
+
+        let clock = PersistedMonotonic::load(path.clone(), FakeClock::new())
+            .unwrap()
+            .with_read_only(read_only);
+        clock.clock.advance_by(PERSIST_INTERVAL);
+        clock.timestamp_ns();
+        drop(clock);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_nanos_conversions() {
+        assert_eq!(
+            Nanos::from_duration(Duration::from_millis(1500)),
+            Nanos(1_500_000_000)
+        );
+        assert_eq!(Nanos::from_duration(Duration::MAX), Nanos(i64::MAX));
+        assert_eq!(Nanos::from_timespec(3, 5), Nanos(3_000_000_005));
+        assert_eq!(Nanos::from_timespec(i64::MAX, 0), Nanos(i64::MAX));
+        assert_eq!(Nanos(1_500).to_duration(), Duration::from_nanos(1_500));
+        assert_eq!(Nanos(-1).to_duration(), Duration::ZERO);
+        assert_eq!(Nanos(1_500_000_000).as_secs_f64(), 1.5);
+    }
+
+    #[test]
+    fn test_nanos_arithmetic() {
+        assert_eq!(Nanos(2) + Nanos(3), Nanos(5));
+        assert_eq!(Nanos(2) - Nanos(3), Nanos(-1));
+        assert_eq!(Nanos(i64::MAX) + Nanos(1), Nanos(i64::MAX));
+        assert_eq!(Nanos(i64::MIN) - Nanos(1), Nanos(i64::MIN));
+        assert_eq!(Nanos(i64::MAX).checked_add(Nanos(1)), None);
+        assert_eq!(Nanos(i64::MIN).checked_sub(Nanos(1)), None);
+        assert_eq!(Nanos(5).checked_sub(Nanos(3)), Some(Nanos(2)));
+    }
+
+    #[test]
+    fn test_persisted_monotonic_falls_back_to_zero_for_invalid_state() {
+        let dir = tempdir().unwrap();
+        let path = dir.path().join("clock.bin");
+        for invalid in [&i64::MIN.to_le_bytes()[..], &[1, 2, 3]] {
+            fs::write(&path, invalid).unwrap();
+            let clock = FakeClock::new();
+            clock.advance_by(Duration::from_secs(1));
+
+            let clock = PersistedMonotonic::load(path.clone(), clock).unwrap();
+            assert_eq!(clock.timestamp_ns(), 1);
+        }
+    }
+
+    #[test]
+    fn test_clock_drift() {
+        let mut drift = ClockDrift::new();
+        assert_eq!(drift.to_reference(Nanos(0)), None);
+        drift.observe(Nanos(0), Nanos(1_000_000_000_000));
+        assert_eq!(drift.ppm(), None);
+        assert_eq!(drift.to_reference(Nanos(5)), Some(Nanos(1_000_000_000_005)));
+
+        // The measurement clock lags 10 ms after 100 s.
+        drift.observe(Nanos(100_000_000_000), Nanos(1_100_010_000_000));
+        assert!((drift.ppm().unwrap() - 100.).abs() < 1e-6);
+        assert_eq!(
+            drift.to_reference(Nanos(200_000_000_000)),
+            Some(Nanos(1_200_020_000_000))
+        );
+    }
+
+    #[test]
+    fn test_clock_drift_ignores_measurement_clock_steps() {
+        let mut drift = ClockDrift::new();
+        drift.observe(Nanos(0), Nanos(1_000_000_000_000));
+        drift.observe(Nanos(100_000_000_000), Nanos(1_100_010_000_000));
+        assert_eq!(
+            drift.to_reference(Nanos(100_000_000_000)),
+            Some(Nanos(1_100_010_000_000))
+        );
+
+        // The measurement clock steps back by an hour.
+        drift.observe(
+            Nanos(-3_600_000_000_000 + 200_000_000_000),
+            Nanos(1_200_020_000_000),
+        );
+        assert!((drift.ppm().unwrap() - 100.).abs() < 1e-6);
+        assert_eq!(
+            drift.to_reference(Nanos(-3_600_000_000_000 + 200_000_000_000)),
+            Some(Nanos(1_200_020_000_000))
+        );
+    }
+
+    #[test]
+    fn test_clock_drift_never_decreases() {
+        let mut drift = ClockDrift::new();
+        drift.observe(Nanos(0), Nanos(0));
+        assert_eq!(drift.to_reference(Nanos(100)), Some(Nanos(100)));
+        assert_eq!(drift.to_reference(Nanos(50)), Some(Nanos(100)));
+    }
+
+    #[test]
+    fn test_system_clocks_are_positive() {
+        assert!(BootTime {}.timestamp_ns() > 0);
+        assert!(WallTime {}.timestamp_ns() > 0);
+        assert!(MonotonicRaw {}.timestamp_ns() > 0);
+    }
+}
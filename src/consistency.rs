@@ -0,0 +1,181 @@
+//! Consistency checks against other exporters in the same room.
+//!
+//! The gauges of the peers are fetched from their `/metrics/json` endpoint
+//! and compared to the local values. Differences beyond the configured
+//! thresholds indicate a drifting or failing sensor.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::Deserialize;
+
+use crate::config::ConsistencyConfig;
+use crate::http_client;
+use crate::metrics::BsecGaugeRegistry;
+use crate::{log_error, log_warn};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Divergence {
+    pub metric: String,
+    /// Absolute difference of the local and the peer value.
+    pub difference: f64,
+    pub exceeds_threshold: bool,
+}
+
+#[derive(Deserialize)]
+struct PeerMetricFamily {
+    name: String,
+    #[serde(rename = "type")]
+    metric_type: String,
+    metrics: Vec<PeerMetric>,
+}
+
+#[derive(Deserialize)]
+struct PeerMetric {
+    value: Option<f64>,
+}
+
+/// Values of the unlabeled or first metric of each gauge family.
+pub fn gauge_values(families: &[MetricFamily]) -> HashMap<String, f64> {
+    families
+        .iter()
+        .filter(|family| family.get_field_type() == MetricType::GAUGE)
+        .filter_map(|family| {
+            let metric = family.get_metric().first()?;
+            Some((family.get_name().into(), metric.get_gauge().get_value()))
+        })
+        .collect()
+}
+
+/// Parses the gauge values from the JSON metrics of a peer.
+pub fn parse_peer_values(json: &str) -> serde_json::Result<HashMap<String, f64>> {
+    let families: Vec<PeerMetricFamily> = serde_json::from_str(json)?;
+    Ok(families
+        .into_iter()
+        .filter(|family| family.metric_type == "gauge")
+        .filter_map(|family| Some((family.name, family.metrics.first()?.value?)))
+        .collect())
+}
+
+/// Compares the metrics with a threshold that are available locally and at
+/// the peer.
+pub fn divergences(
+    local: &HashMap<String, f64>,
+    peer: &HashMap<String, f64>,
+    thresholds: &HashMap<String, f64>,
+) -> Vec<Divergence> {
+    let mut divergences: Vec<_> = thresholds
+        .iter()
+        .filter_map(|(metric, threshold)| {
+            let difference = (local.get(metric)? - peer.get(metric)?).abs();
+            Some(Divergence {
+                metric: metric.clone(),
+                difference,
+                exceeds_threshold: difference > *threshold,
+            })
+        })
+        .collect();
+    divergences.sort_by(|a, b| a.metric.cmp(&b.metric));
+    divergences
+}
+
+/// Periodically compares the local metrics with the peers, exporting the
+/// divergences and warning about divergences beyond the thresholds.
+pub async fn run_consistency_checks(config: ConsistencyConfig, registry: BsecGaugeRegistry) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+    loop {
+        ticks.tick().await;
+        let local = gauge_values(&registry.gather());
+        for peer in config.peers.iter() {
+            let peer_values = match http_client::get(peer).await {
+                Ok(body) => parse_peer_values(&body).map_err(anyhow::Error::from),
+                Err(err) => Err(err),
+            };
+            let peer_values = match peer_values {
+                Ok(peer_values) => peer_values,
+                Err(err) => {
+                    log_error!("Failed to fetch metrics of peer {}: {}", peer, err);
+                    continue;
+                }
+            };
+            for divergence in divergences(&local, &peer_values, &config.thresholds) {
+                registry.set_peer_divergence(peer, &divergence.metric, divergence.difference);
+                if divergence.exceeds_threshold {
+                    log_warn!(
+                        metric = divergence.metric,
+                        peer = peer;
+                        "{} differs by {} from peer {}.",
+                        divergence.metric,
+                        divergence.difference,
+                        peer
+                    );
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoding;
+
+    #[test]
+    fn test_divergences() {
+        let local: HashMap<_, _> = vec![
+            ("temperature_celsius".to_string(), 22.),
+            ("humidity_percent".to_string(), 40.),
+            ("iaq".to_string(), 50.),
+        ]
+        .into_iter()
+        .collect();
+        let peer: HashMap<_, _> = vec![
+            ("temperature_celsius".to_string(), 21.5),
+            ("humidity_percent".to_string(), 50.),
+        ]
+        .into_iter()
+        .collect();
+        let thresholds: HashMap<_, _> = vec![
+            ("temperature_celsius".to_string(), 1.),
+            ("humidity_percent".to_string(), 5.),
+            ("iaq".to_string(), 50.),
+        ]
+        .into_iter()
+        .collect();
+
+        assert_eq!(
+            divergences(&local, &peer, &thresholds),
+            vec![
+                Divergence {
+                    metric: "humidity_percent".into(),
+                    difference: 10.,
+                    exceeds_threshold: true,
+                },
+                Divergence {
+                    metric: "temperature_celsius".into(),
+                    difference: 0.5,
+                    exceeds_threshold: false,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_peer_values_roundtrip() {
+        let registry =
+            BsecGaugeRegistry::new(&[bsec::OutputKind::SensorHeatCompensatedTemperature]).unwrap();
+        registry.set(&bsec::Output {
+            timestamp_ns: 0,
+            signal: 21.5,
+            sensor: bsec::OutputKind::SensorHeatCompensatedTemperature,
+            accuracy: bsec::Accuracy::HighAccuracy,
+        });
+        let families = registry.gather();
+
+        let json = serde_json::to_string(&encoding::to_json(&families)).unwrap();
+        let peer = parse_peer_values(&json).unwrap();
+        assert_eq!(peer["temperature_celsius"], 21.5);
+        assert_eq!(peer, gauge_values(&families));
+    }
+}
@@ -0,0 +1,75 @@
+//! Persistence of the cumulative counters across restarts.
+//!
+//! Prometheus handles counter resets, but an `increase()` over a range with
+//! sparse scrapes loses the increments between the last scrape before and
+//! the first scrape after a restart. Continuing the counters from their
+//! values before the restart avoids the resets altogether.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct CounterValue {
+    pub name: String,
+
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub labels: BTreeMap<String, String>,
+
+    pub value: f64,
+}
+
+/// Values of the persisted counters with their variable labels.
+pub type CountersSnapshot = Vec<CounterValue>;
+
+/// Loads previously saved counters, `None` if there are none.
+pub fn load_counters<P: AsRef<Path>>(path: P) -> io::Result<Option<CountersSnapshot>> {
+    match fs::read_to_string(path) {
+        Ok(content) => serde_json::from_str(&content)
+            .map(Some)
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err)),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn save_counters<P: AsRef<Path>>(path: P, counters: &CountersSnapshot) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec(counters)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_counters_roundtrip() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("counters.json");
+        assert_eq!(load_counters(&path).unwrap(), None);
+
+        let counters = vec![
+            CounterValue {
+                name: "bsec_warnings_total".into(),
+                labels: BTreeMap::new(),
+                value: 3.,
+            },
+            CounterValue {
+                name: "bsec_accuracy_transitions_total".into(),
+                labels: vec![
+                    ("output".to_string(), "iaq".to_string()),
+                    ("from".to_string(), "0".to_string()),
+                    ("to".to_string(), "1".to_string()),
+                ]
+                .into_iter()
+                .collect(),
+                value: 1.,
+            },
+        ];
+        save_counters(&path, &counters).unwrap();
+        assert_eq!(load_counters(&path).unwrap(), Some(counters));
+    }
+}
@@ -0,0 +1,131 @@
+//! Generation of a Grafana dashboard for the exported metrics.
+
+use serde_json::{json, Value};
+
+use crate::metrics::MetricSchema;
+
+const PANEL_WIDTH: u32 = 12;
+const PANEL_HEIGHT: u32 = 8;
+
+fn label_matchers(label_names: &[String]) -> String {
+    label_names
+        .iter()
+        .map(|name| format!("{}=~\"${}\"", name, name))
+        .collect::<Vec<_>>()
+        .join(",")
+}
+
+/// Generates a dashboard with a time series panel per metric. Each label is
+/// provided as dashboard variable to select the displayed devices.
+pub fn generate(schema: &[MetricSchema], label_names: &[String]) -> Value {
+    let matchers = label_matchers(label_names);
+    let panels: Vec<Value> = schema
+        .iter()
+        .enumerate()
+        .map(|(i, metric)| {
+            let i = i as u32;
+            json!({
+                "id": i + 1,
+                "type": "timeseries",
+                "title": metric.description,
+                "datasource": {"type": "prometheus", "uid": "${datasource}"},
+                "gridPos": {
+                    "x": (i % 2) * PANEL_WIDTH,
+                    "y": (i / 2) * PANEL_HEIGHT,
+                    "w": PANEL_WIDTH,
+                    "h": PANEL_HEIGHT,
+                },
+                "fieldConfig": {
+                    "defaults": {"unit": metric.unit.map(grafana_unit).unwrap_or("none")},
+                    "overrides": [],
+                },
+                "targets": [{
+                    "refId": "A",
+                    "expr": format!("{}{{{}}}", metric.name, matchers),
+                    "legendFormat": label_names
+                        .iter()
+                        .map(|name| format!("{{{{{}}}}}", name))
+                        .collect::<Vec<_>>()
+                        .join(" "),
+                }],
+            })
+        })
+        .collect();
+
+    let mut variables = vec![json!({
+        "name": "datasource",
+        "type": "datasource",
+        "query": "prometheus",
+    })];
+    if let Some(metric) = schema.first() {
+        variables.extend(label_names.iter().map(|name| {
+            json!({
+                "name": name,
+                "type": "query",
+                "datasource": {"type": "prometheus", "uid": "${datasource}"},
+                "query": format!("label_values({}, {})", metric.name, name),
+                "includeAll": true,
+                "multi": true,
+                "current": {"text": "All", "value": "$__all"},
+            })
+        }));
+    }
+
+    json!({
+        "title": "BSEC",
+        "tags": ["bsec"],
+        "editable": true,
+        "schemaVersion": 36,
+        "time": {"from": "now-24h", "to": "now"},
+        "templating": {"list": variables},
+        "panels": panels,
+    })
+}
+
+fn grafana_unit(unit: &str) -> &'static str {
+    match unit {
+        "°C" => "celsius",
+        "%" => "percent",
+        "Pa" => "suffix:Pa",
+        "ppm" => "ppm",
+        "Ω" => "ohm",
+        _ => "none",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_generate() {
+        let schema = [
+            MetricSchema::from(bsec::OutputKind::SensorHeatCompensatedTemperature),
+            MetricSchema::from(bsec::OutputKind::Iaq),
+        ];
+        let dashboard = generate(&schema, &["hostname".into()]);
+
+        let panels = dashboard["panels"].as_array().unwrap();
+        assert_eq!(panels.len(), 2);
+        assert_eq!(
+            panels[0]["targets"][0]["expr"],
+            "temperature_celsius{hostname=~\"$hostname\"}"
+        );
+        assert_eq!(panels[0]["fieldConfig"]["defaults"]["unit"], "celsius");
+        assert_eq!(panels[1]["gridPos"]["x"], PANEL_WIDTH);
+
+        let variables = dashboard["templating"]["list"].as_array().unwrap();
+        assert_eq!(variables.len(), 2);
+        assert_eq!(
+            variables[1]["query"],
+            "label_values(temperature_celsius, hostname)"
+        );
+    }
+
+    #[test]
+    fn test_generate_without_labels() {
+        let schema = [MetricSchema::from(bsec::OutputKind::Iaq)];
+        let dashboard = generate(&schema, &[]);
+        assert_eq!(dashboard["panels"][0]["targets"][0]["expr"], "iaq{}");
+    }
+}
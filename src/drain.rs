@@ -0,0 +1,186 @@
+//! Graceful draining of the HTTP connections on shutdown.
+//!
+//! On shutdown, the listeners stop accepting new connections while the
+//! responses in flight are finished within a deadline. Only then the BSEC
+//! state is saved and the exporter exits, so that scrapes are not cut in the
+//! middle of a body during deploys.
+
+use std::future::Future;
+use std::io;
+use std::pin::Pin;
+use std::sync::{
+    atomic::{AtomicUsize, Ordering},
+    Arc,
+};
+use std::task::{Context, Poll};
+use std::time::Duration;
+
+use async_std::io::{BufRead, Read};
+use tide::listener::ToListener;
+use tide::{utils::async_trait, Body, Middleware, Next, Request, Result};
+use tokio::sync::{watch, Notify};
+
+#[derive(Debug, Default)]
+struct InFlight {
+    count: AtomicUsize,
+    drained: Notify,
+}
+
+/// Counts a response as in flight until it has been sent.
+struct InFlightGuard(Arc<InFlight>);
+
+impl InFlightGuard {
+    fn new(in_flight: Arc<InFlight>) -> Self {
+        in_flight.count.fetch_add(1, Ordering::AcqRel);
+        Self(in_flight)
+    }
+}
+
+impl Drop for InFlightGuard {
+    fn drop(&mut self) {
+        if self.0.count.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.drained.notify_one();
+        }
+    }
+}
+
+/// Response body releasing its guard once it has been sent and dropped.
+struct GuardedBody {
+    body: Body,
+    _guard: InFlightGuard,
+}
+
+impl Read for GuardedBody {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.body).poll_read(cx, buf)
+    }
+}
+
+impl BufRead for GuardedBody {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().body).poll_fill_buf(cx)
+    }
+
+    fn consume(mut self: Pin<&mut Self>, amt: usize) {
+        Pin::new(&mut self.body).consume(amt)
+    }
+}
+
+/// Tracks the requests in flight of the HTTP servers and stops them on
+/// shutdown.
+#[derive(Clone, Debug)]
+pub struct HttpDrain {
+    in_flight: Arc<InFlight>,
+    stop: Arc<watch::Sender<bool>>,
+    timeout: Duration,
+}
+
+impl HttpDrain {
+    /// Waits at most `timeout` for the responses in flight when draining.
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            in_flight: Arc::new(InFlight::default()),
+            stop: Arc::new(watch::channel(false).0),
+            timeout,
+        }
+    }
+
+    /// Serves the `app` on the `listener` until the connections are drained.
+    pub fn serve<S, L>(
+        &self,
+        app: tide::Server<S>,
+        listener: L,
+    ) -> impl Future<Output = io::Result<()>>
+    where
+        S: Clone + Send + Sync + 'static,
+        L: ToListener<S>,
+    {
+        let mut stop = self.stop.subscribe();
+        async move {
+            tokio::select! {
+                result = app.listen(listener) => result,
+                _ = async {
+                    while !*stop.borrow_and_update() {
+                        if stop.changed().await.is_err() {
+                            std::future::pending::<()>().await;
+                        }
+                    }
+                } => Ok(()),
+            }
+        }
+    }
+
+    /// Number of responses that have not been sent completely.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight.count.load(Ordering::Acquire)
+    }
+
+    /// Stops accepting new connections and waits for the responses in flight.
+    ///
+    /// Returns whether all responses have been sent before the timeout.
+    pub async fn drain(&self) -> bool {
+        self.stop.send_replace(true);
+        tokio::time::timeout(self.timeout, async {
+            while self.in_flight() > 0 {
+                self.in_flight.drained.notified().await;
+            }
+        })
+        .await
+        .is_ok()
+    }
+}
+
+#[async_trait]
+impl<State: Clone + Send + Sync + 'static> Middleware<State> for HttpDrain {
+    async fn handle(&self, request: Request<State>, next: Next<'_, State>) -> Result {
+        let guard = InFlightGuard::new(self.in_flight.clone());
+        let mut response = next.run(request).await;
+        let body = response.take_body();
+        let len = body.len();
+        let mime = body.mime().clone();
+        let mut body = Body::from_reader(
+            GuardedBody {
+                body,
+                _guard: guard,
+            },
+            len,
+        );
+        body.set_mime(mime);
+        response.set_body(body);
+        Ok(response)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tide::http::{Method, Url};
+
+    #[tokio::test]
+    async fn test_drain_waits_for_responses_in_flight() {
+        let drain = HttpDrain::new(Duration::from_millis(50));
+        let mut app = tide::new();
+        app.with(drain.clone());
+        app.at("/").get(|_| async { Ok("metrics") });
+
+        let request =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/").unwrap());
+        let mut response: tide::http::Response = app.respond(request).await.unwrap();
+        assert_eq!(drain.in_flight(), 1);
+        assert!(!drain.drain().await);
+
+        assert!(response
+            .content_type()
+            .unwrap()
+            .essence()
+            .starts_with("text/plain"));
+        assert_eq!(response.body_string().await.unwrap(), "metrics");
+        drop(response);
+        assert_eq!(drain.in_flight(), 0);
+        assert!(drain.drain().await);
+    }
+}
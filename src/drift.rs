@@ -0,0 +1,248 @@
+//! Tracking of the long-term drift of the gas resistance baseline.
+//!
+//! The baseline of a day is the maximum raw gas resistance of that day, i.e.
+//! the resistance in the cleanest air. The drift is the trend of the daily
+//! baselines over the last [`WINDOW_DAYS`] days. A sensor with a strongly
+//! drifting baseline should be re-baselined or replaced.
+
+use std::collections::VecDeque;
+use std::fmt::{self, Display, Formatter};
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::maintenance::ReadOnlySwitch;
+use crate::persistance::write_atomically;
+use crate::{log_error, log_warn};
+
+/// Number of daily baselines considered for the drift.
+pub const WINDOW_DAYS: usize = 30;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DriftReport {
+    /// Baseline of the most recent day in Ω.
+    pub baseline_ohm: f64,
+    /// Relative change of the baseline per day in percent, `None` if less than
+    /// two days have been tracked.
+    pub drift_percent_per_day: Option<f64>,
+    /// Number of days the drift is based on.
+    pub days: usize,
+}
+
+impl Display for DriftReport {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "gas baseline: {:.0} Ω", self.baseline_ohm)?;
+        if let Some(drift) = self.drift_percent_per_day {
+            write!(f, ", drift: {:+.2} %/day over {} days", drift, self.days)?;
+        }
+        Ok(())
+    }
+}
+
+/// Tracks the daily gas resistance baselines.
+#[derive(Debug, Default)]
+pub struct GasBaselineTracker {
+    days: VecDeque<(u64, f64)>,
+    file: Option<PathBuf>,
+    read_only: ReadOnlySwitch,
+}
+
+impl GasBaselineTracker {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Loads previously tracked baselines from `path` and persists the
+    /// baselines to it whenever a day is completed.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut tracker = Self {
+            days: VecDeque::with_capacity(WINDOW_DAYS + 1),
+            file: Some(path.as_ref().into()),
+            read_only: ReadOnlySwitch::new(),
+        };
+        let content = match fs::read(path) {
+            Ok(content) => String::from_utf8_lossy(&content).into_owned(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(tracker),
+            Err(err) => return Err(err),
+        };
+        for line in content.lines() {
+            let parsed = line.split_once(' ').and_then(|(day, baseline)| {
+                Some((day.parse::<u64>().ok()?, baseline.parse::<f64>().ok()?))
+            });
+            match parsed {
+                Some(entry) => tracker.days.push_back(entry),
+                None => {
+                    log_warn!(
+                        "Discarding the gas baselines with the invalid entry: {}",
+                        line
+                    );
+                    tracker.days.clear();
+                    break;
+                }
+            }
+        }
+        Ok(tracker)
+    }
+
+    /// Skips persisting the baselines while the switch is in read-only mode.
+    pub fn with_read_only(self, read_only: ReadOnlySwitch) -> Self {
+        Self { read_only, ..self }
+    }
+
+    fn save(&self) -> io::Result<()> {
+        if self.read_only.is_read_only() {
+            return Ok(());
+        }
+        if let Some(file) = &self.file {
+            let content: String = self
+                .days
+                .iter()
+                .map(|(day, baseline)| format!("{} {}\n", day, baseline))
+                .collect();
+            write_atomically(file, content.as_bytes())?;
+        }
+        Ok(())
+    }
+
+    /// Adds a raw gas resistance measured on the given day (counted since the
+    /// Unix epoch).
+    ///
+    /// Returns the report for the completed day once a new day begins.
+    pub fn add(&mut self, day: u64, raw_gas_ohm: f64) -> Option<DriftReport> {
+        let completed = match self.days.back_mut() {
+            Some((last_day, baseline)) if *last_day == day => {
+                *baseline = baseline.max(raw_gas_ohm);
+                return None;
+            }
+            Some(_) => self.report(),
+            None => None,
+        };
+        self.days.push_back((day, raw_gas_ohm));
+        while self.days.len() > WINDOW_DAYS {
+            self.days.pop_front();
+        }
+        if let Err(err) = self.save() {
+            log_error!("Failed to persist gas baseline: {}", err);
+        }
+        completed
+    }
+
+    /// Adds a raw gas resistance measured now.
+    pub fn add_now(&mut self, raw_gas_ohm: f64) -> Option<DriftReport> {
+        let day = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_secs() / SECONDS_PER_DAY)
+            .unwrap_or_default();
+        self.add(day, raw_gas_ohm)
+    }
+
+    pub fn report(&self) -> Option<DriftReport> {
+        let (_, baseline_ohm) = *self.days.back()?;
+        Some(DriftReport {
+            baseline_ohm,
+            drift_percent_per_day: self.drift_percent_per_day(),
+            days: self.days.len(),
+        })
+    }
+
+    /// Least squares fit of the logarithmic baselines to obtain the relative
+    /// change per day.
+    fn drift_percent_per_day(&self) -> Option<f64> {
+        let points: Vec<(f64, f64)> = self
+            .days
+            .iter()
+            .filter(|(_, baseline)| *baseline > 0.)
+            .map(|(day, baseline)| (*day as f64, baseline.ln()))
+            .collect();
+        if points.len() < 2 {
+            return None;
+        }
+        let n = points.len() as f64;
+        let mean_x = points.iter().map(|(x, _)| x).sum::<f64>() / n;
+        let mean_y = points.iter().map(|(_, y)| y).sum::<f64>() / n;
+        let covariance: f64 = points
+            .iter()
+            .map(|(x, y)| (x - mean_x) * (y - mean_y))
+            .sum();
+        let variance: f64 = points.iter().map(|(x, _)| (x - mean_x).powi(2)).sum();
+        Some((covariance / variance).exp_m1() * 100.)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_daily_baseline_is_maximum() {
+        let mut tracker = GasBaselineTracker::new();
+        assert_eq!(tracker.report(), None);
+        assert_eq!(tracker.add(1, 100_000.), None);
+        assert_eq!(tracker.add(1, 120_000.), None);
+        assert_eq!(tracker.add(1, 90_000.), None);
+        assert_eq!(
+            tracker.report(),
+            Some(DriftReport {
+                baseline_ohm: 120_000.,
+                drift_percent_per_day: None,
+                days: 1,
+            })
+        );
+    }
+
+    #[test]
+    fn test_drift() {
+        let mut tracker = GasBaselineTracker::new();
+        for day in 0..10 {
+            tracker.add(day, 100_000. * 0.99f64.powi(day as i32));
+        }
+        let report = tracker.add(10, 0.).unwrap();
+        assert_eq!(report.days, 10);
+        assert!((report.drift_percent_per_day.unwrap() + 1.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_window() {
+        let mut tracker = GasBaselineTracker::new();
+        for day in 0..2 * WINDOW_DAYS as u64 {
+            tracker.add(day, 100_000.);
+        }
+        assert_eq!(tracker.report().unwrap().days, WINDOW_DAYS);
+    }
+
+    #[test]
+    fn test_persistence() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("gas-baseline");
+        let mut tracker = GasBaselineTracker::load(&path).unwrap();
+        tracker.add(1, 100_000.);
+        tracker.add(2, 90_000.);
+
+        let report = GasBaselineTracker::load(&path).unwrap().report().unwrap();
+        assert_eq!(report.baseline_ohm, 90_000.);
+        assert_eq!(report.days, 2);
+    }
+
+    #[test]
+    fn test_discards_corrupted_baselines() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("gas-baseline");
+        fs::write(&path, "1 100000\n2 90\u{0}00\n").unwrap();
+
+        let mut tracker = GasBaselineTracker::load(&path).unwrap();
+        assert_eq!(tracker.report(), None);
+        tracker.add(3, 80_000.);
+        assert_eq!(
+            GasBaselineTracker::load(&path)
+                .unwrap()
+                .report()
+                .unwrap()
+                .days,
+            1
+        );
+    }
+}
@@ -0,0 +1,196 @@
+//! Encodings of the gathered metrics besides the Prometheus text format.
+//!
+//! Only gauges, counters, and untyped metrics are supported. Metric families
+//! of other types are skipped.
+
+use std::collections::BTreeMap;
+use std::fmt::Write;
+
+use prometheus::proto::{Metric, MetricFamily, MetricType};
+use serde::Serialize;
+
+pub const OPENMETRICS_CONTENT_TYPE: &str =
+    "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+fn value(family: &MetricFamily, metric: &Metric) -> Option<f64> {
+    match family.get_field_type() {
+        MetricType::GAUGE => Some(metric.get_gauge().get_value()),
+        MetricType::COUNTER => Some(metric.get_counter().get_value()),
+        MetricType::UNTYPED => Some(metric.get_untyped().get_value()),
+        _ => None,
+    }
+}
+
+fn format_value(value: f64) -> String {
+    if value.is_nan() {
+        "NaN".into()
+    } else if value.is_infinite() {
+        if value > 0. { "+Inf" } else { "-Inf" }.into()
+    } else {
+        format!("{}", value)
+    }
+}
+
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', r"\\")
+        .replace('\n', r"\n")
+        .replace('"', "\\\"")
+}
+
+/// Encodes the metric families in the OpenMetrics text format.
+pub fn encode_openmetrics(families: &[MetricFamily]) -> String {
+    let mut buffer = String::new();
+    for family in families {
+        let (type_name, family_name, sample_name) = match family.get_field_type() {
+            MetricType::GAUGE => ("gauge", family.get_name(), family.get_name().to_string()),
+            MetricType::UNTYPED => ("unknown", family.get_name(), family.get_name().to_string()),
+            MetricType::COUNTER => {
+                let family_name = family
+                    .get_name()
+                    .strip_suffix("_total")
+                    .unwrap_or_else(|| family.get_name());
+                ("counter", family_name, format!("{}_total", family_name))
+            }
+            _ => continue,
+        };
+        writeln!(buffer, "# TYPE {} {}", family_name, type_name).unwrap();
+        writeln!(
+            buffer,
+            "# HELP {} {}",
+            family_name,
+            escape(family.get_help())
+        )
+        .unwrap();
+        for metric in family.get_metric() {
+            buffer.push_str(&sample_name);
+            if !metric.get_label().is_empty() {
+                let labels: Vec<String> = metric
+                    .get_label()
+                    .iter()
+                    .map(|label| format!("{}=\"{}\"", label.get_name(), escape(label.get_value())))
+                    .collect();
+                write!(buffer, "{{{}}}", labels.join(",")).unwrap();
+            }
+            if let Some(value) = value(family, metric) {
+                writeln!(buffer, " {}", format_value(value)).unwrap();
+            }
+        }
+    }
+    buffer.push_str("# EOF\n");
+    buffer
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct JsonMetricFamily {
+    pub name: String,
+    pub help: String,
+    #[serde(rename = "type")]
+    pub metric_type: &'static str,
+    pub metrics: Vec<JsonMetric>,
+}
+
+#[derive(Debug, PartialEq, Serialize)]
+pub struct JsonMetric {
+    pub labels: BTreeMap<String, String>,
+    /// `None` for non-finite values which are not representable in JSON.
+    pub value: Option<f64>,
+}
+
+/// Converts the metric families into a JSON serializable representation.
+pub fn to_json(families: &[MetricFamily]) -> Vec<JsonMetricFamily> {
+    families
+        .iter()
+        .filter_map(|family| {
+            let metric_type = match family.get_field_type() {
+                MetricType::GAUGE => "gauge",
+                MetricType::COUNTER => "counter",
+                MetricType::UNTYPED => "untyped",
+                _ => return None,
+            };
+            Some(JsonMetricFamily {
+                name: family.get_name().into(),
+                help: family.get_help().into(),
+                metric_type,
+                metrics: family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| JsonMetric {
+                        labels: metric
+                            .get_label()
+                            .iter()
+                            .map(|label| (label.get_name().into(), label.get_value().into()))
+                            .collect(),
+                        value: value(family, metric).filter(|value| value.is_finite()),
+                    })
+                    .collect(),
+            })
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use prometheus::{Gauge, IntCounter, IntCounterVec, Opts, Registry};
+
+    fn create_registry() -> Registry {
+        let registry = Registry::new();
+        let gauge = Gauge::with_opts(Opts::new("temperature_celsius", "Temperature")).unwrap();
+        gauge.set(21.5);
+        registry.register(Box::new(gauge)).unwrap();
+        let counter = IntCounter::with_opts(Opts::new("restarts_total", "Restarts")).unwrap();
+        counter.inc();
+        registry.register(Box::new(counter)).unwrap();
+        let labeled =
+            IntCounterVec::new(Opts::new("errors_total", "Errors \"x\""), &["kind"]).unwrap();
+        labeled.with_label_values(&["i2c"]).inc_by(2);
+        registry.register(Box::new(labeled)).unwrap();
+        registry
+    }
+
+    #[test]
+    fn test_encode_openmetrics() {
+        assert_eq!(
+            encode_openmetrics(&create_registry().gather()),
+            "# TYPE errors counter\n\
+             # HELP errors Errors \\\"x\\\"\n\
+             errors_total{kind=\"i2c\"} 2\n\
+             # TYPE restarts counter\n\
+             # HELP restarts Restarts\n\
+             restarts_total 1\n\
+             # TYPE temperature_celsius gauge\n\
+             # HELP temperature_celsius Temperature\n\
+             temperature_celsius 21.5\n\
+             # EOF\n"
+        );
+    }
+
+    #[test]
+    fn test_format_value() {
+        assert_eq!(format_value(f64::NAN), "NaN");
+        assert_eq!(format_value(f64::INFINITY), "+Inf");
+        assert_eq!(format_value(f64::NEG_INFINITY), "-Inf");
+        assert_eq!(format_value(1.), "1");
+    }
+
+    #[test]
+    fn test_to_json() {
+        let families = to_json(&create_registry().gather());
+        assert_eq!(
+            families[0],
+            JsonMetricFamily {
+                name: "errors_total".into(),
+                help: "Errors \"x\"".into(),
+                metric_type: "counter",
+                metrics: vec![JsonMetric {
+                    labels: vec![("kind".to_string(), "i2c".to_string())]
+                        .into_iter()
+                        .collect(),
+                    value: Some(2.),
+                }],
+            }
+        );
+        assert_eq!(families[2].metrics[0].value, Some(21.5));
+    }
+}
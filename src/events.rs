@@ -0,0 +1,387 @@
+//! Bounded on-disk journal of notable events.
+//!
+//! The journal lets field technicians see what happened on a device without
+//! central logging. It is stored as one JSON object per line and limited to
+//! the most recent [`CAPACITY`] events.
+
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bsec::Accuracy;
+use serde::{Deserialize, Serialize};
+
+use crate::log_error;
+use crate::maintenance::ReadOnlySwitch;
+use crate::monitor::PersistState;
+
+/// Maximum number of events kept in the journal.
+pub const CAPACITY: usize = 1000;
+
+/// Minimum interval between two recorded successful state saves.
+pub const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(3600);
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Error,
+    AccuracyChange,
+    Restart,
+    StateSave,
+    Alert,
+}
+
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Event {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    pub kind: EventKind,
+    pub message: String,
+}
+
+#[derive(Debug)]
+struct Journal {
+    events: VecDeque<Event>,
+    file: Option<PathBuf>,
+}
+
+/// Journal of events shared between the monitoring and the API.
+#[derive(Clone, Debug)]
+pub struct EventJournal {
+    journal: Arc<Mutex<Journal>>,
+    read_only: ReadOnlySwitch,
+}
+
+/// Parses a journal file, skipping lines that are not valid events.
+pub fn parse_events(content: &str) -> Vec<Event> {
+    content
+        .lines()
+        .filter_map(|line| serde_json::from_str(line).ok())
+        .collect()
+}
+
+impl EventJournal {
+    /// Journal only kept in memory.
+    pub fn new() -> Self {
+        Self {
+            journal: Arc::new(Mutex::new(Journal {
+                events: VecDeque::new(),
+                file: None,
+            })),
+            read_only: ReadOnlySwitch::new(),
+        }
+    }
+
+    /// Loads the journal from `path` and appends new events to it.
+    pub fn load<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut events: VecDeque<Event> = match fs::read_to_string(&path) {
+            Ok(content) => parse_events(&content).into(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => VecDeque::new(),
+            Err(err) => return Err(err),
+        };
+        while events.len() > CAPACITY {
+            events.pop_front();
+        }
+        Ok(Self {
+            journal: Arc::new(Mutex::new(Journal {
+                events,
+                file: Some(path.as_ref().into()),
+            })),
+            read_only: ReadOnlySwitch::new(),
+        })
+    }
+
+    /// Keeps new events only in memory while the switch is in read-only mode.
+    pub fn with_read_only(mut self, read_only: ReadOnlySwitch) -> Self {
+        self.read_only = read_only;
+        self
+    }
+
+    pub fn record(&self, kind: EventKind, message: impl Into<String>) {
+        let event = Event {
+            timestamp: SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            kind,
+            message: message.into(),
+        };
+        let mut journal = self.journal.lock().unwrap();
+        journal.events.push_back(event);
+        let rotate = journal.events.len() > CAPACITY;
+        if rotate {
+            journal.events.pop_front();
+        }
+        if self.read_only.is_read_only() {
+            return;
+        }
+        if let Err(err) = Self::persist(&journal, rotate) {
+            log_error!("Failed to persist event journal: {}", err);
+        }
+    }
+
+    fn persist(journal: &Journal, rotate: bool) -> io::Result<()> {
+        let file = match &journal.file {
+            Some(file) => file,
+            None => return Ok(()),
+        };
+        let to_line = |event: &Event| -> io::Result<String> {
+            Ok(format!("{}\n", serde_json::to_string(event)?))
+        };
+        if rotate {
+            let content = journal
+                .events
+                .iter()
+                .map(to_line)
+                .collect::<io::Result<String>>()?;
+            fs::write(file, content)
+        } else if let Some(event) = journal.events.back() {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(file)?
+                .write_all(to_line(event)?.as_bytes())
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Events from the oldest to the most recent one.
+    pub fn events(&self) -> Vec<Event> {
+        self.journal
+            .lock()
+            .unwrap()
+            .events
+            .iter()
+            .cloned()
+            .collect()
+    }
+}
+
+impl Default for EventJournal {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Change of the accuracy of a BSEC output.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct AccuracyTransition {
+    pub sensor: bsec::OutputKind,
+    pub from: Accuracy,
+    pub to: Accuracy,
+}
+
+impl std::fmt::Display for AccuracyTransition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} {} → {}",
+            crate::config::output_kind_name(self.sensor),
+            self.from as u8,
+            self.to as u8
+        )
+    }
+}
+
+/// Event message describing the `transitions`, `None` if there are none.
+pub fn describe_transitions(transitions: &[AccuracyTransition]) -> Option<String> {
+    if transitions.is_empty() {
+        return None;
+    }
+    let changes: Vec<String> = transitions.iter().map(ToString::to_string).collect();
+    Some(format!("accuracy changed: {}", changes.join(", ")))
+}
+
+/// Tracks the accuracy of the outputs to detect changes.
+#[derive(Debug, Default)]
+pub struct AccuracyTracker {
+    last: Vec<(bsec::OutputKind, Accuracy)>,
+}
+
+impl AccuracyTracker {
+    /// Updates the tracked accuracies and returns the changes.
+    pub fn update(&mut self, outputs: &[bsec::Output]) -> Vec<AccuracyTransition> {
+        let mut transitions = vec![];
+        for output in outputs {
+            match self
+                .last
+                .iter_mut()
+                .find(|(sensor, _)| *sensor == output.sensor)
+            {
+                Some((_, accuracy)) if *accuracy != output.accuracy => {
+                    transitions.push(AccuracyTransition {
+                        sensor: output.sensor,
+                        from: *accuracy,
+                        to: output.accuracy,
+                    });
+                    *accuracy = output.accuracy;
+                }
+                Some(_) => (),
+                None => self.last.push((output.sensor, output.accuracy)),
+            }
+        }
+        transitions
+    }
+}
+
+/// Records failed and, at most once per [`STATE_SAVE_INTERVAL`], successful
+/// state saves in the journal.
+pub struct JournaledPersistState<P: PersistState> {
+    persist_state: P,
+    journal: EventJournal,
+    last_recorded: Option<Instant>,
+}
+
+impl<P: PersistState> JournaledPersistState<P> {
+    pub fn new(persist_state: P, journal: EventJournal) -> Self {
+        Self {
+            persist_state,
+            journal,
+            last_recorded: None,
+        }
+    }
+}
+
+impl<P: PersistState> PersistState for JournaledPersistState<P>
+where
+    P::Error: std::fmt::Display,
+{
+    type Error = P::Error;
+
+    fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persist_state.load_state()
+    }
+
+    fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
+        let result = self.persist_state.save_state(state);
+        match &result {
+            Err(err) => self.journal.record(
+                EventKind::Error,
+                format!("failed to save BSEC state: {}", err),
+            ),
+            Ok(()) => {
+                let due = self
+                    .last_recorded
+                    .is_none_or(|last| last.elapsed() >= STATE_SAVE_INTERVAL);
+                if due {
+                    self.last_recorded = Some(Instant::now());
+                    self.journal.record(
+                        EventKind::StateSave,
+                        format!("saved BSEC state ({} bytes)", state.len()),
+                    );
+                }
+            }
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockPersistState;
+    use bsec::{Output, OutputKind};
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_journal_persistence() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("events.jsonl");
+        let journal = EventJournal::load(&path).unwrap();
+        journal.record(EventKind::Restart, "restarted");
+        journal.record(EventKind::Error, "failed");
+
+        let events = EventJournal::load(&path).unwrap().events();
+        assert_eq!(events.len(), 2);
+        assert_eq!(events[0].kind, EventKind::Restart);
+        assert_eq!(events[1].message, "failed");
+        assert_eq!(
+            parse_events(&fs::read_to_string(&path).unwrap()),
+            journal.events()
+        );
+    }
+
+    #[test]
+    fn test_journal_is_bounded() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("events.jsonl");
+        let journal = EventJournal::load(&path).unwrap();
+        for i in 0..CAPACITY + 5 {
+            journal.record(EventKind::Error, i.to_string());
+        }
+
+        let events = EventJournal::load(&path).unwrap().events();
+        assert_eq!(events.len(), CAPACITY);
+        assert_eq!(events[0].message, "5");
+    }
+
+    #[test]
+    fn test_journal_read_only() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("events.jsonl");
+        let read_only = ReadOnlySwitch::new();
+        read_only.set_read_only(true);
+        let journal = EventJournal::load(&path).unwrap().with_read_only(read_only);
+        journal.record(EventKind::Error, "failed");
+
+        assert_eq!(journal.events().len(), 1);
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn test_accuracy_tracker() {
+        let output = |sensor, accuracy| Output {
+            timestamp_ns: 0,
+            signal: 0.,
+            sensor,
+            accuracy,
+        };
+        let mut tracker = AccuracyTracker::default();
+        assert_eq!(
+            tracker.update(&[
+                output(OutputKind::Iaq, Accuracy::Unreliable),
+                output(OutputKind::RawGas, Accuracy::Unreliable),
+            ]),
+            vec![]
+        );
+        let transitions = tracker.update(&[
+            output(OutputKind::Iaq, Accuracy::LowAccuracy),
+            output(OutputKind::RawGas, Accuracy::Unreliable),
+        ]);
+        assert_eq!(
+            transitions,
+            vec![AccuracyTransition {
+                sensor: OutputKind::Iaq,
+                from: Accuracy::Unreliable,
+                to: Accuracy::LowAccuracy,
+            }]
+        );
+        assert_eq!(
+            describe_transitions(&transitions),
+            Some("accuracy changed: iaq 0 → 1".into())
+        );
+        assert_eq!(
+            tracker.update(&[output(OutputKind::Iaq, Accuracy::LowAccuracy)]),
+            vec![]
+        );
+        assert_eq!(describe_transitions(&[]), None);
+    }
+
+    #[test]
+    fn test_journaled_persist_state() {
+        let journal = EventJournal::new();
+        let mut persist_state =
+            JournaledPersistState::new(MockPersistState::default(), journal.clone());
+        persist_state.save_state(&[1, 2]).unwrap();
+        persist_state.save_state(&[1, 2]).unwrap();
+
+        let events = journal.events();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].kind, EventKind::StateSave);
+        assert_eq!(events[0].message, "saved BSEC state (2 bytes)");
+    }
+}
@@ -0,0 +1,220 @@
+//! Fault injection for end-to-end tests on real hardware.
+//!
+//! Failing and delayed I2C transactions as well as a corrupted BSEC state
+//! exercise the retries, timeouts, failover to the backup sensor, and the
+//! alerting without tampering with the wiring. The faults are set with the
+//! `/api/v1/debug/faults` endpoint. The module is only part of builds with the
+//! `debug` feature, so that production builds do not wrap the sensor and the
+//! state persistence at all.
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use embedded_hal::blocking::i2c::{Read, Write};
+use serde::{Deserialize, Serialize};
+
+use crate::log_warn;
+use crate::monitor::PersistState;
+
+/// Faults to inject.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+pub struct FaultSettings {
+    /// Number of the next I2C transactions to fail.
+    #[serde(default)]
+    pub failing_transactions: u32,
+
+    /// Delay of each I2C transaction, e.g. beyond the measurement timeout to
+    /// simulate a hung bus.
+    #[serde(default)]
+    pub transaction_delay_ms: u64,
+
+    /// Whether to corrupt the next saved BSEC state.
+    #[serde(default)]
+    pub corrupt_next_state: bool,
+}
+
+/// Faults shared by the wrappers and the endpoint setting them.
+#[derive(Clone, Debug, Default)]
+pub struct Faults(Arc<Mutex<FaultSettings>>);
+
+impl Faults {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Faults still to be injected.
+    pub fn settings(&self) -> FaultSettings {
+        *self.0.lock().unwrap()
+    }
+
+    pub fn set(&self, settings: FaultSettings) {
+        if settings != FaultSettings::default() {
+            log_warn!("Injecting faults: {:?}", settings);
+        }
+        *self.0.lock().unwrap() = settings;
+    }
+
+    /// Delay of the next I2C transaction and whether it fails.
+    fn take_transaction_fault(&self) -> (Duration, bool) {
+        let mut settings = self.0.lock().unwrap();
+        let fail = settings.failing_transactions > 0;
+        if fail {
+            settings.failing_transactions -= 1;
+        }
+        (Duration::from_millis(settings.transaction_delay_ms), fail)
+    }
+
+    fn take_state_corruption(&self) -> bool {
+        std::mem::take(&mut self.0.lock().unwrap().corrupt_next_state)
+    }
+}
+
+/// I2C device with the injected faults.
+pub struct FaultyI2c<I> {
+    device: I,
+    faults: Faults,
+}
+
+impl<I, E> FaultyI2c<I>
+where
+    I: Read<Error = E> + Write<Error = E>,
+    E: From<io::Error>,
+{
+    pub fn new(device: I, faults: Faults) -> Self {
+        Self { device, faults }
+    }
+
+    fn inject(&self) -> Result<(), E> {
+        let (delay, fail) = self.faults.take_transaction_fault();
+        if !delay.is_zero() {
+            thread::sleep(delay);
+        }
+        if fail {
+            return Err(io::Error::other("injected I2C fault").into());
+        }
+        Ok(())
+    }
+}
+
+impl<I, E> Read for FaultyI2c<I>
+where
+    I: Read<Error = E> + Write<Error = E>,
+    E: From<io::Error>,
+{
+    type Error = E;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.inject()?;
+        self.device.read(address, buffer)
+    }
+}
+
+impl<I, E> Write for FaultyI2c<I>
+where
+    I: Read<Error = E> + Write<Error = E>,
+    E: From<io::Error>,
+{
+    type Error = E;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.inject()?;
+        self.device.write(address, bytes)
+    }
+}
+
+/// Saves the BSEC state with every byte inverted if a corruption is injected.
+pub struct FaultyPersistState<P: PersistState> {
+    persist_state: P,
+    faults: Faults,
+}
+
+impl<P: PersistState> FaultyPersistState<P> {
+    pub fn new(persist_state: P, faults: Faults) -> Self {
+        Self {
+            persist_state,
+            faults,
+        }
+    }
+}
+
+impl<P: PersistState> PersistState for FaultyPersistState<P> {
+    type Error = P::Error;
+
+    fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persist_state.load_state()
+    }
+
+    fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
+        if self.faults.take_state_corruption() {
+            log_warn!("Saving a corrupted BSEC state as injected fault.");
+            let corrupted: Vec<u8> = state.iter().map(|byte| !byte).collect();
+            return self.persist_state.save_state(&corrupted);
+        }
+        self.persist_state.save_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockPersistState;
+
+    #[derive(Default)]
+    struct CountingI2c {
+        transactions: usize,
+    }
+
+    impl Read for CountingI2c {
+        type Error = io::Error;
+
+        fn read(&mut self, _address: u8, _buffer: &mut [u8]) -> io::Result<()> {
+            self.transactions += 1;
+            Ok(())
+        }
+    }
+
+    impl Write for CountingI2c {
+        type Error = io::Error;
+
+        fn write(&mut self, _address: u8, _bytes: &[u8]) -> io::Result<()> {
+            self.transactions += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_fails_the_given_number_of_transactions() {
+        let faults = Faults::new();
+        let mut i2c = FaultyI2c::new(CountingI2c::default(), faults.clone());
+        i2c.write(0x76, &[1]).unwrap();
+
+        faults.set(FaultSettings {
+            failing_transactions: 2,
+            ..FaultSettings::default()
+        });
+        assert!(i2c.write(0x76, &[1]).is_err());
+        assert!(i2c.read(0x76, &mut [0]).is_err());
+        i2c.read(0x76, &mut [0]).unwrap();
+
+        assert_eq!(i2c.device.transactions, 2);
+        assert_eq!(faults.settings(), FaultSettings::default());
+    }
+
+    #[test]
+    fn test_corrupts_next_saved_state_only() {
+        let mock = MockPersistState::default();
+        let faults = Faults::new();
+        let mut persist_state = FaultyPersistState::new(mock, faults.clone());
+        faults.set(FaultSettings {
+            corrupt_next_state: true,
+            ..FaultSettings::default()
+        });
+
+        persist_state.save_state(&[0x0f, 0x00]).unwrap();
+        assert_eq!(persist_state.load_state().unwrap(), Some(vec![0xf0, 0xff]));
+        persist_state.save_state(&[0x0f, 0x00]).unwrap();
+        assert_eq!(persist_state.load_state().unwrap(), Some(vec![0x0f, 0x00]));
+    }
+}
@@ -0,0 +1,340 @@
+//! Isolation of the BSEC library interactions.
+//!
+//! Panics and invalid data returned from the BSEC library are converted into
+//! a [`BsecCallError`] carrying a diagnostic snapshot of the monitoring, so
+//! that the monitoring can be restarted with a fresh BSEC instance instead of
+//! continuing with a possibly corrupted one.
+//!
+//! BSEC reports warnings and information, e.g. excess outputs or timing
+//! violations, with positive return codes, which the bsec crate returns as
+//! errors like actual failures. These are kept apart as
+//! [`BsecCallErrorKind::Warning`], after which the monitoring continues.
+
+use std::any::Any;
+use std::fmt::{self, Debug, Display, Formatter};
+use std::panic::{self, AssertUnwindSafe};
+
+use bsec::error::{BsecError, Error};
+use libalgobsec_sys::bsec_library_return_t;
+
+use crate::clock::Nanos;
+use crate::monitor::CycleTiming;
+use crate::{log_error, log_warn};
+
+/// Whether the BSEC return code is a warning or information (positive code)
+/// after which the processing can continue rather than an error.
+pub fn is_warning(err: &BsecError) -> bool {
+    use BsecError::*;
+    match err {
+        DoStepsNoOutputsReturnable
+        | DoStepsExcessOutputs
+        | DoStepsTsIntraDiffOutOfRange
+        | UpdateSubscriptionUnkownOutputGate
+        | UpdateSubscriptionModeInNonUlp
+        | UpdateSubscriptionSubscribedOutputGates
+        | SensorControlCallTimingViolation
+        | SensorControlModeExceedsUlpTimelimit
+        | SensorControlModeInsufficientWaitTime => true,
+        Unknown(code) => *code > 0,
+        _ => false,
+    }
+}
+
+/// The return code of the BSEC library for the `err`, e.g. for structured
+/// logging.
+pub fn error_code(err: &BsecError) -> bsec_library_return_t {
+    #![allow(non_upper_case_globals)]
+    use libalgobsec_sys::*;
+    use BsecError::*;
+    match err {
+        DoStepsInvalidInput => bsec_library_return_t_BSEC_E_DOSTEPS_INVALIDINPUT,
+        DoStepsValueLimits => bsec_library_return_t_BSEC_E_DOSTEPS_VALUELIMITS,
+        DoStepsDuplicateInput => bsec_library_return_t_BSEC_E_DOSTEPS_DUPLICATEINPUT,
+        DoStepsNoOutputsReturnable => bsec_library_return_t_BSEC_I_DOSTEPS_NOOUTPUTSRETURNABLE,
+        DoStepsExcessOutputs => bsec_library_return_t_BSEC_W_DOSTEPS_EXCESSOUTPUTS,
+        DoStepsTsIntraDiffOutOfRange => bsec_library_return_t_BSEC_W_DOSTEPS_TSINTRADIFFOUTOFRANGE,
+        UpdateSubscriptionWrongDataRate => bsec_library_return_t_BSEC_E_SU_WRONGDATARATE,
+        UpdateSubscriptionSampleRateLimits => bsec_library_return_t_BSEC_E_SU_SAMPLERATELIMITS,
+        UpdateSubscriptionDuplicateGate => bsec_library_return_t_BSEC_E_SU_DUPLICATEGATE,
+        UpdateSubscriptionInvalidSampleRate => bsec_library_return_t_BSEC_E_SU_INVALIDSAMPLERATE,
+        UpdateSubscriptionGateCountExceedsArray => {
+            bsec_library_return_t_BSEC_E_SU_GATECOUNTEXCEEDSARRAY
+        }
+        UpdateSubscriptionSampleIntervalIntegerMult => {
+            bsec_library_return_t_BSEC_E_SU_SAMPLINTVLINTEGERMULT
+        }
+        UpdateSubscriptionMultGaaSamplInterval => bsec_library_return_t_BSEC_E_SU_MULTGASSAMPLINTVL,
+        UpdateSubscriptionHighHeaterOnDuration => {
+            bsec_library_return_t_BSEC_E_SU_HIGHHEATERONDURATION
+        }
+        UpdateSubscriptionUnkownOutputGate => bsec_library_return_t_BSEC_W_SU_UNKNOWNOUTPUTGATE,
+        UpdateSubscriptionModeInNonUlp => bsec_library_return_t_BSEC_W_SU_MODINNOULP,
+        UpdateSubscriptionSubscribedOutputGates => {
+            bsec_library_return_t_BSEC_I_SU_SUBSCRIBEDOUTPUTGATES
+        }
+        ParseSectionExceedsWorkBuffer => {
+            bsec_library_return_t_BSEC_E_PARSE_SECTIONEXCEEDSWORKBUFFER
+        }
+        ConfigFail => bsec_library_return_t_BSEC_E_CONFIG_FAIL,
+        ConfigVersionMismatch => bsec_library_return_t_BSEC_E_CONFIG_VERSIONMISMATCH,
+        ConfigFeatureMismatch => bsec_library_return_t_BSEC_E_CONFIG_FEATUREMISMATCH,
+        ConfigCrcMismatch => bsec_library_return_t_BSEC_E_CONFIG_CRCMISMATCH,
+        ConfigEmpty => bsec_library_return_t_BSEC_E_CONFIG_EMPTY,
+        ConfigInsufficientWorkBuffer => bsec_library_return_t_BSEC_E_CONFIG_INSUFFICIENTWORKBUFFER,
+        ConfigInvalidStringSize => bsec_library_return_t_BSEC_E_CONFIG_INVALIDSTRINGSIZE,
+        ConfigInsufficientBuffer => bsec_library_return_t_BSEC_E_CONFIG_INSUFFICIENTBUFFER,
+        SetInvalidChannelIdentifier => bsec_library_return_t_BSEC_E_SET_INVALIDCHANNELIDENTIFIER,
+        SetInvalidLength => bsec_library_return_t_BSEC_E_SET_INVALIDLENGTH,
+        SensorControlCallTimingViolation => bsec_library_return_t_BSEC_W_SC_CALL_TIMING_VIOLATION,
+        SensorControlModeExceedsUlpTimelimit => {
+            bsec_library_return_t_BSEC_W_SC_MODEXCEEDULPTIMELIMIT
+        }
+        SensorControlModeInsufficientWaitTime => {
+            bsec_library_return_t_BSEC_W_SC_MODINSUFFICIENTWAITTIME
+        }
+        Unknown(code) => *code,
+    }
+}
+
+/// The BSEC return code of the `err`, if it was returned by the BSEC library.
+pub fn bsec_error_code<E: Debug>(err: &Error<E>) -> Option<bsec_library_return_t> {
+    match err {
+        Error::BsecError(err) => Some(error_code(err)),
+        _ => None,
+    }
+}
+
+/// The BSEC warning of the `err`, if it is one.
+pub fn bsec_warning<E: Debug>(err: &Error<E>) -> Option<&BsecError> {
+    match err {
+        Error::BsecError(err) if is_warning(err) => Some(err),
+        _ => None,
+    }
+}
+
+/// Snapshot of the monitoring at the time of a failed BSEC interaction.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct Diagnostics {
+    pub timestamp_ns: Nanos,
+    pub next_measurement_ns: Nanos,
+    pub timing: CycleTiming,
+}
+
+impl Display for Diagnostics {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "timestamp: {} ns, next measurement: {} ns, last cycle latency: {} ns, missed windows: {}",
+            self.timestamp_ns,
+            self.next_measurement_ns,
+            self.timing.latency_ns,
+            self.timing.missed_windows
+        )
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum BsecCallErrorKind {
+    /// The call panicked with the given message.
+    Panicked(String),
+    /// The BSEC library returned data not representable by the bsec crate.
+    InvalidOutput(String),
+    /// The BSEC library returned the given warning.
+    Warning(String),
+    /// Any other error.
+    Failed(String),
+}
+
+impl BsecCallErrorKind {
+    /// Name of the kind, e.g. for structured logging.
+    pub fn name(&self) -> &'static str {
+        use BsecCallErrorKind::*;
+        match self {
+            Panicked(_) => "panicked",
+            InvalidOutput(_) => "invalid_output",
+            Warning(_) => "warning",
+            Failed(_) => "failed",
+        }
+    }
+}
+
+impl Display for BsecCallErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        use BsecCallErrorKind::*;
+        match self {
+            Panicked(message) => write!(f, "panicked: {}", message),
+            InvalidOutput(err) => write!(f, "invalid output: {}", err),
+            Warning(warning) => write!(f, "warning: {}", warning),
+            Failed(err) => write!(f, "{}", err),
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct BsecCallError {
+    pub operation: &'static str,
+    pub kind: BsecCallErrorKind,
+    /// Return code of the BSEC library, if the error originates from it.
+    pub code: Option<bsec_library_return_t>,
+    pub diagnostics: Diagnostics,
+}
+
+impl Display for BsecCallError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "BSEC {} failed: {} ({})",
+            self.operation, self.kind, self.diagnostics
+        )
+    }
+}
+
+impl std::error::Error for BsecCallError {}
+
+impl BsecCallError {
+    pub fn is_warning(&self) -> bool {
+        matches!(self.kind, BsecCallErrorKind::Warning(_))
+    }
+
+    /// Logs the error with its diagnostics as fields, warnings with the
+    /// warning level.
+    pub fn log(&self) {
+        if self.is_warning() {
+            log_warn!(
+                operation = self.operation,
+                error_kind = self.kind.name(),
+                error_code = self.code;
+                "{}",
+                self
+            );
+        } else {
+            log_error!(
+                operation = self.operation,
+                error_kind = self.kind.name(),
+                error_code = self.code,
+                timestamp_ns = self.diagnostics.timestamp_ns.get(),
+                next_measurement_ns = self.diagnostics.next_measurement_ns.get();
+                "{}",
+                self
+            );
+        }
+    }
+}
+
+fn panic_message(payload: &(dyn Any + Send)) -> String {
+    if let Some(message) = payload.downcast_ref::<&str>() {
+        (*message).into()
+    } else if let Some(message) = payload.downcast_ref::<String>() {
+        message.clone()
+    } else {
+        "unknown panic payload".into()
+    }
+}
+
+/// Calls `f` converting panics, errors, and warnings into a
+/// [`BsecCallError`].
+///
+/// The diagnostics are only collected in case of a failure. The error is not
+/// logged, which is left to the caller handling it.
+pub fn guarded<T, E: Debug>(
+    operation: &'static str,
+    diagnostics: impl FnOnce() -> Diagnostics,
+    f: impl FnOnce() -> Result<T, Error<E>>,
+) -> Result<T, BsecCallError> {
+    let (kind, code) = match panic::catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(value)) => return Ok(value),
+        Ok(Err(Error::ConversionError(err))) => {
+            (BsecCallErrorKind::InvalidOutput(err.to_string()), None)
+        }
+        Ok(Err(err)) => {
+            let kind = match bsec_warning(&err) {
+                Some(warning) => BsecCallErrorKind::Warning(format!("{:?}", warning)),
+                None => BsecCallErrorKind::Failed(err.to_string()),
+            };
+            (kind, bsec_error_code(&err))
+        }
+        Err(payload) => (
+            BsecCallErrorKind::Panicked(panic_message(payload.as_ref())),
+            None,
+        ),
+    };
+    Err(BsecCallError {
+        operation,
+        kind,
+        code,
+        diagnostics: diagnostics(),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::error::ConversionError;
+
+    fn diagnostics() -> Diagnostics {
+        Diagnostics {
+            timestamp_ns: Nanos(2),
+            next_measurement_ns: Nanos(1),
+            timing: CycleTiming::default(),
+        }
+    }
+
+    #[test]
+    fn test_passes_through_values() {
+        assert_eq!(guarded::<_, ()>("test", diagnostics, || Ok(42)), Ok(42));
+    }
+
+    #[test]
+    fn test_converts_invalid_outputs() {
+        let err = guarded::<(), ()>("process_last_measurement", diagnostics, || {
+            Err(Error::ConversionError(ConversionError::InvalidAccuracy(7)))
+        })
+        .unwrap_err();
+        assert_eq!(
+            err,
+            BsecCallError {
+                operation: "process_last_measurement",
+                kind: BsecCallErrorKind::InvalidOutput("invalid accuracy: 7".into()),
+                code: None,
+                diagnostics: diagnostics(),
+            }
+        );
+    }
+
+    #[test]
+    fn test_separates_warnings() {
+        let err = guarded::<(), ()>("process_last_measurement", diagnostics, || {
+            Err(Error::BsecError(BsecError::DoStepsExcessOutputs))
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.kind,
+            BsecCallErrorKind::Warning("DoStepsExcessOutputs".into())
+        );
+        assert!(err.is_warning());
+        assert_eq!(
+            err.code,
+            Some(libalgobsec_sys::bsec_library_return_t_BSEC_W_DOSTEPS_EXCESSOUTPUTS)
+        );
+        let err = guarded::<(), ()>("process_last_measurement", diagnostics, || {
+            Err(Error::BsecError(BsecError::DoStepsInvalidInput))
+        })
+        .unwrap_err();
+        assert!(!err.is_warning());
+        assert!(is_warning(&BsecError::Unknown(100)));
+        assert!(!is_warning(&BsecError::Unknown(-100)));
+        assert_eq!(error_code(&BsecError::Unknown(-100)), -100);
+    }
+
+    #[test]
+    fn test_converts_panics() {
+        let err = guarded::<(), ()>("process_last_measurement", diagnostics, || {
+            panic!("misbehaving library")
+        })
+        .unwrap_err();
+        assert_eq!(
+            err.kind,
+            BsecCallErrorKind::Panicked("misbehaving library".into())
+        );
+    }
+}
@@ -0,0 +1,137 @@
+//! Switching the gas measurements off to save power and avoid heater wear
+//! when only climate data is needed.
+//!
+//! Without the outputs requiring the gas resistance, BSEC does not request
+//! gas measurements and the heater stays off. Temperature, pressure, and
+//! humidity outputs are unaffected.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use bsec::{OutputKind, SampleRate, SubscriptionRequest};
+use tokio::sync::mpsc;
+
+/// Outputs requiring gas measurements.
+pub const GAS_OUTPUTS: [OutputKind; 8] = [
+    OutputKind::Iaq,
+    OutputKind::StaticIaq,
+    OutputKind::Co2Equivalent,
+    OutputKind::BreathVocEquivalent,
+    OutputKind::RawGas,
+    OutputKind::StabilizationStatus,
+    OutputKind::RunInStatus,
+    OutputKind::GasPercentage,
+];
+
+type Profile = Arc<dyn Fn() -> Vec<SubscriptionRequest> + Send + Sync>;
+
+/// Runtime toggle of the gas measurements.
+#[derive(Clone)]
+pub struct GasSwitch {
+    is_enabled: Arc<AtomicBool>,
+    profile: Profile,
+    update_subscription: mpsc::UnboundedSender<Vec<SubscriptionRequest>>,
+}
+
+impl GasSwitch {
+    /// Switch resubscribing to the subscriptions returned by `profile` when
+    /// toggled.
+    pub fn new(
+        enabled: bool,
+        profile: impl Fn() -> Vec<SubscriptionRequest> + Send + Sync + 'static,
+        update_subscription: mpsc::UnboundedSender<Vec<SubscriptionRequest>>,
+    ) -> Self {
+        Self {
+            is_enabled: Arc::new(AtomicBool::new(enabled)),
+            profile: Arc::new(profile),
+            update_subscription,
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.is_enabled.load(Ordering::Acquire)
+    }
+
+    pub fn set_enabled(
+        &self,
+        enabled: bool,
+    ) -> Result<(), mpsc::error::SendError<Vec<SubscriptionRequest>>> {
+        if self.is_enabled.swap(enabled, Ordering::AcqRel) != enabled {
+            self.update_subscription.send((self.profile)())?;
+        }
+        Ok(())
+    }
+
+    /// Disables the outputs requiring gas measurements in the `subscriptions`
+    /// if the gas measurements are switched off.
+    pub fn apply(&self, subscriptions: Vec<SubscriptionRequest>) -> Vec<SubscriptionRequest> {
+        if self.is_enabled() {
+            return subscriptions;
+        }
+        subscriptions
+            .into_iter()
+            .map(|request| SubscriptionRequest {
+                sensor: request.sensor,
+                sample_rate: if GAS_OUTPUTS.contains(&request.sensor) {
+                    SampleRate::Disabled
+                } else {
+                    request.sample_rate
+                },
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn profile() -> Vec<SubscriptionRequest> {
+        vec![
+            SubscriptionRequest {
+                sensor: OutputKind::Iaq,
+                sample_rate: SampleRate::Lp,
+            },
+            SubscriptionRequest {
+                sensor: OutputKind::RawTemperature,
+                sample_rate: SampleRate::Lp,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_apply() {
+        let (update_subscription, _updates) = mpsc::unbounded_channel();
+        let gas = GasSwitch::new(true, profile, update_subscription);
+        assert_eq!(gas.apply(profile()), profile());
+
+        gas.set_enabled(false).unwrap();
+        assert_eq!(
+            gas.apply(profile()),
+            vec![
+                SubscriptionRequest {
+                    sensor: OutputKind::Iaq,
+                    sample_rate: SampleRate::Disabled,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::RawTemperature,
+                    sample_rate: SampleRate::Lp,
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn test_set_enabled_updates_subscription() {
+        let (update_subscription, mut updates) = mpsc::unbounded_channel();
+        let gas = GasSwitch::new(true, profile, update_subscription);
+
+        gas.set_enabled(true).unwrap();
+        assert!(updates.try_recv().is_err());
+        gas.set_enabled(false).unwrap();
+        assert!(!gas.is_enabled());
+        assert_eq!(updates.try_recv().unwrap(), profile());
+    }
+}
@@ -0,0 +1,160 @@
+//! Periodic heartbeat to a central URL for liveness tracking of a fleet of
+//! exporters without Prometheus federation.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, Instant};
+
+use prometheus::proto::{MetricFamily, MetricType};
+use serde::Serialize;
+
+use crate::config::HeartbeatConfig;
+use crate::http_client;
+use crate::identity::Identity;
+use crate::log_error;
+use crate::metrics::BsecGaugeRegistry;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Heartbeat<'a> {
+    #[serde(flatten)]
+    pub identity: &'a Identity,
+    pub uptime_seconds: u64,
+    pub version: &'static str,
+    /// Version of the linked BSEC library, `None` if it could not be queried.
+    pub bsec_version: Option<String>,
+    /// Last accuracy by BSEC output name.
+    pub accuracy: BTreeMap<String, f64>,
+    /// Values of the counters, e.g. restarts and watchdog stalls, by metric
+    /// name.
+    pub counters: BTreeMap<String, f64>,
+}
+
+/// Values of the unlabeled or first metric of each counter family.
+fn counter_values(families: &[MetricFamily]) -> BTreeMap<String, f64> {
+    families
+        .iter()
+        .filter(|family| family.get_field_type() == MetricType::COUNTER)
+        .filter_map(|family| {
+            let metric = family.get_metric().first()?;
+            Some((
+                family.get_name().to_string(),
+                metric.get_counter().get_value(),
+            ))
+        })
+        .collect()
+}
+
+impl<'a> Heartbeat<'a> {
+    pub fn new(identity: &'a Identity, uptime: Duration, registry: &BsecGaugeRegistry) -> Self {
+        Self {
+            identity,
+            uptime_seconds: uptime.as_secs(),
+            version: env!("CARGO_PKG_VERSION"),
+            bsec_version: crate::limits::limits().version,
+            accuracy: registry
+                .snapshot()
+                .into_iter()
+                .map(|(name, value)| (name, value.accuracy))
+                .collect(),
+            counters: counter_values(&registry.gather()),
+        }
+    }
+}
+
+/// Posts a [`Heartbeat`] to the configured URL in the configured interval.
+///
+/// `started` is the start of the exporter the uptime is counted from.
+pub async fn run_heartbeat(
+    config: HeartbeatConfig,
+    identity: Identity,
+    registry: BsecGaugeRegistry,
+    started: Instant,
+) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+    loop {
+        ticks.tick().await;
+        let heartbeat = Heartbeat::new(&identity, started.elapsed(), &registry);
+        let result = match serde_json::to_string(&heartbeat) {
+            Ok(body) => http_client::post_json(&config.url, body).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            log_error!("Failed to send heartbeat to {}: {}", config.url, err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::{Accuracy, Output, OutputKind};
+    use std::collections::HashMap;
+    use std::io::{Read, Write};
+    use uuid::Uuid;
+
+    fn identity() -> Identity {
+        Identity {
+            uuid: Uuid::parse_str("67e55044-10b1-426f-9247-bb680e5fe0c8").unwrap(),
+            labels: HashMap::new(),
+        }
+    }
+
+    #[test]
+    fn test_heartbeat() {
+        let registry = BsecGaugeRegistry::new(&[OutputKind::Iaq]).unwrap();
+        registry.set(&Output {
+            timestamp_ns: 0,
+            signal: 42.,
+            sensor: OutputKind::Iaq,
+            accuracy: Accuracy::MediumAccuracy,
+        });
+        registry.inc_restarts();
+        let identity = identity();
+
+        let heartbeat = Heartbeat::new(&identity, Duration::from_secs(90), &registry);
+        assert_eq!(heartbeat.uptime_seconds, 90);
+        assert_eq!(heartbeat.accuracy["iaq"], 2.);
+        assert_eq!(heartbeat.counters["bsec_monitoring_restarts_total"], 1.);
+        assert_eq!(heartbeat.counters["bsec_watchdog_stalls_total"], 0.);
+
+        let json = serde_json::to_value(&heartbeat).unwrap();
+        assert_eq!(json["uuid"], "67e55044-10b1-426f-9247-bb680e5fe0c8");
+        assert_eq!(json["version"], env!("CARGO_PKG_VERSION"));
+    }
+
+    #[tokio::test]
+    async fn test_run_heartbeat_posts_status() {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/heartbeat", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut request = vec![];
+            let mut buffer = [0; 1024];
+            while !String::from_utf8_lossy(&request).contains("\"counters\"") {
+                let len = stream.read(&mut buffer).unwrap();
+                request.extend_from_slice(&buffer[..len]);
+            }
+            stream
+                .write_all(b"HTTP/1.1 204 No Content\r\ncontent-length: 0\r\n\r\n")
+                .unwrap();
+            String::from_utf8(request).unwrap()
+        });
+
+        let heartbeat = tokio::task::spawn(run_heartbeat(
+            HeartbeatConfig {
+                url,
+                interval_seconds: 3600,
+            },
+            identity(),
+            BsecGaugeRegistry::new(&[]).unwrap(),
+            Instant::now(),
+        ));
+        let request = tokio::task::spawn_blocking(move || server.join().unwrap())
+            .await
+            .unwrap();
+        heartbeat.abort();
+
+        assert!(request.starts_with("POST /heartbeat HTTP/1.1"));
+        assert!(request.contains("application/json"));
+        assert!(request.contains("67e55044-10b1-426f-9247-bb680e5fe0c8"));
+    }
+}
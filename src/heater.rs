@@ -0,0 +1,198 @@
+//! Usage statistics of the gas sensor heater.
+//!
+//! The heater dominates the power consumption of the sensor. Its on-time per
+//! hour allows to estimate the power consumption of a sample rate profile,
+//! e.g. ULP for battery-powered deployments. The cumulative on-time and the
+//! duty cycle also correlate with the wear and drift of the sensor.
+
+use std::collections::VecDeque;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::Input;
+use prometheus::{Counter, Gauge, IntCounter};
+
+const HOUR: Duration = Duration::from_secs(3600);
+
+#[derive(Clone)]
+pub struct HeaterMetrics {
+    pub on_time_per_hour: Gauge,
+    pub measurements_per_hour: Gauge,
+    pub on_time_total: Counter,
+    pub measurements_total: IntCounter,
+    pub duty_cycle: Gauge,
+    pub profile_duration: Gauge,
+}
+
+#[derive(Debug, Default)]
+struct Measurements {
+    /// Start of the first measurement.
+    since: Option<Instant>,
+    /// Start and heater on-time of the measurements within the last hour.
+    last_hour: VecDeque<(Instant, Duration)>,
+}
+
+/// Heater on-times of the measurements.
+#[derive(Clone)]
+pub struct HeaterUsage {
+    measurements: Arc<Mutex<Measurements>>,
+    metrics: HeaterMetrics,
+}
+
+impl HeaterUsage {
+    pub fn new(metrics: HeaterMetrics) -> Self {
+        Self {
+            measurements: Arc::new(Mutex::new(Measurements::default())),
+            metrics,
+        }
+    }
+
+    /// Records a measurement with the given heater on-time.
+    pub fn record(&self, on_time: Duration) {
+        self.record_at(Instant::now(), on_time);
+    }
+
+    /// Records the measurement profile duration, i.e. the time until the
+    /// measurement with the current heater and oversampling settings is
+    /// available.
+    pub fn set_profile_duration(&self, duration: Duration) {
+        self.metrics.profile_duration.set(duration.as_secs_f64());
+    }
+
+    fn record_at(&self, now: Instant, on_time: Duration) {
+        self.metrics.on_time_total.inc_by(on_time.as_secs_f64());
+        self.metrics.measurements_total.inc();
+        let mut measurements = self.measurements.lock().unwrap();
+        let since = *measurements.since.get_or_insert(now);
+        let last_hour = &mut measurements.last_hour;
+        last_hour.push_back((now, on_time));
+        while let Some(&(at, _)) = last_hour.front() {
+            if now.duration_since(at) < HOUR {
+                break;
+            }
+            last_hour.pop_front();
+        }
+        let on_time_last_hour: Duration = last_hour.iter().map(|&(_, on_time)| on_time).sum();
+        self.metrics
+            .on_time_per_hour
+            .set(on_time_last_hour.as_secs_f64());
+        self.metrics
+            .measurements_per_hour
+            .set(last_hour.len() as f64);
+        // Until an hour has passed, only the time since the first
+        // measurement is covered.
+        let covered = (now.duration_since(since) + on_time).min(HOUR);
+        if !covered.is_zero() {
+            self.metrics
+                .duty_cycle
+                .set(on_time_last_hour.as_secs_f64() / covered.as_secs_f64());
+        }
+    }
+}
+
+/// Records the heater on-time of each measurement of the wrapped sensor.
+pub struct HeaterSensor<S: BmeSensor> {
+    sensor: S,
+    usage: HeaterUsage,
+    heating_duration: Option<Duration>,
+}
+
+impl<S: BmeSensor> HeaterSensor<S> {
+    pub fn new(sensor: S, usage: HeaterUsage) -> Self {
+        Self {
+            sensor,
+            usage,
+            heating_duration: None,
+        }
+    }
+
+    /// Records the `heating_duration` overriding the one requested by BSEC,
+    /// if any, as on-time.
+    pub fn with_heating_duration(self, heating_duration: Option<Duration>) -> Self {
+        Self {
+            heating_duration,
+            ..self
+        }
+    }
+
+    /// Heater on-time of a measurement with the given settings.
+    fn on_time(&self, settings: &BmeSettingsHandle) -> Duration {
+        if settings.run_gas() {
+            self.heating_duration
+                .unwrap_or_else(|| Duration::from_millis(settings.heating_duration().into()))
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+impl<S: BmeSensor> BmeSensor for HeaterSensor<S> {
+    type Error = S::Error;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        let duration = self.sensor.start_measurement(settings)?;
+        self.usage.record(self.on_time(settings));
+        self.usage.set_profile_duration(duration);
+        Ok(duration)
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        self.sensor.get_measurement()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn metrics() -> HeaterMetrics {
+        HeaterMetrics {
+            on_time_per_hour: Gauge::new("on_time", "help").unwrap(),
+            measurements_per_hour: Gauge::new("measurements", "help").unwrap(),
+            on_time_total: Counter::new("on_time_total", "help").unwrap(),
+            measurements_total: IntCounter::new("measurements_total", "help").unwrap(),
+            duty_cycle: Gauge::new("duty_cycle", "help").unwrap(),
+            profile_duration: Gauge::new("profile_duration", "help").unwrap(),
+        }
+    }
+
+    #[test]
+    fn test_heater_usage_within_last_hour() {
+        let usage = HeaterUsage::new(metrics());
+        let start = Instant::now();
+        // ULP: one measurement every 300 s
+        for i in 0..24 {
+            usage.record_at(
+                start + Duration::from_secs(300 * i),
+                Duration::from_millis(1950),
+            );
+        }
+        assert_eq!(usage.metrics.measurements_per_hour.get(), 12.);
+        assert!((usage.metrics.on_time_per_hour.get() - 12. * 1.95).abs() < 1e-9);
+
+        usage.record_at(start + Duration::from_secs(300 * 24), Duration::ZERO);
+        assert_eq!(usage.metrics.measurements_per_hour.get(), 12.);
+        assert!((usage.metrics.on_time_per_hour.get() - 11. * 1.95).abs() < 1e-9);
+        assert!((usage.metrics.on_time_total.get() - 24. * 1.95).abs() < 1e-9);
+        assert_eq!(usage.metrics.measurements_total.get(), 25);
+        assert!((usage.metrics.duty_cycle.get() - 11. * 1.95 / 3600.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_duty_cycle_within_first_hour() {
+        let usage = HeaterUsage::new(metrics());
+        let start = Instant::now();
+        usage.record_at(start, Duration::from_secs(1));
+        assert_eq!(usage.metrics.duty_cycle.get(), 1.);
+        usage.record_at(start + Duration::from_secs(3), Duration::from_secs(1));
+        assert_eq!(usage.metrics.duty_cycle.get(), 0.5);
+    }
+
+    #[test]
+    fn test_profile_duration() {
+        let usage = HeaterUsage::new(metrics());
+        usage.set_profile_duration(Duration::from_millis(1950));
+        assert_eq!(usage.metrics.profile_duration.get(), 1.95);
+    }
+}
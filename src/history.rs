@@ -0,0 +1,808 @@
+//! Retention-limited history of the BSEC outputs.
+//!
+//! The history keeps at most one sample per interval for the retention
+//! period, e.g. to show recent trends without a Prometheus server. It is
+//! saved periodically and on shutdown and reloaded on startup to continue
+//! across restarts. The file is replaced atomically when saving, and a
+//! truncated or corrupted trailing record is skipped when loading.
+//!
+//! The file uses a compact binary encoding: the magic bytes `BSH2` followed
+//! by the samples, each consisting of the LEB128 encoded seconds since the
+//! previous sample (since the Unix epoch for the first one), the number of
+//! values, the accuracy of the sample as byte (`0xff` if unknown), and for
+//! each value the BSEC virtual sensor ID as byte and the signal as
+//! little-endian single precision float. Files of the `BSH1` version without
+//! the accuracy are still read. Annotations are stored as
+//! records in between the samples in timestamp order with `0xff` in place of
+//! the number of values, followed by the LEB128 encoded length and UTF-8
+//! bytes of the text, the number of tags, and each tag the same way as the
+//! text.
+//!
+//! Outputs measured while the system time is not synchronized yet, e.g.
+//! after a cold boot of a device without real-time clock, are kept with
+//! their monotonic time and added with reconciled timestamps once it is.
+//!
+//! The samples can be exported in the OpenMetrics text format with the names
+//! of the exported gauges, e.g. to backfill Prometheus with
+//! `promtool tsdb create-blocks-from openmetrics` after it was unreachable.
+
+use std::collections::{BTreeMap, VecDeque};
+use std::convert::TryFrom;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use bsec::{Accuracy, Output, OutputKind};
+use libalgobsec_sys::bsec_virtual_sensor_t;
+use serde::{Serialize, Serializer};
+
+use crate::config::output_kind_name;
+use crate::log_warn;
+use crate::metrics::MetricSchema;
+use crate::persistance::write_atomically;
+use crate::sink::ACCURACY_WEIGHTED_OUTPUTS;
+
+const MAGIC: &[u8] = b"BSH2";
+/// Magic bytes of the version without the accuracy of the samples.
+const MAGIC_V1: &[u8] = b"BSH1";
+/// Byte in place of the accuracy of samples without accuracy-weighted
+/// outputs.
+const UNKNOWN_ACCURACY: u8 = 0xff;
+/// Marker in place of the number of values of annotation records.
+const ANNOTATION: u8 = 0xff;
+/// Maximum number of tags of an annotation.
+pub const MAX_TAGS: usize = 32;
+
+/// Output values at a point in time.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Sample {
+    /// Seconds since the Unix epoch.
+    pub timestamp: u64,
+    /// Signals by output name as used in the configuration.
+    #[serde(serialize_with = "serialize_values")]
+    pub values: Vec<(OutputKind, f32)>,
+    /// Lowest accuracy of the accuracy-weighted outputs, `None` without
+    /// such outputs.
+    #[serde(
+        serialize_with = "serialize_accuracy",
+        skip_serializing_if = "Option::is_none"
+    )]
+    pub accuracy: Option<Accuracy>,
+}
+
+fn serialize_accuracy<S: Serializer>(
+    accuracy: &Option<Accuracy>,
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    accuracy
+        .map(|accuracy| accuracy as u8)
+        .serialize(serializer)
+}
+
+fn serialize_values<S: Serializer>(
+    values: &[(OutputKind, f32)],
+    serializer: S,
+) -> Result<S::Ok, S::Error> {
+    values
+        .iter()
+        .map(|(sensor, signal)| (output_kind_name(*sensor), *signal))
+        .collect::<BTreeMap<_, _>>()
+        .serialize(serializer)
+}
+
+/// Note on a point in time, e.g. "window opened", to correlate events with
+/// changes of the outputs.
+///
+/// Serializes in the annotation format of Grafana with the time in
+/// milliseconds.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Annotation {
+    /// Seconds since the Unix epoch.
+    #[serde(rename = "time", serialize_with = "serialize_millis")]
+    pub timestamp: u64,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+fn serialize_millis<S: Serializer>(timestamp: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+    serializer.serialize_u64(timestamp.saturating_mul(1000))
+}
+
+fn push_leb128(buffer: &mut Vec<u8>, mut value: u64) {
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+        if value == 0 {
+            buffer.push(byte);
+            break;
+        }
+        buffer.push(byte | 0x80);
+    }
+}
+
+fn push_str(buffer: &mut Vec<u8>, value: &str) {
+    push_leb128(buffer, value.len() as u64);
+    buffer.extend(value.as_bytes());
+}
+
+/// Encodes the samples and annotations in the binary file format.
+pub fn encode(samples: &[Sample], annotations: &[Annotation]) -> Vec<u8> {
+    enum Record<'a> {
+        Sample(&'a Sample),
+        Annotation(&'a Annotation),
+    }
+    let mut records: Vec<_> = samples
+        .iter()
+        .map(Record::Sample)
+        .chain(annotations.iter().map(Record::Annotation))
+        .collect();
+    records.sort_by_key(|record| match record {
+        Record::Sample(sample) => sample.timestamp,
+        Record::Annotation(annotation) => annotation.timestamp,
+    });
+
+    let mut buffer = MAGIC.to_vec();
+    let mut previous = 0;
+    for record in records {
+        match record {
+            Record::Sample(sample) => {
+                push_leb128(&mut buffer, sample.timestamp.saturating_sub(previous));
+                previous = sample.timestamp;
+                buffer.push(sample.values.len() as u8);
+                buffer.push(
+                    sample
+                        .accuracy
+                        .map_or(UNKNOWN_ACCURACY, |accuracy| accuracy as u8),
+                );
+                for (sensor, signal) in sample.values.iter() {
+                    buffer.push(bsec_virtual_sensor_t::from(*sensor) as u8);
+                    buffer.extend(signal.to_le_bytes());
+                }
+            }
+            Record::Annotation(annotation) => {
+                push_leb128(&mut buffer, annotation.timestamp.saturating_sub(previous));
+                previous = annotation.timestamp;
+                buffer.push(ANNOTATION);
+                push_str(&mut buffer, &annotation.text);
+                buffer.push(annotation.tags.len().min(MAX_TAGS) as u8);
+                for tag in annotation.tags.iter().take(MAX_TAGS) {
+                    push_str(&mut buffer, tag);
+                }
+            }
+        }
+    }
+    buffer
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+/// Reads a LEB128 encoded number starting with the `first` byte.
+fn read_leb128(first: u8, next: &mut impl FnMut() -> io::Result<u8>) -> io::Result<u64> {
+    let mut byte = first;
+    let mut value: u64 = 0;
+    let mut shift = 0;
+    while byte & 0x80 != 0 {
+        value |= u64::from(byte & 0x7f) << shift;
+        shift += 7;
+        if shift >= 64 {
+            return Err(invalid_data("invalid number in history file"));
+        }
+        byte = next()?;
+    }
+    Ok(value | u64::from(byte) << shift)
+}
+
+fn read_str(next: &mut impl FnMut() -> io::Result<u8>) -> io::Result<String> {
+    let first = next()?;
+    let len = read_leb128(first, next)?;
+    let bytes = (0..len).map(|_| next()).collect::<io::Result<Vec<_>>>()?;
+    String::from_utf8(bytes).map_err(|_| invalid_data("invalid text in history file"))
+}
+
+enum Record {
+    Sample(Sample),
+    Annotation(Annotation),
+}
+
+/// Reads the record starting with the `first` byte, advancing the
+/// `timestamp` of the previous record.
+fn read_record(
+    first: u8,
+    timestamp: &mut u64,
+    with_accuracy: bool,
+    next: &mut impl FnMut() -> io::Result<u8>,
+) -> io::Result<Record> {
+    *timestamp = timestamp.saturating_add(read_leb128(first, next)?);
+
+    let count = next()?;
+    if count == ANNOTATION {
+        let text = read_str(next)?;
+        let tags = (0..next()?)
+            .map(|_| read_str(next))
+            .collect::<io::Result<_>>()?;
+        return Ok(Record::Annotation(Annotation {
+            timestamp: *timestamp,
+            text,
+            tags,
+        }));
+    }
+    let accuracy = match with_accuracy {
+        true => match next()? {
+            UNKNOWN_ACCURACY => None,
+            byte => Some(
+                Accuracy::try_from(byte)
+                    .map_err(|_| invalid_data("invalid accuracy in history file"))?,
+            ),
+        },
+        false => None,
+    };
+    let mut values = Vec::with_capacity(count as usize);
+    for _ in 0..count {
+        let sensor = OutputKind::try_from(next()?)
+            .map_err(|_| invalid_data("invalid output kind in history file"))?;
+        let signal = f32::from_le_bytes([next()?, next()?, next()?, next()?]);
+        values.push((sensor, signal));
+    }
+    Ok(Record::Sample(Sample {
+        timestamp: *timestamp,
+        values,
+        accuracy,
+    }))
+}
+
+/// Samples and annotations of a history file.
+pub type Records = (Vec<Sample>, Vec<Annotation>);
+
+/// Decodes the records up to the first invalid one, which is returned as
+/// error together with the records before it.
+pub fn decode_valid(data: &[u8]) -> io::Result<(Records, Option<io::Error>)> {
+    let (with_accuracy, data) = match (data.strip_prefix(MAGIC), data.strip_prefix(MAGIC_V1)) {
+        (Some(data), _) => (true, data),
+        (None, Some(data)) => (false, data),
+        (None, None) => return Err(invalid_data("not a history file")),
+    };
+    let mut data = data.iter().copied();
+    let mut next = || {
+        data.next()
+            .ok_or_else(|| invalid_data("truncated history file"))
+    };
+    let mut samples = vec![];
+    let mut annotations = vec![];
+    let mut timestamp: u64 = 0;
+    while let Ok(byte) = next() {
+        match read_record(byte, &mut timestamp, with_accuracy, &mut next) {
+            Ok(Record::Sample(sample)) => samples.push(sample),
+            Ok(Record::Annotation(annotation)) => annotations.push(annotation),
+            Err(err) => return Ok(((samples, annotations), Some(err))),
+        }
+    }
+    Ok(((samples, annotations), None))
+}
+
+fn escape_label_value(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+fn format_openmetrics_value(value: f32) -> String {
+    if value == f32::INFINITY {
+        "+Inf".into()
+    } else if value == f32::NEG_INFINITY {
+        "-Inf".into()
+    } else {
+        value.to_string()
+    }
+}
+
+/// Encodes the signals of the samples as gauges in the OpenMetrics text
+/// format with the `labels`, leaving out signals without value (NaN).
+///
+/// The samples of each gauge are grouped as the format requires.
+pub fn to_openmetrics(samples: &[Sample], labels: &BTreeMap<String, String>) -> String {
+    let mut gauges: BTreeMap<String, (MetricSchema, Vec<(u64, f32)>)> = BTreeMap::new();
+    for sample in samples {
+        for (sensor, signal) in sample.values.iter() {
+            if signal.is_nan() {
+                continue;
+            }
+            let schema = MetricSchema::from(*sensor);
+            gauges
+                .entry(schema.name.clone())
+                .or_insert_with(|| (schema, vec![]))
+                .1
+                .push((sample.timestamp, *signal));
+        }
+    }
+    let labels = if labels.is_empty() {
+        String::new()
+    } else {
+        format!(
+            "{{{}}}",
+            labels
+                .iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape_label_value(value)))
+                .collect::<Vec<_>>()
+                .join(",")
+        )
+    };
+    let mut text = String::new();
+    for (name, (schema, values)) in gauges.iter() {
+        text.push_str(&format!("# HELP {} {}\n", name, schema.description));
+        text.push_str(&format!("# TYPE {} gauge\n", name));
+        for (timestamp, signal) in values {
+            text.push_str(&format!(
+                "{}{} {} {}\n",
+                name,
+                labels,
+                format_openmetrics_value(*signal),
+                timestamp
+            ));
+        }
+    }
+    text.push_str("# EOF\n");
+    text
+}
+
+/// Decodes the samples and annotations from the binary file format.
+pub fn decode(data: &[u8]) -> io::Result<Records> {
+    match decode_valid(data)? {
+        (records, None) => Ok(records),
+        (_, Some(err)) => Err(err),
+    }
+}
+
+#[derive(Debug)]
+struct Samples {
+    samples: VecDeque<Sample>,
+    annotations: VecDeque<Annotation>,
+    /// Outputs measured while the system time was not synchronized with the
+    /// monotonic time of their measurement.
+    unsynchronized: VecDeque<(Instant, Vec<Output>)>,
+    retention: Duration,
+    interval: Duration,
+}
+
+impl Samples {
+    fn push(&mut self, timestamp: u64, outputs: &[Output]) -> bool {
+        let interval = self.interval.as_secs();
+        if self
+            .samples
+            .back()
+            .is_some_and(|last| timestamp < last.timestamp.saturating_add(interval))
+        {
+            return false;
+        }
+        self.samples.push_back(Sample {
+            timestamp,
+            values: outputs
+                .iter()
+                .map(|output| (output.sensor, output.signal as f32))
+                .collect(),
+            accuracy: outputs
+                .iter()
+                .filter(|output| ACCURACY_WEIGHTED_OUTPUTS.contains(&output.sensor))
+                .map(|output| output.accuracy)
+                .min_by_key(|accuracy| *accuracy as u8),
+        });
+        self.prune(timestamp);
+        true
+    }
+
+    fn prune(&mut self, now: u64) {
+        let oldest = now.saturating_sub(self.retention.as_secs());
+        while self
+            .samples
+            .front()
+            .is_some_and(|sample| sample.timestamp < oldest)
+        {
+            self.samples.pop_front();
+        }
+        while self
+            .annotations
+            .front()
+            .is_some_and(|annotation| annotation.timestamp < oldest)
+        {
+            self.annotations.pop_front();
+        }
+    }
+}
+
+/// History shared between the monitoring and the API.
+#[derive(Clone, Debug)]
+pub struct History {
+    samples: Arc<Mutex<Samples>>,
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+impl History {
+    /// Keeps a sample at most every `interval` for the `retention` period.
+    pub fn new(retention: Duration, interval: Duration) -> Self {
+        Self {
+            samples: Arc::new(Mutex::new(Samples {
+                samples: VecDeque::new(),
+                annotations: VecDeque::new(),
+                unsynchronized: VecDeque::new(),
+                retention,
+                interval,
+            })),
+        }
+    }
+
+    /// Restores the samples and annotations saved to `path`, if any.
+    ///
+    /// A truncated or corrupted record, e.g. of a write interrupted by a
+    /// power loss, is skipped together with the records after it.
+    pub fn load<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let (loaded_samples, loaded_annotations) = match fs::read(path) {
+            Ok(data) => match decode_valid(&data)? {
+                (records, None) => records,
+                ((samples, annotations), Some(err)) => {
+                    log_warn!(
+                        "Skipping the trailing history records after {} samples and {} annotations: {}",
+                        samples.len(),
+                        annotations.len(),
+                        err
+                    );
+                    (samples, annotations)
+                }
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(err),
+        };
+        let mut samples = self.samples.lock().unwrap();
+        samples.samples = loaded_samples.into();
+        samples.annotations = loaded_annotations.into();
+        samples.prune(unix_now());
+        Ok(())
+    }
+
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        write_atomically(path.as_ref(), &encode(&self.samples(), &self.annotations()))
+    }
+
+    /// Adds the outputs as sample at `timestamp` in seconds since the Unix
+    /// epoch unless the last sample is more recent than the interval.
+    ///
+    /// Returns whether the sample was added.
+    pub fn add(&self, timestamp: u64, outputs: &[Output]) -> bool {
+        self.samples.lock().unwrap().push(timestamp, outputs)
+    }
+
+    /// Adds the outputs as sample measured now.
+    pub fn add_now(&self, outputs: &[Output]) -> bool {
+        self.add(unix_now(), outputs)
+    }
+
+    /// Keeps the outputs measured at the monotonic time `now` while the
+    /// system time is not synchronized, unless the last kept outputs are
+    /// more recent than the interval, until [`History::reconcile`].
+    ///
+    /// Returns whether the outputs were kept.
+    pub fn add_unsynchronized(&self, now: Instant, outputs: &[Output]) -> bool {
+        let mut samples = self.samples.lock().unwrap();
+        let (interval, retention) = (samples.interval, samples.retention);
+        if samples
+            .unsynchronized
+            .back()
+            .is_some_and(|(last, _)| now < *last + interval)
+        {
+            return false;
+        }
+        samples.unsynchronized.push_back((now, outputs.to_vec()));
+        while samples
+            .unsynchronized
+            .front()
+            .is_some_and(|(measured, _)| now.duration_since(*measured) > retention)
+        {
+            samples.unsynchronized.pop_front();
+        }
+        true
+    }
+
+    /// Adds the outputs kept while the system time was not synchronized as
+    /// samples, dating them back from the synchronized system time `now_unix`
+    /// at the monotonic time `now`.
+    ///
+    /// Returns the number of added samples.
+    pub fn reconcile(&self, now: Instant, now_unix: u64) -> usize {
+        let mut samples = self.samples.lock().unwrap();
+        let unsynchronized = std::mem::take(&mut samples.unsynchronized);
+        unsynchronized
+            .into_iter()
+            .filter(|(measured, outputs)| {
+                let age = now.saturating_duration_since(*measured).as_secs();
+                samples.push(now_unix.saturating_sub(age), outputs)
+            })
+            .count()
+    }
+
+    /// Adds the outputs kept while the system time was not synchronized as
+    /// samples dated back from now.
+    pub fn reconcile_now(&self) -> usize {
+        self.reconcile(Instant::now(), unix_now())
+    }
+
+    /// Samples from the oldest to the most recent one.
+    pub fn samples(&self) -> Vec<Sample> {
+        self.samples
+            .lock()
+            .unwrap()
+            .samples
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Adds the annotation in timestamp order, at most `MAX_TAGS` tags are
+    /// kept.
+    pub fn annotate(&self, mut annotation: Annotation) {
+        annotation.tags.truncate(MAX_TAGS);
+        let mut samples = self.samples.lock().unwrap();
+        let index = samples
+            .annotations
+            .partition_point(|other| other.timestamp <= annotation.timestamp);
+        samples.annotations.insert(index, annotation);
+        samples.prune(unix_now());
+    }
+
+    /// Annotations from the oldest to the most recent one.
+    pub fn annotations(&self) -> Vec<Annotation> {
+        self.samples
+            .lock()
+            .unwrap()
+            .annotations
+            .iter()
+            .cloned()
+            .collect()
+    }
+
+    /// Samples at most `seconds` older than the most recent one from the
+    /// oldest to the most recent one.
+    pub fn recent(&self, seconds: u64) -> Vec<Sample> {
+        let samples = self.samples.lock().unwrap();
+        let oldest = match samples.samples.back() {
+            Some(latest) => latest.timestamp.saturating_sub(seconds),
+            None => return vec![],
+        };
+        let start = samples
+            .samples
+            .partition_point(|sample| sample.timestamp < oldest);
+        samples.samples.range(start..).cloned().collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn outputs(signal: f64) -> Vec<Output> {
+        vec![
+            Output {
+                timestamp_ns: 0,
+                signal,
+                sensor: OutputKind::Iaq,
+                accuracy: Accuracy::MediumAccuracy,
+            },
+            Output {
+                timestamp_ns: 0,
+                signal: 21.5,
+                sensor: OutputKind::SensorHeatCompensatedTemperature,
+                accuracy: Accuracy::HighAccuracy,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_history_interval_and_retention() {
+        let history = History::new(Duration::from_secs(360), Duration::from_secs(60));
+        assert!(history.add(1_000, &outputs(1.)));
+        assert!(!history.add(1_030, &outputs(2.)));
+        assert!(history.add(1_060, &outputs(3.)));
+        assert!(history.add(1_400, &outputs(4.)));
+
+        let samples = history.samples();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].timestamp, 1_060);
+        assert_eq!(samples[0].values[0], (OutputKind::Iaq, 3.));
+        assert_eq!(samples[0].accuracy, Some(Accuracy::MediumAccuracy));
+        assert_eq!(samples[1].timestamp, 1_400);
+        assert_eq!(history.recent(339), samples[1..]);
+    }
+
+    #[test]
+    fn test_encoding_roundtrip() {
+        let samples = vec![
+            Sample {
+                timestamp: 1_700_000_000,
+                values: vec![(OutputKind::Iaq, 42.5), (OutputKind::RawGas, 123_456.)],
+                accuracy: Some(Accuracy::HighAccuracy),
+            },
+            Sample {
+                timestamp: 1_700_000_060,
+                values: vec![],
+                accuracy: None,
+            },
+        ];
+        let encoded = encode(&samples, &[]);
+        assert_eq!(encoded.len(), 4 + (5 + 2 + 2 * 5) + (1 + 2));
+        assert_eq!(decode(&encoded).unwrap(), (samples, vec![]));
+        assert!(decode(&encoded[..encoded.len() - 2]).is_err());
+        assert!(decode(b"garbage").is_err());
+    }
+
+    #[test]
+    fn test_decode_version_without_accuracy() {
+        let mut encoded = MAGIC_V1.to_vec();
+        encoded.extend([0x80, 0x01, 1, 1]);
+        encoded.extend(42.5f32.to_le_bytes());
+        let (samples, _) = decode(&encoded).unwrap();
+        assert_eq!(
+            samples,
+            vec![Sample {
+                timestamp: 128,
+                values: vec![(OutputKind::try_from(1u8).unwrap(), 42.5)],
+                accuracy: None,
+            }]
+        );
+    }
+
+    #[test]
+    fn test_annotation_encoding_roundtrip() {
+        let samples = vec![
+            Sample {
+                timestamp: 1_700_000_000,
+                values: vec![(OutputKind::Iaq, 42.5)],
+                accuracy: Some(Accuracy::LowAccuracy),
+            },
+            Sample {
+                timestamp: 1_700_000_120,
+                values: vec![(OutputKind::Iaq, 80.)],
+                accuracy: Some(Accuracy::LowAccuracy),
+            },
+        ];
+        let annotations = vec![Annotation {
+            timestamp: 1_700_000_060,
+            text: "window opened".into(),
+            tags: vec!["ventilation".into()],
+        }];
+        let encoded = encode(&samples, &annotations);
+        assert_eq!(decode(&encoded).unwrap(), (samples, annotations));
+        assert!(decode(&encoded[..encoded.len() - 10]).is_err());
+    }
+
+    #[test]
+    fn test_history_persistence() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("history.bin");
+        let history = History::new(Duration::from_secs(3600), Duration::from_secs(60));
+        history.load(&path).unwrap();
+        history.add_now(&outputs(42.));
+        history.annotate(Annotation {
+            timestamp: unix_now(),
+            text: "painting".into(),
+            tags: vec![],
+        });
+        history.save(&path).unwrap();
+
+        let restored = History::new(Duration::from_secs(3600), Duration::from_secs(60));
+        restored.load(&path).unwrap();
+        assert_eq!(restored.samples(), history.samples());
+        assert_eq!(restored.annotations(), history.annotations());
+    }
+
+    #[test]
+    fn test_history_skips_truncated_trailing_record() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("history.bin");
+        let history = History::new(Duration::from_secs(3600), Duration::from_secs(60));
+        history.add(unix_now() - 120, &outputs(42.));
+        history.add(unix_now() - 60, &outputs(43.));
+        history.save(&path).unwrap();
+        let data = fs::read(&path).unwrap();
+        fs::write(&path, &data[..data.len() - 2]).unwrap();
+
+        let restored = History::new(Duration::from_secs(3600), Duration::from_secs(60));
+        restored.load(&path).unwrap();
+        assert_eq!(restored.samples(), history.samples()[..1]);
+    }
+
+    #[test]
+    fn test_history_reconciles_unsynchronized_outputs() {
+        let history = History::new(Duration::from_secs(3600), Duration::from_secs(60));
+        let boot = Instant::now();
+        let at = |seconds| boot + Duration::from_secs(seconds);
+        assert!(history.add_unsynchronized(at(0), &outputs(1.)));
+        assert!(!history.add_unsynchronized(at(30), &outputs(2.)));
+        assert!(history.add_unsynchronized(at(60), &outputs(3.)));
+        assert!(history.samples().is_empty());
+
+        let now_unix = unix_now();
+        assert_eq!(history.reconcile(at(100), now_unix), 2);
+        let samples = history.samples();
+        assert_eq!(samples.len(), 2);
+        assert_eq!(samples[0].timestamp, now_unix - 100);
+        assert_eq!(samples[0].values[0].1, 1.);
+        assert_eq!(samples[1].timestamp, now_unix - 40);
+        assert_eq!(history.reconcile(at(200), now_unix + 100), 0);
+    }
+
+    #[test]
+    fn test_to_openmetrics() {
+        let samples = vec![
+            Sample {
+                timestamp: 1_700_000_000,
+                values: vec![(OutputKind::Iaq, 42.5), (OutputKind::StaticIaq, f32::NAN)],
+                accuracy: None,
+            },
+            Sample {
+                timestamp: 1_700_000_060,
+                values: vec![(OutputKind::RawPressure, 1013.), (OutputKind::Iaq, 43.)],
+                accuracy: None,
+            },
+        ];
+        let labels = vec![("room".to_string(), "living \"room\"".to_string())]
+            .into_iter()
+            .collect();
+        let text = to_openmetrics(&samples, &labels);
+        let iaq = MetricSchema::from(OutputKind::Iaq);
+        let pressure = MetricSchema::from(OutputKind::RawPressure);
+        assert!(text.contains(&format!(
+            "# TYPE {0} gauge\n{0}{{room=\"living \\\"room\\\"\"}} 42.5 1700000000\n{0}{{room=\"living \\\"room\\\"\"}} 43 1700000060\n",
+            iaq.name
+        )));
+        assert!(text.contains(&format!(
+            "{}{{room=\"living \\\"room\\\"\"}} 1013 1700000060\n",
+            pressure.name
+        )));
+        assert!(!text.contains(&MetricSchema::from(OutputKind::StaticIaq).name));
+        assert!(text.ends_with("# EOF\n"));
+        assert_eq!(to_openmetrics(&[], &BTreeMap::new()), "# EOF\n");
+    }
+
+    #[test]
+    fn test_sample_json() {
+        let sample = Sample {
+            timestamp: 1_700_000_000,
+            values: vec![(OutputKind::Iaq, 42.5)],
+            accuracy: Some(Accuracy::HighAccuracy),
+        };
+        assert_eq!(
+            serde_json::to_value(&sample).unwrap(),
+            serde_json::json!({
+                "timestamp": 1_700_000_000,
+                "values": {"iaq": 42.5},
+                "accuracy": 3,
+            })
+        );
+    }
+
+    #[test]
+    fn test_annotation_json() {
+        let annotation = Annotation {
+            timestamp: 1_700_000_000,
+            text: "window opened".into(),
+            tags: vec!["ventilation".into()],
+        };
+        assert_eq!(
+            serde_json::to_value(&annotation).unwrap(),
+            serde_json::json!({
+                "time": 1_700_000_000_000u64,
+                "text": "window opened",
+                "tags": ["ventilation"],
+            })
+        );
+    }
+}
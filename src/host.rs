@@ -0,0 +1,101 @@
+//! Facts about the host to attach as labels to the exported metrics.
+
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use crate::config::AutoLabelsConfig;
+use crate::log_warn;
+
+/// Locations of the files the host facts are read from.
+pub struct HostFactSources {
+    pub hostname: PathBuf,
+    pub machine_id: PathBuf,
+    pub board_model: Vec<PathBuf>,
+}
+
+impl Default for HostFactSources {
+    fn default() -> Self {
+        Self {
+            hostname: "/proc/sys/kernel/hostname".into(),
+            machine_id: "/etc/machine-id".into(),
+            board_model: vec![
+                "/proc/device-tree/model".into(),
+                "/sys/firmware/devicetree/base/model".into(),
+            ],
+        }
+    }
+}
+
+fn read_fact(path: &Path) -> Option<String> {
+    let fact = fs::read_to_string(path).ok()?;
+    // Device-tree strings are NUL-terminated.
+    let fact = fact.trim_end_matches('\0').trim();
+    if fact.is_empty() {
+        None
+    } else {
+        Some(fact.into())
+    }
+}
+
+impl HostFactSources {
+    /// Reads the host facts enabled in `config`.
+    ///
+    /// Facts that cannot be determined are omitted with a warning.
+    pub fn labels(&self, config: &AutoLabelsConfig) -> HashMap<String, String> {
+        let mut labels = HashMap::new();
+        let mut add = |name: &str, value: Option<String>| match value {
+            Some(value) => {
+                labels.insert(name.into(), value);
+            }
+            None => log_warn!("Could not determine {} label, omitting it.", name),
+        };
+        if config.hostname {
+            add("hostname", read_fact(&self.hostname));
+        }
+        if config.machine_id {
+            add("machine_id", read_fact(&self.machine_id));
+        }
+        if config.board_model {
+            add(
+                "board_model",
+                self.board_model.iter().find_map(|path| read_fact(path)),
+            );
+        }
+        labels
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_labels() {
+        let tmp_dir = tempdir().unwrap();
+        let sources = HostFactSources {
+            hostname: tmp_dir.path().join("hostname"),
+            machine_id: tmp_dir.path().join("machine-id"),
+            board_model: vec![tmp_dir.path().join("missing"), tmp_dir.path().join("model")],
+        };
+        fs::write(&sources.hostname, "sensor-pi\n").unwrap();
+        fs::write(
+            tmp_dir.path().join("model"),
+            "Raspberry Pi 3 Model B Plus Rev 1.3\0",
+        )
+        .unwrap();
+
+        let all = AutoLabelsConfig {
+            hostname: true,
+            machine_id: true,
+            board_model: true,
+        };
+        let labels = sources.labels(&all);
+        assert_eq!(labels.len(), 2);
+        assert_eq!(labels["hostname"], "sensor-pi");
+        assert_eq!(labels["board_model"], "Raspberry Pi 3 Model B Plus Rev 1.3");
+
+        assert!(sources.labels(&AutoLabelsConfig::default()).is_empty());
+    }
+}
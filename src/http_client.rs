@@ -0,0 +1,40 @@
+//! Minimal HTTP client for plain `http://` URLs.
+
+use std::time::Duration;
+
+use http_types::{mime, Method, Request, Url};
+
+use crate::config::check_http_url;
+
+const TIMEOUT: Duration = Duration::from_secs(10);
+
+async fn send_without_timeout(request: Request) -> anyhow::Result<String> {
+    let addrs = request.url().socket_addrs(|| Some(80))?;
+    let stream = async_std::net::TcpStream::connect(&*addrs).await?;
+    let mut response = async_h1::connect(stream, request)
+        .await
+        .map_err(|err| err.into_inner())?;
+    if !response.status().is_success() {
+        anyhow::bail!("server responded with {}", response.status());
+    }
+    response.body_string().await.map_err(|err| err.into_inner())
+}
+
+async fn send(request: Request) -> anyhow::Result<String> {
+    check_http_url(request.url().as_str()).map_err(anyhow::Error::msg)?;
+    tokio::time::timeout(TIMEOUT, send_without_timeout(request)).await?
+}
+
+/// Fetches the body of the response to a `GET` request of the URL.
+pub async fn get(url: &str) -> anyhow::Result<String> {
+    send(Request::new(Method::Get, Url::parse(url)?)).await
+}
+
+/// Sends a `POST` request with a JSON body to the URL and returns the body of
+/// the response.
+pub async fn post_json(url: &str, body: String) -> anyhow::Result<String> {
+    let mut request = Request::new(Method::Post, Url::parse(url)?);
+    request.set_body(body);
+    request.set_content_type(mime::JSON);
+    send(request).await
+}
@@ -0,0 +1,340 @@
+//! Timeouts for I2C transactions to detect a hung bus.
+//!
+//! A lockup of the I2C bus, e.g. by a sensor holding the data line low, can
+//! block the transactions of a measurement indefinitely. The transactions
+//! are thus run on a worker thread that can be given up on. After a timeout,
+//! the hung worker is abandoned and the device is reopened for the following
+//! transactions. An abandoned worker keeps its thread and file descriptor
+//! until the hung transaction returns, so the device is not reopened while
+//! [`MAX_ABANDONED_WORKERS`] of them are still hung.
+
+use std::fmt::Display;
+use std::io;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::Arc;
+use std::thread;
+use std::time::Duration;
+
+use embedded_hal::blocking::i2c::{Read, Write};
+
+use crate::bme680::ClearBus;
+use crate::events::{EventJournal, EventKind};
+use crate::log_error;
+
+/// Address of the general call that no device acknowledges reads from.
+const GENERAL_CALL_ADDRESS: u8 = 0x00;
+
+/// Maximum number of abandoned workers still blocked in a hung transaction.
+pub const MAX_ABANDONED_WORKERS: usize = 4;
+
+enum Transaction {
+    Read { address: u8, len: usize },
+    Write { address: u8, bytes: Vec<u8> },
+}
+
+struct Worker<E> {
+    transactions: mpsc::Sender<Transaction>,
+    results: mpsc::Receiver<Result<Vec<u8>, E>>,
+}
+
+impl<E: Send + 'static> Worker<E> {
+    fn spawn<I>(mut device: I, running: Arc<AtomicUsize>) -> Self
+    where
+        I: Read<Error = E> + Write<Error = E> + Send + 'static,
+    {
+        let (transactions, pending) = mpsc::channel();
+        let (completed, results) = mpsc::channel();
+        running.fetch_add(1, Ordering::SeqCst);
+        thread::spawn(move || {
+            for transaction in pending {
+                let result = match transaction {
+                    Transaction::Read { address, len } => {
+                        let mut buffer = vec![0; len];
+                        device.read(address, &mut buffer).map(|()| buffer)
+                    }
+                    Transaction::Write { address, bytes } => {
+                        device.write(address, &bytes).map(|()| vec![])
+                    }
+                };
+                if completed.send(result).is_err() {
+                    break;
+                }
+            }
+            drop(device);
+            running.fetch_sub(1, Ordering::SeqCst);
+        });
+        Self {
+            transactions,
+            results,
+        }
+    }
+}
+
+type Open<E> = Box<dyn FnMut() -> Result<Worker<E>, E> + Send>;
+
+/// I2C device failing transactions that do not complete within a timeout.
+pub struct TimeoutI2c<E> {
+    open: Open<E>,
+    worker: Option<Worker<E>>,
+    running: Arc<AtomicUsize>,
+    timeout: Option<Duration>,
+    journal: Option<EventJournal>,
+}
+
+impl<E> TimeoutI2c<E>
+where
+    E: From<io::Error> + Display + Send + 'static,
+{
+    /// Opens the device with `open`, which is also used to reopen it after a
+    /// hung transaction. Without `timeout`, transactions are waited for
+    /// indefinitely.
+    pub fn new<I, F>(mut open: F, timeout: Option<Duration>) -> Result<Self, E>
+    where
+        I: Read<Error = E> + Write<Error = E> + Send + 'static,
+        F: FnMut() -> Result<I, E> + Send + 'static,
+    {
+        let running = Arc::new(AtomicUsize::new(0));
+        let worker = Worker::spawn(open()?, running.clone());
+        Ok(Self {
+            open: Box::new({
+                let running = running.clone();
+                move || open().map(|device| Worker::spawn(device, running.clone()))
+            }),
+            worker: Some(worker),
+            running,
+            timeout,
+            journal: None,
+        })
+    }
+
+    /// Records hung transactions in the `journal`.
+    pub fn with_journal(mut self, journal: EventJournal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Number of abandoned workers still blocked in a hung transaction.
+    fn abandoned(&self) -> usize {
+        self.running
+            .load(Ordering::SeqCst)
+            .saturating_sub(self.worker.is_some() as usize)
+    }
+
+    /// Opens the device on a new worker unless too many abandoned workers
+    /// are still hung.
+    fn reopen(&mut self) -> Result<Worker<E>, E> {
+        let abandoned = self.abandoned();
+        if abandoned >= MAX_ABANDONED_WORKERS {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!(
+                    "{} I2C transactions are still hung, not reopening the device",
+                    abandoned
+                ),
+            )
+            .into());
+        }
+        (self.open)()
+    }
+
+    fn run(&mut self, transaction: Transaction) -> Result<Vec<u8>, E> {
+        let worker = match self.worker.take() {
+            Some(worker) => worker,
+            None => self.reopen()?,
+        };
+        let terminated = || io::Error::new(io::ErrorKind::BrokenPipe, "I2C worker terminated");
+        if worker.transactions.send(transaction).is_err() {
+            return Err(terminated().into());
+        }
+        let result = match self.timeout {
+            Some(timeout) => worker.results.recv_timeout(timeout),
+            None => worker.results.recv().map_err(RecvTimeoutError::from),
+        };
+        match result {
+            Ok(result) => {
+                self.worker = Some(worker);
+                result
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                let timeout_ms = self.timeout.unwrap_or_default().as_millis();
+                self.recover(timeout_ms);
+                Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    format!("I2C transaction timed out after {} ms", timeout_ms),
+                )
+                .into())
+            }
+            Err(RecvTimeoutError::Disconnected) => Err(terminated().into()),
+        }
+    }
+
+    /// Reopens the device after a hung transaction.
+    fn recover(&mut self, timeout_ms: u128) {
+        log_error!(
+            "I2C transaction hung for more than {} ms, reopening the device ...",
+            timeout_ms
+        );
+        if let Some(journal) = &self.journal {
+            journal.record(
+                EventKind::Error,
+                format!(
+                    "I2C transaction hung for more than {} ms, reopening the device",
+                    timeout_ms
+                ),
+            );
+        }
+        match self.reopen() {
+            Ok(worker) => self.worker = Some(worker),
+            Err(err) => log_error!("Failed to reopen the I2C device: {}", err),
+        }
+    }
+}
+
+impl<E> ClearBus for TimeoutI2c<E>
+where
+    E: From<io::Error> + Display + Send + 'static,
+{
+    type Error = E;
+
+    /// Reads a byte from the general call address.
+    ///
+    /// The address byte of the read and its acknowledge bit clock nine pulses
+    /// on the clock line, which lets a device holding the data line in the
+    /// middle of a byte finish it. The read itself is not acknowledged. The
+    /// bus driver may not start the read while the data line is held low,
+    /// in which case the bus stays locked.
+    fn clear_bus(&mut self) -> Result<(), Self::Error> {
+        let _ = self.run(Transaction::Read {
+            address: GENERAL_CALL_ADDRESS,
+            len: 1,
+        });
+        Ok(())
+    }
+}
+
+impl<E> Read for TimeoutI2c<E>
+where
+    E: From<io::Error> + Display + Send + 'static,
+{
+    type Error = E;
+
+    fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        let data = self.run(Transaction::Read {
+            address,
+            len: buffer.len(),
+        })?;
+        buffer.copy_from_slice(&data);
+        Ok(())
+    }
+}
+
+impl<E> Write for TimeoutI2c<E>
+where
+    E: From<io::Error> + Display + Send + 'static,
+{
+    type Error = E;
+
+    fn write(&mut self, address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        self.run(Transaction::Write {
+            address,
+            bytes: bytes.to_vec(),
+        })
+        .map(drop)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Device returning its address as data that hangs on writes of
+    /// `hang_on`.
+    struct FakeI2c {
+        hang_on: u8,
+    }
+
+    impl Read for FakeI2c {
+        type Error = io::Error;
+
+        fn read(&mut self, address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+            buffer.fill(address);
+            Ok(())
+        }
+    }
+
+    impl Write for FakeI2c {
+        type Error = io::Error;
+
+        fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+            if bytes == [self.hang_on] {
+                thread::sleep(Duration::from_secs(1));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_transactions() {
+        let mut i2c =
+            TimeoutI2c::new(|| Ok(FakeI2c { hang_on: 0 }), Some(Duration::from_secs(1))).unwrap();
+        let mut buffer = [0; 2];
+        i2c.read(0x76, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x76, 0x76]);
+        i2c.write(0x76, &[1, 2]).unwrap();
+    }
+
+    #[test]
+    fn test_reopens_device_after_hung_transaction() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let journal = EventJournal::new();
+        let mut i2c = TimeoutI2c::new(
+            {
+                let opened = opened.clone();
+                move || {
+                    opened.fetch_add(1, Ordering::SeqCst);
+                    Ok(FakeI2c { hang_on: 42 })
+                }
+            },
+            Some(Duration::from_millis(10)),
+        )
+        .unwrap()
+        .with_journal(journal.clone());
+
+        let err = i2c.write(0x76, &[42]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        assert_eq!(opened.load(Ordering::SeqCst), 2);
+        assert_eq!(journal.events()[0].kind, EventKind::Error);
+
+        let mut buffer = [0; 1];
+        i2c.read(0x77, &mut buffer).unwrap();
+        assert_eq!(buffer, [0x77]);
+    }
+
+    #[test]
+    fn test_caps_abandoned_workers() {
+        let opened = Arc::new(AtomicUsize::new(0));
+        let mut i2c = TimeoutI2c::new(
+            {
+                let opened = opened.clone();
+                move || {
+                    opened.fetch_add(1, Ordering::SeqCst);
+                    Ok(FakeI2c { hang_on: 42 })
+                }
+            },
+            Some(Duration::from_millis(10)),
+        )
+        .unwrap();
+
+        for _ in 0..MAX_ABANDONED_WORKERS {
+            let err = i2c.write(0x76, &[42]).unwrap_err();
+            assert_eq!(err.kind(), io::ErrorKind::TimedOut);
+        }
+        let err = i2c.write(0x76, &[1]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::Other);
+        assert_eq!(opened.load(Ordering::SeqCst), MAX_ABANDONED_WORKERS);
+
+        thread::sleep(Duration::from_millis(1500));
+        i2c.write(0x76, &[1]).unwrap();
+    }
+}
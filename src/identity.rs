@@ -0,0 +1,62 @@
+//! Persistent identity of the exporter instance.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+
+use serde::Serialize;
+use uuid::Uuid;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Identity {
+    pub uuid: Uuid,
+    pub labels: HashMap<String, String>,
+}
+
+/// Loads the instance UUID from `path` or generates and persists a new one
+/// if the file does not exist.
+pub fn load_or_create_uuid<P: AsRef<Path>>(path: P) -> io::Result<Uuid> {
+    let path = path.as_ref();
+    match fs::read_to_string(path) {
+        Ok(content) => Uuid::parse_str(content.trim()).map_err(|err| {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("invalid instance UUID in {}: {}", path.display(), err),
+            )
+        }),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {
+            let uuid = Uuid::new_v4();
+            fs::write(path, format!("{}\n", uuid))?;
+            Ok(uuid)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_uuid_is_persisted() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("instance-id");
+
+        let uuid = load_or_create_uuid(&path).unwrap();
+        assert_eq!(load_or_create_uuid(&path).unwrap(), uuid);
+    }
+
+    #[test]
+    fn test_invalid_uuid_is_rejected() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("instance-id");
+        fs::write(&path, "not a uuid").unwrap();
+
+        assert_eq!(
+            load_or_create_uuid(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+}
@@ -1,8 +1,63 @@
 extern crate lazy_static;
 
+pub mod alerting;
+pub mod alerts;
+pub mod auth;
+pub mod auxiliary;
+pub mod bme280;
+pub mod bme680;
+pub mod bsec_config;
+pub mod burn_in;
+pub mod calibration;
 pub mod clock;
 pub mod config;
+#[cfg(feature = "http-client")]
+pub mod consistency;
+pub mod counters;
+pub mod dashboard;
+pub mod drain;
+pub mod drift;
+pub mod encoding;
+pub mod events;
+#[cfg(feature = "debug")]
+pub mod faults;
+pub mod ffi_guard;
+pub mod gas;
+#[cfg(feature = "http-client")]
+pub mod heartbeat;
+pub mod heater;
+pub mod history;
+pub mod host;
+#[cfg(feature = "http-client")]
+pub mod http_client;
+pub mod i2c_timeout;
+pub mod identity;
+pub mod limits;
+pub mod logging;
+pub mod maintenance;
 pub mod metrics;
 pub mod middleware;
+pub mod migration;
 pub mod monitor;
+#[cfg(feature = "http-client")]
+pub mod notifications;
+pub mod occupancy;
 pub mod persistance;
+pub mod processing;
+pub mod replay;
+pub mod report;
+pub mod restart;
+pub mod rollback;
+#[cfg(feature = "http-client")]
+pub mod rooms;
+pub mod sensor;
+pub mod simulated;
+pub mod sink;
+pub mod snapshot;
+pub mod spi;
+pub mod startup;
+pub mod systemd;
+#[cfg(any(test, feature = "test-support"))]
+pub mod test_support;
+pub mod time_sync;
+pub mod watchdog;
@@ -0,0 +1,80 @@
+//! Introspection of the limits of the linked BSEC library.
+
+use std::fmt::{self, Display, Formatter};
+
+use libalgobsec_sys::{
+    BSEC_MAX_PHYSICAL_SENSOR, BSEC_MAX_PROPERTY_BLOB_SIZE, BSEC_MAX_STATE_BLOB_SIZE,
+    BSEC_MAX_WORKBUFFER_SIZE,
+};
+use serde::Serialize;
+
+use crate::config::OUTPUT_KIND_NAMES;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Limits {
+    /// Version of the linked BSEC library, `None` if it could not be queried.
+    pub version: Option<String>,
+    pub max_state_blob_size: usize,
+    pub max_property_blob_size: usize,
+    pub max_workbuffer_size: usize,
+    pub max_physical_sensors: usize,
+    /// Virtual sensors (BSEC outputs) supported by the exporter.
+    pub virtual_sensors: Vec<&'static str>,
+}
+
+/// Returns the limits of the linked BSEC library.
+pub fn limits() -> Limits {
+    Limits {
+        version: bsec::get_version()
+            .ok()
+            .map(|(major, minor, major_bugfix, minor_bugfix)| {
+                format!("{}.{}.{}.{}", major, minor, major_bugfix, minor_bugfix)
+            }),
+        max_state_blob_size: BSEC_MAX_STATE_BLOB_SIZE as usize,
+        max_property_blob_size: BSEC_MAX_PROPERTY_BLOB_SIZE as usize,
+        max_workbuffer_size: BSEC_MAX_WORKBUFFER_SIZE as usize,
+        max_physical_sensors: BSEC_MAX_PHYSICAL_SENSOR as usize,
+        virtual_sensors: OUTPUT_KIND_NAMES.to_vec(),
+    }
+}
+
+impl Display for Limits {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "BSEC library version: {}",
+            self.version.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(f, "max state blob size: {} bytes", self.max_state_blob_size)?;
+        writeln!(
+            f,
+            "max config blob size: {} bytes",
+            self.max_property_blob_size
+        )?;
+        writeln!(
+            f,
+            "max work buffer size: {} bytes",
+            self.max_workbuffer_size
+        )?;
+        writeln!(f, "max physical sensors: {}", self.max_physical_sensors)?;
+        write!(f, "virtual sensors: {}", self.virtual_sensors.join(", "))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_limits() {
+        let limits = limits();
+        assert!(limits.version.is_some());
+        assert!(limits.max_state_blob_size > 0);
+        assert!(limits.max_property_blob_size > 0);
+        assert!(limits.virtual_sensors.contains(&"iaq"));
+        assert!(limits.to_string().contains(&format!(
+            "max state blob size: {} bytes",
+            limits.max_state_blob_size
+        )));
+    }
+}
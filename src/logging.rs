@@ -0,0 +1,466 @@
+//! Logging in either plain text or structured JSON format.
+//!
+//! In the text format, log messages are written as is, informational
+//! messages to stdout and warnings and errors to stderr. In the JSON format,
+//! each message is written as a JSON object on a single line of the same
+//! stream with the level, the message, a timestamp, and any additional
+//! fields, e.g. for Loki or ELK pipelines.
+//!
+//! Messages are logged with the [`log_info!`](crate::log_info),
+//! [`log_warn!`](crate::log_warn), and [`log_error!`](crate::log_error)
+//! macros, optionally preceded by fields:
+//!
+//! ```
+//! # use linux_bsec_exporter::log_warn;
+//! log_warn!(sink = "lorawan"; "Failed to publish: {}", "timeout");
+//! ```
+//!
+//! Repeated warnings and errors, e.g. I2C errors while the sensor is
+//! unplugged, are logged only once per repeat window. The last suppressed
+//! repetition is logged with a summary once the window expires, see
+//! [`flush_repeats`]. Messages differing only in numbers are considered
+//! identical.
+
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt::Arguments;
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Level {
+    Info,
+    Warn,
+    Error,
+}
+
+impl Level {
+    fn name(self) -> &'static str {
+        match self {
+            Level::Info => "info",
+            Level::Warn => "warn",
+            Level::Error => "error",
+        }
+    }
+}
+
+static FORMAT: AtomicU8 = AtomicU8::new(LogFormat::Text as u8);
+
+pub fn set_format(format: LogFormat) {
+    FORMAT.store(format as u8, Ordering::Release);
+}
+
+pub fn format() -> LogFormat {
+    if FORMAT.load(Ordering::Acquire) == LogFormat::Json as u8 {
+        LogFormat::Json
+    } else {
+        LogFormat::Text
+    }
+}
+
+/// Formats a log line without the trailing newline.
+pub fn format_line(
+    format: LogFormat,
+    level: Level,
+    fields: &[(&str, Value)],
+    message: Arguments<'_>,
+) -> String {
+    match format {
+        LogFormat::Text => message.to_string(),
+        LogFormat::Json => {
+            let mut object = Map::with_capacity(fields.len() + 3);
+            let timestamp = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64();
+            object.insert("timestamp".into(), timestamp.into());
+            object.insert("level".into(), level.name().into());
+            object.insert("message".into(), message.to_string().into());
+            for (key, value) in fields {
+                object.insert((*key).into(), value.clone());
+            }
+            Value::Object(object).to_string()
+        }
+    }
+}
+
+/// Default window in which repetitions of a message are suppressed.
+pub const DEFAULT_REPEAT_WINDOW: Duration = Duration::from_secs(600);
+
+/// Interval in which the summaries of expired repeat windows are logged.
+pub const REPEAT_FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+
+#[derive(Debug)]
+struct Repeat {
+    since: Instant,
+    suppressed: u64,
+    last: Option<Occurrence>,
+}
+
+/// Occurrence of a logged message.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Occurrence {
+    pub level: Level,
+    pub fields: Vec<(String, Value)>,
+    pub message: String,
+}
+
+impl Occurrence {
+    pub fn new(level: Level, fields: &[(&str, Value)], message: &str) -> Self {
+        Self {
+            level,
+            fields: fields
+                .iter()
+                .map(|(key, value)| ((*key).into(), value.clone()))
+                .collect(),
+            message: message.into(),
+        }
+    }
+}
+
+/// Summary of the repetitions suppressed within an expired repeat window.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Summary {
+    /// Last suppressed repetition.
+    pub last: Occurrence,
+    pub suppressed: u64,
+    pub elapsed: Duration,
+}
+
+/// Suppresses repetitions of messages within a window.
+#[derive(Debug)]
+pub struct RepeatFilter {
+    window: Duration,
+    repeats: HashMap<String, Repeat>,
+}
+
+/// Decision of the [`RepeatFilter`] for a message.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Repetition {
+    /// Log the message.
+    First,
+    /// Do not log the message.
+    Suppressed,
+    /// Log the message with a summary of the repetitions suppressed within
+    /// the given duration.
+    Summary(u64, Duration),
+}
+
+/// Key of a message ignoring any numbers, e.g. timestamps.
+fn repeat_key(message: &str) -> String {
+    let mut key = String::with_capacity(message.len());
+    for c in message.chars() {
+        if !c.is_ascii_digit() {
+            key.push(c);
+        } else if !key.ends_with('#') {
+            key.push('#');
+        }
+    }
+    key
+}
+
+impl RepeatFilter {
+    pub fn new(window: Duration) -> Self {
+        Self {
+            window,
+            repeats: HashMap::new(),
+        }
+    }
+
+    pub fn check(&mut self, occurrence: &Occurrence, now: Instant) -> Repetition {
+        let window = self.window;
+        self.repeats
+            .retain(|_, repeat| repeat.suppressed > 0 || now.duration_since(repeat.since) < window);
+        let repeat = match self.repeats.entry(repeat_key(&occurrence.message)) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => {
+                entry.insert(Repeat {
+                    since: now,
+                    suppressed: 0,
+                    last: None,
+                });
+                return Repetition::First;
+            }
+        };
+        let elapsed = now.duration_since(repeat.since);
+        if elapsed < window {
+            repeat.suppressed += 1;
+            repeat.last = Some(occurrence.clone());
+            return Repetition::Suppressed;
+        }
+        let suppressed = repeat.suppressed;
+        *repeat = Repeat {
+            since: now,
+            suppressed: 0,
+            last: None,
+        };
+        if suppressed > 0 {
+            Repetition::Summary(suppressed, elapsed)
+        } else {
+            Repetition::First
+        }
+    }
+
+    /// Removes the repeat windows expired at `now` and returns the summaries
+    /// of those with suppressed repetitions.
+    pub fn expire(&mut self, now: Instant) -> Vec<Summary> {
+        let window = self.window;
+        let mut summaries = vec![];
+        self.repeats.retain(|_, repeat| {
+            let elapsed = now.duration_since(repeat.since);
+            if elapsed < window {
+                return true;
+            }
+            if let Some(last) = repeat.last.take() {
+                summaries.push(Summary {
+                    last,
+                    suppressed: repeat.suppressed,
+                    elapsed,
+                });
+            }
+            false
+        });
+        summaries
+    }
+}
+
+fn summary_line(occurrence: &Occurrence, suppressed: u64, elapsed: Duration) -> String {
+    let fields: Vec<_> = occurrence
+        .fields
+        .iter()
+        .map(|(key, value)| (key.as_str(), value.clone()))
+        .chain(std::iter::once(("repeated", suppressed.into())))
+        .collect();
+    format_line(
+        format(),
+        occurrence.level,
+        &fields,
+        format_args!(
+            "{} (repeated {} times in {})",
+            occurrence.message,
+            suppressed,
+            format_duration(elapsed)
+        ),
+    )
+}
+
+fn format_duration(duration: Duration) -> String {
+    let seconds = duration.as_secs();
+    if seconds >= 3600 {
+        format!("{}h", seconds / 3600)
+    } else if seconds >= 60 {
+        format!("{}m", seconds / 60)
+    } else {
+        format!("{}s", seconds)
+    }
+}
+
+static REPEATS: Mutex<Option<RepeatFilter>> = Mutex::new(None);
+
+/// Sets the window in which repetitions of warnings and errors are
+/// suppressed, zero to log all repetitions.
+pub fn set_repeat_window(window: Duration) {
+    *REPEATS.lock().unwrap() = Some(RepeatFilter::new(window));
+}
+
+/// Logs the summaries of the repeat windows expired since the last call.
+///
+/// Called every [`REPEAT_FLUSH_INTERVAL`], so that the suppressed
+/// repetitions are reported even if the message does not occur again.
+pub fn flush_repeats() {
+    let summaries = match REPEATS.lock().unwrap().as_mut() {
+        Some(filter) => filter.expire(Instant::now()),
+        None => return,
+    };
+    for summary in summaries {
+        eprintln!(
+            "{}",
+            summary_line(&summary.last, summary.suppressed, summary.elapsed)
+        );
+    }
+}
+
+#[doc(hidden)]
+pub use serde_json::json as __json;
+
+#[doc(hidden)]
+pub fn log(level: Level, fields: &[(&str, Value)], message: Arguments<'_>) {
+    if level == Level::Info {
+        println!("{}", format_line(format(), level, fields, message));
+        return;
+    }
+    let occurrence = Occurrence::new(level, fields, &message.to_string());
+    let repetition = REPEATS
+        .lock()
+        .unwrap()
+        .get_or_insert_with(|| RepeatFilter::new(DEFAULT_REPEAT_WINDOW))
+        .check(&occurrence, Instant::now());
+    let line = match repetition {
+        Repetition::First => format_line(
+            format(),
+            level,
+            fields,
+            format_args!("{}", occurrence.message),
+        ),
+        Repetition::Suppressed => return,
+        Repetition::Summary(suppressed, elapsed) => summary_line(&occurrence, suppressed, elapsed),
+    };
+    eprintln!("{}", line);
+}
+
+#[doc(hidden)]
+#[macro_export]
+macro_rules! __log {
+    ($level:expr, $($key:ident = $value:expr),+ ; $($arg:tt)+) => {
+        $crate::logging::log(
+            $level,
+            &[$((stringify!($key), $crate::logging::__json!($value))),+],
+            format_args!($($arg)+),
+        )
+    };
+    ($level:expr, $($arg:tt)+) => {
+        $crate::logging::log($level, &[], format_args!($($arg)+))
+    };
+}
+
+#[macro_export]
+macro_rules! log_info {
+    ($($arg:tt)+) => { $crate::__log!($crate::logging::Level::Info, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_warn {
+    ($($arg:tt)+) => { $crate::__log!($crate::logging::Level::Warn, $($arg)+) };
+}
+
+#[macro_export]
+macro_rules! log_error {
+    ($($arg:tt)+) => { $crate::__log!($crate::logging::Level::Error, $($arg)+) };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_text_format() {
+        assert_eq!(
+            format_line(
+                LogFormat::Text,
+                Level::Warn,
+                &[("sink", "lorawan".into())],
+                format_args!("Failed to publish: {}", 42)
+            ),
+            "Failed to publish: 42"
+        );
+    }
+
+    #[test]
+    fn test_repeat_filter() {
+        let mut filter = RepeatFilter::new(Duration::from_secs(600));
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let warning = |message| Occurrence::new(Level::Warn, &[], message);
+
+        assert_eq!(
+            filter.check(&warning("I2C error at 1 ns"), at(0)),
+            Repetition::First
+        );
+        assert_eq!(
+            filter.check(&warning("I2C error at 2 ns"), at(3)),
+            Repetition::Suppressed
+        );
+        assert_eq!(
+            filter.check(&warning("other error"), at(4)),
+            Repetition::First
+        );
+        assert_eq!(
+            filter.check(&warning("I2C error at 3 ns"), at(6)),
+            Repetition::Suppressed
+        );
+        assert_eq!(
+            filter.check(&warning("I2C error at 4 ns"), at(600)),
+            Repetition::Summary(2, Duration::from_secs(600))
+        );
+        assert_eq!(
+            filter.check(&warning("other error"), at(1300)),
+            Repetition::First
+        );
+    }
+
+    #[test]
+    fn test_repeat_filter_expire() {
+        let mut filter = RepeatFilter::new(Duration::from_secs(600));
+        let start = Instant::now();
+        let at = |seconds| start + Duration::from_secs(seconds);
+        let error = Occurrence::new(
+            Level::Error,
+            &[("operation", "do_steps".into())],
+            "BSEC do_steps failed at 2 ns",
+        );
+
+        filter.check(&Occurrence::new(Level::Warn, &[], "single warning"), at(0));
+        filter.check(&error, at(1));
+        filter.check(&error, at(2));
+        assert_eq!(filter.expire(at(599)), vec![]);
+        assert_eq!(
+            filter.expire(at(601)),
+            vec![Summary {
+                last: error.clone(),
+                suppressed: 1,
+                elapsed: Duration::from_secs(600),
+            }]
+        );
+        assert_eq!(filter.check(&error, at(602)), Repetition::First);
+    }
+
+    #[test]
+    fn test_repeat_filter_disabled() {
+        let mut filter = RepeatFilter::new(Duration::ZERO);
+        let now = Instant::now();
+        let error = Occurrence::new(Level::Error, &[], "error");
+        assert_eq!(filter.check(&error, now), Repetition::First);
+        assert_eq!(
+            filter.check(&error, now + Duration::from_millis(1)),
+            Repetition::First
+        );
+    }
+
+    #[test]
+    fn test_format_duration() {
+        assert_eq!(format_duration(Duration::from_secs(42)), "42s");
+        assert_eq!(format_duration(Duration::from_secs(600)), "10m");
+        assert_eq!(format_duration(Duration::from_secs(7300)), "2h");
+    }
+
+    #[test]
+    fn test_json_format() {
+        let line = format_line(
+            LogFormat::Json,
+            Level::Error,
+            &[
+                ("operation", "do_steps".into()),
+                ("timestamp_ns", 42.into()),
+            ],
+            format_args!("BSEC {} failed", "do_steps"),
+        );
+        assert!(!line.contains('\n'));
+        let object: Value = serde_json::from_str(&line).unwrap();
+        assert_eq!(object["level"], "error");
+        assert_eq!(object["message"], "BSEC do_steps failed");
+        assert_eq!(object["operation"], "do_steps");
+        assert_eq!(object["timestamp_ns"], 42);
+        assert!(object["timestamp"].as_f64().unwrap() > 0.);
+    }
+}
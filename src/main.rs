@@ -1,75 +1,699 @@
 use embedded_hal::blocking::i2c;
-use libsystemd::daemon::{self, NotifyState};
+use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
 use linux_embedded_hal::{Delay, I2cdev};
 use prometheus::Encoder;
+use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::fs::{self, File};
-use std::io::Read;
+use std::fs;
+use std::path::Path;
 use std::sync::Arc;
+use std::time::{Duration, Instant};
 use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, oneshot, watch, Notify};
+use tokio::task::JoinSet;
+use uuid::Uuid;
 
-use bsec::clock::TimePassed;
-use bsec::{bme::bme680::Bme680Sensor, OutputKind};
-use linux_bsec_exporter::middleware::LogErrors;
-use linux_bsec_exporter::{metrics::BsecGaugeRegistry, monitor::bsec_monitor};
-use linux_bsec_exporter::{monitor::PersistState, persistance::StateFile};
+use bsec::{InputKind, OutputKind, SubscriptionRequest};
+use linux_bsec_exporter::alerting::{ActiveAlerts, AlertEngine};
+use linux_bsec_exporter::alerts;
+use linux_bsec_exporter::auth::{Scope, TokenAuth};
+use linux_bsec_exporter::auxiliary::{
+    check_heat_source, run_auxiliary_input, AuxiliaryInput, AuxiliarySensor,
+};
+use linux_bsec_exporter::bme280::Bme280Sensor;
+use linux_bsec_exporter::bme680::{
+    Bme680Sensor, GasAmbientTemperature, MeasurementOverrides, RetryI2c,
+};
+use linux_bsec_exporter::bsec_config;
+use linux_bsec_exporter::burn_in::{self, BurnIn};
+use linux_bsec_exporter::calibration::{self, OffsetSensor, TemperatureOffset};
+use linux_bsec_exporter::clock::{MonotonicGuard, RuntimeClock};
+use linux_bsec_exporter::config::{
+    Config, ReportConfig, ReportPeriod, SensorConfig, SensorModel, Transport,
+};
+#[cfg(feature = "http-client")]
+use linux_bsec_exporter::consistency;
+use linux_bsec_exporter::counters;
+use linux_bsec_exporter::dashboard;
+use linux_bsec_exporter::drain::HttpDrain;
+use linux_bsec_exporter::drift::GasBaselineTracker;
+use linux_bsec_exporter::encoding;
+use linux_bsec_exporter::events::{
+    describe_transitions, AccuracyTracker, EventJournal, EventKind, JournaledPersistState,
+};
+#[cfg(feature = "debug")]
+use linux_bsec_exporter::faults::{FaultSettings, Faults, FaultyI2c, FaultyPersistState};
+use linux_bsec_exporter::ffi_guard::BsecCallError;
+use linux_bsec_exporter::gas::{GasSwitch, GAS_OUTPUTS};
+#[cfg(feature = "http-client")]
+use linux_bsec_exporter::heartbeat;
+use linux_bsec_exporter::heater::{HeaterSensor, HeaterUsage};
+use linux_bsec_exporter::history::{self, Annotation, History};
+use linux_bsec_exporter::host::HostFactSources;
+use linux_bsec_exporter::i2c_timeout::TimeoutI2c;
+use linux_bsec_exporter::identity::{load_or_create_uuid, Identity};
+use linux_bsec_exporter::limits::limits;
+use linux_bsec_exporter::logging;
+use linux_bsec_exporter::maintenance::{ReadOnlyPersistState, ReadOnlySwitch};
+use linux_bsec_exporter::metrics::{BsecGaugeRegistry, MetricSchema, MetricsFilter, MetricsView};
+use linux_bsec_exporter::middleware::{CacheValidation, LogErrors};
+use linux_bsec_exporter::migration;
+use linux_bsec_exporter::monitor::bsec_monitor;
+use linux_bsec_exporter::monitor::{BsecReceiver, BsecSender};
+#[cfg(feature = "http-client")]
+use linux_bsec_exporter::notifications::AlertNotifier;
+use linux_bsec_exporter::occupancy::Occupancy;
+use linux_bsec_exporter::processing::ProcessingChain;
+use linux_bsec_exporter::replay::{Replay, ReplayClock, ReplaySensor};
+use linux_bsec_exporter::report::{self, Report};
+use linux_bsec_exporter::restart::RestartLimiter;
+use linux_bsec_exporter::rollback::{ConfigRollout, LoadedConfig};
+#[cfg(feature = "http-client")]
+use linux_bsec_exporter::rooms;
+use linux_bsec_exporter::sensor::{
+    check_required_inputs, check_supported_outputs, provided_inputs_with, CorrectedSensor,
+    HardwareSensor, HumidityCorrection,
+};
+use linux_bsec_exporter::simulated::{SensorBackend, SimulatedSensor, SIMULATED_DEVICE};
+#[cfg(feature = "cbor-udp")]
+use linux_bsec_exporter::sink::cbor_udp::CborUdpSink;
+#[cfg(feature = "lorawan")]
+use linux_bsec_exporter::sink::lorawan::LorawanSink;
+#[cfg(feature = "mqtt")]
+use linux_bsec_exporter::sink::mqtt::MqttSink;
+#[cfg(any(feature = "cbor-udp", feature = "lorawan", feature = "mqtt"))]
+use linux_bsec_exporter::sink::AccuracyFilter;
+use linux_bsec_exporter::sink::{self, Sink};
+use linux_bsec_exporter::snapshot;
+use linux_bsec_exporter::spi::SpiRegisters;
+use linux_bsec_exporter::startup::{PhasedPersistState, StartupPhases};
+use linux_bsec_exporter::systemd;
+use linux_bsec_exporter::time_sync::{self, TimeSyncStatus};
+use linux_bsec_exporter::watchdog::{self, Watchdog};
+use linux_bsec_exporter::{log_error, log_info, log_warn};
+use linux_bsec_exporter::{
+    monitor::PersistState,
+    persistance::{MeteredPersistState, NoPersistState, StateFile, StatePersistence},
+};
 
-#[macro_use]
-extern crate lazy_static;
-
-lazy_static! {
-    static ref TIME: Arc<TimePassed> = Arc::default();
-}
-
-async fn serve_metrics(req: tide::Request<BsecGaugeRegistry>) -> tide::Result {
+async fn serve_metrics(req: tide::Request<MetricsView>) -> tide::Result {
     let mut buffer = vec![];
     let encoder = prometheus::TextEncoder::new();
     encoder.encode(&req.state().gather(), &mut buffer)?;
     Ok(String::from_utf8(buffer)?.to_string().into())
 }
 
-struct SigTermHandler(Signal);
+async fn serve_openmetrics(req: tide::Request<MetricsView>) -> tide::Result {
+    Ok(tide::Response::builder(200)
+        .body(encoding::encode_openmetrics(&req.state().gather()))
+        .content_type(encoding::OPENMETRICS_CONTENT_TYPE)
+        .build())
+}
+
+async fn get_schema(req: tide::Request<MetricsView>) -> tide::Result {
+    Ok(tide::Body::from_json(&req.state().schema())?.into())
+}
+
+async fn get_identity(req: tide::Request<Identity>) -> tide::Result {
+    Ok(tide::Body::from_json(req.state())?.into())
+}
+
+async fn get_config(req: tide::Request<Arc<Config>>) -> tide::Result {
+    Ok(tide::Body::from_json(req.state().as_ref())?.into())
+}
+
+async fn get_events(req: tide::Request<EventJournal>) -> tide::Result {
+    Ok(tide::Body::from_json(&req.state().events())?.into())
+}
+
+async fn get_startup(req: tide::Request<StartupPhases>) -> tide::Result {
+    Ok(tide::Body::from_json(&req.state().status())?.into())
+}
+
+async fn get_history(req: tide::Request<History>) -> tide::Result {
+    Ok(tide::Body::from_json(&req.state().samples())?.into())
+}
+
+/// Range of annotations in milliseconds since the Unix epoch as passed by
+/// Grafana.
+#[derive(Deserialize)]
+struct AnnotationRange {
+    from: Option<u64>,
+    to: Option<u64>,
+}
+
+async fn get_annotations(req: tide::Request<History>) -> tide::Result {
+    let range: AnnotationRange = req.query()?;
+    let annotations: Vec<_> = req
+        .state()
+        .annotations()
+        .into_iter()
+        .filter(|annotation| {
+            let time = annotation.timestamp.saturating_mul(1000);
+            range.from.is_none_or(|from| time >= from) && range.to.is_none_or(|to| time <= to)
+        })
+        .collect();
+    Ok(tide::Body::from_json(&annotations)?.into())
+}
+
+#[derive(Deserialize)]
+struct NewAnnotation {
+    text: String,
+    #[serde(default)]
+    tags: Vec<String>,
+    /// Seconds since the Unix epoch, now if not given.
+    timestamp: Option<u64>,
+}
 
-impl SigTermHandler {
-    pub fn new() -> std::io::Result<Self> {
-        Ok(Self(signal(SignalKind::terminate())?))
+async fn post_annotation(mut req: tide::Request<History>) -> tide::Result {
+    let new: NewAnnotation = req.body_json().await?;
+    if new.text.trim().is_empty() {
+        return Err(tide::Error::from_str(400, "empty annotation text"));
+    }
+    if new.tags.len() > history::MAX_TAGS {
+        return Err(tide::Error::from_str(400, "too many annotation tags"));
     }
+    let annotation = Annotation {
+        timestamp: new.timestamp.unwrap_or_else(|| {
+            std::time::SystemTime::now()
+                .duration_since(std::time::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs()
+        }),
+        text: new.text,
+        tags: new.tags,
+    };
+    req.state().annotate(annotation.clone());
+    let mut response: tide::Response = tide::Body::from_json(&annotation)?.into();
+    response.set_status(201);
+    Ok(response)
+}
+
+#[derive(Deserialize)]
+struct ReportQuery {
+    period: Option<ReportPeriod>,
+    #[serde(default)]
+    format: ReportFormat,
+}
 
-    pub async fn dispatch_to(mut self, sender: tokio::sync::oneshot::Sender<()>) {
-        self.0.recv().await;
-        let _ = sender.send(());
+#[derive(Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum ReportFormat {
+    #[default]
+    Json,
+    Html,
+}
+
+async fn get_report(req: tide::Request<(History, ReportConfig)>) -> tide::Result {
+    let query: ReportQuery = req.query()?;
+    let (history, config) = req.state();
+    let report = Report::generate(
+        query.period.unwrap_or(config.period),
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        &history.samples(),
+        &config.iaq_thresholds,
+    );
+    Ok(match query.format {
+        ReportFormat::Json => tide::Body::from_json(&report)?.into(),
+        ReportFormat::Html => tide::Response::builder(200)
+            .body(report.to_html())
+            .content_type(tide::http::mime::HTML)
+            .build(),
+    })
+}
+
+async fn get_current(req: tide::Request<MetricsView>) -> tide::Result {
+    Ok(tide::Body::from_json(&req.state().current())?.into())
+}
+
+async fn serve_json_metrics(req: tide::Request<MetricsView>) -> tide::Result {
+    Ok(tide::Body::from_json(&encoding::to_json(&req.state().gather()))?.into())
+}
+
+#[derive(Deserialize, Serialize)]
+struct OccupancyStatus {
+    occupied: bool,
+}
+
+async fn get_occupancy(req: tide::Request<Occupancy>) -> tide::Result {
+    Ok(tide::Body::from_json(&OccupancyStatus {
+        occupied: req.state().is_occupied(),
+    })?
+    .into())
+}
+
+async fn put_occupancy(mut req: tide::Request<Occupancy>) -> tide::Result {
+    let status: OccupancyStatus = req.body_json().await?;
+    req.state().set_occupied(status.occupied)?;
+    Ok(tide::Body::from_json(&status)?.into())
+}
+
+#[derive(Deserialize, Serialize)]
+struct GasStatus {
+    enabled: bool,
+}
+
+async fn get_gas(req: tide::Request<GasSwitch>) -> tide::Result {
+    Ok(tide::Body::from_json(&GasStatus {
+        enabled: req.state().is_enabled(),
+    })?
+    .into())
+}
+
+async fn put_gas(mut req: tide::Request<GasSwitch>) -> tide::Result {
+    let status: GasStatus = req.body_json().await?;
+    req.state().set_enabled(status.enabled)?;
+    Ok(tide::Body::from_json(&status)?.into())
+}
+
+#[derive(Deserialize, Serialize)]
+struct MaintenanceStatus {
+    read_only: bool,
+}
+
+async fn get_maintenance(req: tide::Request<ReadOnlySwitch>) -> tide::Result {
+    Ok(tide::Body::from_json(&MaintenanceStatus {
+        read_only: req.state().is_read_only(),
+    })?
+    .into())
+}
+
+async fn put_maintenance(mut req: tide::Request<ReadOnlySwitch>) -> tide::Result {
+    let status: MaintenanceStatus = req.body_json().await?;
+    req.state().set_read_only(status.read_only);
+    Ok(tide::Body::from_json(&status)?.into())
+}
+
+#[cfg(feature = "debug")]
+async fn get_faults(req: tide::Request<Faults>) -> tide::Result {
+    Ok(tide::Body::from_json(&req.state().settings())?.into())
+}
+
+#[cfg(feature = "debug")]
+async fn put_faults(mut req: tide::Request<Faults>) -> tide::Result {
+    let settings: FaultSettings = req.body_json().await?;
+    req.state().set(settings);
+    Ok(tide::Body::from_json(&settings)?.into())
+}
+
+/// Enters the read-only mode on SIGUSR1 and leaves it on SIGUSR2.
+fn spawn_read_only_signal_handlers(read_only: ReadOnlySwitch) -> std::io::Result<()> {
+    let mut enter = signal(SignalKind::user_defined1())?;
+    let mut leave = signal(SignalKind::user_defined2())?;
+    tokio::task::spawn(async move {
+        loop {
+            tokio::select! {
+                Some(()) = enter.recv() => read_only.set_read_only(true),
+                Some(()) = leave.recv() => read_only.set_read_only(false),
+            }
+        }
+    });
+    Ok(())
+}
+
+fn metrics_app(
+    view: MetricsView,
+    http_drain: &HttpDrain,
+    auth: &TokenAuth,
+) -> tide::Server<MetricsView> {
+    let mut app = tide::with_state(view);
+    app.with(LogErrors);
+    app.with(http_drain.clone());
+    app.at("/metrics")
+        .with(auth.require(Scope::Metrics))
+        .with(CacheValidation)
+        .get(serve_metrics);
+    app.at("/metrics/openmetrics")
+        .with(auth.require(Scope::Metrics))
+        .with(CacheValidation)
+        .get(serve_openmetrics);
+    app.at("/metrics/json")
+        .with(auth.require(Scope::Metrics))
+        .with(CacheValidation)
+        .get(serve_json_metrics);
+    app
+}
+
+#[derive(Deserialize)]
+#[serde(default)]
+struct Acknowledgement {
+    duration_minutes: u64,
+}
+
+impl Default for Acknowledgement {
+    fn default() -> Self {
+        Self {
+            duration_minutes: 60,
+        }
     }
 }
 
-type SensorDevice = Bme680Sensor<linux_embedded_hal::I2cdev, linux_embedded_hal::Delay>;
+async fn get_alerts(req: tide::Request<ActiveAlerts>) -> tide::Result {
+    Ok(tide::Body::from_json(&req.state().firing())?.into())
+}
+
+async fn acknowledge_alert(mut req: tide::Request<ActiveAlerts>) -> tide::Result {
+    let body = req.body_string().await?;
+    let acknowledgement: Acknowledgement = if body.trim().is_empty() {
+        Acknowledgement::default()
+    } else {
+        serde_json::from_str(&body).map_err(|err| tide::Error::new(400, err))?
+    };
+    let until = std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+        .saturating_add(acknowledgement.duration_minutes.saturating_mul(60));
+    let id = req.param("id")?;
+    if !req.state().acknowledge(id, until) {
+        return Ok(tide::Response::new(404));
+    }
+    log_info!(
+        alert = id;
+        "Alert {} acknowledged for {} minutes.",
+        id,
+        acknowledgement.duration_minutes
+    );
+    Ok(tide::Body::from_json(&serde_json::json!({ "acknowledged_until": until }))?.into())
+}
+
+/// Shared state of the endpoints controlling or debugging the exporter.
+struct ControlApi<'a> {
+    journal: &'a EventJournal,
+    read_only: &'a ReadOnlySwitch,
+    startup: &'a StartupPhases,
+    gas: &'a GasSwitch,
+    occupancy: Option<&'a Occupancy>,
+    alerts: &'a ActiveAlerts,
+    auth: &'a TokenAuth,
+    /// Effective configuration with the secrets redacted.
+    config: &'a Arc<Config>,
+    #[cfg(feature = "debug")]
+    faults: &'a Faults,
+}
+
+impl ControlApi<'_> {
+    /// Adds the endpoints to the `app`.
+    fn add_to<S: Clone + Send + Sync + 'static>(&self, app: &mut tide::Server<S>) {
+        let auth = self.auth;
+        let mut maintenance_api = tide::with_state(self.read_only.clone());
+        maintenance_api
+            .at("/")
+            .get(get_maintenance)
+            .put(put_maintenance);
+        app.at("/api/v1/maintenance")
+            .with(auth.require(Scope::Control))
+            .nest(maintenance_api);
+        let mut startup_api = tide::with_state(self.startup.clone());
+        startup_api.at("/").get(get_startup);
+        app.at("/api/v1/startup")
+            .with(auth.require(Scope::Control))
+            .nest(startup_api);
+        let mut events_api = tide::with_state(self.journal.clone());
+        events_api.at("/").get(get_events);
+        app.at("/api/v1/events")
+            .with(auth.require(Scope::Control))
+            .nest(events_api);
+        let mut gas_api = tide::with_state(self.gas.clone());
+        gas_api.at("/").get(get_gas).put(put_gas);
+        app.at("/api/v1/gas")
+            .with(auth.require(Scope::Control))
+            .nest(gas_api);
+        if let Some(occupancy) = self.occupancy {
+            let mut occupancy_api = tide::with_state(occupancy.clone());
+            occupancy_api.at("/").get(get_occupancy).put(put_occupancy);
+            app.at("/api/v1/occupancy")
+                .with(auth.require(Scope::Control))
+                .nest(occupancy_api);
+        }
+        let mut alerts_api = tide::with_state(self.alerts.clone());
+        alerts_api.at("/").get(get_alerts);
+        alerts_api.at("/:id/ack").post(acknowledge_alert);
+        app.at("/api/v1/alerts")
+            .with(auth.require(Scope::Control))
+            .nest(alerts_api);
+        let mut config_api = tide::with_state(self.config.clone());
+        config_api.at("/").get(get_config);
+        app.at("/api/v1/config")
+            .with(auth.require(Scope::Control))
+            .nest(config_api);
+        #[cfg(feature = "debug")]
+        {
+            let mut faults_api = tide::with_state(self.faults.clone());
+            faults_api.at("/").get(get_faults).put(put_faults);
+            app.at("/api/v1/debug/faults")
+                .with(auth.require(Scope::Control))
+                .nest(faults_api);
+        }
+    }
+}
+
+const DEFAULT_BURN_IN_HOURS: f64 = 48.;
+/// Interval of saving the counters in addition to the shutdown, limiting the
+/// increments lost on a crash.
+const COUNTERS_SAVE_INTERVAL: Duration = Duration::from_secs(900);
+
+type Time = MonotonicGuard<RuntimeClock>;
+type I2c = RetryI2c<TimeoutI2c<<I2cdev as i2c::Read>::Error>>;
+type SensorDevice = HeaterSensor<
+    AuxiliarySensor<
+        CorrectedSensor<
+            OffsetSensor<SensorBackend<HardwareSensor<I2c, linux_embedded_hal::Delay>>>,
+        >,
+    >,
+>;
+type SensorBsec = bsec::Bsec<SensorDevice, Time, Arc<Time>>;
+
+enum MonitoringExit {
+    Shutdown,
+    Stalled,
+    /// Shut down to roll back a changed configuration.
+    RollBack,
+}
+
+struct MonitoringContext<'a> {
+    registry: &'a BsecGaugeRegistry,
+    subscription_updates: &'a mut mpsc::UnboundedReceiver<Vec<SubscriptionRequest>>,
+    watchdog: Option<&'a Watchdog>,
+    restart_on_stall: bool,
+    sigterm: &'a mut Signal,
+    http_drain: &'a HttpDrain,
+    raw_temperature: &'a watch::Sender<Option<f64>>,
+    gas_baseline: &'a mut GasBaselineTracker,
+    burn_in: Option<&'a BurnIn>,
+    gas: &'a GasSwitch,
+    sinks: &'a mut [Box<dyn Sink + Send>],
+    processing: &'a mut ProcessingChain,
+    /// Status gating the consumers of wall-clock timestamps, `None` if not
+    /// gated.
+    time_sync_gate: Option<&'a TimeSyncStatus>,
+    startup: &'a StartupPhases,
+    rollout: &'a ConfigRollout,
+    loaded_config: Option<&'a LoadedConfig>,
+    /// Notified when the changed configuration is to be rolled back.
+    rollback: &'a Notify,
+    journal: &'a EventJournal,
+    accuracy: &'a mut AccuracyTracker,
+    history: Option<&'a History>,
+    alerting: &'a mut AlertEngine,
+    alerts: &'a ActiveAlerts,
+    #[cfg(feature = "http-client")]
+    notifier: Option<&'a AlertNotifier>,
+}
+
+/// Evaluates the alerting rules on the history after a new sample.
+fn evaluate_alerts(ctx: &mut MonitoringContext<'_>, history: &History) {
+    if ctx.alerting.is_empty() {
+        return;
+    }
+    let changes = ctx
+        .alerting
+        .evaluate(&history.recent(ctx.alerting.max_window_seconds()));
+    for change in changes.iter() {
+        ctx.registry.set_alert_firing(&change.name, change.firing);
+        let state = if change.firing { "firing" } else { "resolved" };
+        log_warn!(
+            alert = change.name.as_str();
+            "Alert {} {} (value: {:.2}).",
+            change.name,
+            state,
+            change.value
+        );
+        ctx.journal.record(
+            EventKind::Alert,
+            format!(
+                "alert {} {} (value: {:.2})",
+                change.name, state, change.value
+            ),
+        );
+        ctx.alerts.update(change);
+    }
+    #[cfg(feature = "http-client")]
+    if let Some(notifier) = ctx.notifier.filter(|_| !changes.is_empty()) {
+        notifier.notify(changes);
+    }
+}
 
 async fn run_monitoring<P>(
-    bsec: bsec::Bsec<SensorDevice, TimePassed, Arc<TimePassed>>,
-    persistence: P,
-    registry: BsecGaugeRegistry,
-) -> anyhow::Result<()>
+    monitor: BsecSender<SensorDevice, P, Time>,
+    mut rx: BsecReceiver,
+    ctx: &mut MonitoringContext<'_>,
+) -> anyhow::Result<MonitoringExit>
 where
     P: PersistState + Send + Sync + 'static,
     P::Error: std::error::Error + Send + Sync + 'static,
 {
-    let (monitor, mut rx) = bsec_monitor(bsec, persistence, TIME.clone());
-
-    tokio::task::spawn(SigTermHandler::new()?.dispatch_to(rx.initiate_shutdown));
     let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+    let (stall_sender, mut stalls) = mpsc::unbounded_channel();
+    if let Some(watchdog) = ctx.watchdog {
+        tokio::task::spawn(watchdog.clone().observe(rx.current.clone(), stall_sender));
+    }
+    let mut initiate_shutdown = Some(rx.initiate_shutdown);
+    let mut exit = MonitoringExit::Shutdown;
 
-    println!("BSEC monitoring started.");
-    while let Ok(_) = rx.current.changed().await {
-        if let Some(outputs) = rx.current.borrow().as_deref() {
-            for output in outputs.iter() {
-                registry.set(output);
+    log_info!("BSEC monitoring started.");
+    loop {
+        tokio::select! {
+            changed = rx.current.changed() => {
+                if changed.is_err() {
+                    break;
+                }
+                if !ctx.startup.status().complete {
+                    ctx.startup.complete();
+                    if let Some(loaded) = ctx.loaded_config {
+                        if let Err(err) = ctx.rollout.confirm(loaded) {
+                            log_error!("Failed to keep the configuration as last-good: {}", err);
+                        }
+                    }
+                }
+                ctx.registry.set_timing(&rx.timing.borrow());
+                let wall_clock_valid = ctx.time_sync_gate.is_none_or(TimeSyncStatus::is_synchronized);
+                if let Some(outputs) = rx.current.borrow().as_deref() {
+                    let processed = ctx.processing.process(outputs);
+                    if wall_clock_valid {
+                        sink::publish_all(ctx.sinks, &processed);
+                        if let Some(history) = ctx.history {
+                            let reconciled = history.reconcile_now();
+                            if reconciled > 0 {
+                                log_info!(
+                                    "Added {} samples measured before the time synchronization to the history.",
+                                    reconciled
+                                );
+                            }
+                            if history.add_now(outputs) {
+                                evaluate_alerts(ctx, history);
+                            }
+                        }
+                    } else if let Some(history) = ctx.history {
+                        history.add_unsynchronized(Instant::now(), outputs);
+                    }
+                    let transitions = ctx.accuracy.update(outputs);
+                    for transition in transitions.iter() {
+                        ctx.registry.inc_accuracy_transitions(transition);
+                    }
+                    if let Some(change) = describe_transitions(&transitions) {
+                        ctx.journal.record(EventKind::AccuracyChange, change);
+                    }
+                    for output in outputs.iter() {
+                        ctx.registry.set(output);
+                        match output.sensor {
+                            OutputKind::RawTemperature => {
+                                ctx.raw_temperature.send_replace(Some(output.signal));
+                            }
+                            OutputKind::RawGas if wall_clock_valid => {
+                                if let Some(report) = ctx.gas_baseline.add_now(output.signal) {
+                                    log_info!(
+                                        baseline_ohm = report.baseline_ohm,
+                                        drift_percent_per_day = report.drift_percent_per_day;
+                                        "Daily gas sensor report: {}",
+                                        report
+                                    );
+                                }
+                                if let Some(report) = ctx.gas_baseline.report() {
+                                    ctx.registry.set_gas_drift(&report);
+                                }
+                            }
+                            _ => (),
+                        }
+                    }
+                }
+            }
+            Some(requests) = ctx.subscription_updates.recv() => {
+                match ctx.burn_in {
+                    // The switches keep their state, which is applied with the
+                    // normal profile sent at the end of the burn-in.
+                    Some(burn_in) if burn_in.is_active() => {
+                        log_info!("Deferring subscription update until the end of the burn-in.");
+                    }
+                    _ => {
+                        rx.update_subscription.send(ctx.gas.apply(requests))?;
+                        if !ctx.gas.is_enabled() {
+                            ctx.registry.clear(&GAS_OUTPUTS);
+                        }
+                    }
+                }
+            }
+            Some(()) = stalls.recv() => {
+                ctx.registry.inc_watchdog_stalls();
+                let timing = *rx.timing.borrow();
+                log_warn!(
+                    latency_ns = timing.latency_ns.get(),
+                    missed_windows = timing.missed_windows;
+                    "BSEC monitoring stalled: no output for {:?} (last cycle latency: {} ns, missed windows: {}).",
+                    ctx.watchdog.map(Watchdog::timeout).unwrap_or_default(),
+                    timing.latency_ns,
+                    timing.missed_windows,
+                );
+                ctx.journal.record(
+                    EventKind::Error,
+                    format!("BSEC monitoring stalled (missed windows: {})", timing.missed_windows),
+                );
+                if ctx.restart_on_stall {
+                    join_handle.abort();
+                    let _ = join_handle.await;
+                    return Ok(MonitoringExit::Stalled);
+                }
+            }
+            _ = ctx.sigterm.recv() => {
+                drain_and_initiate_shutdown(ctx.http_drain, &mut initiate_shutdown).await;
+            }
+            _ = ctx.rollback.notified() => {
+                exit = MonitoringExit::RollBack;
+                drain_and_initiate_shutdown(ctx.http_drain, &mut initiate_shutdown).await;
             }
         }
     }
 
-    println!("Waiting for BSEC monitoring shutdown ...");
+    log_info!("Flushing sinks ...");
+    sink::flush_all(ctx.sinks);
     join_handle.await??;
-    println!("BSEC monitoring shutdown complete.");
-    Ok(())
+    log_info!("BSEC monitoring shutdown complete.");
+    Ok(exit)
+}
+
+/// Drains the HTTP connections and initiates the shutdown of the monitoring,
+/// unless already initiated.
+async fn drain_and_initiate_shutdown(
+    http_drain: &HttpDrain,
+    initiate_shutdown: &mut Option<oneshot::Sender<()>>,
+) {
+    log_info!("Draining HTTP connections ...");
+    if !http_drain.drain().await {
+        log_warn!(
+            "{} HTTP responses still in flight after the drain timeout.",
+            http_drain.in_flight()
+        );
+    }
+    // The outputs published until the monitoring stopped, e.g. the ones still
+    // pending due to the minimum publish interval, are processed before the
+    // loop ends with the closed channel.
+    if let Some(initiate_shutdown) = initiate_shutdown.take() {
+        log_info!("Waiting for BSEC monitoring shutdown ...");
+        let _ = initiate_shutdown.send(());
+    }
 }
 
 #[derive(Debug)]
@@ -77,69 +701,1097 @@ struct Bme680Error(bme680::Error<<I2cdev as i2c::Read>::Error, <I2cdev as i2c::W
 
 impl std::fmt::Display for Bme680Error {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-        f.write_fmt(format_args!("{:?}", self))
+        f.write_fmt(format_args!("{:?}", self.0))
     }
 }
 
 impl std::error::Error for Bme680Error {}
 
+/// State shared by the sensor wrappers across (re-)initializations.
+struct SensorShared<'a> {
+    temperature_offset: &'a TemperatureOffset,
+    journal: &'a EventJournal,
+    heater_usage: &'a HeaterUsage,
+    #[cfg(feature = "debug")]
+    faults: &'a Faults,
+    auxiliary_inputs: &'a [AuxiliaryInput],
+    /// Outputs of all configured subscriptions.
+    outputs: &'a [OutputKind],
+    /// Replay of the primary sensor driving the clock.
+    replay: Option<&'a Replay>,
+}
+
+/// Kinds of the inputs provided by auxiliary sources.
+fn auxiliary_kinds(shared: &SensorShared) -> Vec<InputKind> {
+    shared
+        .auxiliary_inputs
+        .iter()
+        .map(AuxiliaryInput::kind)
+        .collect()
+}
+
+/// Opens the sensor attached via I2C or SPI.
+fn open_sensor(
+    sensor_config: &SensorConfig,
+    shared: &SensorShared,
+    startup: &StartupPhases,
+) -> anyhow::Result<HardwareSensor<I2c, Delay>> {
+    if sensor_config.model != SensorModel::Bme680 && sensor_config.transport == Transport::Spi {
+        anyhow::bail!(
+            "the {} sensor is only supported via I2C",
+            sensor_config.model
+        );
+    }
+    let device = sensor_config.device.clone();
+    let timeout = Some(Duration::from_millis(sensor_config.measurement_timeout_ms))
+        .filter(|timeout| !timeout.is_zero());
+    #[cfg(feature = "debug")]
+    let faults = shared.faults.clone();
+    let bus = match sensor_config.transport {
+        Transport::I2c => {
+            startup.begin("opening I2C");
+            TimeoutI2c::new(
+                move || {
+                    let i2c = I2cdev::new(&device)?;
+                    #[cfg(feature = "debug")]
+                    let i2c = FaultyI2c::new(i2c, faults.clone());
+                    Ok::<_, LinuxI2CError>(i2c)
+                },
+                timeout,
+            )?
+        }
+        Transport::Spi => {
+            startup.begin("opening SPI");
+            let max_speed_hz = sensor_config.spi_max_speed_hz;
+            TimeoutI2c::new(
+                move || {
+                    let spi =
+                        SpiRegisters::open(&device, max_speed_hz).map_err(LinuxI2CError::from)?;
+                    #[cfg(feature = "debug")]
+                    let spi = FaultyI2c::new(spi, faults.clone());
+                    Ok::<_, LinuxI2CError>(spi)
+                },
+                timeout,
+            )?
+        }
+    };
+    let i2c = RetryI2c::new(
+        bus.with_journal(shared.journal.clone()),
+        sensor_config.i2c_retries,
+    )
+    .with_backoff(Duration::from_millis(sensor_config.i2c_retry_backoff_ms));
+    startup.begin("initializing sensor");
+    if sensor_config.model != SensorModel::Bme680 {
+        let sensor = Bme280Sensor::init(i2c, sensor_config.address).map_err(Bme680Error)?;
+        if sensor_config.model == SensorModel::Bme280 && !sensor.has_humidity() {
+            check_supported_outputs(
+                SensorModel::Bmp280,
+                &auxiliary_kinds(shared),
+                shared.outputs,
+            )?;
+            log_warn!("Configured a BME280, but found a BMP280 without humidity.");
+        }
+        return Ok(HardwareSensor::Bme280(
+            sensor.with_overrides(MeasurementOverrides::from_config(sensor_config)),
+        ));
+    }
+    let mut delay = Delay {};
+    let dev = bme680::Bme680::init(i2c, &mut delay, sensor_config.address).map_err(Bme680Error)?;
+    let ambient_temperature = match sensor_config.gas_ambient_temp_celsius {
+        Some(celsius) => GasAmbientTemperature::Fixed(celsius),
+        None => GasAmbientTemperature::Tracked {
+            initial_celsius: sensor_config.initial_ambient_temp_celsius,
+        },
+    };
+    Ok(HardwareSensor::Bme680(
+        Bme680Sensor::new(dev, delay, ambient_temperature)
+            .with_overrides(MeasurementOverrides::from_config(sensor_config)),
+    ))
+}
+
+fn init_bsec(
+    bsec_config_blob: &[u8],
+    sensor_config: &SensorConfig,
+    subscriptions: &[SubscriptionRequest],
+    time: Arc<Time>,
+    shared: &SensorShared,
+    startup: &StartupPhases,
+) -> anyhow::Result<SensorBsec> {
+    let sensor = if let Some(replay) = &sensor_config.replay {
+        log_warn!("Replaying the recording {}.", replay.file.display());
+        let replay = match shared.replay {
+            Some(shared) if shared.file() == replay.file => shared.clone(),
+            _ => Replay::load(replay)?,
+        };
+        SensorBackend::Replay(ReplaySensor::new(replay))
+    } else if sensor_config.device == SIMULATED_DEVICE {
+        log_warn!("Simulating the sensor, the outputs are synthetic.");
+        SensorBackend::Simulated(SimulatedSensor::new(sensor_config.simulation.clone()))
+    } else {
+        SensorBackend::Hardware(open_sensor(sensor_config, shared, startup)?)
+    };
+    let sensor = HeaterSensor::new(
+        AuxiliarySensor::new(
+            CorrectedSensor::new(
+                OffsetSensor::new(sensor, shared.temperature_offset.clone()),
+                HumidityCorrection::from_config(sensor_config),
+            ),
+            shared.auxiliary_inputs.to_vec(),
+        ),
+        shared.heater_usage.clone(),
+    )
+    .with_heating_duration(
+        sensor_config
+            .heating_duration_ms
+            .map(|duration_ms| Duration::from_millis(duration_ms.into())),
+    );
+    let mut bsec = bsec::Bsec::init(sensor, time)?;
+
+    startup.begin("setting BSEC config");
+    bsec_config::apply(&mut bsec, bsec_config_blob)?;
+
+    startup.begin("subscribing to BSEC outputs");
+    match bsec.update_subscription(subscriptions) {
+        Ok(required) => {
+            check_required_inputs(
+                &required,
+                &provided_inputs_with(sensor_config.model, &auxiliary_kinds(shared)),
+            );
+        }
+        // The bsec crate does not track a subscription BSEC accepted with a
+        // warning, so the outputs of all cycles would be sized wrongly.
+        Err(err) => return Err(err.into()),
+    }
+    Ok(bsec)
+}
+
+/// Loads the BSEC config once for all (re-)initializations and logs whether
+/// it changed since the last start.
+fn load_bsec_config(config: &Config, read_only: &ReadOnlySwitch) -> anyhow::Result<Vec<u8>> {
+    let blob = bsec_config::load(&config.bsec.config)?;
+    let fingerprint = bsec_config::fingerprint(&blob);
+    let fingerprint_file =
+        Path::new(&config.bsec.state_file).with_file_name("bsec-config.fingerprint");
+    match bsec_config::load_fingerprint(&fingerprint_file) {
+        Ok(Some(last)) if last == fingerprint => {
+            log_info!("BSEC config unchanged since the last start.");
+            return Ok(blob);
+        }
+        Ok(Some(_)) => log_warn!(
+            "BSEC config changed since the last start, the saved BSEC state may not match it."
+        ),
+        Ok(None) => (),
+        Err(err) => log_error!("Failed to read BSEC config fingerprint: {}", err),
+    }
+    if !read_only.is_read_only() {
+        if let Err(err) = bsec_config::save_fingerprint(&fingerprint_file, fingerprint) {
+            log_error!("Failed to save BSEC config fingerprint: {}", err);
+        }
+    }
+    Ok(blob)
+}
+
+/// A sensor with its own BSEC state.
+struct SensorSlot<'a> {
+    name: &'static str,
+    config: &'a SensorConfig,
+    state_file: &'a str,
+}
+
+fn sensor_slots(config: &Config) -> Vec<SensorSlot<'_>> {
+    std::iter::once(SensorSlot {
+        name: "primary",
+        config: &config.sensor,
+        state_file: &config.bsec.state_file,
+    })
+    .chain(config.backup_sensor.iter().map(|backup| SensorSlot {
+        name: "backup",
+        config: &backup.sensor,
+        state_file: &backup.state_file,
+    }))
+    .collect()
+}
+
+fn create_watchdog(config: &Config) -> Option<Watchdog> {
+    let profiles = std::iter::once(&config.bsec.subscriptions).chain(
+        config
+            .occupancy
+            .iter()
+            .map(|occupancy| &occupancy.subscriptions),
+    );
+    let interval = profiles
+        .filter_map(|subscriptions| watchdog::output_interval(subscriptions))
+        .max()?;
+    if config.watchdog.max_missed_intervals == 0 {
+        return None;
+    }
+    Some(Watchdog::new(
+        interval * config.watchdog.max_missed_intervals,
+    ))
+}
+
+fn spawn_systemd_watchdog(watchdog: Watchdog) {
+    if let Some(interval) = systemd::watchdog_interval() {
+        tokio::task::spawn(async move {
+            let mut ticks = tokio::time::interval(interval / 2);
+            loop {
+                ticks.tick().await;
+                if watchdog.is_healthy() {
+                    systemd::notify_watchdog();
+                }
+            }
+        });
+    }
+}
+
 #[tokio::main(flavor = "current_thread")]
 pub async fn main() -> Result<(), Box<dyn Error>> {
-    let config: linux_bsec_exporter::config::Config = toml::from_str(&fs::read_to_string(
-        std::env::var("BSEC_CONFIG_PATH").unwrap_or("/etc/linux-bsec-exporter/config.toml".into()),
-    )?)?;
+    #[cfg(feature = "http-client")]
+    let started = std::time::Instant::now();
+    let mut burn_in_duration = None;
+    let mut generate_dashboard = false;
+    let mut generate_alert_rules = false;
+    let mut show_events = false;
+    let mut export_history = false;
+    let mut show_config = false;
+    let mut dry_run = false;
+    let mut migrate_config = false;
+    match std::env::args().nth(1).as_deref() {
+        Some("version" | "--version") => {
+            println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+            println!("{}", limits());
+            return Ok(());
+        }
+        Some("burn-in") => {
+            let hours: f64 = match std::env::args().nth(2) {
+                Some(hours) => hours.parse()?,
+                None => DEFAULT_BURN_IN_HOURS,
+            };
+            burn_in_duration = Some(burn_in::duration_from_hours(hours)?);
+        }
+        Some("generate-dashboard") => generate_dashboard = true,
+        Some("generate-alert-rules") => generate_alert_rules = true,
+        Some("events") => show_events = true,
+        Some("export-history") => export_history = true,
+        Some("show-config") => show_config = true,
+        Some("dry-run" | "--dry-run") => dry_run = true,
+        Some("migrate-config") => migrate_config = true,
+        _ => (),
+    }
 
-    println!("Initializing sensor ...");
-    let i2c = I2cdev::new(config.sensor.device)?;
-    let mut delay = Delay {};
-    let dev = bme680::Bme680::init(i2c, &mut delay, config.sensor.address).map_err(Bme680Error)?;
-    let sensor = bsec::bme::bme680::Bme680SensorBuilder::new(dev, delay)
-        .initial_ambient_temp_celsius(config.sensor.initial_ambient_temp_celsius)
-        .temp_offset_celsius(config.bsec.temperature_offset_celsius)
-        .build();
-    let mut bsec = bsec::Bsec::init(sensor, TIME.clone())?;
-
-    println!("Setting BSEC config ...");
-    let mut bsec_config = Vec::<u8>::new();
-    File::open(config.bsec.config)?.read_to_end(&mut bsec_config)?;
-    bsec.set_configuration(&bsec_config[4..])?; // First four bytes give config length
-
-    println!("Subscribing to BSEC outputs ...");
-    bsec.update_subscription(&config.bsec.subscriptions)?;
-    let registry = BsecGaugeRegistry::new(
-        &config
-            .bsec
-            .subscriptions
+    let config_path =
+        std::env::var("BSEC_CONFIG_PATH").unwrap_or("/etc/linux-bsec-exporter/config.toml".into());
+    if migrate_config {
+        let migrated = migration::migrate(&fs::read_to_string(&config_path)?)?;
+        match std::env::args().nth(2) {
+            Some(output) => {
+                fs::write(&output, &migrated.config)?;
+                for note in migrated.notes.iter() {
+                    log_info!("Migrated {}: {}", config_path, note);
+                }
+            }
+            None => print!("{}", migrated.config),
+        }
+        return Ok(());
+    }
+    let rollout = ConfigRollout::new(&config_path);
+    let loaded_config = if generate_dashboard
+        || generate_alert_rules
+        || show_events
+        || export_history
+        || show_config
+        || dry_run
+    {
+        None
+    } else {
+        Some(rollout.load()?)
+    };
+    let mut config: Config = match &loaded_config {
+        Some(loaded) => loaded.config.clone(),
+        None => toml::from_str(&fs::read_to_string(&config_path)?)?,
+    };
+    if dry_run {
+        log_info!("Dry run with the simulated sensor, without writing any files.");
+        config = config.dry_run();
+    }
+    logging::set_format(config.logging.format);
+    logging::set_repeat_window(Duration::from_secs(config.logging.repeat_window_seconds));
+    tokio::task::spawn(async {
+        let mut interval = tokio::time::interval(logging::REPEAT_FLUSH_INTERVAL);
+        loop {
+            interval.tick().await;
+            logging::flush_repeats();
+        }
+    });
+
+    let exported_schema = || {
+        let mut schema: Vec<MetricSchema> = config
+            .exported_outputs()
+            .into_iter()
+            .map(MetricSchema::from)
+            .collect();
+        schema.sort_by(|a, b| a.name.cmp(&b.name));
+        schema
+    };
+    let events_file = Path::new(&config.bsec.state_file).with_file_name("events.jsonl");
+    if show_events {
+        for event in EventJournal::load(&events_file)?.events() {
+            println!("{} {:?} {}", event.timestamp, event.kind, event.message);
+        }
+        return Ok(());
+    }
+    if export_history {
+        let history_file = Path::new(&config.bsec.state_file).with_file_name("history.bin");
+        let ((samples, _), err) = history::decode_valid(&fs::read(&history_file)?)?;
+        if let Some(err) = err {
+            log_warn!(
+                "Exporting only the {} samples before an unreadable record: {}",
+                samples.len(),
+                err
+            );
+        }
+        let labels = HostFactSources::default().labels(&config.exporter.auto_labels);
+        let openmetrics = history::to_openmetrics(&samples, &labels.into_iter().collect());
+        match std::env::args().nth(2) {
+            Some(output) => fs::write(output, openmetrics)?,
+            None => print!("{}", openmetrics),
+        }
+        return Ok(());
+    }
+    if show_config {
+        print!("{}", toml::to_string_pretty(&config.redacted())?);
+        return Ok(());
+    }
+    if generate_alert_rules {
+        print!("{}", alerts::to_yaml(&alerts::rules(&exported_schema())));
+        return Ok(());
+    }
+    if generate_dashboard {
+        let mut label_names: Vec<String> = HostFactSources::default()
+            .labels(&config.exporter.auto_labels)
+            .into_keys()
+            .collect();
+        label_names.sort();
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&dashboard::generate(&exported_schema(), &label_names))?
+        );
+        return Ok(());
+    }
+
+    check_heat_source(&config)?;
+    let auxiliary_inputs: Vec<AuxiliaryInput> = config
+        .auxiliary_inputs
+        .iter()
+        .map(AuxiliaryInput::from_config)
+        .collect();
+    let mut profiles = vec![config.bsec.subscriptions.clone()];
+    profiles.extend(
+        config
+            .occupancy
+            .iter()
+            .map(|occupancy| occupancy.subscriptions.clone()),
+    );
+    if burn_in_duration.is_some() {
+        profiles.push(BurnIn::profile(&config.bsec.subscriptions));
+    }
+    let shortest_interval = profiles
+        .iter()
+        .filter_map(|subscriptions| watchdog::output_interval(subscriptions))
+        .min();
+    for slot in sensor_slots(&config) {
+        let overrides = MeasurementOverrides::from_config(slot.config);
+        for warning in overrides
+            .check(slot.config.model, shortest_interval)
+            .map_err(|err| anyhow::anyhow!("invalid {} sensor settings: {}", slot.name, err))?
+        {
+            log_warn!("Overriding the {} sensor settings: {}.", slot.name, warning);
+        }
+        check_supported_outputs(
+            slot.config.model,
+            &auxiliary_inputs
+                .iter()
+                .map(AuxiliaryInput::kind)
+                .collect::<Vec<_>>(),
+            &config.exported_outputs(),
+        )?;
+    }
+
+    let startup = StartupPhases::new().with_notifier(|status| {
+        if systemd::booted() {
+            systemd::notify_status(status);
+        }
+    });
+    let (update_subscription, mut subscription_updates) = mpsc::unbounded_channel();
+    let burn_in =
+        burn_in_duration.map(|duration| BurnIn::new(duration, update_subscription.clone()));
+    let occupancy = config.occupancy.as_ref().map(|occupancy| {
+        Occupancy::new(
+            config.bsec.subscriptions.clone(),
+            occupancy.subscriptions.clone(),
+            update_subscription.clone(),
+        )
+    });
+    let labels = HostFactSources::default().labels(&config.exporter.auto_labels);
+    let identity = Identity {
+        uuid: if dry_run {
+            Uuid::new_v4()
+        } else {
+            load_or_create_uuid(Path::new(&config.bsec.state_file).with_file_name("instance-id"))?
+        },
+        labels: labels.clone(),
+    };
+    let registry = BsecGaugeRegistry::with_labels(&config.exported_outputs(), labels)?;
+    registry.register_instance_info(&identity.uuid.to_string())?;
+    let snapshot_file = Path::new(&config.bsec.state_file).with_file_name("last-values.json");
+    if config.exporter.restore_values {
+        match snapshot::load_snapshot(
+            &snapshot_file,
+            Duration::from_secs(config.exporter.restore_max_age_seconds),
+        ) {
+            Ok(values) => registry.restore(&values.unwrap_or_default())?,
+            Err(err) => log_error!("Failed to restore the last values: {}", err),
+        }
+    }
+    let counters_file = Path::new(&config.bsec.state_file).with_file_name("counters.json");
+    if config.exporter.persist_counters {
+        match counters::load_counters(&counters_file) {
+            Ok(counters) => registry.restore_counters(&counters.unwrap_or_default()),
+            Err(err) => log_error!("Failed to restore the counters: {}", err),
+        }
+    }
+    let registry = registry.with_gauge_init(config.exporter.gauge_init)?;
+    let normal_subscriptions = {
+        let occupancy = occupancy.clone();
+        let subscriptions = config.bsec.subscriptions.clone();
+        move || {
+            occupancy
+                .as_ref()
+                .map_or_else(|| subscriptions.clone(), Occupancy::current_profile)
+        }
+    };
+    let gas = GasSwitch::new(
+        config.bsec.run_gas,
+        normal_subscriptions.clone(),
+        update_subscription,
+    );
+    if !gas.is_enabled() {
+        registry.clear(&GAS_OUTPUTS);
+    }
+    let current_subscriptions = || {
+        gas.apply(match &burn_in {
+            Some(burn_in) if burn_in.is_active() => BurnIn::profile(&normal_subscriptions()),
+            _ => normal_subscriptions(),
+        })
+    };
+    if let Some(burn_in) = burn_in.clone() {
+        let remaining = registry.register_burn_in_remaining()?;
+        let normal_subscriptions = normal_subscriptions.clone();
+        tokio::task::spawn(async move {
+            if let Err(err) = burn_in.run(remaining, normal_subscriptions).await {
+                log_error!("Failed to end the burn-in: {}", err);
+            }
+        });
+    }
+    #[cfg(not(feature = "http-client"))]
+    if config.heartbeat.is_some() || config.consistency.is_some() || !config.rooms.is_empty() {
+        return Err(
+            "Heartbeats, consistency checks, and rooms require the http-client feature.".into(),
+        );
+    }
+    #[cfg(feature = "http-client")]
+    if let Some(heartbeat) = config.heartbeat.clone() {
+        tokio::task::spawn(heartbeat::run_heartbeat(
+            heartbeat,
+            identity.clone(),
+            registry.clone(),
+            started,
+        ));
+    }
+    #[cfg(feature = "http-client")]
+    if let Some(consistency) = config.consistency.clone() {
+        tokio::task::spawn(consistency::run_consistency_checks(
+            consistency,
+            registry.clone(),
+        ));
+    }
+    #[cfg(feature = "http-client")]
+    for room in config.rooms.iter().cloned() {
+        tokio::task::spawn(rooms::run_room_aggregation(room, registry.clone()));
+    }
+    let time_sync_status = TimeSyncStatus::new(time_sync::is_synchronized().unwrap_or(false));
+    tokio::task::spawn(time_sync::run_time_sync_check(
+        time_sync_status.clone(),
+        registry.register_time_synchronized()?,
+        Duration::from_secs(config.time_sync.interval_seconds),
+    ));
+    if config.time_sync.gate_wall_clock && !time_sync_status.is_synchronized() {
+        log_info!("Withholding outputs from sinks until the system time is synchronized.");
+    }
+    let watchdog = create_watchdog(&config);
+    if let Some(watchdog) = &watchdog {
+        spawn_systemd_watchdog(watchdog.clone());
+    }
+
+    let read_only = if dry_run {
+        ReadOnlySwitch::pinned()
+    } else {
+        ReadOnlySwitch::new()
+    };
+    spawn_read_only_signal_handlers(read_only.clone())?;
+    let journal = EventJournal::load(&events_file)?.with_read_only(read_only.clone());
+    let history_file = Path::new(&config.bsec.state_file).with_file_name("history.bin");
+    let history = config.history.as_ref().map(|history_config| {
+        let history = History::new(
+            Duration::from_secs(history_config.retention_hours * 3600),
+            Duration::from_secs(history_config.interval_seconds),
+        );
+        if let Err(err) = history.load(&history_file) {
+            log_error!("Failed to restore the history: {}", err);
+        }
+        let periodic = history.clone();
+        let history_file = history_file.clone();
+        let read_only = read_only.clone();
+        let save_interval = Duration::from_secs(history_config.save_interval_seconds);
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + save_interval,
+                save_interval,
+            );
+            loop {
+                interval.tick().await;
+                if read_only.is_read_only() {
+                    continue;
+                }
+                if let Err(err) = periodic.save(&history_file) {
+                    log_error!("Failed to save the history: {}", err);
+                }
+            }
+        });
+        history
+    });
+    if config.exporter.persist_counters {
+        let registry = registry.clone();
+        let counters_file = counters_file.clone();
+        let read_only = read_only.clone();
+        tokio::task::spawn(async move {
+            let mut interval = tokio::time::interval_at(
+                tokio::time::Instant::now() + COUNTERS_SAVE_INTERVAL,
+                COUNTERS_SAVE_INTERVAL,
+            );
+            loop {
+                interval.tick().await;
+                if read_only.is_read_only() {
+                    continue;
+                }
+                if let Err(err) =
+                    counters::save_counters(&counters_file, &registry.counters_snapshot())
+                {
+                    log_error!("Failed to save the counters: {}", err);
+                }
+            }
+        });
+    }
+    let replay = config
+        .sensor
+        .replay
+        .as_ref()
+        .map(Replay::load)
+        .transpose()?;
+    let time = Arc::new(MonotonicGuard::new(match &replay {
+        Some(replay) => RuntimeClock::new(ReplayClock(replay.clone())),
+        None => RuntimeClock::from_config(&config.clock, &read_only)?,
+    }));
+    let mut temperature_offset_celsius = config.bsec.temperature_offset_celsius;
+    let mut pending_calibration = config.temperature_calibration.clone();
+    if let Some(calibration) = &config.temperature_calibration {
+        if let Some(offset) = calibration::load_offset(&calibration.offset_file)? {
+            log_info!("Using calibrated temperature offset of {} °C.", offset);
+            temperature_offset_celsius = offset;
+            pending_calibration = None;
+        }
+    }
+    let temperature_offset = TemperatureOffset::new(temperature_offset_celsius);
+    let (raw_temperature, raw_temperature_updates) = watch::channel(None);
+    if let Some(calibration) = pending_calibration {
+        if !current_subscriptions()
             .iter()
-            .map(|item| item.sensor)
-            .collect::<Vec<OutputKind>>(),
+            .any(|request| request.sensor == OutputKind::RawTemperature)
+        {
+            log_warn!("Temperature calibration requires a raw_temperature subscription.");
+        }
+        tokio::task::spawn(calibration::run_calibration(
+            calibration,
+            temperature_offset.clone(),
+            raw_temperature_updates,
+            read_only.clone(),
+        ));
+    }
+    for (auxiliary_config, auxiliary_input) in
+        config.auxiliary_inputs.iter().zip(auxiliary_inputs.iter())
+    {
+        tokio::task::spawn(run_auxiliary_input(
+            auxiliary_config.clone(),
+            auxiliary_input.clone(),
+        ));
+    }
+    let heater_usage = HeaterUsage::new(registry.register_heater_metrics()?);
+    let state_metrics = registry.register_state_metrics()?;
+    startup.begin("loading BSEC config");
+    let bsec_config_blob = load_bsec_config(&config, &read_only)?;
+    if let Some(mismatch) = bsec_config::linked_version()
+        .and_then(|linked| bsec_config::check_version(&bsec_config_blob, linked))
+    {
+        log_warn!("{}.", mismatch);
+        registry.register_config_version_mismatch(&mismatch)?;
+    }
+    let slots = sensor_slots(&config);
+    #[cfg(feature = "debug")]
+    let faults = Faults::new();
+    let exported_outputs = config.exported_outputs();
+    let sensor_shared = SensorShared {
+        temperature_offset: &temperature_offset,
+        journal: &journal,
+        heater_usage: &heater_usage,
+        #[cfg(feature = "debug")]
+        faults: &faults,
+        auxiliary_inputs: &auxiliary_inputs,
+        outputs: &exported_outputs,
+        replay: replay.as_ref(),
+    };
+    let init_slot = |slot: &SensorSlot| {
+        init_bsec(
+            &bsec_config_blob,
+            slot.config,
+            &current_subscriptions(),
+            time.clone(),
+            &sensor_shared,
+            &startup,
+        )
+    };
+    let mut active = 0;
+    let mut bsec = loop {
+        match init_slot(&slots[active]) {
+            Ok(bsec) => break bsec,
+            Err(err) if active + 1 < slots.len() => {
+                log_error!(
+                    "Failed to initialize {} sensor: {}",
+                    slots[active].name,
+                    err
+                );
+                active += 1;
+            }
+            Err(err) => return Err(err.into()),
+        }
+    };
+    registry.set_active_sensor(slots[active].name);
+    let rollback = Arc::new(Notify::new());
+    if let Some(loaded) = &loaded_config {
+        if loaded.rolled_back.is_some() {
+            registry.register_config_rolled_back()?;
+        }
+        if loaded.pending && loaded.can_roll_back {
+            let grace_period = Duration::from_secs(
+                config
+                    .rollback
+                    .clone()
+                    .unwrap_or_default()
+                    .grace_period_seconds,
+            );
+            let startup = startup.clone();
+            let rollback = rollback.clone();
+            tokio::task::spawn(async move {
+                tokio::time::sleep(grace_period).await;
+                if !startup.status().complete {
+                    log_warn!(
+                        "Changed configuration produced no outputs within {} s, shutting down to roll back.",
+                        grace_period.as_secs()
+                    );
+                    rollback.notify_one();
+                }
+            });
+        }
+    }
+    let mut sigterm = signal(SignalKind::terminate())?;
+    let http_drain = HttpDrain::new(Duration::from_secs(config.exporter.drain_timeout_seconds));
+    let monitoring_registry = registry.clone();
+    let snapshot_registry = registry.clone();
+    let mut gas_baseline = GasBaselineTracker::load(
+        Path::new(&config.bsec.state_file).with_file_name("gas-baseline"),
+    )?
+    .with_read_only(read_only.clone());
+    #[cfg_attr(
+        not(any(feature = "cbor-udp", feature = "lorawan", feature = "mqtt")),
+        allow(unused_mut)
+    )]
+    let mut sinks: Vec<Box<dyn Sink + Send>> = vec![];
+    #[cfg(feature = "cbor-udp")]
+    if let Some(cbor_udp) = &config.sinks.cbor_udp {
+        sinks.push(Box::new(AccuracyFilter::new(
+            Box::new(CborUdpSink::new(cbor_udp)?),
+            cbor_udp.accuracy.clone(),
+        )));
+    }
+    #[cfg(not(feature = "cbor-udp"))]
+    if config.sinks.cbor_udp.is_some() {
+        return Err("The CBOR/UDP sink requires the cbor-udp feature.".into());
+    }
+    #[cfg(feature = "lorawan")]
+    if let Some(lorawan) = &config.sinks.lorawan {
+        sinks.push(Box::new(AccuracyFilter::new(
+            Box::new(LorawanSink::new(lorawan)),
+            lorawan.accuracy.clone(),
+        )));
+    }
+    #[cfg(not(feature = "lorawan"))]
+    if config.sinks.lorawan.is_some() {
+        return Err("The LoRaWAN sink requires the lorawan feature.".into());
+    }
+    #[cfg(feature = "mqtt")]
+    if let Some(mqtt) = &config.sinks.mqtt {
+        sinks.push(Box::new(AccuracyFilter::new(
+            Box::new(MqttSink::new(
+                mqtt,
+                Path::new(&config.bsec.state_file).with_file_name("mqtt-spool.jsonl"),
+                read_only.clone(),
+            )),
+            mqtt.accuracy.clone(),
+        )));
+    }
+    #[cfg(not(feature = "mqtt"))]
+    if config.sinks.mqtt.is_some() {
+        return Err("The MQTT sink requires the mqtt feature.".into());
+    }
+    let mut processing = ProcessingChain::new(&config.processing)?;
+    let mut alerting = AlertEngine::new(
+        &config.alerting.rules,
+        config
+            .history
+            .as_ref()
+            .map_or(0, |history| history.interval_seconds),
     )?;
-    let monitoring = run_monitoring(
-        bsec,
-        StateFile::new(config.bsec.state_file),
-        registry.clone(),
+    if !alerting.is_empty() && history.is_none() {
+        return Err("The alerting rules require the [history] section.".into());
+    }
+    for (alert, firing) in alerting.states() {
+        monitoring_registry.set_alert_firing(alert, firing);
+    }
+    let alerts =
+        ActiveAlerts::load(Path::new(&config.bsec.state_file).with_file_name("alert-acks.json"))?
+            .with_read_only(read_only.clone());
+    #[cfg(feature = "http-client")]
+    let notifier = config.alerting.notifications.clone().map(|notifications| {
+        let (notifier, delivery) = AlertNotifier::new(notifications, alerts.clone());
+        tokio::task::spawn(delivery.run());
+        notifier
+    });
+    #[cfg(not(feature = "http-client"))]
+    if config.alerting.notifications.is_some() {
+        return Err("Alert notifications require the http-client feature.".into());
+    }
+    if let Some(report_config) = &config.report {
+        let retention_hours = match &config.history {
+            Some(history_config) => history_config.retention_hours,
+            None => return Err("The reports require the [history] section.".into()),
+        };
+        if retention_hours * 3600 < report_config.period.seconds() {
+            return Err("The history retention is shorter than the report period.".into());
+        }
+        #[cfg(not(feature = "http-client"))]
+        if report_config.webhook_url.is_some() {
+            return Err("Posting reports to a webhook requires the http-client feature.".into());
+        }
+    }
+    if let (Some(report_config), Some(history)) = (&config.report, &history) {
+        if report_config.webhook_url.is_some() || report_config.email.is_some() {
+            tokio::task::spawn(report::run(report_config.clone(), history.clone()));
+        }
+    }
+    let mut accuracy = AccuracyTracker::default();
+    let mut restart_limiter = RestartLimiter::new(
+        config.restart.max_per_hour as usize,
+        Duration::from_secs(3600),
+        time.clone(),
     );
+    let monitoring = async {
+        let mut ctx = MonitoringContext {
+            registry: &monitoring_registry,
+            subscription_updates: &mut subscription_updates,
+            watchdog: watchdog.as_ref(),
+            restart_on_stall: config.watchdog.restart,
+            sigterm: &mut sigterm,
+            http_drain: &http_drain,
+            raw_temperature: &raw_temperature,
+            gas_baseline: &mut gas_baseline,
+            burn_in: burn_in.as_ref(),
+            gas: &gas,
+            sinks: &mut sinks,
+            processing: &mut processing,
+            startup: &startup,
+            rollout: &rollout,
+            loaded_config: loaded_config.as_ref(),
+            rollback: &rollback,
+            journal: &journal,
+            accuracy: &mut accuracy,
+            history: history.as_ref(),
+            alerting: &mut alerting,
+            alerts: &alerts,
+            #[cfg(feature = "http-client")]
+            notifier: notifier.as_ref(),
+            time_sync_gate: Some(&time_sync_status).filter(|_| config.time_sync.gate_wall_clock),
+        };
+        loop {
+            let state_persistence = if dry_run {
+                StatePersistence::None(NoPersistState::default())
+            } else {
+                let state_file = StateFile::new(slots[active].state_file.to_string());
+                if let Some((modified, bytes)) = state_file.last_save() {
+                    state_metrics.record_save(modified, bytes);
+                }
+                StatePersistence::File(state_file)
+            };
+            let state_persistence =
+                MeteredPersistState::new(state_persistence, state_metrics.clone());
+            #[cfg(feature = "debug")]
+            let state_persistence = FaultyPersistState::new(state_persistence, faults.clone());
+            let (monitor, rx) = bsec_monitor(
+                bsec,
+                JournaledPersistState::new(
+                    PhasedPersistState::new(
+                        ReadOnlyPersistState::new(state_persistence, read_only.clone()),
+                        startup.clone(),
+                    ),
+                    journal.clone(),
+                ),
+                time.clone(),
+            );
+            let mut monitor = monitor
+                .with_provided_inputs(provided_inputs_with(
+                    slots[active].config.model,
+                    &auxiliary_kinds(&sensor_shared),
+                ))
+                .with_subscription(&current_subscriptions())
+                .with_min_publish_interval(Duration::from_millis(
+                    config.bsec.min_publish_interval_ms,
+                ));
+            if config.clock.compensate_drift {
+                monitor = monitor.with_drift_compensation();
+            }
+            let error = match run_monitoring(monitor, rx, &mut ctx).await {
+                Ok(exit @ (MonitoringExit::Shutdown | MonitoringExit::RollBack)) => {
+                    return anyhow::Result::Ok(exit)
+                }
+                Ok(MonitoringExit::Stalled) => None,
+                Err(err) => Some(err),
+            };
+            if !restart_limiter.try_restart() {
+                if active + 1 >= slots.len() {
+                    journal.record(EventKind::Error, "restart limit exceeded, exiting");
+                    return Err(error.unwrap_or_else(|| {
+                        anyhow::anyhow!("BSEC monitoring stalled and restart limit exceeded")
+                    }));
+                }
+                active += 1;
+                log_warn!(
+                    "Restart limit exceeded, switching to the {} sensor ...",
+                    slots[active].name
+                );
+                journal.record(
+                    EventKind::Restart,
+                    format!("switching to the {} sensor", slots[active].name),
+                );
+                restart_limiter = RestartLimiter::new(
+                    config.restart.max_per_hour as usize,
+                    Duration::from_secs(3600),
+                    time.clone(),
+                );
+            }
+            if let Some(err) = error {
+                match err.downcast_ref::<BsecCallError>() {
+                    Some(call_error) => call_error.log(),
+                    None => log_error!("BSEC monitoring failed: {}", err),
+                }
+                journal.record(EventKind::Error, format!("BSEC monitoring failed: {}", err));
+            }
+            log_info!("Restarting BSEC monitoring ...");
+            journal.record(EventKind::Restart, "restarting BSEC monitoring");
+            monitoring_registry.inc_restarts();
+            bsec = loop {
+                match init_slot(&slots[active]) {
+                    Ok(bsec) => break bsec,
+                    Err(err) if active + 1 < slots.len() => {
+                        log_error!(
+                            "Failed to initialize {} sensor: {}",
+                            slots[active].name,
+                            err
+                        );
+                        journal.record(
+                            EventKind::Error,
+                            format!(
+                                "failed to initialize {} sensor: {}",
+                                slots[active].name, err
+                            ),
+                        );
+                        active += 1;
+                    }
+                    Err(err) => {
+                        journal.record(
+                            EventKind::Error,
+                            format!(
+                                "failed to initialize {} sensor: {}",
+                                slots[active].name, err
+                            ),
+                        );
+                        return Err(err);
+                    }
+                }
+            };
+            monitoring_registry.set_active_sensor(slots[active].name);
+        }
+    };
 
-    let mut app = tide::with_state(registry);
-    app.with(LogErrors);
-    app.at("/metrics").get(serve_metrics);
-    println!("Spawning server ...");
-    let join_handle = tokio::task::spawn(app.listen(config.exporter.listen_addrs));
+    let auth = TokenAuth::new(&config.exporter.auth);
+    let mut listeners = JoinSet::new();
+    for listener in config.exporter.listeners.iter() {
+        let app = metrics_app(
+            MetricsView::new(
+                registry.clone(),
+                MetricsFilter::new(
+                    listener.metrics.clone(),
+                    listener.labels.clone(),
+                    &registry.label_names(),
+                )?,
+            ),
+            &http_drain,
+            &auth,
+        );
+        listeners.spawn(http_drain.serve(app, listener.listen_addrs.clone()));
+    }
+    let mut app = metrics_app(
+        MetricsView::new(registry, MetricsFilter::all()),
+        &http_drain,
+        &auth,
+    );
+    app.at("/api/v1/current")
+        .with(auth.require(Scope::Metrics))
+        .with(CacheValidation)
+        .get(get_current);
+    app.at("/api/v1/schema")
+        .with(auth.require(Scope::Metrics))
+        .with(CacheValidation)
+        .get(get_schema);
+    let mut identity_api = tide::with_state(identity);
+    identity_api.at("/").get(get_identity);
+    app.at("/api/v1/identity")
+        .with(auth.require(Scope::Metrics))
+        .nest(identity_api);
+    if let Some(history) = history.clone() {
+        let mut history_api = tide::with_state(history.clone());
+        history_api.at("/").get(get_history);
+        app.at("/api/v1/history")
+            .with(auth.require(Scope::Metrics))
+            .nest(history_api);
+        let mut annotations_api = tide::with_state(history.clone());
+        annotations_api
+            .at("/")
+            .with(auth.require(Scope::Metrics))
+            .get(get_annotations);
+        annotations_api
+            .at("/")
+            .with(auth.require(Scope::Control))
+            .post(post_annotation);
+        app.at("/api/v1/annotations").nest(annotations_api);
+        let mut report_api = tide::with_state((history, config.report.clone().unwrap_or_default()));
+        report_api.at("/").get(get_report);
+        app.at("/api/v1/report")
+            .with(auth.require(Scope::Metrics))
+            .nest(report_api);
+    }
+    let redacted_config = Arc::new(config.redacted());
+    let control_api = ControlApi {
+        journal: &journal,
+        read_only: &read_only,
+        startup: &startup,
+        gas: &gas,
+        occupancy: occupancy.as_ref(),
+        alerts: &alerts,
+        auth: &auth,
+        config: &redacted_config,
+        #[cfg(feature = "debug")]
+        faults: &faults,
+    };
+    match &config.exporter.control_listen_addrs {
+        Some(control_listen_addrs) => {
+            let mut control_app = tide::new();
+            control_app.with(LogErrors);
+            control_app.with(http_drain.clone());
+            control_api.add_to(&mut control_app);
+            listeners.spawn(http_drain.serve(control_app, control_listen_addrs.clone()));
+        }
+        None => control_api.add_to(&mut app),
+    }
+    log_info!("Spawning server ...");
+    // Stopped servers do not end the exporter, which still has to save the
+    // BSEC state.
+    listeners.spawn(http_drain.serve(app, config.exporter.listen_addrs.clone()));
 
-    println!("Ready.");
-    if daemon::booted() {
-        daemon::notify(false, &[NotifyState::Ready])?;
+    log_info!("Ready.");
+    if systemd::booted() {
+        systemd::notify_ready()?;
     }
 
-    tokio::select! {
-        result = join_handle => result??,
+    // The first listener failing ends the exporter, regardless of the order
+    // they were spawned in.
+    let listeners = async {
+        while let Some(result) = listeners.join_next().await {
+            result??;
+        }
+        std::future::pending::<std::io::Result<()>>().await
+    };
+
+    let exit = tokio::select! {
+        result = listeners => {
+            result?;
+            MonitoringExit::Shutdown
+        }
         result = monitoring => result?,
+    };
+
+    if config.exporter.restore_values {
+        if read_only.is_read_only() {
+            log_warn!("Not saving the last values in read-only mode.");
+        } else if let Err(err) =
+            snapshot::save_snapshot(&snapshot_file, &snapshot_registry.snapshot())
+        {
+            log_error!("Failed to save the last values: {}", err);
+        }
     }
 
-    if daemon::booted() {
-        daemon::notify(true, &[NotifyState::Stopping])?;
+    if config.exporter.persist_counters {
+        if read_only.is_read_only() {
+            log_warn!("Not saving the counters in read-only mode.");
+        } else if let Err(err) =
+            counters::save_counters(&counters_file, &snapshot_registry.counters_snapshot())
+        {
+            log_error!("Failed to save the counters: {}", err);
+        }
+    }
+
+    if let Some(history) = &history {
+        if read_only.is_read_only() {
+            log_warn!("Not saving the history in read-only mode.");
+        } else if let Err(err) = history.save(&history_file) {
+            log_error!("Failed to save the history: {}", err);
+        }
     }
-    println!("Shutdown.");
 
+    if systemd::booted() {
+        systemd::notify_stopping()?;
+    }
+    log_info!("Shutdown.");
+
+    if let MonitoringExit::RollBack = exit {
+        return Err("changed configuration produced no outputs, exiting to roll back".into());
+    }
     Ok(())
 }
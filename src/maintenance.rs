@@ -0,0 +1,113 @@
+//! Read-only maintenance mode.
+//!
+//! While the read-only mode is enabled, the BSEC state and all other files
+//! written by the exporter are not written anymore, e.g. while the root
+//! filesystem is being snapshotted or remounted. Metrics are still served.
+//! Writes skipped in the read-only mode are not repeated afterwards, but the
+//! next periodic write will happen as usual. A dry run pins the read-only
+//! mode so that it cannot be left.
+
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use crate::monitor::PersistState;
+use crate::{log_info, log_warn};
+
+#[derive(Clone, Debug, Default)]
+pub struct ReadOnlySwitch {
+    read_only: Arc<AtomicBool>,
+    pinned: bool,
+}
+
+impl ReadOnlySwitch {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Switch staying in the read-only mode.
+    pub fn pinned() -> Self {
+        Self {
+            read_only: Arc::new(AtomicBool::new(true)),
+            pinned: true,
+        }
+    }
+
+    pub fn is_read_only(&self) -> bool {
+        self.read_only.load(Ordering::Acquire)
+    }
+
+    pub fn set_read_only(&self, read_only: bool) {
+        if self.pinned && !read_only {
+            log_warn!("Cannot leave the read-only mode during a dry run.");
+            return;
+        }
+        if self.read_only.swap(read_only, Ordering::AcqRel) != read_only {
+            if read_only {
+                log_info!("Entered read-only maintenance mode.");
+            } else {
+                log_info!("Left read-only maintenance mode.");
+            }
+        }
+    }
+}
+
+/// Skips saving the state while in read-only mode.
+pub struct ReadOnlyPersistState<P: PersistState> {
+    persist_state: P,
+    switch: ReadOnlySwitch,
+}
+
+impl<P: PersistState> ReadOnlyPersistState<P> {
+    pub fn new(persist_state: P, switch: ReadOnlySwitch) -> Self {
+        Self {
+            persist_state,
+            switch,
+        }
+    }
+}
+
+impl<P: PersistState> PersistState for ReadOnlyPersistState<P> {
+    type Error = P::Error;
+
+    fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persist_state.load_state()
+    }
+
+    fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
+        if self.switch.is_read_only() {
+            return Ok(());
+        }
+        self.persist_state.save_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockPersistState;
+
+    #[test]
+    fn test_read_only_persist_state() {
+        let mock = MockPersistState::default();
+        let switch = ReadOnlySwitch::new();
+        let mut persist_state = ReadOnlyPersistState::new(mock.clone(), switch.clone());
+
+        switch.set_read_only(true);
+        persist_state.save_state(&[1]).unwrap();
+        assert_eq!(*mock.state.read().unwrap(), None);
+
+        switch.set_read_only(false);
+        persist_state.save_state(&[2]).unwrap();
+        assert_eq!(persist_state.load_state().unwrap(), Some(vec![2]));
+    }
+
+    #[test]
+    fn test_pinned_read_only_switch() {
+        let switch = ReadOnlySwitch::pinned();
+        assert!(switch.is_read_only());
+        switch.set_read_only(false);
+        assert!(switch.is_read_only());
+    }
+}
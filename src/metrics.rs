@@ -1,6 +1,29 @@
-use std::{collections::HashMap, convert::TryFrom};
+use std::{
+    collections::{HashMap, HashSet},
+    convert::TryFrom,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
 
-use prometheus::{proto::MetricFamily, Gauge, Opts, Registry};
+use prometheus::{
+    core::{Collector, Desc},
+    proto::{LabelPair, MetricFamily},
+    Counter, Gauge, GaugeVec, IntCounter, IntCounterVec, IntGauge, IntGaugeVec, Opts, Registry,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::bsec_config::VersionMismatch;
+use crate::config::output_kind_name;
+use crate::counters::{CounterValue, CountersSnapshot};
+use crate::drift::DriftReport;
+use crate::events::AccuracyTransition;
+use crate::heater::HeaterMetrics;
+use crate::monitor::CycleTiming;
+use crate::snapshot::{OutputValue, ValuesSnapshot};
 
 struct GaugeUnit<'a> {
     ident_suffix: &'a str,
@@ -23,10 +46,24 @@ impl<'a> GaugeUnit<'a> {
     }
 }
 
+/// Value of the BSEC output gauges before the first output.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GaugeInit {
+    /// The gauges are not exported until the first output.
+    Absent,
+    /// The gauges are exported as NaN.
+    Nan,
+    #[default]
+    Zero,
+}
+
 #[derive(Clone)]
 struct BsecGauge {
     value: Gauge,
     accuracy: Gauge,
+    /// Whether the gauges are registered, `false` while absent.
+    registered: Arc<AtomicBool>,
 }
 
 impl BsecGauge {
@@ -46,18 +83,141 @@ impl BsecGauge {
                 format!("{}_accuracy", name),
                 format!("{} (accuracy)", help),
             ))?,
+            registered: Arc::new(AtomicBool::new(false)),
         })
     }
 
     fn register(&self, registry: &Registry) -> prometheus::Result<()> {
         registry.register(Box::new(self.value.clone()))?;
         registry.register(Box::new(self.accuracy.clone()))?;
+        self.registered.store(true, Ordering::Release);
         Ok(())
     }
 
-    fn set(&self, value: f64, accuracy: bsec::Accuracy) {
+    fn unregister(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.unregister(Box::new(self.value.clone()))?;
+        registry.unregister(Box::new(self.accuracy.clone()))?;
+        self.registered.store(false, Ordering::Release);
+        Ok(())
+    }
+
+    /// Sets the gauges, registering them if they are still absent.
+    fn set(&self, registry: &Registry, value: f64, accuracy: f64) {
         self.value.set(value);
-        self.accuracy.set((accuracy as u8).into());
+        self.accuracy.set(accuracy);
+        if !self.registered.swap(true, Ordering::AcqRel) {
+            let _ = registry.register(Box::new(self.value.clone()));
+            let _ = registry.register(Box::new(self.accuracy.clone()));
+        }
+    }
+}
+
+/// Name, description, and unit of the gauge for a BSEC output.
+struct GaugeSpec {
+    name: &'static str,
+    help: &'static str,
+    unit: Option<GaugeUnit<'static>>,
+}
+
+impl GaugeSpec {
+    fn new(name: &'static str, help: &'static str, unit: Option<GaugeUnit<'static>>) -> Self {
+        Self { name, help, unit }
+    }
+
+    fn metric_name(&self) -> String {
+        match &self.unit {
+            Some(unit) => format!("{}_{}", self.name, unit.ident_suffix),
+            None => self.name.into(),
+        }
+    }
+}
+
+fn gauge_spec(sensor: &bsec::OutputKind) -> GaugeSpec {
+    use bsec::OutputKind::*;
+    match sensor {
+        Iaq => GaugeSpec::new("iaq", "Indoor-air-quality estimate [0-500]", None),
+        StaticIaq => GaugeSpec::new("static_iaq", "Unscaled indoor-air-quality estimate", None),
+        Co2Equivalent => GaugeSpec::new(
+            "co2_equivalent",
+            "CO2 equivalent estimate",
+            Some(GaugeUnit::new("ppm")),
+        ),
+        BreathVocEquivalent => GaugeSpec::new(
+            "breath_voc_equivalent",
+            "Breath VOC concentration estimate",
+            Some(GaugeUnit::new("ppm")),
+        ),
+        RawTemperature => GaugeSpec::new(
+            "raw_temperature",
+            "Temperature sensor signal",
+            Some(GaugeUnit::new_with_display("celsius", "°C")),
+        ),
+        RawPressure => GaugeSpec::new(
+            "raw_pressure",
+            "Pressure sensor signal",
+            Some(GaugeUnit::new("Pa")),
+        ),
+        RawHumidity => GaugeSpec::new(
+            "raw_humidity",
+            "Relative humidity sensor signal",
+            Some(GaugeUnit::new_with_display("percent", "%")),
+        ),
+        RawGas => GaugeSpec::new(
+            "raw_gas",
+            "Gas sensor signal",
+            Some(GaugeUnit::new_with_display("ohm", "Ω")),
+        ),
+        StabilizationStatus => GaugeSpec::new(
+            "stabilization_status",
+            "Gas sensor stabilization status (boolean)",
+            None,
+        ),
+        RunInStatus => GaugeSpec::new("run_in_status", "Gas sensor run-in status (boolean)", None),
+        SensorHeatCompensatedTemperature => GaugeSpec::new(
+            "temperature",
+            "Sensor heat compensated temperature",
+            Some(GaugeUnit::new_with_display("celsius", "°C")),
+        ),
+        SensorHeatCompensatedHumidity => GaugeSpec::new(
+            "humidity",
+            "Sensor heat compensated humidity",
+            Some(GaugeUnit::new_with_display("percent", "%")),
+        ),
+        GasPercentage => GaugeSpec::new(
+            "gas",
+            "Percentage of min and max filtered gas value",
+            Some(GaugeUnit::new_with_display("percent", "%")),
+        ),
+    }
+}
+
+/// Meaning of the values of the accuracy gauges.
+pub const ACCURACY_SEMANTICS: &str =
+    "0: unreliable (stabilizing), 1: low accuracy, 2: medium accuracy, 3: high accuracy";
+
+/// Machine-readable description of the gauges exported for a BSEC output.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MetricSchema {
+    pub name: String,
+    pub accuracy_name: String,
+    pub description: &'static str,
+    pub unit: Option<&'static str>,
+    /// Name of the BSEC output as used in the configuration.
+    pub output_kind: &'static str,
+    pub accuracy: &'static str,
+}
+
+impl From<bsec::OutputKind> for MetricSchema {
+    fn from(sensor: bsec::OutputKind) -> Self {
+        let spec = gauge_spec(&sensor);
+        Self {
+            name: spec.metric_name(),
+            accuracy_name: format!("{}_accuracy", spec.name),
+            description: spec.help,
+            unit: spec.unit.map(|unit| unit.display),
+            output_kind: output_kind_name(sensor),
+            accuracy: ACCURACY_SEMANTICS,
+        }
     }
 }
 
@@ -65,63 +225,141 @@ impl TryFrom<&bsec::OutputKind> for BsecGauge {
     type Error = prometheus::Error;
 
     fn try_from(sensor: &bsec::OutputKind) -> Result<Self, Self::Error> {
-        use bsec::OutputKind::*;
-        match sensor {
-            Iaq => BsecGauge::new("iaq", "Indoor-air-quality estimate [0-500]", None),
-            StaticIaq => BsecGauge::new("static_iaq", "Unscaled indoor-air-quality estimate", None),
-            Co2Equivalent => BsecGauge::new(
-                "co2_equivalent",
-                "CO2 equivalent estimate",
-                Some(&GaugeUnit::new("ppm")),
-            ),
-            BreathVocEquivalent => BsecGauge::new(
-                "breath_voc_equivalent",
-                "Breath VOC concentration estimate",
-                Some(&GaugeUnit::new("ppm")),
-            ),
-            RawTemperature => BsecGauge::new(
-                "raw_temperature",
-                "Temperature sensor signal",
-                Some(&GaugeUnit::new_with_display("celsius", "°C")),
-            ),
-            RawPressure => BsecGauge::new(
-                "raw_pressure",
-                "Pressure sensor signal",
-                Some(&GaugeUnit::new("Pa")),
-            ),
-            RawHumidity => BsecGauge::new(
-                "raw_humidity",
-                "Relative humidity sensor signal",
-                Some(&GaugeUnit::new_with_display("percent", "%")),
-            ),
-            RawGas => BsecGauge::new(
-                "raw_gas",
-                "Gas sensor signal",
-                Some(&GaugeUnit::new_with_display("ohm", "Ω")),
-            ),
-            StabilizationStatus => BsecGauge::new(
-                "stabilization_status",
-                "Gas sensor stabilization status (boolean)",
-                None,
-            ),
-            RunInStatus => {
-                BsecGauge::new("run_in_status", "Gas sensor run-in status (boolean)", None)
-            }
-            SensorHeatCompensatedTemperature => BsecGauge::new(
-                "temperature",
-                "Sensor heat compensated temperature",
-                Some(&GaugeUnit::new_with_display("celsius", "°C")),
-            ),
-            SensorHeatCompensatedHumidity => BsecGauge::new(
-                "humidity",
-                "Sensor heat compensated humidity",
-                Some(&GaugeUnit::new_with_display("percent", "%")),
-            ),
-            GasPercentage => BsecGauge::new(
-                "gas",
-                "Percentage of min and max filtered gas value",
-                Some(&GaugeUnit::new_with_display("percent", "%")),
-            ),
+        let spec = gauge_spec(sensor);
+        BsecGauge::new(spec.name, spec.help, spec.unit.as_ref())
+    }
+}
+
+/// Cumulative counts of the monitoring last synced to the counters.
+#[derive(Debug, Default)]
+struct CycleCounts {
+    missed_windows: u64,
+    warnings: u64,
+    throttled_outputs: u64,
+}
+
+/// Increments the counter by the increase of the cumulative `count` since the
+/// `last` one. A decrease means that the monitoring restarted counting from 0.
+fn inc_by_increase(counter: &IntCounter, last: &mut u64, count: u64) {
+    counter.inc_by(count.checked_sub(*last).unwrap_or(count));
+    *last = count;
+}
+
+#[derive(Clone)]
+struct TimingMetrics {
+    latency: Gauge,
+    missed_windows: IntCounter,
+    next_measurement: Gauge,
+    warnings: IntCounter,
+    throttled_outputs: IntCounter,
+    clock_drift: Gauge,
+    last_counts: Arc<Mutex<CycleCounts>>,
+}
+
+impl TimingMetrics {
+    fn new() -> prometheus::Result<Self> {
+        Ok(Self {
+            latency: Gauge::with_opts(Opts::new(
+                "bsec_output_latency_seconds",
+                "Delay of the BSEC output processing completion relative to the scheduled measurement",
+            ))?,
+            missed_windows: IntCounter::with_opts(Opts::new(
+                "bsec_missed_measurement_windows_total",
+                "Number of scheduled BSEC measurement windows that have been missed",
+            ))?,
+            next_measurement: Gauge::with_opts(Opts::new(
+                "bsec_next_measurement_timestamp_seconds",
+                "Unix time of the next measurement scheduled by BSEC",
+            ))?,
+            warnings: IntCounter::with_opts(Opts::new(
+                "bsec_warnings_total",
+                "Number of BSEC measurement cycles that returned a warning instead of outputs",
+            ))?,
+            throttled_outputs: IntCounter::with_opts(Opts::new(
+                "bsec_throttled_outputs_total",
+                "Number of BSEC outputs dropped in favor of a newer output due to the minimum publish interval",
+            ))?,
+            clock_drift: Gauge::with_opts(Opts::new(
+                "bsec_clock_drift_ppm",
+                "Drift of the measurement clock relative to the raw hardware clock, positive if it runs slow (ppm)",
+            ))?,
+            last_counts: Arc::new(Mutex::new(CycleCounts::default())),
+        })
+    }
+
+    fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.latency.clone()))?;
+        registry.register(Box::new(self.missed_windows.clone()))?;
+        registry.register(Box::new(self.next_measurement.clone()))?;
+        registry.register(Box::new(self.warnings.clone()))?;
+        registry.register(Box::new(self.throttled_outputs.clone()))?;
+        registry.register(Box::new(self.clock_drift.clone()))?;
+        Ok(())
+    }
+
+    fn set(&self, timing: &CycleTiming) {
+        self.latency.set(timing.latency_ns.as_secs_f64());
+        let mut last = self.last_counts.lock().unwrap();
+        inc_by_increase(
+            &self.missed_windows,
+            &mut last.missed_windows,
+            timing.missed_windows,
+        );
+        inc_by_increase(&self.warnings, &mut last.warnings, timing.warnings);
+        inc_by_increase(
+            &self.throttled_outputs,
+            &mut last.throttled_outputs,
+            timing.throttled_outputs,
+        );
+        drop(last);
+        if let Some(clock_drift_ppm) = timing.clock_drift_ppm {
+            self.clock_drift.set(clock_drift_ppm);
+        }
+        if let Some(next_measurement) = timing.next_measurement {
+            self.next_measurement.set(
+                next_measurement
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap_or_default()
+                    .as_secs_f64(),
+            );
+        }
+    }
+
+    fn next_measurement(&self) -> Option<f64> {
+        Some(self.next_measurement.get()).filter(|&timestamp| timestamp > 0.)
+    }
+}
+
+#[derive(Clone)]
+struct GasDriftMetrics {
+    baseline: Gauge,
+    drift: Gauge,
+}
+
+impl GasDriftMetrics {
+    fn new() -> prometheus::Result<Self> {
+        Ok(Self {
+            baseline: Gauge::with_opts(Opts::new(
+                "bsec_gas_baseline_ohm",
+                "Daily maximum of the gas sensor signal (Ω)",
+            ))?,
+            drift: Gauge::with_opts(Opts::new(
+                "bsec_gas_baseline_drift_percent_per_day",
+                "Trend of the daily gas sensor baseline (%/day)",
+            ))?,
+        })
+    }
+
+    fn register(&self, registry: &Registry) -> prometheus::Result<()> {
+        registry.register(Box::new(self.baseline.clone()))?;
+        registry.register(Box::new(self.drift.clone()))?;
+        Ok(())
+    }
+
+    fn set(&self, report: &DriftReport) {
+        self.baseline.set(report.baseline_ohm);
+        if let Some(drift) = report.drift_percent_per_day {
+            self.drift.set(drift);
         }
     }
 }
@@ -130,14 +368,131 @@ impl TryFrom<&bsec::OutputKind> for BsecGauge {
 pub struct BsecGaugeRegistry {
     registry: Registry,
     sensor_gauge_map: HashMap<bsec::OutputKind, BsecGauge>,
+    timing: TimingMetrics,
+    gas_drift: GasDriftMetrics,
+    watchdog_stalls: IntCounter,
+    restarts: IntCounter,
+    active_sensor: IntGaugeVec,
+    peer_divergence: GaugeVec,
+    room_average: GaugeVec,
+    room_sensors: IntGaugeVec,
+    accuracy_transitions: IntCounterVec,
+    heater_on_time_total: Counter,
+    measurements_total: IntCounter,
+    alert_firing: IntGaugeVec,
+    values: Arc<Mutex<ValuesSnapshot>>,
+    restored: IntGauge,
+    updated: Arc<Mutex<Option<SystemTime>>>,
 }
 
 impl BsecGaugeRegistry {
     pub fn new(sensors: &[bsec::OutputKind]) -> prometheus::Result<Self> {
+        Self::with_labels(sensors, HashMap::new())
+    }
+
+    /// Creates a registry attaching the given constant labels to all metrics.
+    pub fn with_labels(
+        sensors: &[bsec::OutputKind],
+        labels: HashMap<String, String>,
+    ) -> prometheus::Result<Self> {
         let mut gauge_registry = Self {
-            registry: Registry::new(),
+            registry: Registry::new_custom(None, Some(labels).filter(|labels| !labels.is_empty()))?,
             sensor_gauge_map: HashMap::with_capacity(sensors.len()),
+            timing: TimingMetrics::new()?,
+            gas_drift: GasDriftMetrics::new()?,
+            watchdog_stalls: IntCounter::with_opts(Opts::new(
+                "bsec_watchdog_stalls_total",
+                "Number of times the watchdog detected a stalled BSEC monitoring loop",
+            ))?,
+            restarts: IntCounter::with_opts(Opts::new(
+                "bsec_monitoring_restarts_total",
+                "Number of in-process restarts of the BSEC monitoring",
+            ))?,
+            active_sensor: IntGaugeVec::new(
+                Opts::new(
+                    "bsec_active_sensor",
+                    "Whether the sensor is the one currently used for the BSEC monitoring (boolean)",
+                ),
+                &["sensor"],
+            )?,
+            peer_divergence: GaugeVec::new(
+                Opts::new(
+                    "bsec_peer_divergence",
+                    "Absolute difference of a metric to the value of a peer exporter",
+                ),
+                &["peer", "metric"],
+            )?,
+            room_average: GaugeVec::new(
+                Opts::new(
+                    "bsec_room_average",
+                    "Average of a metric across the sensors of a room",
+                ),
+                &["room", "metric"],
+            )?,
+            room_sensors: IntGaugeVec::new(
+                Opts::new(
+                    "bsec_room_sensors",
+                    "Number of sensors of a room contributing to the average of a metric",
+                ),
+                &["room", "metric"],
+            )?,
+            accuracy_transitions: IntCounterVec::new(
+                Opts::new(
+                    "bsec_accuracy_transitions_total",
+                    "Number of changes of the accuracy of a BSEC output",
+                ),
+                &["output", "from", "to"],
+            )?,
+            heater_on_time_total: Counter::with_opts(Opts::new(
+                "bsec_heater_on_time_seconds_total",
+                "Cumulative gas sensor heater on-time",
+            ))?,
+            measurements_total: IntCounter::with_opts(Opts::new(
+                "bsec_measurements_total",
+                "Number of measurements started",
+            ))?,
+            alert_firing: IntGaugeVec::new(
+                Opts::new(
+                    "bsec_alert_firing",
+                    "Whether the built-in alerting rule is firing",
+                ),
+                &["alert"],
+            )?,
+            values: Arc::new(Mutex::new(HashMap::with_capacity(sensors.len()))),
+            restored: IntGauge::with_opts(Opts::new(
+                "bsec_values_restored",
+                "Whether the BSEC output values are restored from before the last restart and thus stale (boolean)",
+            ))?,
+            updated: Arc::new(Mutex::new(None)),
         };
+        gauge_registry.timing.register(&gauge_registry.registry)?;
+        gauge_registry
+            .gas_drift
+            .register(&gauge_registry.registry)?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.watchdog_stalls.clone()))?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.restarts.clone()))?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.active_sensor.clone()))?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.peer_divergence.clone()))?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.room_average.clone()))?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.room_sensors.clone()))?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.accuracy_transitions.clone()))?;
+        gauge_registry
+            .registry
+            .register(Box::new(gauge_registry.alert_firing.clone()))?;
 
         for sensor in sensors {
             let gauge = BsecGauge::try_from(sensor)?;
@@ -148,22 +503,527 @@ impl BsecGaugeRegistry {
         Ok(gauge_registry)
     }
 
+    /// Applies the `init` behavior to the BSEC output gauges not set yet.
+    pub fn with_gauge_init(self, init: GaugeInit) -> prometheus::Result<Self> {
+        let values = self.values.lock().unwrap();
+        for (sensor, gauge) in self.sensor_gauge_map.iter() {
+            if values.contains_key(output_kind_name(*sensor)) {
+                continue;
+            }
+            match init {
+                GaugeInit::Absent => gauge.unregister(&self.registry)?,
+                GaugeInit::Nan => {
+                    gauge.value.set(f64::NAN);
+                    gauge.accuracy.set(f64::NAN);
+                }
+                GaugeInit::Zero => (),
+            }
+        }
+        drop(values);
+        Ok(self)
+    }
+
     pub fn set(&self, output: &bsec::Output) {
         if let Some(gauge) = self.sensor_gauge_map.get(&output.sensor) {
-            gauge.set(output.signal, output.accuracy)
+            gauge.set(
+                &self.registry,
+                output.signal,
+                (output.accuracy as u8).into(),
+            );
+            self.values.lock().unwrap().insert(
+                output_kind_name(output.sensor).into(),
+                OutputValue {
+                    signal: output.signal,
+                    accuracy: (output.accuracy as u8).into(),
+                },
+            );
+            self.restored.set(0);
+            *self.updated.lock().unwrap() = Some(SystemTime::now());
+        }
+    }
+
+    /// Sets the gauges of the `sensors` to NaN and forgets their last values,
+    /// e.g. for outputs unsubscribed at runtime that would otherwise keep
+    /// exporting their last value.
+    pub fn clear(&self, sensors: &[bsec::OutputKind]) {
+        let mut values = self.values.lock().unwrap();
+        for sensor in sensors {
+            if let Some(gauge) = self.sensor_gauge_map.get(sensor) {
+                gauge.value.set(f64::NAN);
+                gauge.accuracy.set(f64::NAN);
+                values.remove(output_kind_name(*sensor));
+            }
+        }
+    }
+
+    /// Wall-clock time of the last BSEC output, `None` before the first one.
+    pub fn updated(&self) -> Option<SystemTime> {
+        *self.updated.lock().unwrap()
+    }
+
+    /// Last values of the BSEC outputs, including restored values.
+    pub fn snapshot(&self) -> ValuesSnapshot {
+        self.values.lock().unwrap().clone()
+    }
+
+    /// Restores the values of a previous [`snapshot`](Self::snapshot) and
+    /// marks them as stale until the first output is set.
+    pub fn restore(&self, snapshot: &ValuesSnapshot) -> prometheus::Result<()> {
+        self.registry.register(Box::new(self.restored.clone()))?;
+        let mut values = self.values.lock().unwrap();
+        for (sensor, gauge) in self.sensor_gauge_map.iter() {
+            let name = output_kind_name(*sensor);
+            if let Some(value) = snapshot.get(name) {
+                gauge.set(&self.registry, value.signal, value.accuracy);
+                values.insert(name.into(), *value);
+            }
+        }
+        self.restored.set(!values.is_empty() as i64);
+        Ok(())
+    }
+
+    /// Current values of the cumulative counters.
+    pub fn counters_snapshot(&self) -> CountersSnapshot {
+        let counters: [&dyn Collector; 8] = [
+            &self.timing.missed_windows,
+            &self.timing.warnings,
+            &self.timing.throttled_outputs,
+            &self.watchdog_stalls,
+            &self.restarts,
+            &self.accuracy_transitions,
+            &self.heater_on_time_total,
+            &self.measurements_total,
+        ];
+        counters
+            .iter()
+            .flat_map(|counter| counter.collect())
+            .flat_map(|family| {
+                family
+                    .get_metric()
+                    .iter()
+                    .map(|metric| CounterValue {
+                        name: family.get_name().into(),
+                        labels: metric
+                            .get_label()
+                            .iter()
+                            .map(|label| (label.get_name().into(), label.get_value().into()))
+                            .collect(),
+                        value: metric.get_counter().get_value(),
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect()
+    }
+
+    /// Continues the counters from the values of a previous
+    /// [`counters_snapshot`](Self::counters_snapshot). Unknown counters are
+    /// ignored.
+    pub fn restore_counters(&self, snapshot: &CountersSnapshot) {
+        for counter in snapshot {
+            let value = counter.value.max(0.);
+            match counter.name.as_str() {
+                "bsec_missed_measurement_windows_total" => {
+                    self.timing.missed_windows.inc_by(value as u64)
+                }
+                "bsec_warnings_total" => self.timing.warnings.inc_by(value as u64),
+                "bsec_throttled_outputs_total" => {
+                    self.timing.throttled_outputs.inc_by(value as u64)
+                }
+                "bsec_watchdog_stalls_total" => self.watchdog_stalls.inc_by(value as u64),
+                "bsec_monitoring_restarts_total" => self.restarts.inc_by(value as u64),
+                "bsec_accuracy_transitions_total" => {
+                    let labels: HashMap<&str, &str> = counter
+                        .labels
+                        .iter()
+                        .map(|(name, value)| (name.as_str(), value.as_str()))
+                        .collect();
+                    if let Ok(transitions) = self.accuracy_transitions.get_metric_with(&labels) {
+                        transitions.inc_by(value as u64);
+                    }
+                }
+                "bsec_heater_on_time_seconds_total" => self.heater_on_time_total.inc_by(value),
+                "bsec_measurements_total" => self.measurements_total.inc_by(value as u64),
+                _ => (),
+            }
+        }
+    }
+
+    /// Schema of the exported BSEC output metrics sorted by name.
+    pub fn schema(&self) -> Vec<MetricSchema> {
+        let mut schema: Vec<MetricSchema> = self
+            .sensor_gauge_map
+            .keys()
+            .map(|sensor| MetricSchema::from(*sensor))
+            .collect();
+        schema.sort_by(|a, b| a.name.cmp(&b.name));
+        schema
+    }
+
+    pub fn set_timing(&self, timing: &CycleTiming) {
+        self.timing.set(timing);
+    }
+
+    /// Last output values and the next scheduled measurement.
+    pub fn current(&self) -> CurrentValues {
+        CurrentValues {
+            outputs: self.snapshot(),
+            next_measurement_timestamp_seconds: self.timing.next_measurement(),
+        }
+    }
+
+    pub fn set_gas_drift(&self, report: &DriftReport) {
+        self.gas_drift.set(report);
+    }
+
+    pub fn inc_watchdog_stalls(&self) {
+        self.watchdog_stalls.inc();
+    }
+
+    pub fn inc_restarts(&self) {
+        self.restarts.inc();
+    }
+
+    /// Marks the sensor with the given name as active and all others as
+    /// inactive.
+    pub fn set_active_sensor(&self, name: &str) {
+        for metric in self.active_sensor.collect() {
+            for sensor in metric.get_metric() {
+                let label = sensor.get_label()[0].get_value();
+                if label != name {
+                    self.active_sensor.with_label_values(&[label]).set(0);
+                }
+            }
         }
+        self.active_sensor.with_label_values(&[name]).set(1);
+    }
+
+    pub fn set_peer_divergence(&self, peer: &str, metric: &str, difference: f64) {
+        self.peer_divergence
+            .with_label_values(&[peer, metric])
+            .set(difference);
+    }
+
+    pub fn set_room_average(&self, room: &str, metric: &str, value: f64, sensors: usize) {
+        self.room_average
+            .with_label_values(&[room, metric])
+            .set(value);
+        self.room_sensors
+            .with_label_values(&[room, metric])
+            .set(sensors as i64);
+    }
+
+    pub fn set_alert_firing(&self, alert: &str, firing: bool) {
+        self.alert_firing
+            .with_label_values(&[alert])
+            .set(i64::from(firing));
+    }
+
+    pub fn inc_accuracy_transitions(&self, transition: &AccuracyTransition) {
+        self.accuracy_transitions
+            .with_label_values(&[
+                output_kind_name(transition.sensor),
+                &(transition.from as u8).to_string(),
+                &(transition.to as u8).to_string(),
+            ])
+            .inc();
+    }
+
+    /// Registers the gauge reporting the remaining burn-in time.
+    pub fn register_burn_in_remaining(&self) -> prometheus::Result<Gauge> {
+        let remaining = Gauge::with_opts(Opts::new(
+            "bsec_burn_in_remaining_seconds",
+            "Remaining time of the sensor burn-in",
+        ))?;
+        self.registry.register(Box::new(remaining.clone()))?;
+        Ok(remaining)
+    }
+
+    /// Registers the metrics reporting the usage of the gas sensor heater.
+    pub fn register_heater_metrics(&self) -> prometheus::Result<HeaterMetrics> {
+        let metrics = HeaterMetrics {
+            on_time_per_hour: Gauge::with_opts(Opts::new(
+                "bsec_heater_on_time_seconds_per_hour",
+                "Gas sensor heater on-time within the last hour",
+            ))?,
+            measurements_per_hour: Gauge::with_opts(Opts::new(
+                "bsec_measurements_per_hour",
+                "Number of measurements within the last hour",
+            ))?,
+            on_time_total: self.heater_on_time_total.clone(),
+            measurements_total: self.measurements_total.clone(),
+            duty_cycle: Gauge::with_opts(Opts::new(
+                "bsec_heater_duty_cycle_ratio",
+                "Fraction of the last hour the gas sensor heater was on",
+            ))?,
+            profile_duration: Gauge::with_opts(Opts::new(
+                "bsec_measurement_profile_duration_seconds",
+                "Duration of the last measurement with its heater and oversampling settings",
+            ))?,
+        };
+        self.registry
+            .register(Box::new(metrics.on_time_per_hour.clone()))?;
+        self.registry
+            .register(Box::new(metrics.measurements_per_hour.clone()))?;
+        self.registry
+            .register(Box::new(metrics.on_time_total.clone()))?;
+        self.registry
+            .register(Box::new(metrics.measurements_total.clone()))?;
+        self.registry
+            .register(Box::new(metrics.duty_cycle.clone()))?;
+        self.registry
+            .register(Box::new(metrics.profile_duration.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Registers the metrics reporting the age and size of the saved BSEC
+    /// state.
+    pub fn register_state_metrics(&self) -> prometheus::Result<StateMetrics> {
+        let metrics = StateMetrics {
+            last_save: Arc::new(Mutex::new(None)),
+            age: Gauge::with_opts(Opts::new(
+                "bsec_state_age_seconds",
+                "Time since the BSEC state was last saved",
+            ))?,
+            bytes: IntGauge::with_opts(Opts::new(
+                "bsec_state_bytes",
+                "Size of the last saved BSEC state",
+            ))?,
+        };
+        self.registry.register(Box::new(metrics.clone()))?;
+        Ok(metrics)
+    }
+
+    /// Registers the gauge reporting whether the system time is synchronized.
+    pub fn register_time_synchronized(&self) -> prometheus::Result<IntGauge> {
+        let synchronized = IntGauge::with_opts(Opts::new(
+            "bsec_exporter_time_synchronized",
+            "Whether the system time is synchronized (boolean)",
+        ))?;
+        self.registry.register(Box::new(synchronized.clone()))?;
+        Ok(synchronized)
+    }
+
+    /// Registers the metric reporting that the configuration was rolled back.
+    pub fn register_config_rolled_back(&self) -> prometheus::Result<()> {
+        let rolled_back = IntGauge::with_opts(Opts::new(
+            "bsec_exporter_config_rolled_back",
+            "Whether the exporter rolled back to the last-good configuration (boolean)",
+        ))?;
+        rolled_back.set(1);
+        self.registry.register(Box::new(rolled_back))
+    }
+
+    /// Registers the metric warning that the BSEC config was generated for
+    /// another BSEC version than the linked one.
+    pub fn register_config_version_mismatch(
+        &self,
+        mismatch: &VersionMismatch,
+    ) -> prometheus::Result<()> {
+        let warning = IntGauge::with_opts(
+            Opts::new(
+                "bsec_config_version_mismatch",
+                "Whether the BSEC config was generated for another BSEC version than the linked one (boolean)",
+            )
+            .const_label("config_version", mismatch.config.to_string())
+            .const_label("linked_version", mismatch.linked.to_string()),
+        )?;
+        warning.set(1);
+        self.registry.register(Box::new(warning))
+    }
+
+    /// Registers an info metric with the instance UUID as label.
+    pub fn register_instance_info(&self, uuid: &str) -> prometheus::Result<()> {
+        let info = Gauge::with_opts(
+            Opts::new(
+                "bsec_exporter_instance_info",
+                "Identity of the exporter instance",
+            )
+            .const_label("instance_uuid", uuid),
+        )?;
+        info.set(1.);
+        self.registry.register(Box::new(info))
     }
 
     pub fn gather(&self) -> Vec<MetricFamily> {
         self.registry.gather()
     }
+
+    /// Names of the labels of the registered metrics, including the labels
+    /// of metric vectors without any values yet.
+    pub fn label_names(&self) -> HashSet<String> {
+        let vectors: [&dyn Collector; 6] = [
+            &self.active_sensor,
+            &self.peer_divergence,
+            &self.room_average,
+            &self.room_sensors,
+            &self.accuracy_transitions,
+            &self.alert_firing,
+        ];
+        let variable_labels = vectors
+            .iter()
+            .flat_map(|vector| vector.desc())
+            .flat_map(|desc| desc.variable_labels.iter().cloned());
+        self.gather()
+            .iter()
+            .flat_map(|family| family.get_metric())
+            .flat_map(|metric| metric.get_label())
+            .map(|label| label.get_name().to_string())
+            .chain(variable_labels)
+            .collect()
+    }
+}
+
+/// Age and size of the saved BSEC state, the age being updated on each
+/// scrape.
+#[derive(Clone)]
+pub struct StateMetrics {
+    last_save: Arc<Mutex<Option<SystemTime>>>,
+    age: Gauge,
+    bytes: IntGauge,
+}
+
+impl StateMetrics {
+    pub fn record_save(&self, time: SystemTime, bytes: usize) {
+        *self.last_save.lock().unwrap() = Some(time);
+        self.bytes.set(bytes as i64);
+    }
+}
+
+impl Collector for StateMetrics {
+    fn desc(&self) -> Vec<&Desc> {
+        self.age
+            .desc()
+            .into_iter()
+            .chain(self.bytes.desc())
+            .collect()
+    }
+
+    fn collect(&self) -> Vec<MetricFamily> {
+        // Not saved yet, neither during this run nor a previous one.
+        let last_save = match *self.last_save.lock().unwrap() {
+            Some(last_save) => last_save,
+            None => return vec![],
+        };
+        let age = SystemTime::now()
+            .duration_since(last_save)
+            .unwrap_or_default();
+        self.age.set(age.as_secs_f64());
+        self.age
+            .collect()
+            .into_iter()
+            .chain(self.bytes.collect())
+            .collect()
+    }
+}
+
+/// Subset of the metrics with additional labels served by a listener.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct MetricsFilter {
+    metrics: Option<HashSet<String>>,
+    labels: Vec<(String, String)>,
+}
+
+impl MetricsFilter {
+    /// Filter passing all metrics without additional labels.
+    pub fn all() -> Self {
+        Self::default()
+    }
+
+    /// Creates a filter passing the given `metrics`, all if `None`, with the
+    /// additional `labels`.
+    ///
+    /// Fails if an additional label is among the `reserved` label names of
+    /// the metrics, as a metric with duplicate labels fails the scrape.
+    pub fn new(
+        metrics: Option<Vec<String>>,
+        labels: HashMap<String, String>,
+        reserved: &HashSet<String>,
+    ) -> prometheus::Result<Self> {
+        let mut labels: Vec<_> = labels.into_iter().collect();
+        labels.sort();
+        if let Some((name, _)) = labels.iter().find(|(name, _)| reserved.contains(name)) {
+            return Err(prometheus::Error::Msg(format!(
+                "listener label `{}` collides with a label of the metrics",
+                name
+            )));
+        }
+        Ok(Self {
+            metrics: metrics.map(|metrics| metrics.into_iter().collect()),
+            labels,
+        })
+    }
+
+    pub fn apply(&self, families: Vec<MetricFamily>) -> Vec<MetricFamily> {
+        families
+            .into_iter()
+            .filter(|family| match &self.metrics {
+                Some(metrics) => metrics.contains(family.get_name()),
+                None => true,
+            })
+            .map(|mut family| {
+                for metric in family.mut_metric().iter_mut() {
+                    for (name, value) in self.labels.iter() {
+                        let mut label = LabelPair::new();
+                        label.set_name(name.clone());
+                        label.set_value(value.clone());
+                        metric.mut_label().push(label);
+                    }
+                }
+                family
+            })
+            .collect()
+    }
+}
+
+/// Document of the `/api/v1/current` endpoint.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct CurrentValues {
+    /// Last values by BSEC output name as used in the configuration.
+    pub outputs: ValuesSnapshot,
+    /// Unix time of the next measurement scheduled by BSEC, e.g. to align
+    /// polling with the sample rate.
+    pub next_measurement_timestamp_seconds: Option<f64>,
+}
+
+/// Metrics of a [`BsecGaugeRegistry`] as served by a listener.
+#[derive(Clone)]
+pub struct MetricsView {
+    registry: BsecGaugeRegistry,
+    filter: Arc<MetricsFilter>,
+}
+
+impl MetricsView {
+    pub fn new(registry: BsecGaugeRegistry, filter: MetricsFilter) -> Self {
+        Self {
+            registry,
+            filter: Arc::new(filter),
+        }
+    }
+
+    pub fn gather(&self) -> Vec<MetricFamily> {
+        self.filter.apply(self.registry.gather())
+    }
+
+    pub fn schema(&self) -> Vec<MetricSchema> {
+        self.registry.schema()
+    }
+
+    pub fn updated(&self) -> Option<SystemTime> {
+        self.registry.updated()
+    }
+
+    pub fn current(&self) -> CurrentValues {
+        self.registry.current()
+    }
 }
 
 #[cfg(test)]
 pub mod tests {
-    use prometheus::proto::{Gauge, Metric, MetricType};
+    use prometheus::proto::{Counter, Gauge, Metric, MetricType};
 
     use super::*;
+    use crate::clock::Nanos;
+    use std::time::Duration;
 
     #[test]
     fn test_bsec_gauge_registry() {
@@ -190,6 +1050,56 @@ pub mod tests {
         assert_eq!(
             metrics,
             [
+                create_gauge_metric_family(
+                    "bsec_clock_drift_ppm".into(),
+                    0.,
+                    "Drift of the measurement clock relative to the raw hardware clock, positive if it runs slow (ppm)".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_gas_baseline_drift_percent_per_day".into(),
+                    0.,
+                    "Trend of the daily gas sensor baseline (%/day)".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_gas_baseline_ohm".into(),
+                    0.,
+                    "Daily maximum of the gas sensor signal (Ω)".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_missed_measurement_windows_total".into(),
+                    0.,
+                    "Number of scheduled BSEC measurement windows that have been missed".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_monitoring_restarts_total".into(),
+                    0.,
+                    "Number of in-process restarts of the BSEC monitoring".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_next_measurement_timestamp_seconds".into(),
+                    0.,
+                    "Unix time of the next measurement scheduled by BSEC".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_output_latency_seconds".into(),
+                    0.,
+                    "Delay of the BSEC output processing completion relative to the scheduled measurement".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_throttled_outputs_total".into(),
+                    0.,
+                    "Number of BSEC outputs dropped in favor of a newer output due to the minimum publish interval".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_warnings_total".into(),
+                    0.,
+                    "Number of BSEC measurement cycles that returned a warning instead of outputs".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_watchdog_stalls_total".into(),
+                    0.,
+                    "Number of times the watchdog detected a stalled BSEC monitoring loop".into(),
+                ),
                 create_gauge_metric_family(
                     "co2_equivalent_accuracy".into(),
                     (bsec::Accuracy::HighAccuracy as u8).into(),
@@ -204,6 +1114,504 @@ pub mod tests {
         );
     }
 
+    #[test]
+    fn test_bsec_gauge_registry_health_metrics() {
+        let registry = BsecGaugeRegistry::new(&[]).unwrap();
+        registry.set_timing(&CycleTiming {
+            latency_ns: Nanos(1_500_000_000),
+            missed_windows: 2,
+            next_measurement: None,
+            warnings: 0,
+            throttled_outputs: 0,
+            clock_drift_ppm: None,
+        });
+        assert_eq!(registry.current().next_measurement_timestamp_seconds, None);
+        registry.set_timing(&CycleTiming {
+            latency_ns: Nanos(500_000_000),
+            missed_windows: 3,
+            next_measurement: Some(UNIX_EPOCH + Duration::from_millis(1_700_000_000_500)),
+            warnings: 4,
+            throttled_outputs: 5,
+            clock_drift_ppm: Some(-2.5),
+        });
+        assert_eq!(
+            registry.current().next_measurement_timestamp_seconds,
+            Some(1_700_000_000.5)
+        );
+        registry.inc_watchdog_stalls();
+        registry.inc_restarts();
+        registry.set_gas_drift(&DriftReport {
+            baseline_ohm: 120_000.,
+            drift_percent_per_day: Some(-0.5),
+            days: 7,
+        });
+
+        let mut metrics = registry.gather();
+        metrics.sort_by(|a, b| a.get_name().cmp(b.get_name()));
+
+        assert_eq!(
+            metrics,
+            [
+                create_gauge_metric_family(
+                    "bsec_clock_drift_ppm".into(),
+                    -2.5,
+                    "Drift of the measurement clock relative to the raw hardware clock, positive if it runs slow (ppm)".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_gas_baseline_drift_percent_per_day".into(),
+                    -0.5,
+                    "Trend of the daily gas sensor baseline (%/day)".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_gas_baseline_ohm".into(),
+                    120000.,
+                    "Daily maximum of the gas sensor signal (Ω)".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_missed_measurement_windows_total".into(),
+                    3.,
+                    "Number of scheduled BSEC measurement windows that have been missed".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_monitoring_restarts_total".into(),
+                    1.,
+                    "Number of in-process restarts of the BSEC monitoring".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_next_measurement_timestamp_seconds".into(),
+                    1_700_000_000.5,
+                    "Unix time of the next measurement scheduled by BSEC".into(),
+                ),
+                create_gauge_metric_family(
+                    "bsec_output_latency_seconds".into(),
+                    0.5,
+                    "Delay of the BSEC output processing completion relative to the scheduled measurement".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_throttled_outputs_total".into(),
+                    5.,
+                    "Number of BSEC outputs dropped in favor of a newer output due to the minimum publish interval".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_warnings_total".into(),
+                    4.,
+                    "Number of BSEC measurement cycles that returned a warning instead of outputs".into(),
+                ),
+                create_counter_metric_family(
+                    "bsec_watchdog_stalls_total".into(),
+                    1.,
+                    "Number of times the watchdog detected a stalled BSEC monitoring loop".into(),
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_with_labels() {
+        let registry = BsecGaugeRegistry::with_labels(
+            &[bsec::OutputKind::Co2Equivalent],
+            vec![("hostname".to_string(), "sensor-pi".to_string())]
+                .into_iter()
+                .collect(),
+        )
+        .unwrap();
+
+        for family in registry.gather() {
+            for metric in family.get_metric() {
+                let labels = metric.get_label();
+                assert_eq!(labels.len(), 1, "{}", family.get_name());
+                assert_eq!(labels[0].get_name(), "hostname");
+                assert_eq!(labels[0].get_value(), "sensor-pi");
+            }
+        }
+    }
+
+    #[test]
+    fn test_schema() {
+        let registry = BsecGaugeRegistry::new(&[
+            bsec::OutputKind::SensorHeatCompensatedTemperature,
+            bsec::OutputKind::Iaq,
+        ])
+        .unwrap();
+        assert_eq!(
+            registry.schema(),
+            vec![
+                MetricSchema {
+                    name: "iaq".into(),
+                    accuracy_name: "iaq_accuracy".into(),
+                    description: "Indoor-air-quality estimate [0-500]",
+                    unit: None,
+                    output_kind: "iaq",
+                    accuracy: ACCURACY_SEMANTICS,
+                },
+                MetricSchema {
+                    name: "temperature_celsius".into(),
+                    accuracy_name: "temperature_accuracy".into(),
+                    description: "Sensor heat compensated temperature",
+                    unit: Some("°C"),
+                    output_kind: "sensor_heat_compensated_temperature",
+                    accuracy: ACCURACY_SEMANTICS,
+                },
+            ]
+        );
+
+        let names: HashSet<String> = registry
+            .gather()
+            .iter()
+            .map(|family| family.get_name().into())
+            .collect();
+        for schema in registry.schema() {
+            assert!(names.contains(&schema.name));
+            assert!(names.contains(&schema.accuracy_name));
+        }
+    }
+
+    #[test]
+    fn test_gauge_init() {
+        let names = |registry: &BsecGaugeRegistry| -> HashSet<String> {
+            registry
+                .gather()
+                .iter()
+                .map(|family| family.get_name().into())
+                .collect()
+        };
+        let output = bsec::Output {
+            timestamp_ns: 0,
+            signal: 42.,
+            sensor: bsec::OutputKind::Iaq,
+            accuracy: bsec::Accuracy::HighAccuracy,
+        };
+
+        let registry = BsecGaugeRegistry::new(&[bsec::OutputKind::Iaq])
+            .unwrap()
+            .with_gauge_init(GaugeInit::Zero)
+            .unwrap();
+        assert!(names(&registry).contains("iaq"));
+
+        let registry = BsecGaugeRegistry::new(&[bsec::OutputKind::Iaq])
+            .unwrap()
+            .with_gauge_init(GaugeInit::Absent)
+            .unwrap();
+        assert!(!names(&registry).contains("iaq"));
+        assert!(!names(&registry).contains("iaq_accuracy"));
+        registry.set(&output);
+        registry.set(&output);
+        assert!(names(&registry).contains("iaq"));
+        assert!(names(&registry).contains("iaq_accuracy"));
+
+        let registry = BsecGaugeRegistry::new(&[bsec::OutputKind::Iaq])
+            .unwrap()
+            .with_gauge_init(GaugeInit::Nan)
+            .unwrap();
+        let iaq = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "iaq")
+            .unwrap();
+        assert!(iaq.get_metric()[0].get_gauge().get_value().is_nan());
+        registry.set(&output);
+        let iaq = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "iaq")
+            .unwrap();
+        assert_eq!(iaq.get_metric()[0].get_gauge().get_value(), 42.);
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_clear() {
+        let registry =
+            BsecGaugeRegistry::new(&[bsec::OutputKind::Iaq, bsec::OutputKind::RawTemperature])
+                .unwrap();
+        for sensor in [bsec::OutputKind::Iaq, bsec::OutputKind::RawTemperature] {
+            registry.set(&bsec::Output {
+                timestamp_ns: 0,
+                signal: 42.,
+                sensor,
+                accuracy: bsec::Accuracy::HighAccuracy,
+            });
+        }
+
+        registry.clear(&[bsec::OutputKind::Iaq]);
+
+        let value = |name: &str| {
+            registry
+                .gather()
+                .into_iter()
+                .find(|family| family.get_name() == name)
+                .unwrap()
+                .get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+        assert!(value("iaq").is_nan());
+        assert!(value("iaq_accuracy").is_nan());
+        assert_eq!(value("raw_temperature"), 42.);
+        assert_eq!(
+            registry.snapshot().keys().collect::<Vec<_>>(),
+            vec!["raw_temperature"]
+        );
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_accuracy_transitions() {
+        let registry = BsecGaugeRegistry::new(&[]).unwrap();
+        let transition = AccuracyTransition {
+            sensor: bsec::OutputKind::Iaq,
+            from: bsec::Accuracy::Unreliable,
+            to: bsec::Accuracy::HighAccuracy,
+        };
+        registry.inc_accuracy_transitions(&transition);
+        registry.inc_accuracy_transitions(&transition);
+
+        let family = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "bsec_accuracy_transitions_total")
+            .unwrap();
+        let metric = &family.get_metric()[0];
+        let labels: Vec<_> = metric
+            .get_label()
+            .iter()
+            .map(|label| (label.get_name(), label.get_value()))
+            .collect();
+        assert_eq!(labels, vec![("from", "0"), ("output", "iaq"), ("to", "3")]);
+        assert_eq!(metric.get_counter().get_value(), 2.);
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_active_sensor() {
+        let registry = BsecGaugeRegistry::new(&[]).unwrap();
+        registry.set_active_sensor("primary");
+        registry.set_active_sensor("backup");
+
+        let family = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "bsec_active_sensor")
+            .unwrap();
+        let active: HashMap<_, _> = family
+            .get_metric()
+            .iter()
+            .map(|metric| {
+                (
+                    metric.get_label()[0].get_value().to_string(),
+                    metric.get_gauge().get_value(),
+                )
+            })
+            .collect();
+        assert_eq!(active["primary"], 0.);
+        assert_eq!(active["backup"], 1.);
+    }
+
+    #[test]
+    fn test_metrics_view() {
+        let registry = BsecGaugeRegistry::new(&[
+            bsec::OutputKind::Co2Equivalent,
+            bsec::OutputKind::SensorHeatCompensatedTemperature,
+        ])
+        .unwrap();
+        let view = MetricsView::new(
+            registry.clone(),
+            MetricsFilter::new(
+                Some(vec!["temperature_celsius".into()]),
+                vec![("network".to_string(), "external".to_string())]
+                    .into_iter()
+                    .collect(),
+                &registry.label_names(),
+            )
+            .unwrap(),
+        );
+
+        let families = view.gather();
+        assert_eq!(families.len(), 1);
+        assert_eq!(families[0].get_name(), "temperature_celsius");
+        let labels = families[0].get_metric()[0].get_label();
+        assert_eq!(labels.len(), 1);
+        assert_eq!(labels[0].get_name(), "network");
+        assert_eq!(labels[0].get_value(), "external");
+
+        assert_eq!(
+            MetricsView::new(registry.clone(), MetricsFilter::all()).gather(),
+            registry.gather()
+        );
+    }
+
+    #[test]
+    fn test_metrics_filter_rejects_colliding_labels() {
+        let registry = BsecGaugeRegistry::with_labels(
+            &[bsec::OutputKind::Iaq],
+            vec![("host".to_string(), "pi".to_string())]
+                .into_iter()
+                .collect(),
+        )
+        .unwrap();
+        let reserved = registry.label_names();
+        assert!(reserved.contains("host"));
+        assert!(reserved.contains("sensor"));
+        for name in ["host", "sensor", "metric"] {
+            assert!(MetricsFilter::new(
+                None,
+                vec![(name.to_string(), "external".to_string())]
+                    .into_iter()
+                    .collect(),
+                &reserved,
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_snapshot_restore() {
+        let sensors = [bsec::OutputKind::Iaq, bsec::OutputKind::RawGas];
+        let registry = BsecGaugeRegistry::new(&sensors).unwrap();
+        registry.set(&bsec::Output {
+            timestamp_ns: 0,
+            signal: 42.,
+            sensor: bsec::OutputKind::Iaq,
+            accuracy: bsec::Accuracy::HighAccuracy,
+        });
+        let snapshot = registry.snapshot();
+        assert_eq!(
+            snapshot,
+            vec![(
+                "iaq".to_string(),
+                OutputValue {
+                    signal: 42.,
+                    accuracy: 3.,
+                }
+            )]
+            .into_iter()
+            .collect()
+        );
+
+        let restored = BsecGaugeRegistry::new(&sensors).unwrap();
+        restored.restore(&snapshot).unwrap();
+        let value = |name: &str| {
+            restored
+                .gather()
+                .into_iter()
+                .find(|family| family.get_name() == name)
+                .unwrap()
+                .get_metric()[0]
+                .get_gauge()
+                .get_value()
+        };
+        assert_eq!(value("iaq"), 42.);
+        assert_eq!(value("iaq_accuracy"), 3.);
+        assert_eq!(value("bsec_values_restored"), 1.);
+        assert_eq!(restored.snapshot(), snapshot);
+
+        restored.set(&bsec::Output {
+            timestamp_ns: 0,
+            signal: 1000.,
+            sensor: bsec::OutputKind::RawGas,
+            accuracy: bsec::Accuracy::Unreliable,
+        });
+        assert_eq!(value("bsec_values_restored"), 0.);
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_counters_restore() {
+        let registry = BsecGaugeRegistry::new(&[]).unwrap();
+        registry.set_timing(&CycleTiming {
+            latency_ns: Nanos(0),
+            missed_windows: 2,
+            next_measurement: None,
+            warnings: 1,
+            throttled_outputs: 0,
+            clock_drift_ppm: None,
+        });
+        registry.inc_restarts();
+        registry.inc_accuracy_transitions(&AccuracyTransition {
+            sensor: bsec::OutputKind::Iaq,
+            from: bsec::Accuracy::Unreliable,
+            to: bsec::Accuracy::LowAccuracy,
+        });
+        let snapshot = registry.counters_snapshot();
+
+        let restored = BsecGaugeRegistry::new(&[]).unwrap();
+        restored.restore_counters(&snapshot);
+        assert_eq!(restored.counters_snapshot(), snapshot);
+
+        // The restarted monitoring counts from 0 again.
+        restored.set_timing(&CycleTiming {
+            latency_ns: Nanos(0),
+            missed_windows: 1,
+            next_measurement: None,
+            warnings: 0,
+            throttled_outputs: 0,
+            clock_drift_ppm: None,
+        });
+        let counter = |name: &str| {
+            restored
+                .counters_snapshot()
+                .into_iter()
+                .find(|counter| counter.name == name)
+                .unwrap()
+                .value
+        };
+        assert_eq!(counter("bsec_missed_measurement_windows_total"), 3.);
+        assert_eq!(counter("bsec_warnings_total"), 1.);
+        assert_eq!(counter("bsec_monitoring_restarts_total"), 1.);
+        assert_eq!(counter("bsec_accuracy_transitions_total"), 1.);
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_instance_info() {
+        let registry = BsecGaugeRegistry::new(&[]).unwrap();
+        registry
+            .register_instance_info("67e55044-10b1-426f-9247-bb680e5fe0c8")
+            .unwrap();
+
+        let info = registry
+            .gather()
+            .into_iter()
+            .find(|family| family.get_name() == "bsec_exporter_instance_info")
+            .unwrap();
+        let metric = &info.get_metric()[0];
+        assert_eq!(metric.get_gauge().get_value(), 1.);
+        assert_eq!(metric.get_label()[0].get_name(), "instance_uuid");
+        assert_eq!(
+            metric.get_label()[0].get_value(),
+            "67e55044-10b1-426f-9247-bb680e5fe0c8"
+        );
+    }
+
+    #[test]
+    fn test_bsec_gauge_registry_state_metrics() {
+        let registry = BsecGaugeRegistry::new(&[]).unwrap();
+        let state_metrics = registry.register_state_metrics().unwrap();
+        let value = |name: &str| {
+            registry
+                .gather()
+                .into_iter()
+                .find(|family| family.get_name() == name)
+                .map(|family| family.get_metric()[0].get_gauge().get_value())
+        };
+        assert_eq!(value("bsec_state_age_seconds"), None);
+
+        state_metrics.record_save(SystemTime::now() - Duration::from_secs(60), 139);
+        let age = value("bsec_state_age_seconds").unwrap();
+        assert!((60. ..61.).contains(&age));
+        assert_eq!(value("bsec_state_bytes"), Some(139.));
+    }
+
+    fn create_counter_metric_family(name: String, value: f64, help: String) -> MetricFamily {
+        let mut counter = Counter::new();
+        counter.set_value(value);
+
+        let mut metric = Metric::new();
+        metric.set_counter(counter);
+
+        let mut family = MetricFamily::new();
+        family.set_name(name);
+        family.set_help(help);
+        family.set_field_type(MetricType::COUNTER);
+        family.set_metric(protobuf::RepeatedField::from_slice(&[metric]));
+        family
+    }
+
     fn create_gauge_metric_family(name: String, value: f64, help: String) -> MetricFamily {
         let mut gauge = Gauge::new();
         gauge.set_value(value);
@@ -1,4 +1,10 @@
-use tide::{utils::async_trait, Middleware, Next, Request, Result};
+use tide::http::cache::{CacheControl, CacheDirective};
+use tide::http::conditional::{ETag, IfNoneMatch, LastModified};
+use tide::{utils::async_trait, Body, Middleware, Next, Request, Result, StatusCode};
+
+use crate::bsec_config::fingerprint;
+use crate::log_error;
+use crate::metrics::MetricsView;
 
 pub struct LogErrors;
 
@@ -9,8 +15,126 @@ impl<State: Clone + Send + Sync + 'static> Middleware<State> for LogErrors {
         let url = request.url().clone();
         let response = next.run(request).await;
         if let Some(err) = response.error() {
-            eprintln!("Error handling request {} {}: {}", method, url, err);
+            log_error!("Error handling request {} {}: {}", method, url, err);
+        }
+        Ok(response)
+    }
+}
+
+fn tag(etag: &ETag) -> &str {
+    match etag {
+        ETag::Strong(tag) | ETag::Weak(tag) => tag,
+    }
+}
+
+/// Adds `ETag`, `Last-Modified` (time of the last BSEC output), and
+/// `Cache-Control` headers to successful responses and answers conditional
+/// requests for an unchanged body with 304 Not Modified.
+///
+/// `If-Modified-Since` is ignored as the responses may change without a BSEC
+/// output, e.g. by counters, so that only the body tells whether they changed.
+pub struct CacheValidation;
+
+#[async_trait]
+impl Middleware<MetricsView> for CacheValidation {
+    async fn handle(&self, request: Request<MetricsView>, next: Next<'_, MetricsView>) -> Result {
+        let if_none_match = IfNoneMatch::from_headers(&request)?;
+        let updated = request.state().updated();
+        let mut response = next.run(request).await;
+        if response.status() != StatusCode::Ok {
+            return Ok(response);
+        }
+
+        let body = response.take_body().into_bytes().await?;
+        let etag = ETag::new(format!("{:016x}", fingerprint(&body)));
+        etag.apply(&mut response);
+        let mut cache_control = CacheControl::new();
+        cache_control.push(CacheDirective::NoCache);
+        cache_control.apply(&mut response);
+        if let Some(updated) = updated {
+            LastModified::new(updated).apply(&mut response);
+        }
+
+        let not_modified = if_none_match.is_some_and(|if_none_match| {
+            if_none_match.wildcard() || if_none_match.iter().any(|other| tag(other) == tag(&etag))
+        });
+        if not_modified {
+            response.set_status(StatusCode::NotModified);
+        } else {
+            response.set_body(Body::from_bytes(body));
         }
         Ok(response)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metrics::{BsecGaugeRegistry, MetricsFilter};
+    use tide::http::{Method, Url};
+
+    fn app(registry: BsecGaugeRegistry) -> tide::Server<MetricsView> {
+        let mut app = tide::with_state(MetricsView::new(registry, MetricsFilter::all()));
+        app.at("/metrics")
+            .with(CacheValidation)
+            .get(|_| async { Ok("metrics") });
+        app
+    }
+
+    fn request(header: Option<(&str, &str)>) -> tide::http::Request {
+        let mut request =
+            tide::http::Request::new(Method::Get, Url::parse("http://localhost/metrics").unwrap());
+        if let Some((name, value)) = header {
+            request.insert_header(name, value);
+        }
+        request
+    }
+
+    #[tokio::test]
+    async fn test_cache_validation() {
+        let registry = BsecGaugeRegistry::new(&[bsec::OutputKind::Iaq]).unwrap();
+        registry.set(&bsec::Output {
+            timestamp_ns: 0,
+            signal: 42.,
+            sensor: bsec::OutputKind::Iaq,
+            accuracy: bsec::Accuracy::HighAccuracy,
+        });
+        let app = app(registry);
+
+        let mut response: tide::http::Response = app.respond(request(None)).await.unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        assert!(response
+            .content_type()
+            .unwrap()
+            .essence()
+            .starts_with("text/plain"));
+        assert_eq!(response["Cache-Control"], "no-cache");
+        let etag = response["ETag"].as_str().to_string();
+        let last_modified = response["Last-Modified"].as_str().to_string();
+        assert_eq!(response.body_string().await.unwrap(), "metrics");
+
+        let response: tide::http::Response = app
+            .respond(request(Some(("If-None-Match", &etag))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::NotModified);
+        let response: tide::http::Response = app
+            .respond(request(Some(("If-None-Match", "\"other\""))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        let response: tide::http::Response = app
+            .respond(request(Some(("If-Modified-Since", &last_modified))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+        let response: tide::http::Response = app
+            .respond(request(Some((
+                "If-Modified-Since",
+                "Thu, 01 Jan 1970 00:00:00 GMT",
+            ))))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::Ok);
+    }
+}
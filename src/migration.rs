@@ -0,0 +1,203 @@
+//! Migration of configuration files of earlier versions.
+//!
+//! `linux-bsec-exporter migrate-config` moves renamed keys to their current
+//! place and checks that the result is a valid configuration. The migrated
+//! file lists the changes at the top and the sections added since as
+//! commented-out defaults at the bottom. The comments of the original file
+//! are not preserved.
+
+use toml::value::Table;
+use toml::Value;
+
+use crate::config::Config;
+
+/// Keys renamed since the first release as pairs of dotted paths from the
+/// old to the new key, in the order of the renames.
+pub const RENAMED_KEYS: &[(&str, &str)] = &[];
+
+/// Migrated configuration file with the changes made to it.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Migration {
+    pub config: String,
+    pub notes: Vec<String>,
+}
+
+/// Migrates the configuration file `source` to the current schema.
+pub fn migrate(source: &str) -> anyhow::Result<Migration> {
+    migrate_with(source, RENAMED_KEYS)
+}
+
+fn migrate_with(source: &str, renames: &[(&str, &str)]) -> anyhow::Result<Migration> {
+    let mut table: Table = toml::from_str(source)?;
+    let mut notes = Vec::new();
+    for (from, to) in renames {
+        match rename_key(&mut table, from, to) {
+            Renamed::Moved => notes.push(format!("renamed `{}` to `{}`", from, to)),
+            Renamed::Dropped => notes.push(format!(
+                "removed `{}` which is superseded by `{}`",
+                from, to
+            )),
+            Renamed::Absent => (),
+        }
+    }
+
+    let config: Config = Value::Table(table.clone())
+        .try_into()
+        .map_err(|err| anyhow::anyhow!("invalid configuration after migration: {}", err))?;
+    let effective = match Value::try_from(&config)? {
+        Value::Table(effective) => effective,
+        _ => unreachable!("configuration serializes to a table"),
+    };
+    unknown_keys(&table, &effective, "", &mut notes);
+
+    let mut output = format!(
+        "# Migrated by {} {} migrate-config.\n",
+        env!("CARGO_PKG_NAME"),
+        env!("CARGO_PKG_VERSION")
+    );
+    for note in notes.iter() {
+        output.push_str(&format!("# - {}\n", note));
+    }
+    output.push('\n');
+    output.push_str(&toml::to_string_pretty(&table)?);
+    for (key, value) in effective.iter() {
+        if table.contains_key(key) || value.as_array().map_or(false, Vec::is_empty) {
+            continue;
+        }
+        let mut section = Table::new();
+        section.insert(key.clone(), value.clone());
+        output.push_str(&format!(
+            "\n# New section `{}` with its default settings.\n",
+            key
+        ));
+        for line in toml::to_string_pretty(&section)?.lines() {
+            if line.is_empty() {
+                output.push_str("#\n");
+            } else {
+                output.push_str(&format!("#{}\n", line));
+            }
+        }
+    }
+
+    Ok(Migration {
+        config: output,
+        notes,
+    })
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Renamed {
+    Absent,
+    Moved,
+    Dropped,
+}
+
+/// Moves the value at the dotted path `from` to `to` unless the new key is
+/// already set, in which case the old one is dropped.
+fn rename_key(table: &mut Table, from: &str, to: &str) -> Renamed {
+    let value = match remove_path(table, from) {
+        Some(value) => value,
+        None => return Renamed::Absent,
+    };
+    let mut parent = table;
+    let mut segments = to.split('.').peekable();
+    while let Some(segment) = segments.next() {
+        if segments.peek().is_none() {
+            if parent.contains_key(segment) {
+                return Renamed::Dropped;
+            }
+            parent.insert(segment.into(), value);
+            return Renamed::Moved;
+        }
+        parent = match parent
+            .entry(segment)
+            .or_insert_with(|| Value::Table(Table::new()))
+        {
+            Value::Table(child) => child,
+            _ => return Renamed::Dropped,
+        };
+    }
+    Renamed::Absent
+}
+
+fn remove_path(table: &mut Table, path: &str) -> Option<Value> {
+    match path.split_once('.') {
+        None => table.remove(path),
+        Some((head, tail)) => match table.get_mut(head)? {
+            Value::Table(child) => remove_path(child, tail),
+            _ => None,
+        },
+    }
+}
+
+/// Notes the keys of `table` not present in the `effective` configuration,
+/// i.e. the keys ignored by this version.
+fn unknown_keys(table: &Table, effective: &Table, prefix: &str, notes: &mut Vec<String>) {
+    for (key, value) in table.iter() {
+        let path = format!("{}{}", prefix, key);
+        match (value, effective.get(key)) {
+            (_, None) => notes.push(format!("`{}` is unknown and ignored", path)),
+            (Value::Table(child), Some(Value::Table(effective_child))) => {
+                unknown_keys(child, effective_child, &format!("{}.", path), notes)
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_migrates_renamed_keys() {
+        let migration = migrate_with(
+            "[sensor]\ndevice = \"/dev/i2c-1\"\n\n[bsec]\nstate = \"/tmp/state.bin\"\n",
+            &[("bsec.state", "bsec.state_file")],
+        )
+        .unwrap();
+        assert_eq!(
+            migration.notes,
+            vec!["renamed `bsec.state` to `bsec.state_file`".to_string()]
+        );
+        let config: Config = toml::from_str(&migration.config).unwrap();
+        assert_eq!(config.bsec.state_file, "/tmp/state.bin");
+    }
+
+    #[test]
+    fn test_drops_superseded_keys() {
+        let mut table: Table =
+            toml::from_str("[bsec]\nstate = \"old.bin\"\nstate_file = \"new.bin\"\n").unwrap();
+        assert_eq!(
+            rename_key(&mut table, "bsec.state", "bsec.state_file"),
+            Renamed::Dropped
+        );
+        assert_eq!(
+            rename_key(&mut table, "bsec.state", "bsec.state_file"),
+            Renamed::Absent
+        );
+        assert_eq!(
+            toml::to_string(&table).unwrap(),
+            "[bsec]\nstate_file = \"new.bin\"\n"
+        );
+    }
+
+    #[test]
+    fn test_comments_new_sections_and_unknown_keys() {
+        let migration = migrate("[sensor]\ndevice = \"/dev/i2c-1\"\nspeed = 3\n").unwrap();
+        assert_eq!(
+            migration.notes,
+            vec!["`sensor.speed` is unknown and ignored".to_string()]
+        );
+        assert!(migration.config.contains("# New section `exporter`"));
+        assert!(migration.config.contains("#listen_addrs = "));
+
+        let config: Config = toml::from_str(&migration.config).unwrap();
+        assert_eq!(config.sensor.device, "/dev/i2c-1");
+    }
+
+    #[test]
+    fn test_invalid_config() {
+        assert!(migrate("[bsec]\nstate_file = \"state.bin\"\n").is_err());
+    }
+}
@@ -1,11 +1,25 @@
+use crate::clock::{ClockDrift, ClockExt, MonotonicRaw, Nanos};
+use crate::config::output_kind_name;
+use crate::ffi_guard::{bsec_error_code, bsec_warning, guarded, BsecCallError, Diagnostics};
+use crate::sensor::check_required_inputs;
+use crate::{log_error, log_warn};
 use anyhow::Result;
-use bsec::{self, bme::BmeSensor, clock::Clock, Bsec};
+use bsec::{self, bme::BmeSensor, clock::Clock, Bsec, OutputKind, SampleRate};
 use nb::block;
+use std::collections::HashMap;
 use std::future::Future;
 use std::sync::Arc;
-use tokio::sync::{oneshot, watch};
+use std::time::SystemTime;
+use tokio::sync::{mpsc, oneshot, watch};
 use tokio::time::Duration;
 
+/// Interval of the periodic BSEC state saves.
+const STATE_SAVE_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Wait after a BSEC warning that prevented the scheduling of the next
+/// measurement.
+const WARNING_BACKOFF: Duration = Duration::from_secs(1);
+
 pub trait PersistState {
     type Error;
 
@@ -19,9 +33,89 @@ pub trait Sleep {
     fn sleep(&self, duration: Duration) -> Self::SleepFuture;
 }
 
+/// Timing of the measurement cycles relative to the schedule requested by BSEC.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct CycleTiming {
+    /// Time from the scheduled measurement until the processing of the last
+    /// measurement was completed.
+    pub latency_ns: Nanos,
+    /// Number of measurement windows missed since the start of monitoring.
+    pub missed_windows: u64,
+    /// Wall-clock time of the next measurement scheduled by BSEC.
+    pub next_measurement: Option<SystemTime>,
+    /// Number of measurement cycles since the start of monitoring that
+    /// returned a BSEC warning instead of outputs.
+    pub warnings: u64,
+    /// Number of outputs since the start of monitoring that were superseded
+    /// by a newer output of the same kind before being published due to the
+    /// minimum publish interval.
+    pub throttled_outputs: u64,
+    /// Drift of the measurement clock relative to the raw hardware clock in parts
+    /// per million, positive if it runs slow.
+    pub clock_drift_ppm: Option<f64>,
+}
+
+/// Limits the rate of the published outputs.
+///
+/// The outputs of the measurement cycles within the minimum publish interval
+/// are merged, keeping the newest output of each kind, so that outputs with
+/// a low sample rate are not lost.
+#[derive(Debug)]
+struct OutputThrottle {
+    min_interval: Nanos,
+    last_publish: Option<Nanos>,
+    pending: Vec<bsec::Output>,
+}
+
+impl OutputThrottle {
+    fn new(min_interval: Nanos) -> Self {
+        Self {
+            min_interval,
+            last_publish: None,
+            pending: vec![],
+        }
+    }
+
+    /// Adds the `outputs` of a measurement cycle completed at `now`.
+    ///
+    /// Returns the outputs to publish, if the minimum interval has passed,
+    /// and the number of pending outputs superseded by the `outputs`.
+    fn add(&mut self, now: Nanos, outputs: Vec<bsec::Output>) -> (Option<Vec<bsec::Output>>, u64) {
+        let mut superseded = 0;
+        for output in outputs {
+            match self
+                .pending
+                .iter_mut()
+                .find(|pending| pending.sensor == output.sensor)
+            {
+                Some(pending) => {
+                    *pending = output;
+                    superseded += 1;
+                }
+                None => self.pending.push(output),
+            }
+        }
+        let due = self
+            .last_publish
+            .is_none_or(|last_publish| now - last_publish >= self.min_interval);
+        if !due {
+            return (None, superseded);
+        }
+        self.last_publish = Some(now);
+        (Some(std::mem::take(&mut self.pending)), superseded)
+    }
+
+    /// Returns the pending outputs not yet published, if any.
+    fn flush(&mut self) -> Option<Vec<bsec::Output>> {
+        Some(std::mem::take(&mut self.pending)).filter(|pending| !pending.is_empty())
+    }
+}
+
 pub struct BsecReceiver {
     pub current: watch::Receiver<Option<Vec<bsec::Output>>>,
+    pub timing: watch::Receiver<CycleTiming>,
     pub initiate_shutdown: oneshot::Sender<()>,
+    pub update_subscription: mpsc::UnboundedSender<Vec<bsec::SubscriptionRequest>>,
 }
 
 pub struct BsecSender<S, P, C>
@@ -31,10 +125,17 @@ where
     C: Clock + Sleep + 'static,
 {
     sender: watch::Sender<Option<Vec<bsec::Output>>>,
+    timing_sender: watch::Sender<CycleTiming>,
     shutdown_request_receiver: oneshot::Receiver<()>,
+    subscription_receiver: mpsc::UnboundedReceiver<Vec<bsec::SubscriptionRequest>>,
     bsec: Bsec<S, C, Arc<C>>,
     persistence: P,
     clock: Arc<C>,
+    provided_inputs: Option<Vec<bsec::InputKind>>,
+    subscription: HashMap<OutputKind, SampleRate>,
+    throttle: Option<OutputThrottle>,
+    drift: ClockDrift,
+    compensate_drift: bool,
 }
 
 impl<S, P, C> BsecSender<S, P, C>
@@ -45,41 +146,190 @@ where
     P::Error: std::error::Error + Send + Sync + 'static,
     S::Error: std::fmt::Debug + Send + Sync + 'static,
 {
+    /// Warn about inputs required after subscription updates that are not
+    /// among the `provided` inputs of the sensor.
+    pub fn with_provided_inputs(mut self, provided: Vec<bsec::InputKind>) -> Self {
+        self.provided_inputs = Some(provided);
+        self
+    }
+
+    /// Track the subscription `requests` BSEC was initialized with, to restore
+    /// them after subscription updates rejected with a warning.
+    pub fn with_subscription(mut self, requests: &[bsec::SubscriptionRequest]) -> Self {
+        self.subscription = requests
+            .iter()
+            .map(|request| (request.sensor, request.sample_rate))
+            .collect();
+        self
+    }
+
+    /// Publish the outputs at most once per `min_interval`, merging the
+    /// outputs of the measurement cycles in between.
+    pub fn with_min_publish_interval(mut self, min_interval: Duration) -> Self {
+        self.throttle = Some(min_interval)
+            .filter(|min_interval| !min_interval.is_zero())
+            .map(|min_interval| OutputThrottle::new(Nanos::from_duration(min_interval)));
+        self
+    }
+
+    /// Convert the output timestamps to the [`MonotonicRaw`] clock corrected
+    /// for the drift of the measurement clock.
+    pub fn with_drift_compensation(mut self) -> Self {
+        self.compensate_drift = true;
+        self
+    }
+
     pub async fn monitoring_loop(mut self) -> Result<(Bsec<S, C, Arc<C>>, P)> {
-        let mut last_state_save = self.clock.timestamp_ns();
+        let mut last_state_save = self.clock.now();
+        let mut timing = CycleTiming::default();
+        let mut is_first_cycle = true;
 
         if let Some(state) = self.persistence.load_state()? {
             self.bsec.set_state(&state)?;
         }
 
         while self.shutdown_request_receiver.try_recv().is_err() {
-            self.sender.send(Some(
-                Self::next_measurement(&mut self.bsec, self.clock.clone()).await?,
-            ))?;
-            if self.clock.timestamp_ns() - last_state_save >= 60_000_000_000 {
-                last_state_save = self.clock.timestamp_ns();
+            while let Ok(requests) = self.subscription_receiver.try_recv() {
+                self.update_subscription(&requests)?;
+            }
+            let scheduled = Nanos(self.bsec.next_measurement());
+            if !is_first_cycle && self.clock.now() > scheduled {
+                timing.missed_windows += 1;
+            }
+            is_first_cycle = false;
+            match Self::next_measurement(&mut self.bsec, self.clock.clone(), timing).await {
+                Ok(mut outputs) => {
+                    timing.latency_ns = self.clock.now() - scheduled;
+                    let until_next = Nanos(self.bsec.next_measurement()) - self.clock.now();
+                    timing.next_measurement = Some(SystemTime::now() + until_next.to_duration());
+                    self.drift.observe(self.clock.now(), MonotonicRaw {}.now());
+                    timing.clock_drift_ppm = self.drift.ppm();
+                    if self.compensate_drift {
+                        for output in outputs.iter_mut() {
+                            if let Some(reference) =
+                                self.drift.to_reference(Nanos(output.timestamp_ns))
+                            {
+                                output.timestamp_ns = reference.get();
+                            }
+                        }
+                    }
+                    let outputs = match &mut self.throttle {
+                        Some(throttle) => {
+                            let (outputs, superseded) = throttle.add(self.clock.now(), outputs);
+                            timing.throttled_outputs += superseded;
+                            outputs
+                        }
+                        None => Some(outputs),
+                    };
+                    self.timing_sender.send(timing)?;
+                    if let Some(outputs) = outputs {
+                        self.sender.send(Some(outputs))?;
+                    }
+                }
+                Err(err) if err.is_warning() => {
+                    err.log();
+                    timing.warnings += 1;
+                    self.timing_sender.send(timing)?;
+                    if Nanos(self.bsec.next_measurement()) <= scheduled {
+                        self.clock.sleep(WARNING_BACKOFF).await;
+                    }
+                }
+                Err(err) => return Err(err.into()),
+            }
+            if self.clock.now() - last_state_save >= Nanos::from_duration(STATE_SAVE_INTERVAL) {
+                last_state_save = self.clock.now();
                 self.persistence.save_state(&self.bsec.get_state()?)?;
             }
             tokio::task::yield_now().await;
         }
 
+        if let Some(outputs) = self.throttle.as_mut().and_then(OutputThrottle::flush) {
+            // The sinks might already have stopped, which must not prevent
+            // saving the state.
+            let _ = self.sender.send(Some(outputs));
+        }
         self.persistence.save_state(&self.bsec.get_state()?)?;
 
         Ok((self.bsec, self.persistence))
     }
 
+    fn update_subscription(&mut self, requests: &[bsec::SubscriptionRequest]) -> Result<()> {
+        let output_kinds: Vec<_> = requests
+            .iter()
+            .map(|request| output_kind_name(request.sensor))
+            .collect();
+        match self.bsec.update_subscription(requests) {
+            Ok(required) => {
+                if let Some(provided) = &self.provided_inputs {
+                    check_required_inputs(&required, provided);
+                }
+                for request in requests {
+                    self.subscription
+                        .insert(request.sensor, request.sample_rate);
+                }
+            }
+            Err(err) => match bsec_warning(&err) {
+                Some(warning) => {
+                    // BSEC applied the subscription, but the bsec crate did
+                    // not track it and would size the outputs of all further
+                    // cycles wrongly. Restoring the previous sample rates
+                    // brings both in line again.
+                    log_warn!(
+                        output_kinds = output_kinds,
+                        error_code = bsec_error_code(&err);
+                        "BSEC subscription returned a warning, keeping the previous subscription: {:?}",
+                        warning
+                    );
+                    let previous: Vec<_> = requests
+                        .iter()
+                        .map(|request| bsec::SubscriptionRequest {
+                            sensor: request.sensor,
+                            sample_rate: self
+                                .subscription
+                                .get(&request.sensor)
+                                .copied()
+                                .unwrap_or(SampleRate::Disabled),
+                        })
+                        .collect();
+                    if let Err(err) = self.bsec.update_subscription(&previous) {
+                        anyhow::bail!("failed to restore the BSEC subscription: {:?}", err);
+                    }
+                }
+                // BSEC left the subscription unchanged, so the monitoring
+                // continues with the previous one.
+                None => log_error!(
+                    output_kinds = output_kinds,
+                    error_code = bsec_error_code(&err);
+                    "BSEC rejected the subscription update, keeping the previous subscription: {:?}",
+                    err
+                ),
+            },
+        }
+        Ok(())
+    }
+
     async fn next_measurement(
         bsec: &mut Bsec<S, C, Arc<C>>,
         time: Arc<C>,
-    ) -> Result<Vec<bsec::Output>, bsec::error::Error<S::Error>> {
-        let sleep_duration = bsec.next_measurement() - time.timestamp_ns();
-        if sleep_duration > 0 {
-            time.sleep(Duration::from_nanos(sleep_duration as u64))
-                .await;
+        timing: CycleTiming,
+    ) -> Result<Vec<bsec::Output>, BsecCallError> {
+        let next_measurement_ns = Nanos(bsec.next_measurement());
+        let diagnostics = || Diagnostics {
+            timestamp_ns: time.now(),
+            next_measurement_ns,
+            timing,
+        };
+        let sleep_duration = next_measurement_ns - time.now();
+        if sleep_duration > Nanos::ZERO {
+            time.sleep(sleep_duration.to_duration()).await;
         }
-        let duration = block!(bsec.start_next_measurement())?;
+        let duration = guarded("start_next_measurement", diagnostics, || {
+            block!(bsec.start_next_measurement())
+        })?;
         time.sleep(duration).await;
-        block!(bsec.process_last_measurement())
+        guarded("process_last_measurement", diagnostics, || {
+            block!(bsec.process_last_measurement())
+        })
     }
 }
 
@@ -96,18 +346,29 @@ where
     S::Error: std::fmt::Debug + Send + Sync + 'static,
 {
     let (sender, receiver) = watch::channel(None);
+    let (timing_sender, timing) = watch::channel(CycleTiming::default());
     let (initiate_shutdown, shutdown_request_receiver) = oneshot::channel();
+    let (update_subscription, subscription_receiver) = mpsc::unbounded_channel();
     (
         BsecSender {
             sender,
+            timing_sender,
             shutdown_request_receiver,
+            subscription_receiver,
             bsec,
             persistence,
             clock,
+            provided_inputs: None,
+            subscription: HashMap::new(),
+            throttle: None,
+            drift: ClockDrift::new(),
+            compensate_drift: false,
         },
         BsecReceiver {
             current: receiver,
+            timing,
             initiate_shutdown,
+            update_subscription,
         },
     )
 }
@@ -115,50 +376,23 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
-    use bsec::bme::test_support::FakeBmeSensor;
-    use bsec::clock::test_support::FakeClock;
+    use crate::test_support::{fake_bsec, FakeBmeSensor, FakeClock, MockPersistState};
     use serial_test::serial;
-    use std::future::{self, Ready};
-
-    impl Sleep for FakeClock {
-        type SleepFuture = Ready<()>;
-
-        fn sleep(&self, duration: Duration) -> Self::SleepFuture {
-            self.advance_by(duration);
-            future::ready(())
-        }
-    }
-
-    #[derive(Default)]
-    struct MockPersistState {
-        pub state: Arc<std::sync::RwLock<Option<Vec<u8>>>>,
-    }
-
-    impl PersistState for MockPersistState {
-        type Error = std::convert::Infallible;
-
-        fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
-            Ok(self.state.read().unwrap().clone())
-        }
-
-        fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
-            *self.state.write().unwrap() = Some(Vec::from(state));
-            Ok(())
-        }
-    }
 
-    fn create_minimal_subscribed_bsec<C: Clock>(time: Arc<C>) -> Bsec<FakeBmeSensor, C, Arc<C>> {
-        let bme = FakeBmeSensor::new(Ok(vec![bsec::Input {
-            sensor: bsec::InputKind::Temperature,
-            signal: 22.,
-        }]));
-        let mut bsec = Bsec::init(bme, time).unwrap();
-        bsec.update_subscription(&[bsec::SubscriptionRequest {
-            sample_rate: bsec::SampleRate::Continuous,
-            sensor: bsec::OutputKind::RawTemperature,
-        }])
-        .unwrap();
-        bsec
+    fn create_minimal_subscribed_bsec(
+        clock: Arc<FakeClock>,
+    ) -> Bsec<FakeBmeSensor, FakeClock, Arc<FakeClock>> {
+        fake_bsec(
+            vec![bsec::Input {
+                sensor: bsec::InputKind::Temperature,
+                signal: 22.,
+            }],
+            &[bsec::SubscriptionRequest {
+                sample_rate: bsec::SampleRate::Continuous,
+                sensor: bsec::OutputKind::RawTemperature,
+            }],
+            clock,
+        )
     }
 
     #[tokio::test]
@@ -195,6 +429,34 @@ mod tests {
         join_handle.await.unwrap().unwrap();
     }
 
+    #[tokio::test]
+    #[serial]
+    async fn publishes_the_measured_signal() {
+        let clock = Arc::new(FakeClock::new());
+        let bme = FakeBmeSensor::new(Ok(vec![bsec::Input {
+            sensor: bsec::InputKind::Temperature,
+            signal: 22.,
+        }]));
+        let mut bsec = Bsec::init(bme, clock.clone()).unwrap();
+        bsec.update_subscription(&[bsec::SubscriptionRequest {
+            sample_rate: bsec::SampleRate::Continuous,
+            sensor: bsec::OutputKind::RawTemperature,
+        }])
+        .unwrap();
+
+        let (monitor, mut rx) = bsec_monitor(bsec, MockPersistState::default(), clock.clone());
+        let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+        rx.current.changed().await.unwrap();
+        {
+            let borrow = rx.current.borrow();
+            let outputs = borrow.as_deref().unwrap();
+            assert!((outputs[0].signal - 22.).abs() < f64::EPSILON);
+        }
+
+        rx.initiate_shutdown.send(()).unwrap();
+        join_handle.await.unwrap().unwrap();
+    }
+
     #[tokio::test]
     #[serial]
     async fn loads_and_persists_state() {
@@ -237,4 +499,193 @@ mod tests {
         rx.initiate_shutdown.send(()).unwrap();
         join_handle.await.unwrap().unwrap();
     }
+
+    #[tokio::test]
+    #[serial]
+    async fn publishes_cycle_timing() {
+        let clock = Arc::new(FakeClock::new());
+        let bsec = create_minimal_subscribed_bsec(clock.clone());
+
+        let (monitor, mut rx) = bsec_monitor(bsec, MockPersistState::default(), clock.clone());
+        let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+
+        rx.current.changed().await.unwrap();
+        rx.current.changed().await.unwrap();
+        let timing = *rx.timing.borrow();
+        assert!(timing.latency_ns >= Nanos::ZERO);
+        assert_eq!(timing.missed_windows, 0);
+
+        rx.initiate_shutdown.send(()).unwrap();
+        join_handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn converts_output_timestamps_to_raw_clock() {
+        let clock = Arc::new(FakeClock::new());
+        let bsec = create_minimal_subscribed_bsec(clock.clone());
+
+        let (monitor, mut rx) = bsec_monitor(bsec, MockPersistState::default(), clock.clone());
+        let join_handle = tokio::task::spawn(monitor.with_drift_compensation().monitoring_loop());
+
+        rx.current.changed().await.unwrap();
+        let timestamp_ns = rx.current.borrow().as_ref().unwrap()[0].timestamp_ns;
+        assert!(
+            (MonotonicRaw {}.now() - Nanos(timestamp_ns)).get().abs()
+                < Nanos::from_duration(Duration::from_secs(10)).get()
+        );
+
+        rx.initiate_shutdown.send(()).unwrap();
+        join_handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn runs_ulp_cycles() {
+        let clock = Arc::new(FakeClock::new());
+        let bsec = fake_bsec(
+            vec![bsec::Input {
+                sensor: bsec::InputKind::Temperature,
+                signal: 22.,
+            }],
+            &[bsec::SubscriptionRequest {
+                sample_rate: bsec::SampleRate::Ulp,
+                sensor: bsec::OutputKind::RawTemperature,
+            }],
+            clock.clone(),
+        );
+        let state = Arc::new(std::sync::RwLock::new(None));
+        let persist_state = MockPersistState {
+            state: state.clone(),
+        };
+
+        let (monitor, mut rx) = bsec_monitor(bsec, persist_state, clock.clone());
+        let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+
+        let mut timestamps = vec![];
+        for _ in 0..3 {
+            rx.current.changed().await.unwrap();
+            timestamps.push(rx.current.borrow().as_ref().unwrap()[0].timestamp_ns);
+        }
+        for interval in timestamps.windows(2) {
+            assert!((interval[1] - interval[0] - 300_000_000_000).abs() < 100_000_000);
+        }
+        assert_eq!(rx.timing.borrow().missed_windows, 0);
+        assert!(state.read().unwrap().is_some());
+
+        rx.initiate_shutdown.send(()).unwrap();
+        join_handle.await.unwrap().unwrap();
+    }
+
+    #[test]
+    fn test_output_throttle() {
+        let output = |sensor, timestamp_ns| bsec::Output {
+            timestamp_ns,
+            signal: 22.,
+            sensor,
+            accuracy: bsec::Accuracy::HighAccuracy,
+        };
+        let mut throttle = OutputThrottle::new(Nanos(3_000_000_000));
+
+        let (outputs, superseded) =
+            throttle.add(Nanos(0), vec![output(bsec::OutputKind::RawTemperature, 0)]);
+        assert_eq!(outputs.unwrap().len(), 1);
+        assert_eq!(superseded, 0);
+
+        let (outputs, superseded) = throttle.add(
+            Nanos(1_000_000_000),
+            vec![output(bsec::OutputKind::RawTemperature, 1)],
+        );
+        assert_eq!(outputs, None);
+        assert_eq!(superseded, 0);
+        let (outputs, superseded) = throttle.add(
+            Nanos(2_000_000_000),
+            vec![
+                output(bsec::OutputKind::RawTemperature, 2),
+                output(bsec::OutputKind::Iaq, 2),
+            ],
+        );
+        assert_eq!(outputs, None);
+        assert_eq!(superseded, 1);
+
+        let (outputs, superseded) = throttle.add(Nanos(3_000_000_000), vec![]);
+        assert_eq!(
+            outputs,
+            Some(vec![
+                output(bsec::OutputKind::RawTemperature, 2),
+                output(bsec::OutputKind::Iaq, 2),
+            ])
+        );
+        assert_eq!(superseded, 0);
+
+        throttle.add(
+            Nanos(4_000_000_000),
+            vec![output(bsec::OutputKind::RawTemperature, 4)],
+        );
+        assert_eq!(
+            throttle.flush(),
+            Some(vec![output(bsec::OutputKind::RawTemperature, 4)])
+        );
+        assert_eq!(throttle.flush(), None);
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn applies_subscription_updates() {
+        let clock = Arc::new(FakeClock::new());
+        let bsec = create_minimal_subscribed_bsec(clock.clone());
+
+        let (monitor, mut rx) = bsec_monitor(bsec, MockPersistState::default(), clock.clone());
+        rx.update_subscription
+            .send(vec![bsec::SubscriptionRequest {
+                sample_rate: bsec::SampleRate::Lp,
+                sensor: bsec::OutputKind::RawTemperature,
+            }])
+            .unwrap();
+        let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+
+        rx.current.changed().await.unwrap();
+        {
+            let borrow = rx.current.borrow();
+            let outputs = borrow.as_deref().unwrap();
+            assert_eq!(outputs.len(), 1);
+            assert_eq!(outputs[0].sensor, bsec::OutputKind::RawTemperature);
+        }
+
+        rx.initiate_shutdown.send(()).unwrap();
+        join_handle.await.unwrap().unwrap();
+    }
+
+    #[tokio::test]
+    #[serial]
+    async fn keeps_subscription_on_rejected_update() {
+        let clock = Arc::new(FakeClock::new());
+        let bsec = create_minimal_subscribed_bsec(clock.clone());
+
+        let (monitor, mut rx) = bsec_monitor(bsec, MockPersistState::default(), clock.clone());
+        rx.update_subscription
+            .send(vec![
+                bsec::SubscriptionRequest {
+                    sample_rate: bsec::SampleRate::Lp,
+                    sensor: bsec::OutputKind::RawTemperature,
+                },
+                bsec::SubscriptionRequest {
+                    sample_rate: bsec::SampleRate::Ulp,
+                    sensor: bsec::OutputKind::RawTemperature,
+                },
+            ])
+            .unwrap();
+        let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+
+        rx.current.changed().await.unwrap();
+        {
+            let borrow = rx.current.borrow();
+            let outputs = borrow.as_deref().unwrap();
+            assert_eq!(outputs.len(), 1);
+            assert_eq!(outputs[0].sensor, bsec::OutputKind::RawTemperature);
+        }
+
+        rx.initiate_shutdown.send(()).unwrap();
+        join_handle.await.unwrap().unwrap();
+    }
 }
@@ -0,0 +1,412 @@
+//! Routing of the built-in alert notifications to webhooks.
+//!
+//! A notification is posted as JSON document when an alert starts firing and
+//! when it is resolved. Firing alerts are notified again after the repeat
+//! interval, and an alert firing again within the deduplication window after
+//! its last notification, e.g. because it flaps around the threshold, is only
+//! notified if it is still firing at the end of the window. A resolved
+//! notification is only sent for a notified firing. No notifications are sent
+//! during the quiet hours; alerts still firing afterwards are notified then.
+//! Alerts that have not been acknowledged within the escalation delay are
+//! additionally posted to the escalation URL once. Notifications that failed
+//! to post are retried with the next check.
+
+use std::collections::BTreeMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+use tokio::sync::mpsc;
+
+use crate::alerting::{ActiveAlerts, AlertChange};
+use crate::clock::LocalTime;
+use crate::config::NotificationsConfig;
+use crate::http_client;
+use crate::log_error;
+
+/// Interval in which repeats, escalations, and the end of the quiet hours are
+/// checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum AlertStatus {
+    Firing,
+    Resolved,
+}
+
+/// Document posted to the webhooks.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Notification {
+    pub alert: String,
+    pub status: AlertStatus,
+    /// Value the condition is based on, e.g. the rate of change.
+    pub value: f64,
+    /// Seconds since the Unix epoch since which the alert is firing.
+    pub since: u64,
+    pub escalated: bool,
+}
+
+#[derive(Clone, Debug, Default)]
+struct AlertState {
+    firing: bool,
+    value: f64,
+    since: u64,
+    /// Whether the webhook was notified of the firing and not yet of its
+    /// resolution.
+    notified: bool,
+    /// Time of the last successful firing notification, if known.
+    last_notified: Option<u64>,
+    escalated: bool,
+}
+
+impl AlertState {
+    /// Whether a firing at `now` is within the deduplication window after the
+    /// last notification.
+    fn deduplicated(&self, now: u64, dedup_window: u64) -> bool {
+        self.last_notified
+            .is_some_and(|last| now.saturating_sub(last) < dedup_window)
+    }
+}
+
+/// Decides which notifications are due.
+#[derive(Debug)]
+pub struct Router {
+    config: NotificationsConfig,
+    alerts: BTreeMap<String, AlertState>,
+}
+
+impl Router {
+    pub fn new(config: NotificationsConfig) -> Self {
+        Self {
+            config,
+            alerts: BTreeMap::new(),
+        }
+    }
+
+    /// Applies the change of an alert at `now` in seconds since the Unix
+    /// epoch.
+    pub fn update(&mut self, change: &AlertChange, now: u64) {
+        let dedup_window = self.config.dedup_window_minutes * 60;
+        let alert = self.alerts.entry(change.name.clone()).or_default();
+        alert.value = change.value;
+        if change.firing == alert.firing {
+            return;
+        }
+        alert.firing = change.firing;
+        if change.firing && !alert.notified && !alert.deduplicated(now, dedup_window) {
+            alert.since = now;
+            alert.escalated = false;
+        }
+    }
+
+    /// Reverts the state for a `notification` that failed to post, so that it
+    /// is due again with the next check if still applicable.
+    pub fn failed(&mut self, notification: &Notification) {
+        let alert = match self.alerts.get_mut(&notification.alert) {
+            Some(alert) => alert,
+            None => return,
+        };
+        match (notification.status, notification.escalated) {
+            (AlertStatus::Resolved, _) => alert.notified = true,
+            (AlertStatus::Firing, true) => alert.escalated = false,
+            (AlertStatus::Firing, false) => {
+                alert.notified = false;
+                alert.last_notified = None;
+            }
+        }
+    }
+
+    /// Notifications due at `now` with the URL to post them to. Repeats and
+    /// escalations of `acknowledged` alerts are not due.
+    pub fn due(
+        &mut self,
+        now: u64,
+        quiet: bool,
+        acknowledged: impl Fn(&str) -> bool,
+    ) -> Vec<(String, Notification)> {
+        let mut due = vec![];
+        if quiet {
+            return due;
+        }
+        let dedup_window = self.config.dedup_window_minutes * 60;
+        for (name, alert) in self.alerts.iter_mut() {
+            let notification = |alert: &AlertState, status, escalated| Notification {
+                alert: name.clone(),
+                status,
+                value: alert.value,
+                since: alert.since,
+                escalated,
+            };
+            if !alert.firing {
+                if alert.notified {
+                    alert.notified = false;
+                    due.push((
+                        self.config.webhook_url.clone(),
+                        notification(alert, AlertStatus::Resolved, false),
+                    ));
+                }
+                continue;
+            }
+            let held_back = match alert.notified {
+                true => acknowledged(name),
+                false => alert.deduplicated(now, dedup_window),
+            };
+            if held_back {
+                continue;
+            }
+            let repeat_interval = self.config.repeat_interval_minutes * 60;
+            let repeat = alert.notified
+                && repeat_interval > 0
+                && alert
+                    .last_notified
+                    .is_some_and(|last| now.saturating_sub(last) >= repeat_interval);
+            if !alert.notified || repeat {
+                due.push((
+                    self.config.webhook_url.clone(),
+                    notification(alert, AlertStatus::Firing, false),
+                ));
+                alert.notified = true;
+                alert.last_notified = Some(now);
+            }
+            if let Some(escalation_url) = &self.config.escalation_url {
+                let escalate_after = self.config.escalate_after_minutes * 60;
+                if !alert.escalated && now.saturating_sub(alert.since) >= escalate_after {
+                    due.push((
+                        escalation_url.clone(),
+                        notification(alert, AlertStatus::Firing, true),
+                    ));
+                    alert.escalated = true;
+                }
+            }
+        }
+        due
+    }
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Passes the alert changes of the monitoring to the delivery.
+#[derive(Clone, Debug)]
+pub struct AlertNotifier {
+    changes: mpsc::UnboundedSender<Vec<AlertChange>>,
+}
+
+impl AlertNotifier {
+    /// Creates the notifier and the delivery it passes the changes to. The
+    /// acknowledgements are taken from the `alerts`.
+    pub fn new(config: NotificationsConfig, alerts: ActiveAlerts) -> (Self, Delivery) {
+        let (changes, updates) = mpsc::unbounded_channel();
+        let delivery = Delivery {
+            router: Router::new(config),
+            alerts,
+            updates,
+        };
+        (Self { changes }, delivery)
+    }
+
+    /// Hands the `changes` to the delivery, which posts the notifications due
+    /// with them.
+    pub fn notify(&self, changes: Vec<AlertChange>) {
+        if self.changes.send(changes).is_err() {
+            log_error!("Alert notification delivery stopped, dropping alert changes.");
+        }
+    }
+}
+
+/// Posts the notifications of a single task, so that a notification is never
+/// delivered twice concurrently.
+#[derive(Debug)]
+pub struct Delivery {
+    router: Router,
+    alerts: ActiveAlerts,
+    updates: mpsc::UnboundedReceiver<Vec<AlertChange>>,
+}
+
+impl Delivery {
+    /// Posts the notifications due now.
+    async fn deliver(&mut self) {
+        let now = unix_now();
+        let quiet = self
+            .router
+            .config
+            .quiet_hours
+            .is_some_and(|quiet_hours| quiet_hours.contains(LocalTime::at(now).minute_of_day));
+        let alerts = &self.alerts;
+        let due = self
+            .router
+            .due(now, quiet, |name| alerts.is_acknowledged_at(name, now));
+        for (url, notification) in due {
+            let result = match serde_json::to_string(&notification) {
+                Ok(body) => http_client::post_json(&url, body).await,
+                Err(err) => Err(err.into()),
+            };
+            if let Err(err) = result {
+                log_error!(
+                    "Failed to post notification of alert {} to {}, retrying with the next check: {}",
+                    notification.alert,
+                    url,
+                    err
+                );
+                self.router.failed(&notification);
+            }
+        }
+    }
+
+    /// Delivers the notifications due with the alert changes, as well as
+    /// repeats, escalations, and notifications held back during the quiet
+    /// hours.
+    pub async fn run(mut self) {
+        let mut interval = tokio::time::interval(CHECK_INTERVAL);
+        loop {
+            tokio::select! {
+                _ = interval.tick() => (),
+                changes = self.updates.recv() => match changes {
+                    Some(changes) => {
+                        let now = unix_now();
+                        for change in changes.iter() {
+                            self.router.update(change, now);
+                        }
+                    }
+                    None => return,
+                },
+            }
+            self.deliver().await;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn router() -> Router {
+        Router::new(NotificationsConfig {
+            webhook_url: "http://alerts/hook".into(),
+            repeat_interval_minutes: 60,
+            dedup_window_minutes: 10,
+            quiet_hours: None,
+            escalation_url: Some("http://alerts/escalate".into()),
+            escalate_after_minutes: 30,
+        })
+    }
+
+    fn change(firing: bool) -> AlertChange {
+        AlertChange {
+            name: "co2_rising".into(),
+            firing,
+            value: 60.,
+        }
+    }
+
+    fn statuses(due: &[(String, Notification)]) -> Vec<(&str, AlertStatus)> {
+        due.iter()
+            .map(|(url, notification)| (url.as_str(), notification.status))
+            .collect()
+    }
+
+    #[test]
+    fn test_acknowledged_alerts_are_not_repeated() {
+        let mut router = router();
+        router.update(&change(true), 0);
+        assert_eq!(
+            statuses(&router.due(0, false, |_| false)),
+            vec![("http://alerts/hook", AlertStatus::Firing)]
+        );
+        assert_eq!(router.due(60, false, |_| false), vec![]);
+        assert_eq!(router.due(3600, false, |_| true), vec![]);
+        router.update(&change(false), 3660);
+        assert_eq!(
+            statuses(&router.due(3660, false, |_| true)),
+            vec![("http://alerts/hook", AlertStatus::Resolved)]
+        );
+    }
+
+    #[test]
+    fn test_repeat_and_escalation() {
+        let mut router = router();
+        router.update(&change(true), 0);
+        router.due(0, false, |_| false);
+        let due = router.due(1800, false, |_| false);
+        assert_eq!(
+            statuses(&due),
+            vec![("http://alerts/escalate", AlertStatus::Firing)]
+        );
+        assert!(due[0].1.escalated);
+        assert_eq!(
+            statuses(&router.due(3600, false, |_| false)),
+            vec![("http://alerts/hook", AlertStatus::Firing)]
+        );
+    }
+
+    #[test]
+    fn test_deduplication() {
+        let mut router = router();
+        router.update(&change(true), 0);
+        router.due(0, false, |_| false);
+        router.update(&change(false), 60);
+        router.due(60, false, |_| false);
+        router.update(&change(true), 120);
+        assert_eq!(router.due(120, false, |_| false), vec![]);
+        router.update(&change(false), 180);
+        router.due(180, false, |_| false);
+        router.update(&change(true), 900);
+        assert_eq!(router.due(900, false, |_| false).len(), 1);
+    }
+
+    #[test]
+    fn test_deduplicated_firing_is_not_resolved() {
+        let mut router = router();
+        router.update(&change(true), 0);
+        router.due(0, false, |_| false);
+        router.update(&change(false), 60);
+        assert_eq!(
+            statuses(&router.due(60, false, |_| false)),
+            vec![("http://alerts/hook", AlertStatus::Resolved)]
+        );
+        router.update(&change(true), 120);
+        assert_eq!(router.due(120, false, |_| false), vec![]);
+        router.update(&change(false), 180);
+        assert_eq!(router.due(180, false, |_| false), vec![]);
+
+        router.update(&change(true), 240);
+        assert_eq!(router.due(540, false, |_| false), vec![]);
+        assert_eq!(
+            statuses(&router.due(600, false, |_| false)),
+            vec![("http://alerts/hook", AlertStatus::Firing)]
+        );
+    }
+
+    #[test]
+    fn test_failed_notifications_are_retried() {
+        let mut router = router();
+        router.update(&change(true), 0);
+        let due = router.due(0, false, |_| false);
+        router.failed(&due[0].1);
+        assert_eq!(
+            statuses(&router.due(60, false, |_| false)),
+            vec![("http://alerts/hook", AlertStatus::Firing)]
+        );
+
+        router.update(&change(false), 120);
+        let due = router.due(120, false, |_| false);
+        router.failed(&due[0].1);
+        assert_eq!(
+            statuses(&router.due(180, false, |_| false)),
+            vec![("http://alerts/hook", AlertStatus::Resolved)]
+        );
+        assert_eq!(router.due(240, false, |_| false), vec![]);
+    }
+
+    #[test]
+    fn test_quiet_hours_hold_back_notifications() {
+        let mut router = router();
+        router.update(&change(true), 0);
+        assert_eq!(router.due(0, true, |_| false), vec![]);
+        assert_eq!(router.due(600, false, |_| false).len(), 1);
+    }
+}
@@ -0,0 +1,157 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+
+use bsec::{OutputKind, SampleRate, SubscriptionRequest};
+use tokio::sync::mpsc;
+
+/// Switches the BSEC subscriptions between a profile for vacant and a profile
+/// for occupied periods based on an external presence trigger.
+#[derive(Clone)]
+pub struct Occupancy {
+    vacant: Vec<SubscriptionRequest>,
+    occupied: Vec<SubscriptionRequest>,
+    is_occupied: Arc<AtomicBool>,
+    update_subscription: mpsc::UnboundedSender<Vec<SubscriptionRequest>>,
+}
+
+impl Occupancy {
+    pub fn new(
+        vacant: Vec<SubscriptionRequest>,
+        occupied: Vec<SubscriptionRequest>,
+        update_subscription: mpsc::UnboundedSender<Vec<SubscriptionRequest>>,
+    ) -> Self {
+        Self {
+            vacant,
+            occupied,
+            is_occupied: Arc::new(AtomicBool::new(false)),
+            update_subscription,
+        }
+    }
+
+    pub fn is_occupied(&self) -> bool {
+        self.is_occupied.load(Ordering::Acquire)
+    }
+
+    pub fn set_occupied(
+        &self,
+        occupied: bool,
+    ) -> Result<(), mpsc::error::SendError<Vec<SubscriptionRequest>>> {
+        if self.is_occupied.swap(occupied, Ordering::AcqRel) != occupied {
+            self.update_subscription.send(self.profile(occupied))?;
+        }
+        Ok(())
+    }
+
+    /// Subscriptions of the currently active profile including disabling the
+    /// outputs only present in the inactive profile.
+    pub fn current_profile(&self) -> Vec<SubscriptionRequest> {
+        self.profile(self.is_occupied())
+    }
+
+    /// All outputs that may be provided in either of the profiles.
+    pub fn sensors(&self) -> Vec<OutputKind> {
+        let mut sensors: Vec<OutputKind> = self.vacant.iter().map(|item| item.sensor).collect();
+        for item in self.occupied.iter() {
+            if !sensors.contains(&item.sensor) {
+                sensors.push(item.sensor);
+            }
+        }
+        sensors
+    }
+
+    fn profile(&self, occupied: bool) -> Vec<SubscriptionRequest> {
+        let (active, inactive) = if occupied {
+            (&self.occupied, &self.vacant)
+        } else {
+            (&self.vacant, &self.occupied)
+        };
+        let mut requests = active.clone();
+        for item in inactive.iter() {
+            if !active.iter().any(|active| active.sensor == item.sensor) {
+                requests.push(SubscriptionRequest {
+                    sensor: item.sensor,
+                    sample_rate: SampleRate::Disabled,
+                });
+            }
+        }
+        requests
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn create_occupancy() -> (Occupancy, mpsc::UnboundedReceiver<Vec<SubscriptionRequest>>) {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        let occupancy = Occupancy::new(
+            vec![SubscriptionRequest {
+                sensor: OutputKind::RawTemperature,
+                sample_rate: SampleRate::Ulp,
+            }],
+            vec![
+                SubscriptionRequest {
+                    sensor: OutputKind::RawTemperature,
+                    sample_rate: SampleRate::Lp,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::Co2Equivalent,
+                    sample_rate: SampleRate::Lp,
+                },
+            ],
+            sender,
+        );
+        (occupancy, receiver)
+    }
+
+    #[test]
+    fn test_sensors_are_union_of_profiles() {
+        let (occupancy, _) = create_occupancy();
+        assert_eq!(
+            occupancy.sensors(),
+            vec![OutputKind::RawTemperature, OutputKind::Co2Equivalent]
+        );
+    }
+
+    #[test]
+    fn test_switching_profiles() {
+        let (occupancy, mut receiver) = create_occupancy();
+        assert!(!occupancy.is_occupied());
+
+        occupancy.set_occupied(false).unwrap();
+        assert!(receiver.try_recv().is_err());
+
+        occupancy.set_occupied(true).unwrap();
+        assert!(occupancy.is_occupied());
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            vec![
+                SubscriptionRequest {
+                    sensor: OutputKind::RawTemperature,
+                    sample_rate: SampleRate::Lp,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::Co2Equivalent,
+                    sample_rate: SampleRate::Lp,
+                },
+            ]
+        );
+
+        occupancy.set_occupied(false).unwrap();
+        assert_eq!(
+            receiver.try_recv().unwrap(),
+            vec![
+                SubscriptionRequest {
+                    sensor: OutputKind::RawTemperature,
+                    sample_rate: SampleRate::Ulp,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::Co2Equivalent,
+                    sample_rate: SampleRate::Disabled,
+                },
+            ]
+        );
+    }
+}
@@ -1,7 +1,9 @@
+use super::metrics::StateMetrics;
 use super::monitor::PersistState;
-use std::fs::File;
+use std::fs::{self, File};
 use std::io::{Read, Write};
 use std::path::Path;
+use std::time::SystemTime;
 
 #[derive(Default)]
 pub struct NoPersistState {}
@@ -26,6 +28,15 @@ impl<P: AsRef<Path>> StateFile<P> {
     pub fn new(path: P) -> Self {
         Self { path }
     }
+
+    /// Modification time and size of a non-empty state file.
+    pub fn last_save(&self) -> Option<(SystemTime, usize)> {
+        let metadata = fs::metadata(self.path.as_ref()).ok()?;
+        if metadata.len() == 0 {
+            return None;
+        }
+        Some((metadata.modified().ok()?, metadata.len() as usize))
+    }
 }
 
 impl<P: AsRef<Path>> PersistState for StateFile<P> {
@@ -36,6 +47,10 @@ impl<P: AsRef<Path>> PersistState for StateFile<P> {
             Ok(mut file) => {
                 let mut state = vec![];
                 file.read_to_end(&mut state)?;
+                // An empty file is left behind if writing the state was interrupted.
+                if state.is_empty() {
+                    return Ok(None);
+                }
                 Ok(Some(state))
             }
             Err(error) => match error.kind() {
@@ -51,9 +66,80 @@ impl<P: AsRef<Path>> PersistState for StateFile<P> {
     }
 }
 
+/// Persistence of the BSEC state in a file or, e.g. for a dry run, not at
+/// all.
+pub enum StatePersistence<P: AsRef<Path>> {
+    File(StateFile<P>),
+    None(NoPersistState),
+}
+
+impl<P: AsRef<Path>> PersistState for StatePersistence<P> {
+    type Error = std::io::Error;
+
+    fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        match self {
+            Self::File(state_file) => state_file.load_state(),
+            Self::None(no_persist_state) => {
+                no_persist_state.load_state().map_err(|err| match err {})
+            }
+        }
+    }
+
+    fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
+        match self {
+            Self::File(state_file) => state_file.save_state(state),
+            Self::None(no_persist_state) => no_persist_state
+                .save_state(state)
+                .map_err(|err| match err {}),
+        }
+    }
+}
+
+/// Records the age and size of each saved state in the [`StateMetrics`].
+pub struct MeteredPersistState<P: PersistState> {
+    persist_state: P,
+    metrics: StateMetrics,
+}
+
+impl<P: PersistState> MeteredPersistState<P> {
+    pub fn new(persist_state: P, metrics: StateMetrics) -> Self {
+        Self {
+            persist_state,
+            metrics,
+        }
+    }
+}
+
+impl<P: PersistState> PersistState for MeteredPersistState<P> {
+    type Error = P::Error;
+
+    fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.persist_state.load_state()
+    }
+
+    fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
+        self.persist_state.save_state(state)?;
+        self.metrics.record_save(SystemTime::now(), state.len());
+        Ok(())
+    }
+}
+
+/// Replaces the file at `path` with `contents` by writing a temporary file
+/// next to it and renaming it, so that an interrupted write leaves the
+/// previous file in place.
+pub fn write_atomically(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let mut file = File::create(&tmp_path)?;
+    file.write_all(contents)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, path)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::test_support::TestRng;
     use tempfile::tempdir;
 
     #[test]
@@ -72,4 +158,47 @@ mod tests {
         assert_eq!(state_file.save_state(&overwritten_state).unwrap(), ());
         assert_eq!(state_file.load_state().unwrap(), Some(overwritten_state));
     }
+
+    #[test]
+    fn test_empty_state_file_is_no_state() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("state_file");
+        File::create(&path).unwrap();
+        assert_eq!(StateFile::new(path).load_state().unwrap(), None);
+    }
+
+    #[test]
+    fn test_last_save_of_state_file() {
+        let tmp_dir = tempdir().unwrap();
+        let mut state_file = StateFile::new(tmp_dir.path().join("state_file"));
+        assert_eq!(state_file.last_save(), None);
+
+        let before = SystemTime::now() - std::time::Duration::from_secs(1);
+        state_file.save_state(&[1, 2, 3]).unwrap();
+        let (modified, bytes) = state_file.last_save().unwrap();
+        assert!(modified >= before);
+        assert_eq!(bytes, 3);
+    }
+
+    #[test]
+    fn test_write_atomically() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("file");
+        write_atomically(&path, &[1, 2]).unwrap();
+        write_atomically(&path, &[3]).unwrap();
+        assert_eq!(fs::read(&path).unwrap(), vec![3]);
+        assert_eq!(fs::read_dir(tmp_dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_arbitrary_state_roundtrips() {
+        let tmp_dir = tempdir().unwrap();
+        let mut state_file = StateFile::new(tmp_dir.path().join("state_file"));
+        let mut rng = TestRng::new(3179);
+        for _ in 0..100 {
+            let state = rng.bytes(1..1024);
+            state_file.save_state(&state).unwrap();
+            assert_eq!(state_file.load_state().unwrap(), Some(state));
+        }
+    }
 }
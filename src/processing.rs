@@ -0,0 +1,227 @@
+//! Post-processing of the BSEC outputs before they are published to the
+//! sinks.
+//!
+//! Each output can be given a chain of steps, e.g. an offset, a scale,
+//! smoothing, a unit conversion, and a rename, which are applied in the
+//! configured order. Outputs without steps are passed through unchanged. The
+//! HTTP endpoints always export the unprocessed outputs in the units of their
+//! metric names.
+
+use bsec::{Output, OutputKind};
+use serde::{Deserialize, Serialize};
+
+use crate::config::{deserialize_output_kind, output_kind_name, serialize_output_kind};
+
+/// Unit an output can be converted to from the BSEC unit.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Unit {
+    /// Temperature in °F.
+    Fahrenheit,
+    /// Temperature in K.
+    Kelvin,
+    /// Pressure in hPa.
+    Hectopascal,
+    /// Pressure in kPa.
+    Kilopascal,
+    /// Pressure in inHg.
+    InchesOfMercury,
+}
+
+impl Unit {
+    /// Whether the output can be converted to the unit.
+    pub fn applies_to(self, sensor: OutputKind) -> bool {
+        use OutputKind::*;
+        match self {
+            Unit::Fahrenheit | Unit::Kelvin => {
+                matches!(sensor, RawTemperature | SensorHeatCompensatedTemperature)
+            }
+            Unit::Hectopascal | Unit::Kilopascal | Unit::InchesOfMercury => sensor == RawPressure,
+        }
+    }
+
+    /// Converts the signal from °C or Pa.
+    fn convert(self, signal: f64) -> f64 {
+        match self {
+            Unit::Fahrenheit => signal * 1.8 + 32.,
+            Unit::Kelvin => signal + 273.15,
+            Unit::Hectopascal => signal / 100.,
+            Unit::Kilopascal => signal / 1000.,
+            Unit::InchesOfMercury => signal / 3386.389,
+        }
+    }
+}
+
+/// Step of the post-processing chain of an output.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ProcessingStep {
+    /// Adds the value to the signal.
+    Offset(f64),
+    /// Multiplies the signal with the value.
+    Scale(f64),
+    /// Exponential moving average with the given weight of the previous
+    /// value in [0, 1).
+    Smoothing(f64),
+    /// Converts the signal from the BSEC unit.
+    Unit(Unit),
+    /// Publishes the output as another output kind, e.g. on the channel a
+    /// receiver expects.
+    Rename(
+        #[serde(
+            deserialize_with = "deserialize_output_kind",
+            serialize_with = "serialize_output_kind"
+        )]
+        OutputKind,
+    ),
+}
+
+/// Post-processing steps of an output.
+#[derive(Clone, Debug, PartialEq)]
+pub struct OutputProcessing {
+    pub sensor: OutputKind,
+    pub steps: Vec<ProcessingStep>,
+}
+
+struct Chain {
+    sensor: OutputKind,
+    steps: Vec<ProcessingStep>,
+    /// Last value of each smoothing step.
+    smoothed: Vec<Option<f64>>,
+}
+
+impl Chain {
+    fn process(&mut self, mut output: Output) -> Output {
+        let mut smoothed = self.smoothed.iter_mut();
+        for step in self.steps.iter() {
+            match *step {
+                ProcessingStep::Offset(offset) => output.signal += offset,
+                ProcessingStep::Scale(scale) => output.signal *= scale,
+                ProcessingStep::Smoothing(weight) => {
+                    let last = smoothed.next().expect("missing smoothing state");
+                    let value = match *last {
+                        Some(last) => weight * last + (1. - weight) * output.signal,
+                        None => output.signal,
+                    };
+                    *last = Some(value);
+                    output.signal = value;
+                }
+                ProcessingStep::Unit(unit) => output.signal = unit.convert(output.signal),
+                ProcessingStep::Rename(sensor) => output.sensor = sensor,
+            }
+        }
+        output
+    }
+}
+
+/// Post-processing chains of the outputs.
+#[derive(Default)]
+pub struct ProcessingChain {
+    chains: Vec<Chain>,
+}
+
+impl ProcessingChain {
+    pub fn new(config: &[OutputProcessing]) -> anyhow::Result<Self> {
+        let mut chains = vec![];
+        for processing in config {
+            let name = output_kind_name(processing.sensor);
+            let mut smoothing_steps = 0;
+            for step in processing.steps.iter() {
+                match *step {
+                    ProcessingStep::Smoothing(weight) => {
+                        if !(0. ..1.).contains(&weight) {
+                            anyhow::bail!(
+                                "smoothing of {} must be in [0, 1), got {}",
+                                name,
+                                weight
+                            );
+                        }
+                        smoothing_steps += 1;
+                    }
+                    ProcessingStep::Unit(unit) if !unit.applies_to(processing.sensor) => {
+                        anyhow::bail!("{} cannot be converted to {:?}", name, unit);
+                    }
+                    _ => (),
+                }
+            }
+            chains.push(Chain {
+                sensor: processing.sensor,
+                steps: processing.steps.clone(),
+                smoothed: vec![None; smoothing_steps],
+            });
+        }
+        Ok(Self { chains })
+    }
+
+    /// Applies the chains to the `outputs`.
+    pub fn process(&mut self, outputs: &[Output]) -> Vec<Output> {
+        outputs
+            .iter()
+            .map(|output| {
+                match self
+                    .chains
+                    .iter_mut()
+                    .find(|chain| chain.sensor == output.sensor)
+                {
+                    Some(chain) => chain.process(*output),
+                    None => *output,
+                }
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::Accuracy;
+
+    fn output(sensor: OutputKind, signal: f64) -> Output {
+        Output {
+            timestamp_ns: 0,
+            signal,
+            sensor,
+            accuracy: Accuracy::HighAccuracy,
+        }
+    }
+
+    #[test]
+    fn test_processing_chain() {
+        let mut chain = ProcessingChain::new(&[OutputProcessing {
+            sensor: OutputKind::SensorHeatCompensatedTemperature,
+            steps: vec![
+                ProcessingStep::Offset(-1.),
+                ProcessingStep::Scale(2.),
+                ProcessingStep::Smoothing(0.5),
+                ProcessingStep::Unit(Unit::Kelvin),
+                ProcessingStep::Rename(OutputKind::RawTemperature),
+            ],
+        }])
+        .unwrap();
+
+        let processed = chain.process(&[
+            output(OutputKind::SensorHeatCompensatedTemperature, 11.),
+            output(OutputKind::Iaq, 50.),
+        ]);
+        assert_eq!(processed[0].sensor, OutputKind::RawTemperature);
+        assert!((processed[0].signal - 293.15).abs() < 1e-9);
+        assert_eq!(processed[1], output(OutputKind::Iaq, 50.));
+
+        let processed = chain.process(&[output(OutputKind::SensorHeatCompensatedTemperature, 21.)]);
+        assert!((processed[0].signal - 303.15).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_rejects_invalid_steps() {
+        assert!(ProcessingChain::new(&[OutputProcessing {
+            sensor: OutputKind::Iaq,
+            steps: vec![ProcessingStep::Unit(Unit::Fahrenheit)],
+        }])
+        .is_err());
+        assert!(ProcessingChain::new(&[OutputProcessing {
+            sensor: OutputKind::RawPressure,
+            steps: vec![ProcessingStep::Smoothing(1.)],
+        }])
+        .is_err());
+    }
+}
@@ -0,0 +1,351 @@
+//! Replay of recorded raw measurements.
+//!
+//! With a `[sensor.replay]` section, the inputs are read from a recording
+//! instead of being measured, e.g. to reproduce calibration issues
+//! deterministically or to regression-test the monitoring with real data.
+//! The recording is a JSON Lines file with one measurement per line:
+//!
+//! ```json
+//! {"timestamp_ns": 0, "temperature_celsius": 21.5, "pressure_hpa": 1013.2, "humidity_percent": 45.1, "gas_resistance_ohm": 120000.0}
+//! ```
+//!
+//! Each measurement returns the next record, so that BSEC processes every
+//! record exactly once. The [`ReplayClock`] provides the recorded timestamps
+//! relative to the first record to BSEC, and the measurements are delayed
+//! until the record is due at the replay speed.
+
+use std::convert::Infallible;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::clock::Clock;
+use bsec::{Input, InputKind};
+use serde::{Deserialize, Serialize};
+
+use crate::config::ReplayConfig;
+
+/// Duration of a replayed measurement without gas measurement.
+const TPH_DURATION: Duration = Duration::from_millis(10);
+
+/// Recorded raw signals of a measurement.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct RecordedMeasurement {
+    pub timestamp_ns: u64,
+    pub temperature_celsius: f64,
+    pub pressure_hpa: f64,
+    pub humidity_percent: f64,
+
+    /// Gas resistance, absent for measurements without gas measurement.
+    #[serde(default)]
+    pub gas_resistance_ohm: Option<f64>,
+}
+
+/// Loads the recorded measurements sorted by timestamp.
+pub fn load_recording<P: AsRef<Path>>(path: P) -> io::Result<Vec<RecordedMeasurement>> {
+    let mut recording = fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| {
+            serde_json::from_str(line)
+                .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+        })
+        .collect::<io::Result<Vec<RecordedMeasurement>>>()?;
+    if recording.is_empty() {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "recording without measurements",
+        ));
+    }
+    recording.sort_by_key(|measurement| measurement.timestamp_ns);
+    Ok(recording)
+}
+
+struct Position {
+    recording: Vec<RecordedMeasurement>,
+    speed: f64,
+    repeat: bool,
+    /// Index of the next record.
+    index: usize,
+    /// Time added to the recorded timestamps after the end of the recording.
+    offset_ns: u64,
+    start: Instant,
+}
+
+impl Position {
+    fn timestamp_ns(&self) -> u64 {
+        self.recording[self.index].timestamp_ns - self.recording[0].timestamp_ns + self.offset_ns
+    }
+
+    /// Interval between the last two records, continuing the timestamps
+    /// after the end of the recording.
+    fn last_interval_ns(&self) -> u64 {
+        match self.recording.as_slice() {
+            [.., second_to_last, last] => last.timestamp_ns - second_to_last.timestamp_ns,
+            _ => TPH_DURATION.as_nanos() as u64,
+        }
+    }
+
+    /// Moves to the next record. At the end of the recording, the last record
+    /// is held or the recording starts over with continuing timestamps.
+    fn advance(&mut self) {
+        if self.index + 1 < self.recording.len() {
+            self.index += 1;
+            return;
+        }
+        self.offset_ns += self.last_interval_ns();
+        if self.repeat {
+            self.offset_ns +=
+                self.recording[self.index].timestamp_ns - self.recording[0].timestamp_ns;
+            self.index = 0;
+        }
+    }
+}
+
+/// Position in a recording shared by the [`ReplaySensor`] and the
+/// [`ReplayClock`].
+#[derive(Clone)]
+pub struct Replay {
+    file: PathBuf,
+    position: Arc<Mutex<Position>>,
+}
+
+impl Replay {
+    pub fn new(recording: Vec<RecordedMeasurement>, config: &ReplayConfig) -> Self {
+        assert!(!recording.is_empty(), "recording without measurements");
+        Self {
+            file: config.file.clone(),
+            position: Arc::new(Mutex::new(Position {
+                recording,
+                speed: config.speed,
+                repeat: config.repeat,
+                index: 0,
+                offset_ns: 0,
+                start: Instant::now(),
+            })),
+        }
+    }
+
+    pub fn load(config: &ReplayConfig) -> io::Result<Self> {
+        Ok(Self::new(load_recording(&config.file)?, config))
+    }
+
+    /// File of the recording.
+    pub fn file(&self) -> &Path {
+        &self.file
+    }
+
+    /// Recorded timestamp of the next record relative to the first one.
+    pub fn timestamp_ns(&self) -> u64 {
+        self.position.lock().unwrap().timestamp_ns()
+    }
+
+    /// Time until the next record is due at the replay speed.
+    fn until_due(&self) -> Duration {
+        let position = self.position.lock().unwrap();
+        let due = Duration::from_secs_f64(position.timestamp_ns() as f64 / 1e9 / position.speed);
+        due.saturating_sub(position.start.elapsed())
+    }
+
+    /// Returns the next record and moves past it.
+    fn next_record(&self) -> RecordedMeasurement {
+        let mut position = self.position.lock().unwrap();
+        let measurement = position.recording[position.index];
+        position.advance();
+        measurement
+    }
+}
+
+/// Clock providing the recorded timestamp of the next record of a
+/// [`Replay`].
+pub struct ReplayClock(pub Replay);
+
+impl Clock for ReplayClock {
+    fn timestamp_ns(&self) -> i64 {
+        self.0.timestamp_ns() as i64
+    }
+}
+
+/// Sensor providing the inputs of a recording.
+pub struct ReplaySensor {
+    replay: Replay,
+    measurement_started: bool,
+}
+
+impl ReplaySensor {
+    pub fn new(replay: Replay) -> Self {
+        Self {
+            replay,
+            measurement_started: false,
+        }
+    }
+
+    fn inputs(measurement: &RecordedMeasurement) -> Vec<Input> {
+        let input = |sensor, signal: f64| Input {
+            sensor,
+            signal: signal as f32,
+        };
+        let mut inputs = vec![
+            input(InputKind::Temperature, measurement.temperature_celsius),
+            input(InputKind::Pressure, measurement.pressure_hpa),
+            input(InputKind::Humidity, measurement.humidity_percent),
+        ];
+        if let Some(gas_resistance) = measurement.gas_resistance_ohm {
+            inputs.push(input(InputKind::GasResistor, gas_resistance));
+        }
+        inputs.push(input(InputKind::HeatSource, 0.));
+        inputs
+    }
+}
+
+impl BmeSensor for ReplaySensor {
+    type Error = Infallible;
+
+    /// The measurement lasts until the next record is due.
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        self.measurement_started = true;
+        let duration = if settings.run_gas() {
+            TPH_DURATION + Duration::from_millis(settings.heating_duration().into())
+        } else {
+            TPH_DURATION
+        };
+        Ok(duration.max(self.replay.until_due()))
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        if !self.measurement_started {
+            panic!("must call start_measurement before get_measurement");
+        }
+        self.measurement_started = false;
+        Ok(Self::inputs(&self.replay.next_record()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    fn measurement(timestamp_s: u64, temperature_celsius: f64) -> RecordedMeasurement {
+        RecordedMeasurement {
+            timestamp_ns: timestamp_s * 1_000_000_000,
+            temperature_celsius,
+            pressure_hpa: 1013.,
+            humidity_percent: 45.,
+            gas_resistance_ohm: Some(120_000.),
+        }
+    }
+
+    fn replay(speed: f64, repeat: bool) -> Replay {
+        Replay::new(
+            vec![
+                measurement(100, 20.),
+                measurement(103, 21.),
+                measurement(106, 22.),
+            ],
+            &ReplayConfig {
+                file: "recording.jsonl".into(),
+                speed,
+                repeat,
+            },
+        )
+    }
+
+    #[test]
+    fn test_load_recording() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("recording.jsonl");
+        fs::write(
+            &path,
+            concat!(
+                r#"{"timestamp_ns": 3000000000, "temperature_celsius": 21.0, "pressure_hpa": 1013.0, "humidity_percent": 45.0}"#,
+                "\n\n",
+                r#"{"timestamp_ns": 0, "temperature_celsius": 20.0, "pressure_hpa": 1013.0, "humidity_percent": 45.0, "gas_resistance_ohm": 120000.0}"#,
+                "\n",
+            ),
+        )
+        .unwrap();
+        let recording = load_recording(&path).unwrap();
+        assert_eq!(recording.len(), 2);
+        assert_eq!(recording[0].temperature_celsius, 20.);
+        assert_eq!(recording[1].gas_resistance_ohm, None);
+    }
+
+    #[test]
+    fn test_load_empty_recording() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("recording.jsonl");
+        fs::write(&path, "").unwrap();
+        assert_eq!(
+            load_recording(&path).unwrap_err().kind(),
+            io::ErrorKind::InvalidData
+        );
+    }
+
+    #[test]
+    fn test_replays_each_record_with_its_timestamp() {
+        let replay = replay(1., false);
+        let clock = ReplayClock(replay.clone());
+        let mut records = vec![];
+        for _ in 0..5 {
+            records.push((
+                clock.timestamp_ns() / 1_000_000_000,
+                replay.next_record().temperature_celsius,
+            ));
+        }
+        assert_eq!(
+            records,
+            vec![(0, 20.), (3, 21.), (6, 22.), (9, 22.), (12, 22.)]
+        );
+    }
+
+    #[test]
+    fn test_repeats_with_continuing_timestamps() {
+        let replay = replay(1., true);
+        let clock = ReplayClock(replay.clone());
+        let mut records = vec![];
+        for _ in 0..5 {
+            records.push((
+                clock.timestamp_ns() / 1_000_000_000,
+                replay.next_record().temperature_celsius,
+            ));
+        }
+        assert_eq!(
+            records,
+            vec![(0, 20.), (3, 21.), (6, 22.), (9, 20.), (12, 21.)]
+        );
+    }
+
+    #[test]
+    fn test_records_are_due_at_the_replay_speed() {
+        let replay = replay(3., false);
+        assert_eq!(replay.until_due(), Duration::ZERO);
+        replay.next_record();
+        let until_due = replay.until_due();
+        assert!(until_due <= Duration::from_secs(1));
+        assert!(until_due > Duration::from_millis(900));
+    }
+
+    #[test]
+    fn test_inputs_without_gas() {
+        let kinds: Vec<_> = ReplaySensor::inputs(&RecordedMeasurement {
+            gas_resistance_ohm: None,
+            ..measurement(0, 20.)
+        })
+        .iter()
+        .map(|input| input.sensor)
+        .collect();
+        assert_eq!(
+            kinds,
+            vec![
+                InputKind::Temperature,
+                InputKind::Pressure,
+                InputKind::Humidity,
+                InputKind::HeatSource,
+            ]
+        );
+    }
+}
@@ -0,0 +1,407 @@
+//! Daily and weekly summary reports of the history.
+//!
+//! A report summarizes the samples of the history over the period: the
+//! minimum, maximum, and average of each output, the time the IAQ was above
+//! each threshold, and the share of time the accuracy-weighted outputs were
+//! fully calibrated. Reports are served by the `/api/v1/report` endpoint as
+//! JSON or HTML and, if configured, sent on a schedule to a webhook or by
+//! mail.
+
+use std::collections::BTreeMap;
+use std::io::{self, Write};
+use std::process::{Command, Stdio};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bsec::{Accuracy, OutputKind};
+use serde::Serialize;
+
+use crate::clock::LocalTime;
+use crate::config::{output_kind_name, EmailConfig, ReportConfig, ReportPeriod};
+use crate::history::{History, Sample};
+use crate::{log_error, log_info};
+
+/// Longest gap between two samples still attributed to the earlier one, e.g.
+/// not while the exporter was stopped.
+const MAX_GAP_SECONDS: u64 = 900;
+
+/// Interval in which the schedule is checked.
+pub const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct OutputSummary {
+    pub min: f64,
+    pub max: f64,
+    pub avg: f64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ThresholdSummary {
+    pub iaq_above: f64,
+    pub seconds: u64,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Report {
+    pub period: ReportPeriod,
+    /// Start of the period in seconds since the Unix epoch.
+    pub from: u64,
+    /// End of the period in seconds since the Unix epoch.
+    pub to: u64,
+    pub samples: usize,
+    /// Summaries by output name as used in the configuration.
+    pub outputs: BTreeMap<&'static str, OutputSummary>,
+    pub time_above_iaq: Vec<ThresholdSummary>,
+    /// Share of the time with high accuracy of the accuracy-weighted outputs,
+    /// `None` without such outputs.
+    pub accuracy_uptime: Option<f64>,
+}
+
+impl Report {
+    /// Summarizes the `samples` in the period ending at `to`.
+    pub fn generate(
+        period: ReportPeriod,
+        to: u64,
+        samples: &[Sample],
+        iaq_thresholds: &[f64],
+    ) -> Self {
+        let from = to.saturating_sub(period.seconds());
+        let samples: Vec<_> = samples
+            .iter()
+            .filter(|sample| sample.timestamp >= from && sample.timestamp < to)
+            .collect();
+        let durations: Vec<u64> = samples
+            .iter()
+            .zip(samples.iter().skip(1))
+            .map(|(sample, next)| next.timestamp - sample.timestamp)
+            .map(|gap| if gap > MAX_GAP_SECONDS { 0 } else { gap })
+            .chain(std::iter::once(0))
+            .collect();
+
+        let mut totals: BTreeMap<&'static str, (OutputSummary, usize)> = BTreeMap::new();
+        let mut time_above_iaq: Vec<_> = iaq_thresholds
+            .iter()
+            .map(|threshold| ThresholdSummary {
+                iaq_above: *threshold,
+                seconds: 0,
+            })
+            .collect();
+        let (mut accurate_seconds, mut accuracy_seconds) = (0, 0);
+        for (sample, duration) in samples.iter().zip(durations) {
+            for (sensor, signal) in sample.values.iter() {
+                let signal = f64::from(*signal);
+                if !signal.is_finite() {
+                    continue;
+                }
+                let (summary, count) = totals.entry(output_kind_name(*sensor)).or_insert((
+                    OutputSummary {
+                        min: signal,
+                        max: signal,
+                        avg: 0.,
+                    },
+                    0,
+                ));
+                summary.min = summary.min.min(signal);
+                summary.max = summary.max.max(signal);
+                summary.avg += signal;
+                *count += 1;
+                if *sensor == OutputKind::Iaq {
+                    for threshold in time_above_iaq.iter_mut() {
+                        if signal > threshold.iaq_above {
+                            threshold.seconds += duration;
+                        }
+                    }
+                }
+            }
+            if let Some(accuracy) = sample.accuracy {
+                accuracy_seconds += duration;
+                if accuracy == Accuracy::HighAccuracy {
+                    accurate_seconds += duration;
+                }
+            }
+        }
+
+        Self {
+            period,
+            from,
+            to,
+            samples: samples.len(),
+            outputs: totals
+                .into_iter()
+                .map(|(name, (summary, count))| {
+                    (
+                        name,
+                        OutputSummary {
+                            avg: summary.avg / count as f64,
+                            ..summary
+                        },
+                    )
+                })
+                .collect(),
+            time_above_iaq,
+            accuracy_uptime: Some(accurate_seconds as f64 / accuracy_seconds as f64)
+                .filter(|_| accuracy_seconds > 0),
+        }
+    }
+
+    pub fn title(&self) -> String {
+        let period = match self.period {
+            ReportPeriod::Daily => "Daily",
+            ReportPeriod::Weekly => "Weekly",
+        };
+        format!("{} air quality report", period)
+    }
+
+    /// Renders the report as standalone HTML document.
+    pub fn to_html(&self) -> String {
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html>\n<head><meta charset=\"utf-8\"><title>{title}</title></head>\n\
+             <body>\n<h1>{title}</h1>\n<p>{samples} samples from {from} to {to} (Unix time)</p>\n",
+            title = self.title(),
+            samples = self.samples,
+            from = self.from,
+            to = self.to,
+        );
+        html.push_str("<table>\n<tr><th>Output</th><th>Min</th><th>Max</th><th>Avg</th></tr>\n");
+        for (name, summary) in self.outputs.iter() {
+            html.push_str(&format!(
+                "<tr><td>{}</td><td>{:.2}</td><td>{:.2}</td><td>{:.2}</td></tr>\n",
+                name, summary.min, summary.max, summary.avg
+            ));
+        }
+        html.push_str("</table>\n<ul>\n");
+        for threshold in self.time_above_iaq.iter() {
+            html.push_str(&format!(
+                "<li>IAQ above {}: {:.1} h</li>\n",
+                threshold.iaq_above,
+                threshold.seconds as f64 / 3600.
+            ));
+        }
+        if let Some(uptime) = self.accuracy_uptime {
+            html.push_str(&format!(
+                "<li>Fully calibrated: {:.1} %</li>\n",
+                uptime * 100.
+            ));
+        }
+        html.push_str("</ul>\n</body>\n</html>\n");
+        html
+    }
+}
+
+const MINUTES_PER_DAY: u64 = 24 * 60;
+
+/// Time in seconds since the Unix epoch the report of the `period` was last
+/// scheduled at before `now`, with `local` as the local time at `now`.
+pub fn last_scheduled(period: ReportPeriod, send_at: u16, now: u64, local: LocalTime) -> u64 {
+    // Minutes since the start of the day or of the week starting on Monday.
+    let (minute, minutes_per_period) = match period {
+        ReportPeriod::Daily => (local.minute_of_day as u64, MINUTES_PER_DAY),
+        ReportPeriod::Weekly => (
+            (local.weekday as u64 + 6) % 7 * MINUTES_PER_DAY + local.minute_of_day as u64,
+            7 * MINUTES_PER_DAY,
+        ),
+    };
+    let minutes_since = (minute + minutes_per_period - send_at as u64) % minutes_per_period;
+    (now - now % 60).saturating_sub(minutes_since * 60)
+}
+
+/// Whether the report of the `period` is due at `now`, i.e. it was scheduled
+/// since it was `last_sent`, with `local` as the local time at `now`.
+///
+/// The report is thus sent on the first check at or after the scheduled time,
+/// even if a check was delayed past the scheduled minute.
+pub fn is_due(
+    period: ReportPeriod,
+    send_at: u16,
+    last_sent: u64,
+    now: u64,
+    local: LocalTime,
+) -> bool {
+    last_scheduled(period, send_at, now, local) > last_sent
+}
+
+/// Mails the HTML report with the `sendmail` command.
+pub fn send_mail(config: &EmailConfig, report: &Report) -> io::Result<()> {
+    let mut child = Command::new(&config.sendmail_command)
+        .arg("-t")
+        .stdin(Stdio::piped())
+        .spawn()?;
+    let mut message = format!("To: {}\r\n", config.to);
+    if let Some(from) = &config.from {
+        message.push_str(&format!("From: {}\r\n", from));
+    }
+    message.push_str(&format!(
+        "Subject: {}\r\nMIME-Version: 1.0\r\nContent-Type: text/html; charset=utf-8\r\n\r\n{}",
+        report.title(),
+        report.to_html()
+    ));
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(message.as_bytes())?;
+    }
+    let status = child.wait()?;
+    if !status.success() {
+        return Err(io::Error::other(format!(
+            "{} exited with {}",
+            config.sendmail_command, status
+        )));
+    }
+    Ok(())
+}
+
+fn unix_now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Sends the report to the configured destinations.
+async fn send(config: &ReportConfig, report: Report) {
+    #[cfg(feature = "http-client")]
+    if let Some(url) = &config.webhook_url {
+        let result = match serde_json::to_string(&report) {
+            Ok(body) => crate::http_client::post_json(url, body).await,
+            Err(err) => Err(err.into()),
+        };
+        if let Err(err) = result {
+            log_error!("Failed to post the report to {}: {}", url, err);
+        }
+    }
+    if let Some(email) = config.email.clone() {
+        let result = tokio::task::spawn_blocking(move || send_mail(&email, &report)).await;
+        match result {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => log_error!("Failed to mail the report: {}", err),
+            Err(err) => log_error!("Failed to mail the report: {}", err),
+        }
+    }
+}
+
+/// Sends the reports of the `history` on the configured schedule.
+pub async fn run(config: ReportConfig, history: History) {
+    let mut interval = tokio::time::interval(CHECK_INTERVAL);
+    let mut last_sent = unix_now();
+    loop {
+        interval.tick().await;
+        let now = unix_now();
+        if !is_due(
+            config.period,
+            config.send_at,
+            last_sent,
+            now,
+            LocalTime::at(now),
+        ) {
+            continue;
+        }
+        last_sent = now;
+        let report = Report::generate(
+            config.period,
+            now,
+            &history.samples(),
+            &config.iaq_thresholds,
+        );
+        log_info!("Sending the {}.", report.title().to_lowercase());
+        send(&config, report).await;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample(timestamp: u64, iaq: f32, accuracy: Accuracy) -> Sample {
+        Sample {
+            timestamp,
+            values: vec![(OutputKind::Iaq, iaq)],
+            accuracy: Some(accuracy),
+        }
+    }
+
+    #[test]
+    fn test_generate() {
+        let to = 100_000;
+        let samples = vec![
+            sample(to - 90_000, 500., Accuracy::HighAccuracy),
+            sample(to - 600, 50., Accuracy::LowAccuracy),
+            sample(to - 300, 150., Accuracy::HighAccuracy),
+            sample(to - 240, 250., Accuracy::HighAccuracy),
+            sample(to - 180, 100., Accuracy::HighAccuracy),
+            sample(to - 120, 100., Accuracy::HighAccuracy),
+        ];
+        let report = Report::generate(ReportPeriod::Daily, to, &samples, &[100., 200.]);
+        assert_eq!(report.from, to - 24 * 3600);
+        assert_eq!(report.samples, 5);
+        assert_eq!(
+            report.outputs["iaq"],
+            OutputSummary {
+                min: 50.,
+                max: 250.,
+                avg: 130.,
+            }
+        );
+        assert_eq!(
+            report.time_above_iaq,
+            vec![
+                ThresholdSummary {
+                    iaq_above: 100.,
+                    seconds: 120,
+                },
+                ThresholdSummary {
+                    iaq_above: 200.,
+                    seconds: 60,
+                },
+            ]
+        );
+        assert_eq!(report.accuracy_uptime, Some(180. / 480.));
+        assert!(report.to_html().contains("<td>iaq</td>"));
+    }
+
+    #[test]
+    fn test_is_due() {
+        let now = 1_000 * 7 * 24 * 3600 + 8 * 3600;
+        let monday = LocalTime {
+            weekday: 1,
+            minute_of_day: 8 * 60,
+        };
+        let tuesday = LocalTime {
+            weekday: 2,
+            ..monday
+        };
+        assert!(is_due(ReportPeriod::Daily, 8 * 60, now - 60, now, tuesday));
+        assert!(!is_due(ReportPeriod::Daily, 8 * 60, now, now, tuesday));
+        assert!(!is_due(ReportPeriod::Daily, 9 * 60, now - 60, now, tuesday));
+        assert!(is_due(ReportPeriod::Weekly, 8 * 60, now - 60, now, monday));
+        assert!(!is_due(
+            ReportPeriod::Weekly,
+            8 * 60,
+            now - 60,
+            now,
+            tuesday
+        ));
+    }
+
+    #[test]
+    fn test_is_due_after_missed_minute() {
+        let now = 1_000 * 7 * 24 * 3600 + 8 * 3600 + 5 * 60 + 30;
+        let tuesday = LocalTime {
+            weekday: 2,
+            minute_of_day: 8 * 60 + 5,
+        };
+        let last_sent = now - 24 * 3600;
+        assert_eq!(
+            last_scheduled(ReportPeriod::Daily, 8 * 60, now, tuesday),
+            now - 5 * 60 - 30
+        );
+        assert!(is_due(ReportPeriod::Daily, 8 * 60, last_sent, now, tuesday));
+        assert_eq!(
+            last_scheduled(ReportPeriod::Weekly, 8 * 60, now, tuesday),
+            now - 24 * 3600 - 5 * 60 - 30
+        );
+        assert!(!is_due(
+            ReportPeriod::Weekly,
+            8 * 60,
+            last_sent,
+            now,
+            tuesday
+        ));
+    }
+}
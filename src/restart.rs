@@ -0,0 +1,68 @@
+use std::collections::VecDeque;
+use std::sync::Arc;
+use std::time::Duration;
+
+use bsec::clock::Clock;
+
+/// Limits the number of restarts within a sliding time window.
+pub struct RestartLimiter<C: Clock> {
+    max_restarts: usize,
+    window_ns: i64,
+    restarts: VecDeque<i64>,
+    clock: Arc<C>,
+}
+
+impl<C: Clock> RestartLimiter<C> {
+    pub fn new(max_restarts: usize, window: Duration, clock: Arc<C>) -> Self {
+        Self {
+            max_restarts,
+            window_ns: window.as_nanos() as i64,
+            restarts: VecDeque::with_capacity(max_restarts),
+            clock,
+        }
+    }
+
+    /// Records a restart and returns `true` if it is within the limit.
+    pub fn try_restart(&mut self) -> bool {
+        let now = self.clock.timestamp_ns();
+        while let Some(&oldest) = self.restarts.front() {
+            if now - oldest < self.window_ns {
+                break;
+            }
+            self.restarts.pop_front();
+        }
+        if self.restarts.len() >= self.max_restarts {
+            return false;
+        }
+        self.restarts.push_back(now);
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::clock::test_support::FakeClock;
+
+    #[test]
+    fn test_limits_restarts_within_window() {
+        let clock = Arc::new(FakeClock::new());
+        let mut limiter = RestartLimiter::new(2, Duration::from_secs(3600), clock.clone());
+
+        assert!(limiter.try_restart());
+        clock.advance_by(Duration::from_secs(600));
+        assert!(limiter.try_restart());
+        assert!(!limiter.try_restart());
+
+        clock.advance_by(Duration::from_secs(3000));
+        assert!(limiter.try_restart());
+        assert!(!limiter.try_restart());
+    }
+
+    #[test]
+    fn test_zero_restarts_allowed() {
+        let mut limiter =
+            RestartLimiter::new(0, Duration::from_secs(3600), Arc::new(FakeClock::new()));
+        assert!(!limiter.try_restart());
+    }
+}
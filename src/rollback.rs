@@ -0,0 +1,283 @@
+//! Rollout of configuration changes with automatic rollback.
+//!
+//! The last configuration that produced BSEC outputs is kept as last-good
+//! copy next to the configuration file. A changed configuration is pending
+//! until it produces the first output. The exporter rolls back to the
+//! last-good configuration if a changed configuration fails to parse or is
+//! still pending after the grace period, e.g. because the exporter keeps
+//! failing and being restarted by systemd. The marker of the pending
+//! configuration is kept next to the BSEC state file, so that a read-only
+//! configuration directory does not prevent the start.
+
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::bsec_config::fingerprint;
+use crate::config::{Config, RollbackConfig};
+use crate::{log_error, log_warn};
+
+/// Configuration chosen by [`ConfigRollout::load`].
+#[derive(Clone, Debug)]
+pub struct LoadedConfig {
+    pub config: Config,
+    source: String,
+    /// The reason if the last-good configuration is used instead of the
+    /// current one.
+    pub rolled_back: Option<String>,
+    /// Whether the configuration has not produced outputs yet.
+    pub pending: bool,
+    /// Whether there is a last-good configuration to roll back to.
+    pub can_roll_back: bool,
+}
+
+pub struct ConfigRollout {
+    path: PathBuf,
+}
+
+impl ConfigRollout {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().into(),
+        }
+    }
+
+    fn sibling(&self, suffix: &str) -> PathBuf {
+        let mut name = self
+            .path
+            .file_name()
+            .map(OsString::from)
+            .unwrap_or_default();
+        name.push(suffix);
+        self.path.with_file_name(name)
+    }
+
+    fn last_good_path(&self) -> PathBuf {
+        self.sibling(".last-good")
+    }
+
+    /// Marker of the pending `config` next to its BSEC state file.
+    fn pending_path(config: &Config) -> PathBuf {
+        Path::new(&config.bsec.state_file).with_file_name("config.pending")
+    }
+
+    /// Time since the configuration with the given fingerprint became pending.
+    fn pending_since(config: &Config, fingerprint: u64) -> Option<Duration> {
+        let pending = Self::pending_path(config);
+        let content = fs::read_to_string(&pending).ok()?;
+        if u64::from_str_radix(content.trim(), 16).ok()? != fingerprint {
+            return None;
+        }
+        let modified = fs::metadata(&pending).ok()?.modified().ok()?;
+        Some(
+            SystemTime::now()
+                .duration_since(modified)
+                .unwrap_or_default(),
+        )
+    }
+
+    fn roll_back(&self, last_good: String, reason: String) -> anyhow::Result<LoadedConfig> {
+        log_warn!(
+            "Rolling back to the last-good configuration {}: {}",
+            self.last_good_path().display(),
+            reason
+        );
+        Ok(LoadedConfig {
+            config: toml::from_str(&last_good)?,
+            source: last_good,
+            rolled_back: Some(reason),
+            pending: false,
+            can_roll_back: false,
+        })
+    }
+
+    /// Loads the current configuration or rolls back to the last-good
+    /// configuration.
+    pub fn load(&self) -> anyhow::Result<LoadedConfig> {
+        let source = fs::read_to_string(&self.path)?;
+        let last_good = fs::read_to_string(self.last_good_path()).ok();
+        if last_good.as_ref() == Some(&source) {
+            return Ok(LoadedConfig {
+                config: toml::from_str(&source)?,
+                source,
+                rolled_back: None,
+                pending: false,
+                can_roll_back: false,
+            });
+        }
+
+        let config: Config = match (toml::from_str(&source), &last_good) {
+            (Ok(config), _) => config,
+            (Err(err), Some(last_good)) => {
+                return self.roll_back(last_good.clone(), format!("invalid configuration: {}", err))
+            }
+            (Err(err), None) => return Err(err.into()),
+        };
+        let grace_period = match (&config.rollback, &last_good) {
+            (Some(rollback), _) => Duration::from_secs(rollback.grace_period_seconds),
+            (None, Some(_)) => Duration::from_secs(RollbackConfig::default().grace_period_seconds),
+            (None, None) => {
+                return Ok(LoadedConfig {
+                    config,
+                    source,
+                    rolled_back: None,
+                    pending: false,
+                    can_roll_back: false,
+                })
+            }
+        };
+
+        let fingerprint = fingerprint(source.as_bytes());
+        match (Self::pending_since(&config, fingerprint), last_good) {
+            (Some(since), Some(last_good)) if since > grace_period => self.roll_back(
+                last_good,
+                format!("no outputs within {} s", grace_period.as_secs()),
+            ),
+            (since, last_good) => {
+                if since.is_none() {
+                    let pending = Self::pending_path(&config);
+                    if let Err(err) = fs::write(&pending, format!("{:016x}\n", fingerprint)) {
+                        log_error!(
+                            "Failed to mark the configuration as pending in {}: {}",
+                            pending.display(),
+                            err
+                        );
+                    }
+                }
+                Ok(LoadedConfig {
+                    config,
+                    source,
+                    rolled_back: None,
+                    pending: true,
+                    can_roll_back: last_good.is_some(),
+                })
+            }
+        }
+    }
+
+    /// Keeps a pending configuration as last-good configuration once it
+    /// produced outputs.
+    pub fn confirm(&self, loaded: &LoadedConfig) -> io::Result<()> {
+        if !loaded.pending {
+            return Ok(());
+        }
+        fs::write(self.last_good_path(), &loaded.source)?;
+        match fs::remove_file(Self::pending_path(&loaded.config)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    /// Configuration of the `device` with the BSEC state in `dir`.
+    fn config(dir: &Path, device: &str, grace_period_seconds: Option<u64>) -> String {
+        let mut config = format!(
+            "[sensor]\ndevice = \"{}\"\n\n[bsec]\nstate_file = \"{}\"\n",
+            device,
+            dir.join("bsec-state.bin").display()
+        );
+        if let Some(seconds) = grace_period_seconds {
+            config.push_str(&format!(
+                "\n[rollback]\ngrace_period_seconds = {}\n",
+                seconds
+            ));
+        }
+        config
+    }
+
+    #[test]
+    fn test_confirm_keeps_last_good() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        let good = config(tmp_dir.path(), "/dev/i2c-1", Some(0));
+        fs::write(&path, &good).unwrap();
+        let rollout = ConfigRollout::new(&path);
+
+        let loaded = rollout.load().unwrap();
+        assert!(loaded.pending);
+        assert!(!loaded.can_roll_back);
+        assert_eq!(loaded.rolled_back, None);
+        assert!(tmp_dir.path().join("config.pending").exists());
+        rollout.confirm(&loaded).unwrap();
+
+        assert_eq!(fs::read_to_string(rollout.last_good_path()).unwrap(), good);
+        assert!(!tmp_dir.path().join("config.pending").exists());
+        assert!(!rollout.load().unwrap().pending);
+    }
+
+    #[test]
+    fn test_without_rollback_config() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        fs::write(&path, config(tmp_dir.path(), "/dev/i2c-2", None)).unwrap();
+
+        let loaded = ConfigRollout::new(&path).load().unwrap();
+        assert!(!loaded.pending);
+        assert_eq!(loaded.rolled_back, None);
+    }
+
+    #[test]
+    fn test_rolls_back_invalid_config() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        let rollout = ConfigRollout::new(&path);
+        fs::write(
+            rollout.last_good_path(),
+            config(tmp_dir.path(), "/dev/i2c-1", Some(0)),
+        )
+        .unwrap();
+        fs::write(&path, "[sensor").unwrap();
+
+        let loaded = rollout.load().unwrap();
+        assert_eq!(loaded.config.sensor.device, "/dev/i2c-1");
+        assert!(loaded
+            .rolled_back
+            .unwrap()
+            .starts_with("invalid configuration"));
+    }
+
+    #[test]
+    fn test_rolls_back_config_pending_beyond_grace_period() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        let rollout = ConfigRollout::new(&path);
+        fs::write(
+            rollout.last_good_path(),
+            config(tmp_dir.path(), "/dev/i2c-1", Some(0)),
+        )
+        .unwrap();
+        fs::write(&path, config(tmp_dir.path(), "/dev/i2c-2", Some(0))).unwrap();
+
+        let loaded = rollout.load().unwrap();
+        assert!(loaded.pending);
+        assert!(loaded.can_roll_back);
+        assert_eq!(loaded.config.sensor.device, "/dev/i2c-2");
+
+        std::thread::sleep(Duration::from_millis(10));
+        let loaded = rollout.load().unwrap();
+        assert!(!loaded.pending);
+        assert_eq!(loaded.config.sensor.device, "/dev/i2c-1");
+        assert_eq!(loaded.rolled_back.unwrap(), "no outputs within 0 s");
+    }
+
+    #[test]
+    fn test_starts_with_unwritable_pending_marker() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("config.toml");
+        fs::write(
+            &path,
+            config(&tmp_dir.path().join("missing"), "/dev/i2c-1", Some(60)),
+        )
+        .unwrap();
+
+        let loaded = ConfigRollout::new(&path).load().unwrap();
+        assert!(loaded.pending);
+    }
+}
@@ -0,0 +1,122 @@
+//! Room-level aggregation of the metrics of several sensors.
+//!
+//! The gauges of the sensors in a room, the local one and the peers' fetched
+//! from their `/metrics/json` endpoint, are averaged and exported in addition
+//! to the per-sensor series. Dashboards of rooms with multiple sensors thus
+//! need a single series per metric.
+
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::config::RoomConfig;
+use crate::consistency::{gauge_values, parse_peer_values};
+use crate::http_client;
+use crate::log_error;
+use crate::metrics::BsecGaugeRegistry;
+
+/// Average of a metric across the sensors of a room.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoomAverage {
+    pub metric: String,
+    pub value: f64,
+    /// Number of sensors providing the metric.
+    pub sensors: usize,
+}
+
+/// Averages the `metrics` across the `sensors` providing them, ignoring NaN
+/// values.
+pub fn room_averages(sensors: &[HashMap<String, f64>], metrics: &[String]) -> Vec<RoomAverage> {
+    metrics
+        .iter()
+        .filter_map(|metric| {
+            let values: Vec<f64> = sensors
+                .iter()
+                .filter_map(|values| values.get(metric))
+                .copied()
+                .filter(|value| !value.is_nan())
+                .collect();
+            if values.is_empty() {
+                return None;
+            }
+            Some(RoomAverage {
+                metric: metric.clone(),
+                value: values.iter().sum::<f64>() / values.len() as f64,
+                sensors: values.len(),
+            })
+        })
+        .collect()
+}
+
+/// Periodically aggregates the metrics of the sensors in the room.
+pub async fn run_room_aggregation(config: RoomConfig, registry: BsecGaugeRegistry) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(config.interval_seconds));
+    loop {
+        ticks.tick().await;
+        let mut sensors = Vec::with_capacity(config.peers.len() + 1);
+        if config.local {
+            sensors.push(gauge_values(&registry.gather()));
+        }
+        for peer in config.peers.iter() {
+            let peer_values = match http_client::get(peer).await {
+                Ok(body) => parse_peer_values(&body).map_err(anyhow::Error::from),
+                Err(err) => Err(err),
+            };
+            match peer_values {
+                Ok(peer_values) => sensors.push(peer_values),
+                Err(err) => log_error!("Failed to fetch metrics of peer {}: {}", peer, err),
+            }
+        }
+        for average in room_averages(&sensors, &config.metrics) {
+            registry.set_room_average(
+                &config.name,
+                &average.metric,
+                average.value,
+                average.sensors,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_room_averages() {
+        let sensors: Vec<HashMap<_, _>> = vec![
+            vec![
+                ("temperature_celsius".to_string(), 22.),
+                ("humidity_percent".to_string(), f64::NAN),
+            ]
+            .into_iter()
+            .collect(),
+            vec![
+                ("temperature_celsius".to_string(), 21.),
+                ("humidity_percent".to_string(), 40.),
+            ]
+            .into_iter()
+            .collect(),
+        ];
+        let metrics = vec![
+            "humidity_percent".to_string(),
+            "temperature_celsius".to_string(),
+            "iaq".to_string(),
+        ];
+
+        assert_eq!(
+            room_averages(&sensors, &metrics),
+            vec![
+                RoomAverage {
+                    metric: "humidity_percent".into(),
+                    value: 40.,
+                    sensors: 1,
+                },
+                RoomAverage {
+                    metric: "temperature_celsius".into(),
+                    value: 21.5,
+                    sensors: 2,
+                },
+            ]
+        );
+    }
+}
@@ -0,0 +1,359 @@
+//! Capabilities and corrections of the physical sensors.
+
+use std::fmt::{self, Debug, Display, Formatter};
+use std::time::Duration;
+
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::{Input, InputKind, OutputKind, RequiredInput};
+use embedded_hal::blocking::delay::DelayMs;
+use embedded_hal::blocking::i2c::{Read, Write};
+use libalgobsec_sys::BSEC_SAMPLE_RATE_DISABLED;
+
+use crate::bme280::Bme280Sensor;
+use crate::bme680::Bme680Sensor;
+use crate::config::{output_kind_name, SensorConfig, SensorModel};
+use crate::log_warn;
+
+/// Inputs provided by the [`Bme680Sensor`](crate::bme680::Bme680Sensor).
+///
+/// The oversampling and heater settings for each measurement are taken from
+/// BSEC's sensor control and thus always match the subscribed outputs.
+pub const BME680_INPUTS: [InputKind; 5] = [
+    InputKind::Temperature,
+    InputKind::Pressure,
+    InputKind::Humidity,
+    InputKind::GasResistor,
+    InputKind::HeatSource,
+];
+
+/// Inputs provided by the [`Bme280Sensor`].
+pub const BME280_INPUTS: [InputKind; 4] = [
+    InputKind::Temperature,
+    InputKind::Pressure,
+    InputKind::Humidity,
+    InputKind::HeatSource,
+];
+
+/// Inputs provided by the [`Bme280Sensor`] with a BMP280.
+pub const BMP280_INPUTS: [InputKind; 3] = [
+    InputKind::Temperature,
+    InputKind::Pressure,
+    InputKind::HeatSource,
+];
+
+/// Inputs provided by the sensor `model`.
+pub fn provided_inputs(model: SensorModel) -> &'static [InputKind] {
+    match model {
+        SensorModel::Bme680 => &BME680_INPUTS,
+        SensorModel::Bme280 => &BME280_INPUTS,
+        SensorModel::Bmp280 => &BMP280_INPUTS,
+    }
+}
+
+/// Inputs provided by the sensor `model` together with the `auxiliary`
+/// inputs.
+pub fn provided_inputs_with(model: SensorModel, auxiliary: &[InputKind]) -> Vec<InputKind> {
+    let mut inputs = provided_inputs(model).to_vec();
+    for input in auxiliary {
+        if !inputs.contains(input) {
+            inputs.push(*input);
+        }
+    }
+    inputs
+}
+
+/// Physical inputs the BSEC `output` is computed from.
+fn output_inputs(output: OutputKind) -> &'static [InputKind] {
+    match output {
+        OutputKind::RawTemperature | OutputKind::SensorHeatCompensatedTemperature => {
+            &[InputKind::Temperature]
+        }
+        OutputKind::RawPressure => &[InputKind::Pressure],
+        OutputKind::RawHumidity | OutputKind::SensorHeatCompensatedHumidity => {
+            &[InputKind::Humidity]
+        }
+        OutputKind::Iaq
+        | OutputKind::StaticIaq
+        | OutputKind::Co2Equivalent
+        | OutputKind::BreathVocEquivalent
+        | OutputKind::RawGas
+        | OutputKind::StabilizationStatus
+        | OutputKind::RunInStatus
+        | OutputKind::GasPercentage => &[InputKind::GasResistor],
+    }
+}
+
+/// Subscription to outputs that the sensor cannot provide.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct UnsupportedOutputs {
+    pub model: SensorModel,
+    pub outputs: Vec<OutputKind>,
+}
+
+impl Display for UnsupportedOutputs {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        let names: Vec<&str> = self
+            .outputs
+            .iter()
+            .map(|output| output_kind_name(*output))
+            .collect();
+        write!(
+            f,
+            "the {} sensor does not measure the inputs of the subscribed outputs {}; remove them \
+             from the subscriptions",
+            self.model,
+            names.join(", ")
+        )
+    }
+}
+
+impl std::error::Error for UnsupportedOutputs {}
+
+/// Checks that the sensor `model` or the `auxiliary` inputs provide the
+/// inputs of all `outputs`.
+///
+/// BSEC itself only fails later with an unspecific error when the inputs of
+/// the subscribed outputs are missing.
+pub fn check_supported_outputs(
+    model: SensorModel,
+    auxiliary: &[InputKind],
+    outputs: &[OutputKind],
+) -> Result<(), UnsupportedOutputs> {
+    let provided = provided_inputs_with(model, auxiliary);
+    let unsupported: Vec<OutputKind> = outputs
+        .iter()
+        .filter(|output| {
+            output_inputs(**output)
+                .iter()
+                .any(|input| !provided.contains(input))
+        })
+        .copied()
+        .collect();
+    if unsupported.is_empty() {
+        Ok(())
+    } else {
+        Err(UnsupportedOutputs {
+            model,
+            outputs: unsupported,
+        })
+    }
+}
+
+/// Either sensor model attached to the hardware.
+pub enum HardwareSensor<I2C, D>
+where
+    I2C: Read + Write,
+    D: DelayMs<u8>,
+{
+    Bme680(Bme680Sensor<I2C, D>),
+    Bme280(Bme280Sensor<I2C>),
+}
+
+impl<I2C, D> BmeSensor for HardwareSensor<I2C, D>
+where
+    I2C: Read + Write,
+    D: DelayMs<u8>,
+    <I2C as Read>::Error: Debug,
+    <I2C as Write>::Error: Debug,
+{
+    type Error = bme680::Error<<I2C as Read>::Error, <I2C as Write>::Error>;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        match self {
+            Self::Bme680(sensor) => sensor.start_measurement(settings),
+            Self::Bme280(sensor) => sensor.start_measurement(settings),
+        }
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        match self {
+            Self::Bme680(sensor) => sensor.get_measurement(),
+            Self::Bme280(sensor) => sensor.get_measurement(),
+        }
+    }
+}
+
+/// Required inputs that are not among the `provided` inputs.
+pub fn unsatisfied_inputs(
+    required: &[RequiredInput],
+    provided: &[InputKind],
+) -> Vec<RequiredInput> {
+    required
+        .iter()
+        .filter(|input| {
+            input.sample_rate < BSEC_SAMPLE_RATE_DISABLED as f32
+                && !provided.contains(&input.sensor)
+        })
+        .cloned()
+        .collect()
+}
+
+/// Logs a warning for each required input that is not provided.
+///
+/// Returns `true` if all required inputs are provided.
+pub fn check_required_inputs(required: &[RequiredInput], provided: &[InputKind]) -> bool {
+    let unsatisfied = unsatisfied_inputs(required, provided);
+    for input in unsatisfied.iter() {
+        log_warn!(
+            input_kind = format!("{:?}", input.sensor);
+            "BSEC requires {:?} input at {} Hz, but the sensor does not provide it.",
+            input.sensor, input.sample_rate
+        );
+    }
+    unsatisfied.is_empty()
+}
+
+/// Linear correction of the humidity readings in %RH.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct HumidityCorrection {
+    pub offset_percent: f32,
+    pub scale: f32,
+}
+
+impl HumidityCorrection {
+    pub fn from_config(config: &SensorConfig) -> Self {
+        Self {
+            offset_percent: config.humidity_offset_percent,
+            scale: config.humidity_scale,
+        }
+    }
+
+    /// Corrected humidity, limited to the range of 0 to 100%RH.
+    pub fn apply(&self, humidity_percent: f32) -> f32 {
+        (humidity_percent * self.scale + self.offset_percent).clamp(0., 100.)
+    }
+}
+
+impl Default for HumidityCorrection {
+    fn default() -> Self {
+        Self {
+            offset_percent: 0.,
+            scale: 1.,
+        }
+    }
+}
+
+/// Applies a [`HumidityCorrection`] to the humidity input of the wrapped
+/// sensor. The exported raw humidity is thus the corrected humidity as well.
+pub struct CorrectedSensor<S: BmeSensor> {
+    sensor: S,
+    humidity: HumidityCorrection,
+}
+
+impl<S: BmeSensor> CorrectedSensor<S> {
+    pub fn new(sensor: S, humidity: HumidityCorrection) -> Self {
+        Self { sensor, humidity }
+    }
+}
+
+impl<S: BmeSensor> BmeSensor for CorrectedSensor<S> {
+    type Error = S::Error;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        self.sensor.start_measurement(settings)
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        let mut inputs = self.sensor.get_measurement()?;
+        for input in inputs.iter_mut() {
+            if input.sensor == InputKind::Humidity {
+                input.signal = self.humidity.apply(input.signal);
+            }
+        }
+        Ok(inputs)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::FakeBmeSensor;
+
+    #[test]
+    fn test_unsatisfied_inputs() {
+        let required = [
+            RequiredInput {
+                sample_rate: 1. / 3.,
+                sensor: InputKind::Temperature,
+            },
+            RequiredInput {
+                sample_rate: 1. / 3.,
+                sensor: InputKind::DisableBaselineTracker,
+            },
+            RequiredInput {
+                sample_rate: BSEC_SAMPLE_RATE_DISABLED as f32,
+                sensor: InputKind::Other(42),
+            },
+        ];
+        assert_eq!(
+            unsatisfied_inputs(&required, &BME680_INPUTS),
+            vec![required[1]]
+        );
+        assert!(!check_required_inputs(&required, &BME680_INPUTS));
+        assert!(check_required_inputs(&required[..1], &BME680_INPUTS));
+    }
+
+    #[test]
+    fn test_check_supported_outputs() {
+        let outputs = [
+            OutputKind::RawTemperature,
+            OutputKind::RawHumidity,
+            OutputKind::Iaq,
+        ];
+        assert_eq!(
+            check_supported_outputs(SensorModel::Bme680, &[], &outputs),
+            Ok(())
+        );
+        let err = check_supported_outputs(SensorModel::Bme280, &[], &outputs).unwrap_err();
+        assert_eq!(err.outputs, vec![OutputKind::Iaq]);
+        assert_eq!(
+            err.to_string(),
+            "the BME280 sensor does not measure the inputs of the subscribed outputs iaq; remove \
+             them from the subscriptions"
+        );
+        assert_eq!(
+            check_supported_outputs(SensorModel::Bmp280, &[], &outputs[..2])
+                .unwrap_err()
+                .outputs,
+            vec![OutputKind::RawHumidity]
+        );
+        assert_eq!(
+            check_supported_outputs(SensorModel::Bmp280, &[InputKind::Humidity], &outputs[..2]),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_corrected_sensor() {
+        let mut sensor = CorrectedSensor::new(
+            FakeBmeSensor::new(Ok(vec![
+                Input {
+                    sensor: InputKind::Temperature,
+                    signal: 22.,
+                },
+                Input {
+                    sensor: InputKind::Humidity,
+                    signal: 40.,
+                },
+            ])),
+            HumidityCorrection {
+                offset_percent: 5.,
+                scale: 1.1,
+            },
+        );
+
+        let inputs = sensor.get_measurement().unwrap();
+        assert_eq!(inputs[0].signal, 22.);
+        assert!((inputs[1].signal - 49.).abs() < 1e-4);
+    }
+
+    #[test]
+    fn test_humidity_correction_is_limited() {
+        let correction = HumidityCorrection {
+            offset_percent: 10.,
+            scale: 1.,
+        };
+        assert_eq!(correction.apply(95.), 100.);
+        assert_eq!(HumidityCorrection::default().apply(-1.), 0.);
+    }
+}
@@ -0,0 +1,189 @@
+//! Simulated sensor for running the exporter without a BME680.
+//!
+//! With `device = "simulated"` in the `[sensor]` section, the inputs are
+//! generated from sine waves around a mean instead of being measured, e.g.
+//! to develop dashboards on a machine without the sensor attached. BSEC
+//! processes the synthetic inputs like real ones.
+
+use std::convert::Infallible;
+use std::f64::consts::TAU;
+use std::time::{Duration, Instant};
+
+use bsec::bme::{BmeSensor, BmeSettingsHandle};
+use bsec::{Input, InputKind};
+use serde::{Deserialize, Serialize};
+
+use crate::config::SimulationConfig;
+use crate::replay::ReplaySensor;
+
+/// Value of `device` selecting the simulated sensor.
+pub const SIMULATED_DEVICE: &str = "simulated";
+
+/// Duration of a simulated measurement without gas measurement.
+const TPH_DURATION: Duration = Duration::from_millis(10);
+
+/// Sine wave with the given period around the mean, constant for a period of
+/// 0.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Waveform {
+    pub mean: f64,
+
+    #[serde(default)]
+    pub amplitude: f64,
+
+    #[serde(default)]
+    pub period_seconds: f64,
+}
+
+impl Waveform {
+    /// Value of the waveform the `elapsed` time after the start.
+    pub fn value(&self, elapsed: Duration) -> f64 {
+        if self.period_seconds <= 0. {
+            return self.mean;
+        }
+        let phase = elapsed.as_secs_f64() / self.period_seconds;
+        self.mean + self.amplitude * (TAU * phase).sin()
+    }
+}
+
+/// Sensor providing the inputs of the configured waveforms.
+pub struct SimulatedSensor {
+    config: SimulationConfig,
+    start: Instant,
+    measurement_started: Option<Instant>,
+}
+
+impl SimulatedSensor {
+    pub fn new(config: SimulationConfig) -> Self {
+        Self {
+            config,
+            start: Instant::now(),
+            measurement_started: None,
+        }
+    }
+
+    fn inputs(&self, elapsed: Duration) -> Vec<Input> {
+        let input = |sensor, waveform: &Waveform| Input {
+            sensor,
+            signal: waveform.value(elapsed) as f32,
+        };
+        vec![
+            input(InputKind::Temperature, &self.config.temperature_celsius),
+            input(InputKind::Pressure, &self.config.pressure_hpa),
+            input(InputKind::Humidity, &self.config.humidity_percent),
+            input(InputKind::GasResistor, &self.config.gas_resistance_ohm),
+            Input {
+                sensor: InputKind::HeatSource,
+                signal: 0.,
+            },
+        ]
+    }
+}
+
+impl BmeSensor for SimulatedSensor {
+    type Error = Infallible;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        self.measurement_started = Some(Instant::now());
+        if settings.run_gas() {
+            Ok(TPH_DURATION + Duration::from_millis(settings.heating_duration().into()))
+        } else {
+            Ok(TPH_DURATION)
+        }
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        match self.measurement_started {
+            None => panic!("must call start_measurement before get_measurement"),
+            Some(started) => Ok(self.inputs(started.duration_since(self.start))),
+        }
+    }
+}
+
+fn infallible<E>(err: nb::Error<Infallible>) -> nb::Error<E> {
+    match err {
+        nb::Error::WouldBlock => nb::Error::WouldBlock,
+        nb::Error::Other(err) => match err {},
+    }
+}
+
+/// Either a sensor attached to the hardware, the simulated sensor, or the
+/// replay of a recording.
+pub enum SensorBackend<S: BmeSensor> {
+    Hardware(S),
+    Simulated(SimulatedSensor),
+    Replay(ReplaySensor),
+}
+
+impl<S: BmeSensor> BmeSensor for SensorBackend<S> {
+    type Error = S::Error;
+
+    fn start_measurement(&mut self, settings: &BmeSettingsHandle) -> Result<Duration, Self::Error> {
+        match self {
+            Self::Hardware(sensor) => sensor.start_measurement(settings),
+            Self::Simulated(sensor) => sensor
+                .start_measurement(settings)
+                .map_err(|err| match err {}),
+            Self::Replay(sensor) => sensor
+                .start_measurement(settings)
+                .map_err(|err| match err {}),
+        }
+    }
+
+    fn get_measurement(&mut self) -> nb::Result<Vec<Input>, Self::Error> {
+        match self {
+            Self::Hardware(sensor) => sensor.get_measurement(),
+            Self::Simulated(sensor) => sensor.get_measurement().map_err(infallible),
+            Self::Replay(sensor) => sensor.get_measurement().map_err(infallible),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_waveform() {
+        let waveform = Waveform {
+            mean: 20.,
+            amplitude: 2.,
+            period_seconds: 3600.,
+        };
+        assert_eq!(waveform.value(Duration::ZERO), 20.);
+        assert!((waveform.value(Duration::from_secs(900)) - 22.).abs() < 1e-9);
+        assert!((waveform.value(Duration::from_secs(2700)) - 18.).abs() < 1e-9);
+        assert!((waveform.value(Duration::from_secs(3600)) - 20.).abs() < 1e-9);
+    }
+
+    #[test]
+    fn test_constant_waveform() {
+        let waveform = Waveform {
+            mean: 1013.,
+            amplitude: 5.,
+            period_seconds: 0.,
+        };
+        assert_eq!(waveform.value(Duration::from_secs(42)), 1013.);
+    }
+
+    #[test]
+    fn test_inputs() {
+        let sensor = SimulatedSensor::new(SimulationConfig::default());
+        let inputs = sensor.inputs(Duration::ZERO);
+        let kinds: Vec<_> = inputs.iter().map(|input| input.sensor).collect();
+        assert_eq!(
+            kinds,
+            vec![
+                InputKind::Temperature,
+                InputKind::Pressure,
+                InputKind::Humidity,
+                InputKind::GasResistor,
+                InputKind::HeatSource,
+            ]
+        );
+        assert_eq!(
+            inputs[0].signal,
+            SimulationConfig::default().temperature_celsius.mean as f32
+        );
+    }
+}
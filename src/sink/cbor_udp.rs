@@ -0,0 +1,143 @@
+//! Compact CBOR encoded outputs sent as UDP datagrams.
+//!
+//! Each datagram contains a CBOR array
+//! `[device_id, timestamp_ns, [[output_kind, signal, accuracy], ...]]` with
+//! the output kind given as BSEC virtual sensor ID, e.g. 1 for the IAQ, and
+//! the signal as single precision float. The timestamp is the one of the
+//! measurement clock, by default monotonic since boot rather than wall-clock
+//! time. The datagrams are not encrypted; use a VPN or similar for untrusted
+//! networks.
+
+use std::net::UdpSocket;
+
+use bsec::Output;
+use libalgobsec_sys::bsec_virtual_sensor_t;
+use serde::Serialize;
+
+use super::Sink;
+use crate::config::CborUdpConfig;
+
+#[derive(Debug, PartialEq, Serialize)]
+struct Datagram<'a>(Option<&'a str>, i64, Vec<(u16, f32, u8)>);
+
+pub struct CborUdpSink {
+    socket: UdpSocket,
+    device_id: Option<String>,
+}
+
+impl CborUdpSink {
+    pub fn new(config: &CborUdpConfig) -> std::io::Result<Self> {
+        let socket = UdpSocket::bind(&config.bind_addr)?;
+        socket.connect(&config.target)?;
+        Ok(Self {
+            socket,
+            device_id: config.device_id.clone(),
+        })
+    }
+}
+
+/// Encodes the outputs into the CBOR payload of a datagram.
+pub fn encode(device_id: Option<&str>, outputs: &[Output]) -> Vec<u8> {
+    let timestamp_ns = outputs
+        .iter()
+        .map(|output| output.timestamp_ns)
+        .max()
+        .unwrap_or_default();
+    let datagram = Datagram(
+        device_id,
+        timestamp_ns,
+        outputs
+            .iter()
+            .map(|output| {
+                (
+                    bsec_virtual_sensor_t::from(output.sensor) as u16,
+                    output.signal as f32,
+                    output.accuracy as u8,
+                )
+            })
+            .collect(),
+    );
+    let mut buffer = vec![];
+    // Serializing into a Vec cannot fail.
+    ciborium::ser::into_writer(&datagram, &mut buffer).expect("CBOR encoding failed");
+    buffer
+}
+
+impl Sink for CborUdpSink {
+    fn name(&self) -> &'static str {
+        "CBOR/UDP"
+    }
+
+    fn publish(&mut self, outputs: &[Output]) -> anyhow::Result<()> {
+        self.socket
+            .send(&encode(self.device_id.as_deref(), outputs))?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::{Accuracy, OutputKind};
+    use ciborium::value::Value;
+
+    fn outputs() -> Vec<Output> {
+        vec![
+            Output {
+                timestamp_ns: 42,
+                signal: 21.5,
+                sensor: OutputKind::SensorHeatCompensatedTemperature,
+                accuracy: Accuracy::HighAccuracy,
+            },
+            Output {
+                timestamp_ns: 42,
+                signal: 50.,
+                sensor: OutputKind::Iaq,
+                accuracy: Accuracy::LowAccuracy,
+            },
+        ]
+    }
+
+    #[test]
+    fn test_encode() {
+        let payload = encode(Some("kitchen"), &outputs());
+        let value: Value = ciborium::de::from_reader(&payload[..]).unwrap();
+        assert_eq!(
+            value,
+            Value::Array(vec![
+                Value::Text("kitchen".into()),
+                Value::Integer(42.into()),
+                Value::Array(vec![
+                    Value::Array(vec![
+                        Value::Integer(14.into()),
+                        Value::Float(21.5),
+                        Value::Integer(3.into()),
+                    ]),
+                    Value::Array(vec![
+                        Value::Integer(1.into()),
+                        Value::Float(50.),
+                        Value::Integer(1.into()),
+                    ]),
+                ]),
+            ])
+        );
+        assert!(payload.len() < 40);
+    }
+
+    #[test]
+    fn test_publish() {
+        let receiver = UdpSocket::bind("127.0.0.1:0").unwrap();
+        let mut sink = CborUdpSink::new(&CborUdpConfig {
+            target: receiver.local_addr().unwrap().to_string(),
+            bind_addr: "127.0.0.1:0".into(),
+            device_id: None,
+            accuracy: Default::default(),
+        })
+        .unwrap();
+
+        sink.publish(&outputs()).unwrap();
+        let mut buffer = [0u8; 512];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &encode(None, &outputs())[..]);
+    }
+}
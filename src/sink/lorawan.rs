@@ -0,0 +1,330 @@
+//! Cayenne LPP payloads handed to an attached LoRaWAN modem.
+//!
+//! The outputs are averaged over the configured interval to respect the duty
+//! cycle limitations, leaving out signals without value (NaN), e.g. below the
+//! minimum accuracy of the sink. Each output kind is encoded on its own
+//! channel, given by its position in [`CHANNELS`], with the following types:
+//!
+//! * temperatures: temperature (103), 0.1 °C,
+//! * humidities: relative humidity (104), 0.5 %,
+//! * pressure: barometer (115), 0.1 hPa,
+//! * CO2 equivalent: concentration (125), 1 ppm,
+//! * breath VOC equivalent and gas percentage: analog input (2), 0.01,
+//! * IAQ, static IAQ, and gas resistance: generic sensor (100), 1,
+//! * stabilization and run-in status: digital input (0).
+
+use std::collections::BTreeMap;
+use std::io::Write;
+use std::os::unix::net::UnixDatagram;
+use std::process::{Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use bsec::{Output, OutputKind};
+
+use super::Sink;
+use crate::config::{LorawanConfig, LorawanTarget, SinkShutdownConfig, SinkShutdownMode};
+use crate::log_error;
+
+/// Output kinds in the order of their Cayenne LPP channels starting at 1.
+pub const CHANNELS: [OutputKind; 13] = [
+    OutputKind::SensorHeatCompensatedTemperature,
+    OutputKind::SensorHeatCompensatedHumidity,
+    OutputKind::RawPressure,
+    OutputKind::Iaq,
+    OutputKind::StaticIaq,
+    OutputKind::Co2Equivalent,
+    OutputKind::BreathVocEquivalent,
+    OutputKind::RawTemperature,
+    OutputKind::RawHumidity,
+    OutputKind::RawGas,
+    OutputKind::GasPercentage,
+    OutputKind::StabilizationStatus,
+    OutputKind::RunInStatus,
+];
+
+const DIGITAL_INPUT: u8 = 0;
+const ANALOG_INPUT: u8 = 2;
+const GENERIC_SENSOR: u8 = 100;
+const TEMPERATURE: u8 = 103;
+const HUMIDITY: u8 = 104;
+const BAROMETER: u8 = 115;
+const CONCENTRATION: u8 = 125;
+
+fn encode_value(payload: &mut Vec<u8>, channel: u8, sensor: OutputKind, signal: f64) {
+    use OutputKind::*;
+    payload.push(channel);
+    match sensor {
+        SensorHeatCompensatedTemperature | RawTemperature => {
+            payload.push(TEMPERATURE);
+            payload.extend(((signal * 10.).round().clamp(-32768., 32767.) as i16).to_be_bytes());
+        }
+        SensorHeatCompensatedHumidity | RawHumidity => {
+            payload.push(HUMIDITY);
+            payload.push((signal * 2.).round().clamp(0., 255.) as u8);
+        }
+        RawPressure => {
+            payload.push(BAROMETER);
+            payload.extend(((signal / 10.).round().clamp(0., 65535.) as u16).to_be_bytes());
+        }
+        Co2Equivalent => {
+            payload.push(CONCENTRATION);
+            payload.extend((signal.round().clamp(0., 65535.) as u16).to_be_bytes());
+        }
+        BreathVocEquivalent | GasPercentage => {
+            payload.push(ANALOG_INPUT);
+            payload.extend(((signal * 100.).round().clamp(-32768., 32767.) as i16).to_be_bytes());
+        }
+        Iaq | StaticIaq | RawGas => {
+            payload.push(GENERIC_SENSOR);
+            payload.extend((signal.round().clamp(0., u32::MAX as f64) as u32).to_be_bytes());
+        }
+        StabilizationStatus | RunInStatus => {
+            payload.push(DIGITAL_INPUT);
+            payload.push(u8::from(signal > 0.5));
+        }
+    }
+}
+
+/// Encodes the values as Cayenne LPP payload.
+pub fn encode_cayenne_lpp(values: &BTreeMap<u8, (OutputKind, f64)>) -> Vec<u8> {
+    let mut payload = vec![];
+    for (channel, (sensor, signal)) in values.iter() {
+        encode_value(&mut payload, *channel, *sensor, *signal);
+    }
+    payload
+}
+
+#[derive(Default)]
+struct Mean {
+    sum: f64,
+    count: u32,
+}
+
+pub struct LorawanSink {
+    interval_ns: i64,
+    target: LorawanTarget,
+    shutdown: SinkShutdownConfig,
+    last_sent_ns: Option<i64>,
+    means: BTreeMap<u8, (OutputKind, Mean)>,
+    /// Threads feeding the payloads to the commands still running.
+    sending: Vec<JoinHandle<()>>,
+}
+
+impl LorawanSink {
+    pub fn new(config: &LorawanConfig) -> Self {
+        Self {
+            interval_ns: config.interval_seconds as i64 * 1_000_000_000,
+            target: config.target.clone(),
+            shutdown: config.shutdown.clone(),
+            last_sent_ns: None,
+            means: BTreeMap::new(),
+            sending: vec![],
+        }
+    }
+
+    fn add(&mut self, outputs: &[Output]) {
+        for output in outputs {
+            if output.signal.is_nan() {
+                continue;
+            }
+            if let Some(index) = CHANNELS.iter().position(|sensor| *sensor == output.sensor) {
+                let (_, mean) = self
+                    .means
+                    .entry(index as u8 + 1)
+                    .or_insert((output.sensor, Mean::default()));
+                mean.sum += output.signal;
+                mean.count += 1;
+            }
+        }
+    }
+
+    /// Takes the payload of the averaged values if the interval has passed.
+    fn take_payload(&mut self, timestamp_ns: i64) -> Option<Vec<u8>> {
+        let last_sent_ns = *self.last_sent_ns.get_or_insert(timestamp_ns);
+        if timestamp_ns - last_sent_ns < self.interval_ns || self.means.is_empty() {
+            return None;
+        }
+        self.last_sent_ns = Some(timestamp_ns);
+        self.take_averages()
+    }
+
+    /// Takes the payload of the values averaged so far, if any.
+    fn take_averages(&mut self) -> Option<Vec<u8>> {
+        if self.means.is_empty() {
+            return None;
+        }
+        let values = std::mem::take(&mut self.means)
+            .into_iter()
+            .map(|(channel, (sensor, mean))| (channel, (sensor, mean.sum / mean.count as f64)))
+            .collect();
+        Some(encode_cayenne_lpp(&values))
+    }
+
+    fn send(&mut self, payload: Vec<u8>) -> anyhow::Result<()> {
+        match &self.target {
+            LorawanTarget::Command { program, args } => {
+                let mut child = Command::new(program)
+                    .args(args)
+                    .stdin(Stdio::piped())
+                    .spawn()?;
+                let mut stdin = child.stdin.take().expect("stdin is piped");
+                self.sending.retain(|sending| !sending.is_finished());
+                self.sending.push(std::thread::spawn(move || {
+                    let result = stdin.write_all(&payload);
+                    drop(stdin);
+                    match child.wait() {
+                        Ok(status) if status.success() && result.is_ok() => (),
+                        Ok(status) => log_error!("LoRaWAN command failed: {}", status),
+                        Err(err) => log_error!("LoRaWAN command failed: {}", err),
+                    }
+                }));
+            }
+            LorawanTarget::UnixSocket { path } => {
+                UnixDatagram::unbound()?.send_to(&payload, path)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Sink for LorawanSink {
+    fn name(&self) -> &'static str {
+        "LoRaWAN"
+    }
+
+    fn publish(&mut self, outputs: &[Output]) -> anyhow::Result<()> {
+        self.add(outputs);
+        let timestamp_ns = match outputs.iter().map(|output| output.timestamp_ns).max() {
+            Some(timestamp_ns) => timestamp_ns,
+            None => return Ok(()),
+        };
+        match self.take_payload(timestamp_ns) {
+            Some(payload) => self.send(payload),
+            None => Ok(()),
+        }
+    }
+
+    /// Sends the values averaged within the current interval in the block
+    /// mode and waits for the commands still running.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.shutdown.mode == SinkShutdownMode::Drop {
+            return Ok(());
+        }
+        let deadline = Instant::now() + Duration::from_secs(self.shutdown.flush_timeout_seconds);
+        if let Some(payload) = self.take_averages() {
+            self.send(payload)?;
+        }
+        loop {
+            self.sending.retain(|sending| !sending.is_finished());
+            if self.sending.is_empty() {
+                return Ok(());
+            }
+            if Instant::now() >= deadline {
+                anyhow::bail!(
+                    "{} LoRaWAN commands still running after the flush timeout",
+                    self.sending.len()
+                );
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::Accuracy;
+    use tempfile::tempdir;
+
+    fn output(timestamp_ns: i64, sensor: OutputKind, signal: f64) -> Output {
+        Output {
+            timestamp_ns,
+            signal,
+            sensor,
+            accuracy: Accuracy::HighAccuracy,
+        }
+    }
+
+    #[test]
+    fn test_encode_cayenne_lpp() {
+        let values = vec![
+            (1, (OutputKind::SensorHeatCompensatedTemperature, -4.1)),
+            (2, (OutputKind::SensorHeatCompensatedHumidity, 40.5)),
+            (3, (OutputKind::RawPressure, 101325.)),
+            (4, (OutputKind::Iaq, 75.4)),
+            (12, (OutputKind::StabilizationStatus, 1.)),
+        ]
+        .into_iter()
+        .collect();
+        assert_eq!(
+            encode_cayenne_lpp(&values),
+            vec![
+                1, 103, 0xff, 0xd7, // -4.1 °C
+                2, 104, 81, // 40.5 %
+                3, 115, 0x27, 0x95, // 1013.3 hPa
+                4, 100, 0, 0, 0, 75, // IAQ 75
+                12, 0, 1, // stabilized
+            ]
+        );
+    }
+
+    #[test]
+    fn test_averages_over_interval() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("lora.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        let mut sink = LorawanSink::new(&LorawanConfig {
+            interval_seconds: 10,
+            target: LorawanTarget::UnixSocket { path },
+            accuracy: Default::default(),
+            shutdown: Default::default(),
+        });
+
+        let temperature = OutputKind::SensorHeatCompensatedTemperature;
+        sink.publish(&[output(0, temperature, 20.)]).unwrap();
+        sink.publish(&[output(5_000_000_000, temperature, 21.)])
+            .unwrap();
+        sink.publish(&[output(10_000_000_000, temperature, 22.)])
+            .unwrap();
+
+        let mut buffer = [0u8; 64];
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[1, 103, 0, 210]);
+    }
+
+    #[test]
+    fn test_flushes_averages_on_shutdown() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("lora.sock");
+        let receiver = UnixDatagram::bind(&path).unwrap();
+        receiver.set_nonblocking(true).unwrap();
+        let sink = |mode| {
+            LorawanSink::new(&LorawanConfig {
+                interval_seconds: 10,
+                target: LorawanTarget::UnixSocket { path: path.clone() },
+                accuracy: Default::default(),
+                shutdown: SinkShutdownConfig {
+                    mode,
+                    flush_timeout_seconds: 1,
+                },
+            })
+        };
+
+        let temperature = OutputKind::SensorHeatCompensatedTemperature;
+        let mut dropping = sink(SinkShutdownMode::Drop);
+        dropping.publish(&[output(0, temperature, 20.)]).unwrap();
+        dropping.flush().unwrap();
+        let mut buffer = [0u8; 64];
+        assert!(receiver.recv(&mut buffer).is_err());
+
+        let mut blocking = sink(SinkShutdownMode::Block);
+        blocking.publish(&[output(0, temperature, 20.)]).unwrap();
+        blocking
+            .publish(&[output(5_000_000_000, temperature, 21.)])
+            .unwrap();
+        blocking.flush().unwrap();
+        let len = receiver.recv(&mut buffer).unwrap();
+        assert_eq!(&buffer[..len], &[1, 103, 0, 205]);
+    }
+}
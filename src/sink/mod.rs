@@ -0,0 +1,155 @@
+//! Publishing of the BSEC outputs to destinations other than the HTTP
+//! endpoints.
+
+use bsec::{Output, OutputKind};
+
+use crate::config::{AccuracyPolicy, BelowMinAccuracy};
+use crate::log_error;
+
+#[cfg(feature = "cbor-udp")]
+pub mod cbor_udp;
+#[cfg(feature = "lorawan")]
+pub mod lorawan;
+#[cfg(feature = "mqtt")]
+pub mod mqtt;
+#[cfg(feature = "mqtt")]
+pub mod spool;
+
+/// Destination receiving each new set of BSEC outputs.
+pub trait Sink {
+    fn name(&self) -> &'static str;
+
+    fn publish(&mut self, outputs: &[Output]) -> anyhow::Result<()>;
+
+    /// Handles the outputs buffered by the sink before the shutdown
+    /// according to its shutdown configuration.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        Ok(())
+    }
+}
+
+/// Outputs weighted by the accuracy of the IAQ algorithm calibration.
+pub const ACCURACY_WEIGHTED_OUTPUTS: [OutputKind; 4] = [
+    OutputKind::Iaq,
+    OutputKind::StaticIaq,
+    OutputKind::Co2Equivalent,
+    OutputKind::BreathVocEquivalent,
+];
+
+/// Applies the accuracy policy of a sink to the accuracy-weighted outputs.
+pub struct AccuracyFilter {
+    sink: Box<dyn Sink + Send>,
+    policy: AccuracyPolicy,
+}
+
+impl AccuracyFilter {
+    pub fn new(sink: Box<dyn Sink + Send>, policy: AccuracyPolicy) -> Self {
+        Self { sink, policy }
+    }
+
+    /// Outputs passing the policy.
+    pub fn filter(&self, outputs: &[Output]) -> Vec<Output> {
+        let min_accuracy = self.policy.min_accuracy as u8;
+        outputs
+            .iter()
+            .filter_map(|output| {
+                if (output.accuracy as u8) >= min_accuracy
+                    || !ACCURACY_WEIGHTED_OUTPUTS.contains(&output.sensor)
+                {
+                    return Some(*output);
+                }
+                match self.policy.below_min_accuracy {
+                    BelowMinAccuracy::Nan => Some(Output {
+                        signal: f64::NAN,
+                        ..*output
+                    }),
+                    BelowMinAccuracy::Omit => None,
+                }
+            })
+            .collect()
+    }
+}
+
+impl Sink for AccuracyFilter {
+    fn name(&self) -> &'static str {
+        self.sink.name()
+    }
+
+    fn publish(&mut self, outputs: &[Output]) -> anyhow::Result<()> {
+        let outputs = self.filter(outputs);
+        self.sink.publish(&outputs)
+    }
+
+    fn flush(&mut self) -> anyhow::Result<()> {
+        self.sink.flush()
+    }
+}
+
+/// Publishes the outputs to all sinks, logging failures.
+pub fn publish_all(sinks: &mut [Box<dyn Sink + Send>], outputs: &[Output]) {
+    for sink in sinks.iter_mut() {
+        if let Err(err) = sink.publish(outputs) {
+            log_error!(sink = sink.name(); "Failed to publish to {} sink: {}", sink.name(), err);
+        }
+    }
+}
+
+/// Flushes all sinks before the shutdown, logging failures.
+pub fn flush_all(sinks: &mut [Box<dyn Sink + Send>]) {
+    for sink in sinks.iter_mut() {
+        if let Err(err) = sink.flush() {
+            log_error!(sink = sink.name(); "Failed to flush the {} sink: {}", sink.name(), err);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::Accuracy;
+
+    struct NullSink;
+
+    impl Sink for NullSink {
+        fn name(&self) -> &'static str {
+            "null"
+        }
+
+        fn publish(&mut self, _outputs: &[Output]) -> anyhow::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn output(sensor: OutputKind, accuracy: Accuracy) -> Output {
+        Output {
+            timestamp_ns: 0,
+            signal: 1.,
+            sensor,
+            accuracy,
+        }
+    }
+
+    #[test]
+    fn test_accuracy_filter() {
+        let outputs = [
+            output(OutputKind::Iaq, Accuracy::LowAccuracy),
+            output(OutputKind::Co2Equivalent, Accuracy::MediumAccuracy),
+            output(OutputKind::RawTemperature, Accuracy::Unreliable),
+        ];
+        let policy = |below_min_accuracy| AccuracyPolicy {
+            min_accuracy: Accuracy::MediumAccuracy,
+            below_min_accuracy,
+        };
+
+        let filter = AccuracyFilter::new(Box::new(NullSink), policy(BelowMinAccuracy::Nan));
+        let filtered = filter.filter(&outputs);
+        assert!(filtered[0].signal.is_nan());
+        assert_eq!(filtered[1..], outputs[1..]);
+
+        let filter = AccuracyFilter::new(Box::new(NullSink), policy(BelowMinAccuracy::Omit));
+        assert_eq!(filter.filter(&outputs), outputs[1..]);
+
+        let filter = AccuracyFilter::new(Box::new(NullSink), AccuracyPolicy::default());
+        assert_eq!(filter.filter(&outputs), outputs);
+    }
+}
@@ -0,0 +1,842 @@
+//! Outputs published to an MQTT broker.
+//!
+//! Each output is published as plain number to the topic of its template,
+//! leaving out signals without value (NaN), e.g. below the minimum accuracy
+//! of the sink. The topic, QoS, and retain flag can be overridden per output.
+//! With an availability topic, a retained online message is published after
+//! connecting and the offline message is registered as last will and
+//! published on shutdown, as home-automation systems expect from sensor
+//! bridges.
+//!
+//! Only the parts of MQTT 3.1.1 needed for publishing are implemented. A ping
+//! is sent after half of the keep alive interval without publishing, e.g.
+//! during the burn-in or while outputs are throttled or filtered, so that the
+//! broker neither drops the connection nor publishes the offline message.
+//! After a connection failure, the outputs are dropped until reconnecting at
+//! most every [`RECONNECT_INTERVAL`]. With `spool_max_bytes`, the messages
+//! not delivered in the meantime are spooled to disk instead and published
+//! before the next outputs once reconnected, also after a restart. The
+//! payload carries no timestamp, so that late messages appear to subscribers
+//! as if measured when they are published.
+//!
+//! The client runs on its own thread, so that resolving and connecting to the
+//! broker and waiting for acknowledgements do not block the monitoring and
+//! the HTTP endpoints. Outputs are dropped while [`QUEUE_CAPACITY`] sets of
+//! outputs are waiting to be published. On shutdown, the queued outputs are
+//! dropped or, in the block mode, published, and the client is given up to
+//! the flush timeout to publish the offline message and disconnect.
+
+use std::collections::VecDeque;
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender, TrySendError};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+use bsec::Output;
+use serde::{Deserialize, Serialize};
+
+use super::spool::Spool;
+use super::Sink;
+use crate::config::{output_kind_name, MqttConfig, MqttQos, SinkShutdownConfig, SinkShutdownMode};
+use crate::log_error;
+use crate::maintenance::ReadOnlySwitch;
+
+/// Minimum time between connection attempts.
+pub const RECONNECT_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Timeout of connecting and of each read and write on the connection.
+const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Maximum number of sets of outputs waiting to be published.
+pub const QUEUE_CAPACITY: usize = 8;
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const PUBREC: u8 = 0x50;
+const PUBREL: u8 = 0x62;
+const PUBCOMP: u8 = 0x70;
+const PINGREQ: u8 = 0xc0;
+const PINGRESP: u8 = 0xd0;
+const DISCONNECT: u8 = 0xe0;
+
+fn encode_remaining_length(buffer: &mut Vec<u8>, mut length: usize) {
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        buffer.push(byte);
+        if length == 0 {
+            return;
+        }
+    }
+}
+
+fn encode_bytes(buffer: &mut Vec<u8>, bytes: &[u8]) {
+    buffer.extend((bytes.len() as u16).to_be_bytes());
+    buffer.extend(bytes);
+}
+
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    encode_remaining_length(&mut packet, body.len());
+    packet.extend(body);
+    packet
+}
+
+/// Last will published by the broker when the connection is lost.
+pub struct Will<'a> {
+    pub topic: &'a str,
+    pub payload: &'a [u8],
+    pub qos: MqttQos,
+}
+
+/// Encodes a CONNECT packet with a clean session and retained last will.
+pub fn encode_connect(config: &MqttConfig, will: Option<&Will>) -> Vec<u8> {
+    let mut flags = 0x02;
+    let mut body = vec![];
+    encode_bytes(&mut body, b"MQTT");
+    body.push(4);
+    if let Some(will) = will {
+        flags |= 0x04 | ((will.qos as u8) << 3) | 0x20;
+    }
+    if config.password.is_some() {
+        flags |= 0x40;
+    }
+    if config.username.is_some() {
+        flags |= 0x80;
+    }
+    body.push(flags);
+    body.extend(config.keep_alive_seconds.to_be_bytes());
+    encode_bytes(&mut body, config.client_id.as_bytes());
+    if let Some(will) = will {
+        encode_bytes(&mut body, will.topic.as_bytes());
+        encode_bytes(&mut body, will.payload);
+    }
+    if let Some(username) = &config.username {
+        encode_bytes(&mut body, username.as_bytes());
+    }
+    if let Some(password) = &config.password {
+        encode_bytes(&mut body, password.as_bytes());
+    }
+    packet(CONNECT, &body)
+}
+
+/// Encodes a PUBLISH packet, the packet ID is only included for a QoS above
+/// 0.
+pub fn encode_publish(
+    topic: &str,
+    payload: &[u8],
+    qos: MqttQos,
+    retain: bool,
+    packet_id: u16,
+) -> Vec<u8> {
+    let mut body = vec![];
+    encode_bytes(&mut body, topic.as_bytes());
+    if qos != MqttQos::AtMostOnce {
+        body.extend(packet_id.to_be_bytes());
+    }
+    body.extend(payload);
+    packet(PUBLISH | ((qos as u8) << 1) | u8::from(retain), &body)
+}
+
+/// Reads a packet, returning its fixed header byte and body.
+pub fn read_packet<R: Read>(reader: &mut R) -> io::Result<(u8, Vec<u8>)> {
+    let mut byte = [0u8; 1];
+    reader.read_exact(&mut byte)?;
+    let header = byte[0];
+    let mut length = 0usize;
+    for shift in (0..28).step_by(7) {
+        reader.read_exact(&mut byte)?;
+        length |= ((byte[0] & 0x7f) as usize) << shift;
+        if byte[0] & 0x80 == 0 {
+            let mut body = vec![0u8; length];
+            reader.read_exact(&mut body)?;
+            return Ok((header, body));
+        }
+    }
+    Err(io::Error::new(
+        io::ErrorKind::InvalidData,
+        "malformed remaining length",
+    ))
+}
+
+/// Topic of the template with the placeholders replaced.
+pub fn expand_topic(template: &str, output: &str, client_id: &str) -> String {
+    template
+        .replace("{output}", output)
+        .replace("{client_id}", client_id)
+}
+
+fn connack_error(return_code: u8) -> &'static str {
+    match return_code {
+        1 => "unacceptable protocol version",
+        2 => "client ID rejected",
+        3 => "server unavailable",
+        4 => "bad user name or password",
+        5 => "not authorized",
+        _ => "unknown return code",
+    }
+}
+
+struct Connection {
+    stream: TcpStream,
+    last_packet_id: u16,
+    last_sent: Instant,
+}
+
+impl Connection {
+    fn open(config: &MqttConfig) -> anyhow::Result<Self> {
+        let mut last_err = None;
+        let mut stream = None;
+        for addr in config.broker.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, IO_TIMEOUT) {
+                Ok(connected) => {
+                    stream = Some(connected);
+                    break;
+                }
+                Err(err) => last_err = Some(err),
+            }
+        }
+        let stream = match (stream, last_err) {
+            (Some(stream), _) => stream,
+            (None, Some(err)) => return Err(err.into()),
+            (None, None) => anyhow::bail!("no address for broker {}", config.broker),
+        };
+        stream.set_read_timeout(Some(IO_TIMEOUT))?;
+        stream.set_write_timeout(Some(IO_TIMEOUT))?;
+        stream.set_nodelay(true)?;
+
+        let mut connection = Self {
+            stream,
+            last_packet_id: 0,
+            last_sent: Instant::now(),
+        };
+        let will = config.availability.as_ref().map(|availability| Will {
+            topic: &availability.topic,
+            payload: availability.offline_payload.as_bytes(),
+            qos: config.qos,
+        });
+        connection.send(&encode_connect(config, will.as_ref()))?;
+        match read_packet(&mut connection.stream)? {
+            (CONNACK, body) if body.len() == 2 && body[1] == 0 => (),
+            (CONNACK, body) if body.len() == 2 => anyhow::bail!(
+                "connection refused by the broker: {}",
+                connack_error(body[1])
+            ),
+            (header, _) => anyhow::bail!("unexpected packet 0x{:02x} instead of CONNACK", header),
+        }
+
+        if let Some(availability) = &config.availability {
+            connection.publish(
+                &availability.topic,
+                availability.online_payload.as_bytes(),
+                config.qos,
+                true,
+            )?;
+        }
+        Ok(connection)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    /// Sends a ping and waits for the response.
+    fn ping(&mut self) -> io::Result<()> {
+        self.send(&packet(PINGREQ, &[]))?;
+        loop {
+            let (header, _) = read_packet(&mut self.stream)?;
+            if header == PINGRESP {
+                return Ok(());
+            }
+        }
+    }
+
+    fn next_packet_id(&mut self) -> u16 {
+        self.last_packet_id = self.last_packet_id.checked_add(1).unwrap_or(1);
+        self.last_packet_id
+    }
+
+    fn publish(
+        &mut self,
+        topic: &str,
+        payload: &[u8],
+        qos: MqttQos,
+        retain: bool,
+    ) -> io::Result<()> {
+        let packet_id = match qos {
+            MqttQos::AtMostOnce => 0,
+            _ => self.next_packet_id(),
+        };
+        self.send(&encode_publish(topic, payload, qos, retain, packet_id))?;
+        match qos {
+            MqttQos::AtMostOnce => Ok(()),
+            MqttQos::AtLeastOnce => self.await_ack(PUBACK, packet_id),
+            MqttQos::ExactlyOnce => {
+                self.await_ack(PUBREC, packet_id)?;
+                self.send(&packet(PUBREL, &packet_id.to_be_bytes()))?;
+                self.await_ack(PUBCOMP, packet_id)
+            }
+        }
+    }
+
+    fn publish_message(&mut self, message: &Message) -> io::Result<()> {
+        self.publish(
+            &message.topic,
+            message.payload.as_bytes(),
+            message.qos,
+            message.retain,
+        )
+    }
+
+    /// Waits for the acknowledgement of the packet, skipping other packets.
+    fn await_ack(&mut self, kind: u8, packet_id: u16) -> io::Result<()> {
+        loop {
+            let (header, body) = read_packet(&mut self.stream)?;
+            if header & 0xf0 == kind && body[..] == packet_id.to_be_bytes() {
+                return Ok(());
+            }
+        }
+    }
+
+    fn disconnect(mut self) -> io::Result<()> {
+        self.send(&packet(DISCONNECT, &[]))
+    }
+}
+
+/// Message published for an output.
+#[derive(Clone, Debug, Deserialize, PartialEq, Serialize)]
+pub struct Message {
+    pub topic: String,
+    pub payload: String,
+    pub qos: MqttQos,
+    pub retain: bool,
+}
+
+/// Client publishing the outputs on the thread of the sink.
+struct MqttClient {
+    config: MqttConfig,
+    connection: Option<Connection>,
+    last_attempt: Option<Instant>,
+    spool: Option<Spool<Message>>,
+    /// Set to drop the queued outputs instead of publishing them.
+    discard: Arc<AtomicBool>,
+}
+
+impl MqttClient {
+    fn new(config: &MqttConfig, spool: Option<Spool<Message>>) -> Self {
+        Self {
+            config: config.clone(),
+            connection: None,
+            last_attempt: None,
+            spool,
+            discard: Arc::new(AtomicBool::new(false)),
+        }
+    }
+
+    /// Time without publishing after which a ping is sent, `None` without
+    /// keep alive.
+    fn ping_interval(&self) -> Option<Duration> {
+        match self.config.keep_alive_seconds {
+            0 => None,
+            seconds => Some(Duration::from_secs(seconds.into()) / 2),
+        }
+    }
+
+    /// Publishes the received outputs until the sender is dropped, then shuts
+    /// down.
+    fn run(mut self, queue: Receiver<Vec<Output>>) -> anyhow::Result<()> {
+        loop {
+            let received = match self.ping_interval() {
+                Some(interval) => queue.recv_timeout(interval),
+                None => queue.recv().map_err(|_| RecvTimeoutError::Disconnected),
+            };
+            match received {
+                Ok(_) if self.discard.load(Ordering::Relaxed) => (),
+                Ok(outputs) => {
+                    if let Err(err) = self.publish(&outputs) {
+                        log_error!(sink = "MQTT"; "Failed to publish to MQTT sink: {}", err);
+                    }
+                }
+                Err(RecvTimeoutError::Disconnected) => return self.shutdown(),
+                Err(RecvTimeoutError::Timeout) => (),
+            }
+            if let Err(err) = self.keep_alive() {
+                log_error!(sink = "MQTT"; "Lost the connection to the MQTT broker: {}", err);
+            }
+        }
+    }
+
+    /// Pings the broker if nothing was sent for the ping interval.
+    fn keep_alive(&mut self) -> io::Result<()> {
+        let interval = match self.ping_interval() {
+            Some(interval) => interval,
+            None => return Ok(()),
+        };
+        let connection = match &mut self.connection {
+            Some(connection) if connection.last_sent.elapsed() >= interval => connection,
+            _ => return Ok(()),
+        };
+        let result = connection.ping();
+        if result.is_err() {
+            self.connection = None;
+        }
+        result
+    }
+
+    /// Connection to the broker, `None` while waiting to reconnect.
+    fn connection(&mut self) -> anyhow::Result<Option<&mut Connection>> {
+        if self.connection.is_none() {
+            if let Some(last_attempt) = self.last_attempt {
+                if last_attempt.elapsed() < RECONNECT_INTERVAL {
+                    return Ok(None);
+                }
+            }
+            self.last_attempt = Some(Instant::now());
+            self.connection = Some(Connection::open(&self.config)?);
+        }
+        Ok(self.connection.as_mut())
+    }
+
+    fn messages(&self, outputs: &[Output]) -> VecDeque<Message> {
+        outputs
+            .iter()
+            .filter(|output| !output.signal.is_nan())
+            .map(|output| {
+                let overrides = self.config.outputs.get(&output.sensor);
+                let topic = overrides
+                    .and_then(|overrides| overrides.topic.as_deref())
+                    .unwrap_or(&self.config.topic);
+                Message {
+                    topic: expand_topic(
+                        topic,
+                        output_kind_name(output.sensor),
+                        &self.config.client_id,
+                    ),
+                    payload: output.signal.to_string(),
+                    qos: overrides
+                        .and_then(|overrides| overrides.qos)
+                        .unwrap_or(self.config.qos),
+                    retain: overrides
+                        .and_then(|overrides| overrides.retain)
+                        .unwrap_or(self.config.retain),
+                }
+            })
+            .collect()
+    }
+
+    /// Publishes the spooled messages and then the `messages`, removing the
+    /// published ones.
+    fn publish_messages(&mut self, messages: &mut VecDeque<Message>) -> anyhow::Result<()> {
+        if self.connection()?.is_none() {
+            return Ok(());
+        }
+        let connection = match &mut self.connection {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        if let Some(spool) = &mut self.spool {
+            spool.replay(|message| connection.publish_message(message))?;
+        }
+        while let Some(message) = messages.front() {
+            connection.publish_message(message)?;
+            messages.pop_front();
+        }
+        Ok(())
+    }
+
+    fn publish(&mut self, outputs: &[Output]) -> anyhow::Result<()> {
+        let mut messages = self.messages(outputs);
+        let result = self.publish_messages(&mut messages);
+        if result.is_err() {
+            self.connection = None;
+        }
+        if let Some(spool) = &mut self.spool {
+            spool.push(messages);
+        }
+        result
+    }
+
+    /// Publishes the offline message, if configured, and disconnects.
+    fn shutdown(&mut self) -> anyhow::Result<()> {
+        let mut connection = match self.connection.take() {
+            Some(connection) => connection,
+            None => return Ok(()),
+        };
+        if let Some(availability) = &self.config.availability {
+            connection.publish(
+                &availability.topic,
+                availability.offline_payload.as_bytes(),
+                self.config.qos,
+                true,
+            )?;
+        }
+        connection.disconnect()?;
+        Ok(())
+    }
+}
+
+pub struct MqttSink {
+    queue: Option<SyncSender<Vec<Output>>>,
+    discard: Arc<AtomicBool>,
+    shutdown: SinkShutdownConfig,
+    worker: Option<JoinHandle<anyhow::Result<()>>>,
+}
+
+impl MqttSink {
+    /// Creates the sink spooling undelivered messages to `spool_file` if
+    /// enabled by the `config`.
+    pub fn new(config: &MqttConfig, spool_file: PathBuf, read_only: ReadOnlySwitch) -> Self {
+        let (queue, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        let spool = Some(config.spool_max_bytes)
+            .filter(|&max_bytes| max_bytes > 0)
+            .map(|max_bytes| Spool::load(spool_file, max_bytes, read_only));
+        let client = MqttClient::new(config, spool);
+        Self {
+            queue: Some(queue),
+            discard: client.discard.clone(),
+            shutdown: config.shutdown.clone(),
+            worker: Some(thread::spawn(move || client.run(receiver))),
+        }
+    }
+}
+
+impl Sink for MqttSink {
+    fn name(&self) -> &'static str {
+        "MQTT"
+    }
+
+    fn publish(&mut self, outputs: &[Output]) -> anyhow::Result<()> {
+        let queue = match &self.queue {
+            Some(queue) => queue,
+            None => anyhow::bail!("sink already flushed"),
+        };
+        match queue.try_send(outputs.to_vec()) {
+            Ok(()) => Ok(()),
+            Err(TrySendError::Full(_)) => {
+                anyhow::bail!("broker too slow, dropping the outputs")
+            }
+            Err(TrySendError::Disconnected(_)) => anyhow::bail!("client thread stopped"),
+        }
+    }
+
+    /// Drops the queued outputs or publishes them in the block mode, then
+    /// publishes the offline message, if configured, and disconnects within
+    /// the flush timeout.
+    fn flush(&mut self) -> anyhow::Result<()> {
+        if self.shutdown.mode == SinkShutdownMode::Drop {
+            self.discard.store(true, Ordering::Relaxed);
+        }
+        // The client shuts down once it received the queued outputs.
+        self.queue = None;
+        let worker = match self.worker.take() {
+            Some(worker) => worker,
+            None => return Ok(()),
+        };
+        let deadline = Instant::now() + Duration::from_secs(self.shutdown.flush_timeout_seconds);
+        while !worker.is_finished() {
+            if Instant::now() >= deadline {
+                anyhow::bail!("client still publishing after the flush timeout");
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        worker
+            .join()
+            .map_err(|_| anyhow::anyhow!("client thread panicked"))?
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::{MqttAvailabilityConfig, MqttOutputConfig};
+    use bsec::{Accuracy, OutputKind};
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::thread;
+
+    fn config(broker: String) -> MqttConfig {
+        toml::from_str(&format!("broker = \"{}\"", broker)).unwrap()
+    }
+
+    fn output(sensor: OutputKind, signal: f64) -> Output {
+        Output {
+            timestamp_ns: 0,
+            signal,
+            sensor,
+            accuracy: Accuracy::HighAccuracy,
+        }
+    }
+
+    #[test]
+    fn test_encode_remaining_length() {
+        let encode = |length| {
+            let mut buffer = vec![];
+            encode_remaining_length(&mut buffer, length);
+            buffer
+        };
+        assert_eq!(encode(0), vec![0x00]);
+        assert_eq!(encode(127), vec![0x7f]);
+        assert_eq!(encode(128), vec![0x80, 0x01]);
+        assert_eq!(encode(16_383), vec![0xff, 0x7f]);
+        assert_eq!(encode(16_384), vec![0x80, 0x80, 0x01]);
+    }
+
+    #[test]
+    fn test_encode_connect() {
+        let config = MqttConfig {
+            client_id: "c".into(),
+            username: Some("u".into()),
+            keep_alive_seconds: 60,
+            ..config("localhost:1883".into())
+        };
+        let will = Will {
+            topic: "t",
+            payload: b"off",
+            qos: MqttQos::AtLeastOnce,
+        };
+        assert_eq!(
+            encode_connect(&config, Some(&will)),
+            vec![
+                CONNECT, 24, // fixed header
+                0, 4, b'M', b'Q', b'T', b'T', 4, // protocol
+                0xae, 0, 60, // flags, keep alive
+                0, 1, b'c', // client ID
+                0, 1, b't', 0, 3, b'o', b'f', b'f', // will
+                0, 1, b'u', // user name
+            ]
+        );
+    }
+
+    #[test]
+    fn test_encode_publish() {
+        assert_eq!(
+            encode_publish("a/b", b"21.5", MqttQos::AtMostOnce, true, 7),
+            vec![0x31, 9, 0, 3, b'a', b'/', b'b', b'2', b'1', b'.', b'5']
+        );
+        assert_eq!(
+            encode_publish("a", b"1", MqttQos::ExactlyOnce, false, 7),
+            vec![0x34, 6, 0, 1, b'a', 0, 7, b'1']
+        );
+    }
+
+    #[test]
+    fn test_read_packet() {
+        let packet = encode_publish("a", &[0; 200], MqttQos::AtMostOnce, false, 0);
+        let (header, body) = read_packet(&mut &packet[..]).unwrap();
+        assert_eq!(header, PUBLISH);
+        assert_eq!(body.len(), 203);
+    }
+
+    #[test]
+    fn test_expand_topic() {
+        assert_eq!(
+            expand_topic("home/{client_id}/{output}", "iaq", "kitchen"),
+            "home/kitchen/iaq"
+        );
+    }
+
+    #[test]
+    fn test_pings_when_idle() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = MqttSink::new(
+            &MqttConfig {
+                keep_alive_seconds: 1,
+                ..config(listener.local_addr().unwrap().to_string())
+            },
+            PathBuf::new(),
+            ReadOnlySwitch::new(),
+        );
+
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut headers = vec![];
+            while let Ok((header, _)) = read_packet(&mut stream) {
+                match header {
+                    CONNECT => stream.write_all(&[CONNACK, 2, 0, 0]).unwrap(),
+                    PINGREQ => stream.write_all(&[PINGRESP, 0]).unwrap(),
+                    _ => (),
+                }
+                headers.push(header);
+            }
+            headers
+        });
+
+        sink.publish(&[output(OutputKind::Iaq, 42.)]).unwrap();
+        thread::sleep(Duration::from_millis(1200));
+        sink.flush().unwrap();
+
+        let headers = broker.join().unwrap();
+        assert_eq!(headers[..2], [CONNECT, 0x30]);
+        assert!(headers.contains(&PINGREQ));
+        assert_eq!(headers.last(), Some(&DISCONNECT));
+    }
+
+    #[test]
+    fn test_publish() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = MqttSink::new(
+            &MqttConfig {
+                qos: MqttQos::AtLeastOnce,
+                outputs: vec![(
+                    OutputKind::Iaq,
+                    MqttOutputConfig {
+                        topic: Some("air/quality".into()),
+                        qos: Some(MqttQos::AtMostOnce),
+                        retain: Some(true),
+                    },
+                )]
+                .into_iter()
+                .collect(),
+                availability: Some(MqttAvailabilityConfig {
+                    topic: "status".into(),
+                    online_payload: "online".into(),
+                    offline_payload: "offline".into(),
+                }),
+                shutdown: SinkShutdownConfig {
+                    mode: SinkShutdownMode::Block,
+                    ..SinkShutdownConfig::default()
+                },
+                ..config(listener.local_addr().unwrap().to_string())
+            },
+            PathBuf::new(),
+            ReadOnlySwitch::new(),
+        );
+
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut packets = vec![];
+            loop {
+                let (header, body) = match read_packet(&mut stream) {
+                    Ok(packet) => packet,
+                    Err(_) => return packets,
+                };
+                match header & 0xf0 {
+                    CONNECT => stream.write_all(&[CONNACK, 2, 0, 0]).unwrap(),
+                    PUBLISH if header & 0x06 != 0 => {
+                        let topic_len = u16::from_be_bytes([body[0], body[1]]) as usize;
+                        let packet_id = &body[2 + topic_len..4 + topic_len];
+                        stream.write_all(&[PUBACK, 2]).unwrap();
+                        stream.write_all(packet_id).unwrap();
+                    }
+                    _ => (),
+                }
+                packets.push((header, body));
+            }
+        });
+
+        sink.publish(&[
+            output(OutputKind::Iaq, 42.),
+            output(OutputKind::RawTemperature, 21.5),
+            output(OutputKind::StaticIaq, f64::NAN),
+        ])
+        .unwrap();
+        sink.flush().unwrap();
+        let packets = broker.join().unwrap();
+
+        let headers: Vec<_> = packets.iter().map(|(header, _)| *header).collect();
+        assert_eq!(headers, vec![CONNECT, 0x33, 0x31, 0x32, 0x33, DISCONNECT]);
+        assert!(packets[1].1.ends_with(b"online"));
+        assert_eq!(&packets[2].1[..], b"\x00\x0bair/quality42");
+        assert!(packets[3].1.starts_with(b"\x00\x14bsec/raw_temperature"));
+        assert!(packets[3].1.ends_with(b"21.5"));
+        assert!(packets[4].1.ends_with(b"offline"));
+    }
+
+    #[test]
+    fn test_spools_undelivered_messages() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let unreachable = TcpListener::bind("127.0.0.1:0").unwrap();
+        let unreachable_addr = unreachable.local_addr().unwrap().to_string();
+        drop(unreachable);
+        let spool = Spool::load(
+            tmp_dir.path().join("spool.jsonl"),
+            1024,
+            ReadOnlySwitch::new(),
+        );
+        let mut client = MqttClient::new(&config(unreachable_addr), Some(spool));
+
+        assert!(client.publish(&[output(OutputKind::Iaq, 42.)]).is_err());
+        client.publish(&[output(OutputKind::Iaq, 43.)]).unwrap();
+        assert_eq!(client.spool.as_ref().unwrap().len(), 2);
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        client.config.broker = listener.local_addr().unwrap().to_string();
+        client.last_attempt = None;
+        let broker = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut payloads = vec![];
+            while let Ok((header, body)) = read_packet(&mut stream) {
+                match header & 0xf0 {
+                    CONNECT => stream.write_all(&[CONNACK, 2, 0, 0]).unwrap(),
+                    PUBLISH => payloads.push(String::from_utf8(body[10..].to_vec()).unwrap()),
+                    _ => (),
+                }
+            }
+            payloads
+        });
+        client.publish(&[output(OutputKind::Iaq, 44.)]).unwrap();
+        client.shutdown().unwrap();
+
+        assert_eq!(broker.join().unwrap(), vec!["42", "43", "44"]);
+        assert!(client.spool.as_ref().unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_flush_drops_queued_outputs() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        listener.set_nonblocking(true).unwrap();
+        let client = MqttClient::new(&config(listener.local_addr().unwrap().to_string()), None);
+        client.discard.store(true, Ordering::Relaxed);
+        let (queue, receiver) = mpsc::sync_channel(QUEUE_CAPACITY);
+        queue.send(vec![output(OutputKind::Iaq, 42.)]).unwrap();
+        drop(queue);
+
+        client.run(receiver).unwrap();
+
+        assert_eq!(
+            listener.accept().unwrap_err().kind(),
+            io::ErrorKind::WouldBlock
+        );
+    }
+
+    #[test]
+    fn test_flush_timeout() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut sink = MqttSink::new(
+            &MqttConfig {
+                qos: MqttQos::AtLeastOnce,
+                shutdown: SinkShutdownConfig {
+                    mode: SinkShutdownMode::Block,
+                    flush_timeout_seconds: 1,
+                },
+                ..config(listener.local_addr().unwrap().to_string())
+            },
+            PathBuf::new(),
+            ReadOnlySwitch::new(),
+        );
+        // The broker never acknowledges the publishing.
+        let broker = thread::spawn(move || -> TcpStream {
+            let (mut stream, _) = listener.accept().unwrap();
+            read_packet(&mut stream).unwrap();
+            stream.write_all(&[CONNACK, 2, 0, 0]).unwrap();
+            stream
+        });
+
+        sink.publish(&[output(OutputKind::Iaq, 42.)]).unwrap();
+        let started = Instant::now();
+        assert!(sink.flush().is_err());
+        assert!(started.elapsed() < IO_TIMEOUT);
+        drop(broker.join().unwrap());
+    }
+}
@@ -0,0 +1,278 @@
+//! Disk-backed queue of the messages a push sink could not deliver.
+//!
+//! The messages are kept in memory and appended to the file as one JSON
+//! document per line, so that they are delivered after the connection
+//! recovers, also across restarts. The oldest messages are dropped once the
+//! encoded messages exceed the maximum size. To spare the flash storage, the
+//! file is only rewritten without the dropped or delivered messages after a
+//! replay or once it grew to twice the maximum size. Until then, loading
+//! keeps the newest messages within the maximum size. Unreadable lines, e.g.
+//! partially appended ones, are skipped when loading.
+
+use std::collections::VecDeque;
+use std::fs::{self, OpenOptions};
+use std::io::{self, Write};
+use std::path::PathBuf;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::maintenance::ReadOnlySwitch;
+use crate::persistance::write_atomically;
+use crate::{log_error, log_warn};
+
+pub struct Spool<T> {
+    path: PathBuf,
+    max_bytes: usize,
+    read_only: ReadOnlySwitch,
+    /// Messages with the size of their encoded line.
+    messages: VecDeque<(T, usize)>,
+    bytes: usize,
+    /// Size of the file, which may still contain dropped messages before the
+    /// spooled ones.
+    file_bytes: usize,
+}
+
+impl<T: Serialize + DeserializeOwned> Spool<T> {
+    /// Loads the messages spooled in the file at `path`, if any.
+    pub fn load(path: PathBuf, max_bytes: usize, read_only: ReadOnlySwitch) -> Self {
+        let mut spool = Self {
+            path,
+            max_bytes,
+            read_only,
+            messages: VecDeque::new(),
+            bytes: 0,
+            file_bytes: 0,
+        };
+        let content = match fs::read_to_string(&spool.path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return spool,
+            Err(err) => {
+                log_warn!(
+                    "Discarding the spooled messages in {}: {}",
+                    spool.path.display(),
+                    err
+                );
+                return spool;
+            }
+        };
+        spool.file_bytes = content.len();
+        let mut skipped = 0;
+        for line in content.lines() {
+            match serde_json::from_str(line) {
+                Ok(message) => spool.messages.push_back((message, line.len() + 1)),
+                Err(_) => skipped += 1,
+            }
+        }
+        if skipped > 0 {
+            log_warn!(
+                "Skipped {} unreadable spooled messages in {}.",
+                skipped,
+                spool.path.display()
+            );
+        }
+        spool.bytes = spool.messages.iter().map(|(_, size)| size).sum();
+        // Messages beyond the maximum size were already dropped when pushed.
+        spool.truncate();
+        spool
+    }
+
+    pub fn len(&self) -> usize {
+        self.messages.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Appends the `messages`, dropping the oldest ones exceeding the
+    /// maximum size.
+    pub fn push(&mut self, messages: impl IntoIterator<Item = T>) {
+        let mut lines = String::new();
+        for message in messages {
+            let line = match serde_json::to_string(&message) {
+                Ok(line) => line,
+                Err(err) => {
+                    log_error!("Failed to spool a message: {}", err);
+                    continue;
+                }
+            };
+            lines.push_str(&line);
+            lines.push('\n');
+            self.messages.push_back((message, line.len() + 1));
+            self.bytes += line.len() + 1;
+        }
+        if lines.is_empty() {
+            return;
+        }
+        let dropped = self.truncate();
+        if dropped > 0 {
+            log_warn!(
+                "Spool {} full, dropped the {} oldest messages.",
+                self.path.display(),
+                dropped
+            );
+        }
+        if self.file_bytes + lines.len() > 2 * self.max_bytes {
+            self.save();
+        } else {
+            self.append(&lines);
+        }
+    }
+
+    /// Delivers the spooled messages in order with `deliver` until it fails,
+    /// removing the delivered ones.
+    pub fn replay<E>(&mut self, mut deliver: impl FnMut(&T) -> Result<(), E>) -> Result<(), E> {
+        let mut result = Ok(());
+        let spooled = self.messages.len();
+        while let Some((message, _)) = self.messages.front() {
+            if let Err(err) = deliver(message) {
+                result = Err(err);
+                break;
+            }
+            if let Some((_, size)) = self.messages.pop_front() {
+                self.bytes -= size;
+            }
+        }
+        if self.messages.len() != spooled {
+            self.save();
+        }
+        result
+    }
+
+    /// Drops the oldest messages exceeding the maximum size, returning their
+    /// number.
+    fn truncate(&mut self) -> usize {
+        let mut dropped = 0;
+        while self.bytes > self.max_bytes {
+            match self.messages.pop_front() {
+                Some((_, size)) => {
+                    self.bytes -= size;
+                    dropped += 1;
+                }
+                None => break,
+            }
+        }
+        dropped
+    }
+
+    fn append(&mut self, lines: &str) {
+        if self.read_only.is_read_only() {
+            return;
+        }
+        let result = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()));
+        match result {
+            Ok(()) => self.file_bytes += lines.len(),
+            Err(err) => log_error!(
+                "Failed to append the spooled messages to {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+
+    /// Rewrites the file with the spooled messages only.
+    fn save(&mut self) {
+        if self.read_only.is_read_only() {
+            return;
+        }
+        let mut content = String::with_capacity(self.bytes);
+        for (message, _) in self.messages.iter() {
+            if let Ok(line) = serde_json::to_string(message) {
+                content.push_str(&line);
+                content.push('\n');
+            }
+        }
+        match write_atomically(&self.path, content.as_bytes()) {
+            Ok(()) => self.file_bytes = content.len(),
+            Err(err) => log_error!(
+                "Failed to save the spooled messages to {}: {}",
+                self.path.display(),
+                err
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tempfile::tempdir;
+
+    #[test]
+    fn test_spool_roundtrips() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("spool.jsonl");
+        let mut spool = Spool::load(path.clone(), 1024, ReadOnlySwitch::new());
+        assert!(spool.is_empty());
+        spool.push(vec!["a".to_string(), "b".to_string()]);
+
+        let mut spool: Spool<String> = Spool::load(path.clone(), 1024, ReadOnlySwitch::new());
+        assert_eq!(spool.len(), 2);
+        let mut delivered = vec![];
+        let result = spool.replay(|message| {
+            if message == "b" {
+                return Err("broker gone");
+            }
+            delivered.push(message.clone());
+            Ok(())
+        });
+        assert_eq!(result, Err("broker gone"));
+        assert_eq!(delivered, vec!["a".to_string()]);
+
+        let mut spool: Spool<String> = Spool::load(path, 1024, ReadOnlySwitch::new());
+        assert_eq!(spool.replay(|_| Ok::<_, ()>(())), Ok(()));
+        assert!(spool.is_empty());
+    }
+
+    #[test]
+    fn test_spool_drops_oldest_messages() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("spool.jsonl");
+        // Each message takes 4 bytes: the quoted character and a newline.
+        let mut spool = Spool::load(path.clone(), 8, ReadOnlySwitch::new());
+        spool.push(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        assert_eq!(spool.len(), 2);
+
+        let mut spool: Spool<String> = Spool::load(path, 8, ReadOnlySwitch::new());
+        let mut delivered = vec![];
+        spool
+            .replay(|message| {
+                delivered.push(message.clone());
+                Ok::<_, ()>(())
+            })
+            .unwrap();
+        assert_eq!(delivered, vec!["b".to_string(), "c".to_string()]);
+    }
+
+    #[test]
+    fn test_spool_appends_until_compaction() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("spool.jsonl");
+        let mut spool = Spool::load(path.clone(), 8, ReadOnlySwitch::new());
+        spool.push(vec!["a".to_string(), "b".to_string(), "c".to_string()]);
+        spool.push(vec!["d".to_string()]);
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            "\"a\"\n\"b\"\n\"c\"\n\"d\"\n"
+        );
+        spool.push(vec!["e".to_string()]);
+        assert_eq!(fs::read_to_string(&path).unwrap(), "\"d\"\n\"e\"\n");
+
+        spool.replay(|_| Ok::<_, ()>(())).unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "");
+    }
+
+    #[test]
+    fn test_spool_skips_unreadable_lines() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("spool.jsonl");
+        fs::write(&path, "\"a\"\n{corrupted\n\"b").unwrap();
+        let spool: Spool<String> = Spool::load(path, 1024, ReadOnlySwitch::new());
+        assert_eq!(spool.len(), 1);
+    }
+}
@@ -0,0 +1,104 @@
+//! Persistence of the last exported BSEC output values across restarts.
+//!
+//! Restoring the values avoids gaps and zeros on dashboards during a brief
+//! restart until the first BSEC output is available. Restored values are
+//! marked as stale with the `bsec_values_restored` metric until replaced by
+//! actual outputs.
+
+use std::collections::HashMap;
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::time::Duration;
+
+use serde::{Deserialize, Serialize};
+
+use crate::log_info;
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Serialize)]
+pub struct OutputValue {
+    pub signal: f64,
+    pub accuracy: f64,
+}
+
+/// Last values by BSEC output name as used in the configuration.
+pub type ValuesSnapshot = HashMap<String, OutputValue>;
+
+/// Loads a previously saved snapshot, `None` if there is none or it was saved
+/// more than `max_age` ago.
+///
+/// The age is determined from the modification time of the file. A snapshot
+/// modified in the future, e.g. before a cold boot of a device without
+/// real-time clock, is considered too old.
+pub fn load_snapshot<P: AsRef<Path>>(
+    path: P,
+    max_age: Duration,
+) -> io::Result<Option<ValuesSnapshot>> {
+    let path = path.as_ref();
+    let modified = match fs::metadata(path) {
+        Ok(metadata) => metadata.modified()?,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    match modified.elapsed() {
+        Ok(age) if age <= max_age => (),
+        Ok(age) => {
+            log_info!(
+                "Not restoring the last values saved {} s ago.",
+                age.as_secs()
+            );
+            return Ok(None);
+        }
+        Err(_) => {
+            log_info!("Not restoring the last values saved in the future.");
+            return Ok(None);
+        }
+    }
+    serde_json::from_str(&fs::read_to_string(path)?)
+        .map(Some)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+pub fn save_snapshot<P: AsRef<Path>>(path: P, snapshot: &ValuesSnapshot) -> io::Result<()> {
+    fs::write(path, serde_json::to_vec(snapshot)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::SystemTime;
+    use tempfile::tempdir;
+
+    const MAX_AGE: Duration = Duration::from_secs(600);
+
+    #[test]
+    fn test_snapshot_roundtrip() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("last-values.json");
+        assert_eq!(load_snapshot(&path, MAX_AGE).unwrap(), None);
+
+        let snapshot: ValuesSnapshot = vec![(
+            "iaq".to_string(),
+            OutputValue {
+                signal: 42.,
+                accuracy: 3.,
+            },
+        )]
+        .into_iter()
+        .collect();
+        save_snapshot(&path, &snapshot).unwrap();
+        assert_eq!(load_snapshot(&path, MAX_AGE).unwrap(), Some(snapshot));
+    }
+
+    #[test]
+    fn test_skips_old_snapshots() {
+        let tmp_dir = tempdir().unwrap();
+        let path = tmp_dir.path().join("last-values.json");
+        save_snapshot(&path, &ValuesSnapshot::new()).unwrap();
+        let file = fs::File::options().write(true).open(&path).unwrap();
+        file.set_modified(SystemTime::now() - 2 * MAX_AGE).unwrap();
+        assert_eq!(load_snapshot(&path, MAX_AGE).unwrap(), None);
+        file.set_modified(SystemTime::now() + MAX_AGE).unwrap();
+        assert_eq!(load_snapshot(&path, MAX_AGE).unwrap(), None);
+    }
+}
@@ -0,0 +1,229 @@
+//! SPI transport of the BME680 sensor.
+//!
+//! The bme680 crate only talks I2C. [`SpiRegisters`] thus emulates the
+//! register access of the crate over SPI: a write of a single byte sets the
+//! register to read from, writes of register and value pairs set registers,
+//! and reads read consecutive registers from the set register. The I2C
+//! address is ignored.
+//!
+//! SPI addresses only 7 bits with the most significant bit selecting a read.
+//! The registers are split into two memory pages selected with the
+//! `spi_mem_page` bit of the status register. Page 0 holds the registers
+//! 0x80 to 0xFF of the I2C address space, page 1 the registers 0x00 to 0x7F.
+
+use std::io;
+
+use embedded_hal::blocking::i2c::{Read, Write};
+use embedded_hal::blocking::spi::Transfer;
+use linux_embedded_hal::i2cdev::linux::LinuxI2CError;
+use linux_embedded_hal::spidev::{SpiModeFlags, SpidevOptions};
+use linux_embedded_hal::Spidev;
+
+/// Status register holding the memory page, present in both pages.
+const STATUS_REGISTER: u8 = 0x73;
+const MEM_PAGE_BIT: u8 = 1 << 4;
+const READ_BIT: u8 = 0x80;
+const SOFT_RESET_REGISTER: u8 = 0xe0;
+
+/// Memory page of a register in the I2C address space.
+fn mem_page(register: u8) -> u8 {
+    if register & 0x80 == 0 {
+        MEM_PAGE_BIT
+    } else {
+        0
+    }
+}
+
+/// BME680 connected via SPI with the register access of an I2C device.
+pub struct SpiRegisters<S> {
+    spi: S,
+    register: u8,
+    /// Selected memory page, unknown after opening and resets.
+    page: Option<u8>,
+}
+
+impl SpiRegisters<Spidev> {
+    /// Opens the spidev device in SPI mode 0.
+    pub fn open(device: &str, max_speed_hz: u32) -> io::Result<Self> {
+        let mut spi = Spidev::open(device)?;
+        spi.configure(
+            &SpidevOptions::new()
+                .bits_per_word(8)
+                .max_speed_hz(max_speed_hz)
+                .mode(SpiModeFlags::SPI_MODE_0)
+                .build(),
+        )?;
+        Ok(Self::new(spi))
+    }
+}
+
+impl<S> SpiRegisters<S>
+where
+    S: Transfer<u8, Error = io::Error>,
+{
+    pub fn new(spi: S) -> Self {
+        Self {
+            spi,
+            register: 0,
+            page: None,
+        }
+    }
+
+    fn select_page(&mut self, register: u8) -> io::Result<()> {
+        let page = mem_page(register);
+        if self.page == Some(page) {
+            return Ok(());
+        }
+        let mut status = [STATUS_REGISTER | READ_BIT, 0];
+        self.spi.transfer(&mut status)?;
+        let mut write = [STATUS_REGISTER, (status[1] & !MEM_PAGE_BIT) | page];
+        self.spi.transfer(&mut write)?;
+        self.page = Some(page);
+        Ok(())
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> io::Result<()> {
+        self.select_page(register)?;
+        self.spi.transfer(&mut [register & !READ_BIT, value])?;
+        if register == SOFT_RESET_REGISTER {
+            self.page = None;
+        }
+        Ok(())
+    }
+}
+
+impl<S> Read for SpiRegisters<S>
+where
+    S: Transfer<u8, Error = io::Error>,
+{
+    type Error = LinuxI2CError;
+
+    fn read(&mut self, _address: u8, buffer: &mut [u8]) -> Result<(), Self::Error> {
+        self.select_page(self.register)?;
+        let mut transfer = vec![0; buffer.len() + 1];
+        transfer[0] = self.register | READ_BIT;
+        self.spi.transfer(&mut transfer)?;
+        buffer.copy_from_slice(&transfer[1..]);
+        Ok(())
+    }
+}
+
+impl<S> Write for SpiRegisters<S>
+where
+    S: Transfer<u8, Error = io::Error>,
+{
+    type Error = LinuxI2CError;
+
+    fn write(&mut self, _address: u8, bytes: &[u8]) -> Result<(), Self::Error> {
+        match bytes {
+            [register] => self.register = *register,
+            _ => {
+                for pair in bytes.chunks(2) {
+                    match pair {
+                        [register, value] => self.write_register(*register, *value)?,
+                        _ => {
+                            return Err(io::Error::new(
+                                io::ErrorKind::InvalidInput,
+                                "register write without value",
+                            )
+                            .into())
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Register file of a BME680 answering SPI transfers.
+    struct FakeBme680 {
+        registers: [u8; 256],
+        page_switches: usize,
+    }
+
+    impl FakeBme680 {
+        fn page_offset(&self) -> usize {
+            if self.registers[STATUS_REGISTER as usize] & MEM_PAGE_BIT == 0 {
+                0x80
+            } else {
+                0
+            }
+        }
+    }
+
+    impl Transfer<u8> for FakeBme680 {
+        type Error = io::Error;
+
+        fn transfer<'w>(&mut self, words: &'w mut [u8]) -> Result<&'w [u8], io::Error> {
+            let address = (words[0] & !READ_BIT) as usize;
+            let register = |address: usize, offset: usize| {
+                if address == STATUS_REGISTER as usize {
+                    address
+                } else {
+                    address + offset
+                }
+            };
+            if words[0] & READ_BIT != 0 {
+                for (i, word) in words.iter_mut().skip(1).enumerate() {
+                    *word = self.registers[register(address + i, self.page_offset())];
+                }
+            } else {
+                if address == STATUS_REGISTER as usize {
+                    self.page_switches += 1;
+                }
+                let register = register(address, self.page_offset());
+                self.registers[register] = words[1];
+            }
+            Ok(words)
+        }
+    }
+
+    fn fake_bme680() -> FakeBme680 {
+        let mut registers = [0; 256];
+        registers[0xd0] = 0x61;
+        registers[0x1d] = 0x80;
+        registers[0x1e] = 0x12;
+        FakeBme680 {
+            registers,
+            page_switches: 0,
+        }
+    }
+
+    #[test]
+    fn test_reads_registers_of_both_pages() {
+        let mut spi = SpiRegisters::new(fake_bme680());
+        let mut chip_id = [0];
+        spi.write(0x76, &[0xd0]).unwrap();
+        spi.read(0x76, &mut chip_id).unwrap();
+        assert_eq!(chip_id, [0x61]);
+
+        let mut field = [0; 2];
+        spi.write(0x76, &[0x1d]).unwrap();
+        spi.read(0x76, &mut field).unwrap();
+        assert_eq!(field, [0x80, 0x12]);
+    }
+
+    #[test]
+    fn test_writes_register_pairs() {
+        let mut spi = SpiRegisters::new(fake_bme680());
+        spi.write(0x76, &[0x74, 0x25, 0xf5, 0x08]).unwrap();
+        assert_eq!(spi.spi.registers[0x74], 0x25);
+        assert_eq!(spi.spi.registers[0xf5], 0x08);
+        assert!(spi.write(0x76, &[0x74, 0x25, 0x72]).is_err());
+    }
+
+    #[test]
+    fn test_switches_page_only_when_required() {
+        let mut spi = SpiRegisters::new(fake_bme680());
+        spi.write(0x76, &[0x74, 0x25, 0x72, 0x01]).unwrap();
+        assert_eq!(spi.spi.page_switches, 1);
+        spi.write(0x76, &[0xe0, 0xb6]).unwrap();
+        spi.write(0x76, &[0x74, 0x25]).unwrap();
+        assert_eq!(spi.spi.page_switches, 3);
+    }
+}
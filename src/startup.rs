@@ -0,0 +1,210 @@
+//! Tracking of the startup phases to diagnose slow or stuck boots.
+
+use std::fmt::{self, Debug, Formatter};
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+use serde::Serialize;
+
+use crate::log_info;
+use crate::monitor::PersistState;
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Phase {
+    pub name: &'static str,
+    /// Start of the phase relative to the start of the tracking.
+    pub started_seconds: f64,
+    /// Duration of the phase, `None` while the phase is in progress.
+    pub duration_seconds: Option<f64>,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct StartupStatus {
+    pub phases: Vec<Phase>,
+    pub complete: bool,
+}
+
+type Notifier = Arc<dyn Fn(&str) + Send + Sync>;
+
+/// Startup phases shared between the startup code and the `/startup`
+/// endpoint.
+///
+/// Once the startup is complete, further phases are ignored, e.g. during
+/// in-process restarts of the monitoring.
+#[derive(Clone)]
+pub struct StartupPhases {
+    start: Instant,
+    status: Arc<Mutex<StartupStatus>>,
+    notifier: Option<Notifier>,
+}
+
+impl Debug for StartupPhases {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        f.debug_struct("StartupPhases")
+            .field("start", &self.start)
+            .field("status", &self.status)
+            .finish()
+    }
+}
+
+impl StartupPhases {
+    pub fn new() -> Self {
+        Self {
+            start: Instant::now(),
+            status: Arc::default(),
+            notifier: None,
+        }
+    }
+
+    /// Calls `notifier` with a status message whenever a phase begins or the
+    /// startup completes, e.g. to report it to systemd.
+    pub fn with_notifier(mut self, notifier: impl Fn(&str) + Send + Sync + 'static) -> Self {
+        self.notifier = Some(Arc::new(notifier));
+        self
+    }
+
+    fn finish_current(&self, status: &mut StartupStatus) {
+        let elapsed = self.start.elapsed().as_secs_f64();
+        if let Some(phase) = status.phases.last_mut() {
+            if phase.duration_seconds.is_none() {
+                phase.duration_seconds = Some(elapsed - phase.started_seconds);
+            }
+        }
+    }
+
+    fn notify(&self, message: &str) {
+        log_info!("{}", message);
+        if let Some(notifier) = &self.notifier {
+            notifier(message);
+        }
+    }
+
+    /// Ends the current phase and begins the phase with the given name.
+    pub fn begin(&self, name: &'static str) {
+        let mut status = self.status.lock().unwrap();
+        if status.complete {
+            return;
+        }
+        self.finish_current(&mut status);
+        status.phases.push(Phase {
+            name,
+            started_seconds: self.start.elapsed().as_secs_f64(),
+            duration_seconds: None,
+        });
+        drop(status);
+        self.notify(&format!("Startup: {} ...", name));
+    }
+
+    /// Ends the current phase and marks the startup as complete.
+    pub fn complete(&self) {
+        let mut status = self.status.lock().unwrap();
+        if status.complete {
+            return;
+        }
+        self.finish_current(&mut status);
+        status.complete = true;
+        drop(status);
+        self.notify(&format!(
+            "Startup complete after {:.1} s.",
+            self.start.elapsed().as_secs_f64()
+        ));
+    }
+
+    pub fn status(&self) -> StartupStatus {
+        self.status.lock().unwrap().clone()
+    }
+}
+
+impl Default for StartupPhases {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Tracks the loading of the state and the wait for the first measurement as
+/// startup phases.
+pub struct PhasedPersistState<P: PersistState> {
+    persist_state: P,
+    phases: StartupPhases,
+}
+
+impl<P: PersistState> PhasedPersistState<P> {
+    pub fn new(persist_state: P, phases: StartupPhases) -> Self {
+        Self {
+            persist_state,
+            phases,
+        }
+    }
+}
+
+impl<P: PersistState> PersistState for PhasedPersistState<P> {
+    type Error = P::Error;
+
+    fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        self.phases.begin("loading state");
+        let state = self.persist_state.load_state();
+        self.phases.begin("first measurement");
+        state
+    }
+
+    fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
+        self.persist_state.save_state(state)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_support::MockPersistState;
+
+    #[test]
+    fn test_startup_phases() {
+        let messages = Arc::new(Mutex::new(vec![]));
+        let phases = StartupPhases::new().with_notifier({
+            let messages = messages.clone();
+            move |message| messages.lock().unwrap().push(message.to_string())
+        });
+
+        phases.begin("opening I2C");
+        let status = phases.status();
+        assert_eq!(status.phases.len(), 1);
+        assert_eq!(status.phases[0].duration_seconds, None);
+        assert!(!status.complete);
+
+        phases.begin("initializing sensor");
+        phases.complete();
+        phases.begin("ignored after completion");
+
+        let status = phases.status();
+        assert!(status.complete);
+        assert_eq!(
+            status
+                .phases
+                .iter()
+                .map(|phase| phase.name)
+                .collect::<Vec<_>>(),
+            vec!["opening I2C", "initializing sensor"]
+        );
+        assert!(status
+            .phases
+            .iter()
+            .all(|phase| phase.duration_seconds.is_some()));
+        let messages = messages.lock().unwrap();
+        assert_eq!(messages.len(), 3);
+        assert_eq!(messages[0], "Startup: opening I2C ...");
+    }
+
+    #[test]
+    fn test_phased_persist_state() {
+        let phases = StartupPhases::new();
+        let mut persist_state =
+            PhasedPersistState::new(MockPersistState::default(), phases.clone());
+        persist_state.load_state().unwrap();
+
+        let status = phases.status();
+        assert_eq!(status.phases.len(), 2);
+        assert_eq!(status.phases[0].name, "loading state");
+        assert!(status.phases[0].duration_seconds.is_some());
+        assert_eq!(status.phases[1].name, "first measurement");
+    }
+}
@@ -0,0 +1,59 @@
+//! Notifications of the systemd service manager.
+//!
+//! Without the `systemd` feature, the exporter never considers itself to be
+//! run by systemd and the notifications are no-ops.
+
+use std::time::Duration;
+
+#[cfg(feature = "systemd")]
+use libsystemd::daemon::{self, NotifyState};
+
+/// Whether the system was booted with systemd.
+pub fn booted() -> bool {
+    #[cfg(feature = "systemd")]
+    return daemon::booted();
+    #[cfg(not(feature = "systemd"))]
+    return false;
+}
+
+#[cfg(feature = "systemd")]
+fn notify(unset_env: bool, state: NotifyState) -> anyhow::Result<()> {
+    daemon::notify(unset_env, &[state])?;
+    Ok(())
+}
+
+/// Notifies that the startup is finished.
+pub fn notify_ready() -> anyhow::Result<()> {
+    #[cfg(feature = "systemd")]
+    notify(false, NotifyState::Ready)?;
+    Ok(())
+}
+
+/// Notifies that the shutdown has begun. Must be the last notification.
+pub fn notify_stopping() -> anyhow::Result<()> {
+    #[cfg(feature = "systemd")]
+    notify(true, NotifyState::Stopping)?;
+    Ok(())
+}
+
+/// Reports the `status` of the service, ignoring failures.
+pub fn notify_status(status: &str) {
+    #[cfg(feature = "systemd")]
+    let _ = notify(false, NotifyState::Status(status.into()));
+    #[cfg(not(feature = "systemd"))]
+    let _ = status;
+}
+
+/// Keeps the service watchdog alive, ignoring failures.
+pub fn notify_watchdog() {
+    #[cfg(feature = "systemd")]
+    let _ = notify(false, NotifyState::Watchdog);
+}
+
+/// Interval of the service watchdog, `None` if it is disabled.
+pub fn watchdog_interval() -> Option<Duration> {
+    #[cfg(feature = "systemd")]
+    return daemon::watchdog_enabled(false);
+    #[cfg(not(feature = "systemd"))]
+    return None;
+}
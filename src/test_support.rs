@@ -0,0 +1,132 @@
+//! Building blocks for deterministic tests of the BSEC monitoring.
+//!
+//! This module is only available if the **test-support** feature is enabled.
+//!
+//! Note that BSEC can only be instantiated once at a time. Tests creating a
+//! [`Bsec`] instance need to be run serially, e.g. with the `serial_test`
+//! crate.
+//!
+//! # Example
+//!
+//! ```
+//! # use std::sync::Arc;
+//! # use bsec::{Input, InputKind, OutputKind, SampleRate, SubscriptionRequest};
+//! # use linux_bsec_exporter::monitor::bsec_monitor;
+//! # use linux_bsec_exporter::test_support::{fake_bsec, FakeClock, MockPersistState};
+//! # tokio::runtime::Builder::new_current_thread().build().unwrap().block_on(async {
+//! let clock = Arc::new(FakeClock::new());
+//! let bsec = fake_bsec(
+//!     vec![Input {
+//!         sensor: InputKind::Temperature,
+//!         signal: 22.,
+//!     }],
+//!     &[SubscriptionRequest {
+//!         sensor: OutputKind::RawTemperature,
+//!         sample_rate: SampleRate::Lp,
+//!     }],
+//!     clock.clone(),
+//! );
+//! let (monitor, mut rx) = bsec_monitor(bsec, MockPersistState::default(), clock);
+//! let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+//!
+//! rx.current.changed().await.unwrap();
+//! assert_eq!(rx.current.borrow().as_ref().unwrap()[0].signal, 22.);
+//!
+//! rx.initiate_shutdown.send(()).unwrap();
+//! join_handle.await.unwrap().unwrap();
+//! # });
+//! ```
+
+use std::future::{self, Ready};
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use bsec::{Bsec, Input, SubscriptionRequest};
+
+use super::monitor::{PersistState, Sleep};
+
+pub use bsec::bme::test_support::FakeBmeSensor;
+pub use bsec::clock::test_support::FakeClock;
+
+/// Sleeping advances the [`FakeClock`] immediately instead of waiting.
+impl Sleep for FakeClock {
+    type SleepFuture = Ready<()>;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        self.advance_by(duration);
+        future::ready(())
+    }
+}
+
+/// Keeps the persisted state in memory.
+///
+/// Clones share the same state, so the state can be inspected and modified
+/// while the monitoring owns the instance.
+#[derive(Clone, Default)]
+pub struct MockPersistState {
+    pub state: Arc<RwLock<Option<Vec<u8>>>>,
+}
+
+impl PersistState for MockPersistState {
+    type Error = std::convert::Infallible;
+
+    fn load_state(&mut self) -> Result<Option<Vec<u8>>, Self::Error> {
+        Ok(self.state.read().unwrap().clone())
+    }
+
+    fn save_state(&mut self, state: &[u8]) -> Result<(), Self::Error> {
+        *self.state.write().unwrap() = Some(Vec::from(state));
+        Ok(())
+    }
+}
+
+/// Initializes BSEC with a [`FakeBmeSensor`] always providing `inputs` and
+/// subscribes to `subscriptions`.
+///
+/// # Panics
+///
+/// If BSEC is already in use or rejects the subscriptions.
+pub fn fake_bsec(
+    inputs: Vec<Input>,
+    subscriptions: &[SubscriptionRequest],
+    clock: Arc<FakeClock>,
+) -> Bsec<FakeBmeSensor, FakeClock, Arc<FakeClock>> {
+    let mut bsec = Bsec::init(FakeBmeSensor::new(Ok(inputs)), clock).unwrap();
+    bsec.update_subscription(subscriptions).unwrap();
+    bsec
+}
+
+/// Deterministic pseudo-random number generator (xorshift64*) for tests
+/// checking many generated inputs.
+#[derive(Clone, Debug)]
+pub struct TestRng(u64);
+
+impl TestRng {
+    /// Creates a generator producing the same sequence for the same `seed`.
+    pub fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Random number in `range`.
+    pub fn below(&mut self, range: std::ops::Range<usize>) -> usize {
+        range.start + (self.next_u64() % (range.end - range.start) as u64) as usize
+    }
+
+    /// Random element of the non-empty `items`.
+    pub fn select<'a, T>(&mut self, items: &'a [T]) -> &'a T {
+        &items[self.below(0..items.len())]
+    }
+
+    /// Random bytes with a length in `len`.
+    pub fn bytes(&mut self, len: std::ops::Range<usize>) -> Vec<u8> {
+        let len = self.below(len);
+        (0..len).map(|_| self.next_u64() as u8).collect()
+    }
+}
@@ -0,0 +1,94 @@
+//! Detection of the synchronization of the system time.
+//!
+//! Devices without real-time clock, like the Raspberry Pi, start with a
+//! system time far in the past after a cold boot until NTP synchronized the
+//! time. Outputs attached with wall-clock timestamps during that period are
+//! bogus.
+
+use std::io;
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use prometheus::IntGauge;
+
+use crate::{log_error, log_info};
+
+/// Whether the kernel considers the system time synchronized given the clock
+/// `state` and `status` flags returned by `adjtimex`.
+fn is_synchronized_state(state: libc::c_int, status: libc::c_int) -> bool {
+    state != libc::TIME_ERROR && status & libc::STA_UNSYNC == 0
+}
+
+/// Queries the kernel whether the system time is synchronized, e.g. by NTP.
+pub fn is_synchronized() -> io::Result<bool> {
+    // Safety: all-zero is a valid `timex` and with `modes` set to zero
+    // `adjtimex` only reads the clock state.
+    let mut timex: libc::timex = unsafe { std::mem::zeroed() };
+    let state = unsafe { libc::adjtimex(&mut timex) };
+    if state == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(is_synchronized_state(state, timex.status))
+}
+
+/// Last known synchronization status of the system time, shared between the
+/// periodic check and the consumers of wall-clock timestamps.
+#[derive(Clone, Debug, Default)]
+pub struct TimeSyncStatus(Arc<AtomicBool>);
+
+impl TimeSyncStatus {
+    pub fn new(synchronized: bool) -> Self {
+        Self(Arc::new(AtomicBool::new(synchronized)))
+    }
+
+    pub fn is_synchronized(&self) -> bool {
+        self.0.load(Ordering::Acquire)
+    }
+
+    pub fn set_synchronized(&self, synchronized: bool) {
+        if self.0.swap(synchronized, Ordering::AcqRel) != synchronized {
+            if synchronized {
+                log_info!("System time is synchronized.");
+            } else {
+                log_info!("System time is not synchronized.");
+            }
+        }
+    }
+}
+
+/// Periodically updates the `status` and `gauge` with the synchronization
+/// status of the system time.
+pub async fn run_time_sync_check(status: TimeSyncStatus, gauge: IntGauge, interval: Duration) {
+    let mut ticks = tokio::time::interval(interval);
+    loop {
+        ticks.tick().await;
+        match is_synchronized() {
+            Ok(synchronized) => {
+                status.set_synchronized(synchronized);
+                gauge.set(synchronized as i64);
+            }
+            Err(err) => log_error!("Failed to query time synchronization: {}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_is_synchronized_state() {
+        assert!(is_synchronized_state(libc::TIME_OK, 0));
+        assert!(is_synchronized_state(libc::TIME_INS, libc::STA_PLL));
+        assert!(!is_synchronized_state(libc::TIME_ERROR, 0));
+        assert!(!is_synchronized_state(libc::TIME_OK, libc::STA_UNSYNC));
+    }
+
+    #[test]
+    fn test_is_synchronized_queries_kernel() {
+        assert!(is_synchronized().is_ok());
+    }
+}
@@ -0,0 +1,151 @@
+use std::sync::{
+    atomic::{AtomicBool, Ordering},
+    Arc,
+};
+use std::time::Duration;
+
+use bsec::{SampleRate, SubscriptionRequest};
+use tokio::sync::{mpsc, watch};
+
+/// Detects stalls of the monitoring loop by observing its outputs.
+#[derive(Clone)]
+pub struct Watchdog {
+    timeout: Duration,
+    healthy: Arc<AtomicBool>,
+}
+
+impl Watchdog {
+    pub fn new(timeout: Duration) -> Self {
+        Self {
+            timeout,
+            healthy: Arc::new(AtomicBool::new(true)),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    /// Whether outputs have been received within the timeout on the last
+    /// observation.
+    pub fn is_healthy(&self) -> bool {
+        self.healthy.load(Ordering::Acquire)
+    }
+
+    /// Waits until no new outputs have been received for the duration of the
+    /// timeout.
+    ///
+    /// Returns an error if the sending side of the channel has been dropped.
+    pub async fn wait_for_stall<T>(
+        &self,
+        outputs: &mut watch::Receiver<T>,
+    ) -> Result<(), watch::error::RecvError> {
+        loop {
+            match tokio::time::timeout(self.timeout, outputs.changed()).await {
+                Ok(result) => {
+                    result?;
+                    self.healthy.store(true, Ordering::Release);
+                }
+                Err(_) => {
+                    self.healthy.store(false, Ordering::Release);
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    /// Reports each detected stall to `stalls` until either channel is closed.
+    pub async fn observe<T>(
+        self,
+        mut outputs: watch::Receiver<T>,
+        stalls: mpsc::UnboundedSender<()>,
+    ) {
+        while self.wait_for_stall(&mut outputs).await.is_ok() {
+            if stalls.send(()).is_err() {
+                break;
+            }
+        }
+    }
+}
+
+/// Interval in which BSEC provides outputs for the given subscriptions.
+///
+/// Returns `None` if no output is subscribed with a periodic sample rate.
+pub fn output_interval(subscriptions: &[SubscriptionRequest]) -> Option<Duration> {
+    subscriptions
+        .iter()
+        .filter_map(|item| match item.sample_rate {
+            SampleRate::Continuous => Some(Duration::from_secs(1)),
+            SampleRate::Lp => Some(Duration::from_secs(3)),
+            SampleRate::Ulp => Some(Duration::from_secs(300)),
+            SampleRate::Disabled | SampleRate::UlpMeasurementOnDemand => None,
+        })
+        .min()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bsec::OutputKind;
+
+    #[test]
+    fn test_output_interval() {
+        assert_eq!(output_interval(&[]), None);
+        assert_eq!(
+            output_interval(&[
+                SubscriptionRequest {
+                    sensor: OutputKind::Iaq,
+                    sample_rate: SampleRate::Ulp,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::RawGas,
+                    sample_rate: SampleRate::Lp,
+                },
+                SubscriptionRequest {
+                    sensor: OutputKind::RawTemperature,
+                    sample_rate: SampleRate::Disabled,
+                },
+            ]),
+            Some(Duration::from_secs(3))
+        );
+    }
+
+    #[tokio::test]
+    async fn detects_stall() {
+        let (sender, mut receiver) = watch::channel(0);
+        let watchdog = Watchdog::new(Duration::from_millis(50));
+
+        let feeder = tokio::task::spawn(async move {
+            for i in 1..5 {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+                sender.send(i).unwrap();
+            }
+            sender
+        });
+
+        watchdog.wait_for_stall(&mut receiver).await.unwrap();
+        assert!(!watchdog.is_healthy());
+        assert_eq!(*receiver.borrow(), 4);
+
+        let sender = feeder.await.unwrap();
+        sender.send(5).unwrap();
+        drop(sender);
+        assert!(watchdog.wait_for_stall(&mut receiver).await.is_err());
+        assert!(watchdog.is_healthy());
+    }
+
+    #[tokio::test]
+    async fn reports_stalls() {
+        let (sender, receiver) = watch::channel(0);
+        let (stall_sender, mut stalls) = mpsc::unbounded_channel();
+        let watchdog = Watchdog::new(Duration::from_millis(10));
+
+        let join_handle = tokio::task::spawn(watchdog.clone().observe(receiver, stall_sender));
+        assert_eq!(stalls.recv().await, Some(()));
+        assert_eq!(stalls.recv().await, Some(()));
+
+        drop(sender);
+        join_handle.await.unwrap();
+        assert_eq!(stalls.recv().await, None);
+    }
+}
@@ -0,0 +1,156 @@
+//! Soak test driving the full monitoring pipeline at accelerated time.
+//!
+//! The test is ignored by default. Run it with
+//!
+//! ```sh
+//! cargo test --release --test soak -- --ignored --nocapture
+//! ```
+//!
+//! The simulated duration can be set with the `SOAK_SIMULATED_DAYS`
+//! environment variable (default: 7).
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::future::{self, Ready};
+use std::sync::atomic::{AtomicIsize, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use bsec::bme::test_support::FakeBmeSensor;
+use bsec::clock::{test_support::FakeClock, Clock};
+use bsec::{Bsec, Input, InputKind, OutputKind, SampleRate, SubscriptionRequest};
+use linux_bsec_exporter::metrics::BsecGaugeRegistry;
+use linux_bsec_exporter::monitor::{bsec_monitor, Sleep};
+use linux_bsec_exporter::persistance::StateFile;
+
+struct CountingAllocator;
+
+static ALLOCATED_BYTES: AtomicIsize = AtomicIsize::new(0);
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        ALLOCATED_BYTES.fetch_add(layout.size() as isize, Ordering::Relaxed);
+        System.alloc(layout)
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        ALLOCATED_BYTES.fetch_sub(layout.size() as isize, Ordering::Relaxed);
+        System.dealloc(ptr, layout)
+    }
+}
+
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
+
+#[derive(Default)]
+struct SimulatedClock(FakeClock);
+
+impl Clock for SimulatedClock {
+    fn timestamp_ns(&self) -> i64 {
+        self.0.timestamp_ns()
+    }
+}
+
+impl Sleep for SimulatedClock {
+    type SleepFuture = Ready<()>;
+
+    fn sleep(&self, duration: Duration) -> Self::SleepFuture {
+        self.0.advance_by(duration);
+        future::ready(())
+    }
+}
+
+const DAY_NS: i64 = 24 * 3600 * 1_000_000_000;
+const MAX_HEAP_GROWTH_BYTES: isize = 64 * 1024;
+const MAX_LATENCY_NS: i64 = 1_000_000_000;
+
+fn simulated_days() -> i64 {
+    std::env::var("SOAK_SIMULATED_DAYS")
+        .ok()
+        .and_then(|days| days.parse().ok())
+        .unwrap_or(7)
+}
+
+#[tokio::test(flavor = "current_thread")]
+#[ignore]
+async fn soak() {
+    let state_dir = tempfile::tempdir().unwrap();
+    let state_path = state_dir.path().join("bsec-state.bin");
+
+    let clock = Arc::new(SimulatedClock::default());
+    let bme = FakeBmeSensor::new(Ok(vec![
+        Input {
+            sensor: InputKind::Temperature,
+            signal: 22.,
+        },
+        Input {
+            sensor: InputKind::Humidity,
+            signal: 40.,
+        },
+    ]));
+    let sensors = [OutputKind::RawTemperature, OutputKind::RawHumidity];
+    let mut bsec = Bsec::init(bme, clock.clone()).unwrap();
+    let subscriptions: Vec<_> = sensors
+        .iter()
+        .map(|&sensor| SubscriptionRequest {
+            sensor,
+            sample_rate: SampleRate::Lp,
+        })
+        .collect();
+    bsec.update_subscription(&subscriptions).unwrap();
+    let registry = BsecGaugeRegistry::new(&sensors).unwrap();
+
+    let (monitor, mut rx) = bsec_monitor(bsec, StateFile::new(state_path.clone()), clock.clone());
+    let join_handle = tokio::task::spawn(monitor.monitoring_loop());
+
+    let end_ns = simulated_days() * DAY_NS;
+    let mut next_report_ns = DAY_NS;
+    let mut baseline: Option<(isize, u64)> = None;
+    let mut cycles = 0u64;
+    while clock.0.timestamp_ns() < end_ns {
+        rx.current.changed().await.unwrap();
+        cycles += 1;
+        let timing = *rx.timing.borrow();
+        registry.set_timing(&timing);
+        if let Some(outputs) = rx.current.borrow().as_deref() {
+            for output in outputs.iter() {
+                registry.set(output);
+            }
+        }
+        assert!(
+            timing.latency_ns.get() <= MAX_LATENCY_NS,
+            "latency drifted to {} ns after {} cycles",
+            timing.latency_ns,
+            cycles
+        );
+
+        let now = clock.0.timestamp_ns();
+        if now >= next_report_ns {
+            next_report_ns += DAY_NS;
+            let heap = ALLOCATED_BYTES.load(Ordering::Relaxed);
+            let state_size = std::fs::metadata(&state_path).unwrap().len();
+            println!(
+                "day {}: {} cycles, heap {} bytes, state file {} bytes, missed windows {}",
+                now / DAY_NS,
+                cycles,
+                heap,
+                state_size,
+                timing.missed_windows
+            );
+            match baseline {
+                None => baseline = Some((heap, state_size)),
+                Some((baseline_heap, baseline_state_size)) => {
+                    assert!(
+                        heap - baseline_heap <= MAX_HEAP_GROWTH_BYTES,
+                        "heap grew by {} bytes since day 1",
+                        heap - baseline_heap
+                    );
+                    assert_eq!(state_size, baseline_state_size, "state file grew");
+                }
+            }
+        }
+    }
+
+    rx.initiate_shutdown.send(()).unwrap();
+    join_handle.await.unwrap().unwrap();
+    assert_eq!(rx.timing.borrow().missed_windows, 0);
+}